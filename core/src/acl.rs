@@ -0,0 +1,105 @@
+use crate::ban::Offense;
+use crate::filter::{Admission, DatagramFilter, Flow, StreamFilter};
+use crate::geoip::CountryFilter;
+use crate::state::SharedState;
+use ipnet::IpNet;
+use log::{debug, warn};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Client address filter built from a rule's `allow` / `deny` lists.
+// Deny entries always win; a non-empty allow list rejects everything else.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Acl {
+    pub fn from_lists(allow: &[String], deny: &[String]) -> anyhow::Result<Self> {
+        Ok(Self {
+            allow: parse_networks(allow)?,
+            deny: parse_networks(deny)?,
+        })
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 clients as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+// A rule's allow/deny and country lists, the first filter on its chain.
+// Denied clients count towards bans.
+pub struct AccessFilter {
+    rule_name: String,
+    acl: Acl,
+    countries: Option<CountryFilter>,
+    shared: Arc<SharedState>,
+}
+
+impl AccessFilter {
+    pub fn new(rule_name: String, acl: Acl, countries: Option<CountryFilter>, shared: Arc<SharedState>) -> Self {
+        Self { rule_name, acl, countries, shared }
+    }
+
+    // Which list denied the client, if one did
+    fn denied_by(&self, ip: IpAddr) -> Option<&'static str> {
+        if !self.acl.permits(ip) {
+            return Some("allow/deny rules");
+        }
+        if let (Some(countries), Some(geoip)) = (&self.countries, &self.shared.geoip)
+            && !countries.permits(geoip, ip)
+        {
+            return Some("country filter");
+        }
+        None
+    }
+
+    fn record_denial(&self, ip: IpAddr, offense: Offense) {
+        if let Some(bans) = &self.shared.bans {
+            bans.record(ip, offense);
+        }
+    }
+}
+
+impl StreamFilter for AccessFilter {
+    fn on_connect(&self, flow: &Flow) -> Admission {
+        let Some(list) = self.denied_by(flow.client_addr.ip()) else {
+            return Admission::Accept;
+        };
+        warn!("TCP forwarder '{}' rejecting {} by {}", self.rule_name, flow.client_addr, list);
+        self.record_denial(flow.client_addr.ip(), Offense::Denied);
+        Admission::Reject
+    }
+}
+
+impl DatagramFilter for AccessFilter {
+    // Denied clients get no session, so this runs for each of their
+    // datagrams; it only logs at debug level and the ban list counts
+    // a burst of them as one denial
+    fn on_session(&self, flow: &Flow) -> Admission {
+        let Some(list) = self.denied_by(flow.client_addr.ip()) else {
+            return Admission::Accept;
+        };
+        debug!("UDP forwarder '{}' dropping packet from {} by {}", self.rule_name, flow.client_addr, list);
+        self.record_denial(flow.client_addr.ip(), Offense::DeniedDatagram);
+        Admission::Reject
+    }
+}
+
+fn parse_networks(entries: &[String]) -> anyhow::Result<Vec<IpNet>> {
+    entries
+        .iter()
+        .map(|entry| {
+            IpNet::from_str(entry)
+                .or_else(|_| IpAddr::from_str(entry).map(IpNet::from))
+                .map_err(|_| anyhow::anyhow!("invalid CIDR or IP address '{}'", entry))
+        })
+        .collect()
+}
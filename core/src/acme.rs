@@ -0,0 +1,400 @@
+use crate::config::AcmeConfig;
+use crate::connector::connect_happy_eyeballs;
+use crate::http::{parse_response, HttpResponse};
+use crate::task::AbortOnDrop;
+use crate::tls::{self, CertResolver};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::{debug, error, info, warn};
+use rcgen::{CertificateParams, CustomExtension, KeyPair, PKCS_ECDSA_P256_SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{sleep, timeout};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: usize = 60;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_RESPONSE: usize = 1024 * 1024;
+
+// Keeps a TLS rule's certificate issued and renewed through ACME, answering
+// TLS-ALPN-01 challenges on the rule's own listener, until the returned
+// guard is dropped
+pub fn spawn(config: AcmeConfig, certs: Arc<CertResolver>, rule_name: String) -> AbortOnDrop {
+    AbortOnDrop::spawn(async move {
+        let cache = CertCache::new(&config);
+        loop {
+            let due = match cache.load() {
+                Ok(Some(cached)) => {
+                    certs.set_certificate(cached.key);
+                    // Renew once two thirds of the lifetime has passed
+                    let renew_at = cached.not_before + (cached.not_after - cached.not_before) * 2 / 3;
+                    unix_now() >= renew_at
+                }
+                Ok(None) => true,
+                Err(e) => {
+                    warn!("Ignoring cached certificate for '{}': {}", rule_name, e);
+                    true
+                }
+            };
+
+            if due {
+                info!("Requesting certificate for {:?} ('{}')", config.domains, rule_name);
+                match issue(&config, &certs, &cache).await {
+                    Ok(()) => {
+                        info!("Obtained certificate for {:?} ('{}')", config.domains, rule_name);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("ACME certificate request for '{}' failed: {:#}", rule_name, e);
+                        sleep(RETRY_INTERVAL).await;
+                        continue;
+                    }
+                }
+            }
+            sleep(CHECK_INTERVAL).await;
+        }
+    })
+}
+
+async fn issue(config: &AcmeConfig, certs: &CertResolver, cache: &CertCache) -> Result<()> {
+    let account_key = cache.account_key()?;
+    let mut client = AcmeClient::connect(config.directory_url(), account_key).await?;
+    client.register(config.email.as_deref()).await?;
+
+    let identifiers: Vec<Value> = config.domains.iter()
+        .map(|domain| json!({ "type": "dns", "value": domain }))
+        .collect();
+    let new_order = client.directory_url("newOrder")?;
+    let response = client.post(&new_order, Some(json!({ "identifiers": identifiers }))).await?;
+    let order_url = response.header("location").context("order has no Location")?.to_string();
+    let order = response.json()?;
+
+    for authorization in order["authorizations"].as_array().into_iter().flatten() {
+        let url = authorization.as_str().context("malformed authorization URL")?;
+        client.authorize(url, certs).await?;
+    }
+
+    let cert_key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+    let csr = CertificateParams::new(config.domains.clone())?.serialize_request(&cert_key)?;
+    let finalize = order["finalize"].as_str().context("order has no finalize URL")?;
+    client.post(finalize, Some(json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) }))).await?;
+
+    let order = client.poll(&order_url, &["pending", "ready", "processing"]).await?;
+    if order["status"] != "valid" {
+        anyhow::bail!("order ended in status {}: {}", order["status"], order["error"]);
+    }
+    let certificate_url = order["certificate"].as_str().context("order has no certificate URL")?;
+    let chain = client.post(certificate_url, None).await?.body;
+
+    cache.store(&chain, &cert_key.serialize_pem())
+}
+
+struct CachedCert {
+    key: Arc<rustls::sign::CertifiedKey>,
+    not_before: i64,
+    not_after: i64,
+}
+
+// Account key and issued certificate, kept across restarts so we don't
+// hit the CA's rate limits
+struct CertCache {
+    dir: PathBuf,
+    name: String,
+}
+
+impl CertCache {
+    fn new(config: &AcmeConfig) -> Self {
+        Self {
+            dir: PathBuf::from(&config.cache_dir),
+            name: config.domains[0].to_ascii_lowercase(),
+        }
+    }
+
+    fn cert_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.crt", self.name))
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.key", self.name))
+    }
+
+    fn load(&self) -> Result<Option<CachedCert>> {
+        let (cert_path, key_path) = (self.cert_path(), self.key_path());
+        if !cert_path.exists() || !key_path.exists() {
+            return Ok(None);
+        }
+        let certs = tls::load_certs(&cert_path.to_string_lossy())?;
+        let key = tls::load_private_key(&key_path.to_string_lossy())?;
+        let (_, parsed) = x509_parser::parse_x509_certificate(&certs[0])
+            .map_err(|e| anyhow::anyhow!("failed to parse cached certificate: {}", e))?;
+        let validity = parsed.validity();
+        Ok(Some(CachedCert {
+            not_before: validity.not_before.timestamp(),
+            not_after: validity.not_after.timestamp(),
+            key: tls::certified_key(certs, key)?,
+        }))
+    }
+
+    fn store(&self, chain: &[u8], key_pem: &str) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        write_private(&self.key_path(), key_pem.as_bytes())?;
+        std::fs::write(self.cert_path(), chain)?;
+        Ok(())
+    }
+
+    fn account_key(&self) -> Result<EcdsaKeyPair> {
+        let path = self.dir.join("account.key");
+        let key = if path.exists() {
+            KeyPair::from_pem(&std::fs::read_to_string(&path)?)
+                .with_context(|| format!("invalid ACME account key '{}'", path.display()))?
+        } else {
+            let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+            std::fs::create_dir_all(&self.dir)?;
+            write_private(&path, key.serialize_pem().as_bytes())?;
+            key
+        };
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der(), &SystemRandom::new())
+            .map_err(|e| anyhow::anyhow!("unusable ACME account key: {}", e))
+    }
+}
+
+fn write_private(path: &std::path::Path, contents: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)?;
+    Ok(())
+}
+
+// Just enough of RFC 8555 to order a certificate
+struct AcmeClient {
+    directory: Value,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    nonce: Option<String>,
+    account_url: Option<String>,
+}
+
+impl AcmeClient {
+    async fn connect(directory_url: &str, key: EcdsaKeyPair) -> Result<Self> {
+        let directory = http_request("GET", directory_url, None).await?.json()?;
+        Ok(Self {
+            directory,
+            key,
+            rng: SystemRandom::new(),
+            nonce: None,
+            account_url: None,
+        })
+    }
+
+    fn directory_url(&self, name: &str) -> Result<String> {
+        self.directory[name].as_str()
+            .map(str::to_string)
+            .with_context(|| format!("ACME directory has no {}", name))
+    }
+
+    async fn register(&mut self, email: Option<&str>) -> Result<()> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let new_account = self.directory_url("newAccount")?;
+        let response = self.post(&new_account, Some(payload)).await?;
+        let account_url = response.header("location").context("account has no Location")?;
+        self.account_url = Some(account_url.to_string());
+        Ok(())
+    }
+
+    async fn authorize(&mut self, url: &str, certs: &CertResolver) -> Result<()> {
+        let authorization = self.post(url, None).await?.json()?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let domain = authorization["identifier"]["value"].as_str()
+            .context("authorization has no identifier")?
+            .to_string();
+        let challenge = authorization["challenges"].as_array().into_iter().flatten()
+            .find(|c| c["type"] == "tls-alpn-01")
+            .with_context(|| format!("CA offered no tls-alpn-01 challenge for {}", domain))?;
+        let token = challenge["token"].as_str().context("challenge has no token")?;
+        let challenge_url = challenge["url"].as_str().context("challenge has no URL")?.to_string();
+
+        let key_authorization = format!("{}.{}", token, self.thumbprint());
+        let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+        let mut params = CertificateParams::new(vec![domain.clone()])?;
+        params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+        let challenge_key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+        let challenge_cert = params.self_signed(&challenge_key)?;
+        let private_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(challenge_key.serialize_der()));
+        certs.add_challenge(&domain, tls::certified_key(vec![challenge_cert.der().clone()], private_key)?);
+
+        debug!("Answering tls-alpn-01 challenge for {}", domain);
+        let result = async {
+            self.post(&challenge_url, Some(json!({}))).await?;
+            self.poll(url, &["pending", "processing"]).await
+        }.await;
+        certs.remove_challenge(&domain);
+
+        let authorization = result?;
+        if authorization["status"] != "valid" {
+            let errors: Vec<&Value> = authorization["challenges"].as_array().into_iter().flatten()
+                .filter_map(|c| c.get("error"))
+                .collect();
+            anyhow::bail!("authorization for {} failed: {:?}", domain, errors);
+        }
+        Ok(())
+    }
+
+    // POST-as-GET `url` until its status leaves `pending_states`
+    async fn poll(&mut self, url: &str, pending_states: &[&str]) -> Result<Value> {
+        for _ in 0..MAX_POLLS {
+            let resource = self.post(url, None).await?.json()?;
+            let status = resource["status"].as_str().unwrap_or_default();
+            if !pending_states.contains(&status) {
+                return Ok(resource);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        anyhow::bail!("gave up waiting on {}", url)
+    }
+
+    // A `None` payload is a POST-as-GET
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<HttpResponse> {
+        // A stale nonce is rejected with badNonce; retry once with the fresh one
+        for attempt in 0..2 {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let body = self.sign(url, &nonce, payload.as_ref())?;
+            let response = http_request("POST", url, Some(body)).await?;
+            self.nonce = response.header("replay-nonce").map(str::to_string);
+
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let problem = response.json().unwrap_or_default();
+            if attempt == 0 && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+            anyhow::bail!("ACME request to {} failed ({}): {}", url, response.status, problem);
+        }
+        unreachable!()
+    }
+
+    async fn new_nonce(&self) -> Result<String> {
+        let response = http_request("HEAD", &self.directory_url("newNonce")?, None).await?;
+        response.header("replay-nonce")
+            .map(str::to_string)
+            .context("CA returned no Replay-Nonce")
+    }
+
+    fn jwk(&self) -> Value {
+        // Uncompressed point: 0x04 || x || y
+        let public = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&public[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&public[33..65]),
+        })
+    }
+
+    // RFC 7638 thumbprint; serde_json keeps keys sorted, as required
+    fn thumbprint(&self) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, self.jwk().to_string().as_bytes());
+        URL_SAFE_NO_PAD.encode(digest.as_ref())
+    }
+
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<String> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.account_url {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload
+            .map(|p| URL_SAFE_NO_PAD.encode(p.to_string()))
+            .unwrap_or_default();
+        let signature = self.key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow::anyhow!("failed to sign ACME request"))?;
+        let jws = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        });
+        Ok(jws.to_string())
+    }
+}
+
+// One-shot HTTPS request with `Connection: close`
+async fn http_request(method: &str, url: &str, body: Option<String>) -> Result<HttpResponse> {
+    timeout(HTTP_TIMEOUT, send_request(method, url, body))
+        .await
+        .with_context(|| format!("{} {} timed out", method, url))?
+}
+
+async fn send_request(method: &str, url: &str, body: Option<String>) -> Result<HttpResponse> {
+    let rest = url.strip_prefix("https://").with_context(|| format!("not an https URL: {}", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse()?),
+        _ => (authority, 443),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let stream = connect_happy_eyeballs(host, port).await?;
+    let connector = tls::connector(None, false)?;
+    let mut stream = connector.connect(tls::server_name(host)?, stream).await?;
+
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: porture/{}\r\nAccept: */*\r\nConnection: close\r\n",
+        method, path, authority, env!("CARGO_PKG_VERSION")
+    );
+    if let Some(ref body) = body {
+        request.push_str(&format!("Content-Type: application/jose+json\r\nContent-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    request.push_str(body.as_deref().unwrap_or_default());
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut raw = Vec::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        // Servers often skip close_notify; treat that as a normal end of body
+        let n = match stream.read(&mut buffer).await {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            break;
+        }
+        raw.extend_from_slice(&buffer[..n]);
+        if raw.len() > MAX_RESPONSE {
+            anyhow::bail!("response from {} too large", url);
+        }
+    }
+    parse_response(&raw, method == "HEAD")
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
@@ -0,0 +1,134 @@
+use crate::sockopt::{self, BindOptions};
+use crate::state::SharedState;
+use anyhow::Result;
+use log::{debug, error, info};
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const MAX_REQUEST_HEAD: usize = 8192;
+
+struct Response {
+    status: &'static str,
+    body: serde_json::Value,
+}
+
+impl Response {
+    fn ok(body: serde_json::Value) -> Self {
+        Self { status: "200 OK", body }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Self { status, body: json!({ "error": message }) }
+    }
+}
+
+// Minimal JSON-over-HTTP admin API. Bind it to localhost: there is no auth.
+pub async fn serve(addr: SocketAddr, shared: Arc<SharedState>) -> Result<()> {
+    let listener = sockopt::tcp_listener(addr, BindOptions::default())?;
+    info!("Admin API listening on {}", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_request(stream, &shared).await {
+                        debug!("Admin API request from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept admin API connection: {}", e),
+        }
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, shared: &SharedState) -> Result<()> {
+    let head = timeout(Duration::from_secs(5), read_request_head(&mut stream)).await??;
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    let response = route(method, path, shared);
+    let body = response.body.to_string();
+    let reply = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        body.len(),
+        body
+    );
+    stream.write_all(reply.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_request_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buffer[..n]);
+        if head.len() > MAX_REQUEST_HEAD {
+            anyhow::bail!("request head too large");
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn route(method: &str, path: &str, shared: &SharedState) -> Response {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["bans"]) => match &shared.bans {
+            Some(bans) => Response::ok(json!(bans.active_bans())),
+            None => Response::ok(json!([])),
+        },
+        ("DELETE", ["bans", ip]) => {
+            let Ok(ip) = IpAddr::from_str(ip) else {
+                return Response::error("400 Bad Request", "invalid IP address");
+            };
+            match &shared.bans {
+                Some(bans) if bans.unban(ip) => Response::ok(json!({ "unbanned": ip })),
+                _ => Response::error("404 Not Found", "no such ban"),
+            }
+        }
+        ("POST", ["tls", "reload"]) => {
+            let results: Vec<serde_json::Value> = shared.certificates.reload(true)
+                .into_iter()
+                .map(|(rule, error)| match error {
+                    Some(error) => json!({ "rule": rule, "reloaded": false, "error": error }),
+                    None => json!({ "rule": rule, "reloaded": true }),
+                })
+                .collect();
+            Response::ok(json!(results))
+        }
+        ("GET", ["udp", "sessions"]) => {
+            let rules: Vec<serde_json::Value> = shared.udp_sessions.snapshot()
+                .into_iter()
+                .map(|(rule, max_sessions, evicted)| json!({ "rule": rule, "max_sessions": max_sessions, "evicted": evicted }))
+                .collect();
+            Response::ok(json!(rules))
+        }
+        ("GET", ["captures"]) => Response::ok(json!(shared.captures.snapshot())),
+        ("POST", ["captures", protocol, rule]) => match shared.captures.get(protocol, rule) {
+            Some(capture) => match capture.start() {
+                Ok(()) => Response::ok(capture.status()),
+                Err(e) => Response::error("500 Internal Server Error", &format!("failed to start capture: {}", e)),
+            },
+            None => Response::error("404 Not Found", "no such rule with a capture_file"),
+        },
+        ("DELETE", ["captures", protocol, rule]) => match shared.captures.get(protocol, rule) {
+            Some(capture) if capture.stop() => Response::ok(capture.status()),
+            Some(_) => Response::error("409 Conflict", "not capturing"),
+            None => Response::error("404 Not Found", "no such rule with a capture_file"),
+        },
+        _ => Response::error("404 Not Found", "not found"),
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::BanConfig;
+use log::warn;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Spoofed UDP sources each create a record, so stop tracking new clients
+// past this many until prune() makes room
+const MAX_CLIENTS: usize = 65536;
+
+const DATAGRAM_DENIAL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Client behaviour that counts towards a ban
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    // Any new connection, so floods trip `max_connects`
+    Connect,
+    // Connection closed quickly without sending anything
+    ShortSession,
+    // Rejected by allow/deny or country rules
+    Denied,
+    // A UDP client's datagrams rejected by allow/deny or country rules;
+    // counts towards `max_denials` at most once per DATAGRAM_DENIAL_INTERVAL
+    // so a single burst of packets isn't a string of offenses
+    DeniedDatagram,
+}
+
+impl Offense {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Offense::Connect => "connect flood",
+            Offense::ShortSession => "repeated immediate disconnects",
+            Offense::Denied | Offense::DeniedDatagram => "repeatedly denied by access rules",
+        }
+    }
+}
+
+#[derive(Default)]
+struct ClientRecord {
+    connects: VecDeque<Instant>,
+    short_sessions: VecDeque<Instant>,
+    denials: VecDeque<Instant>,
+    datagram_denied: Option<Instant>,
+    banned_until: Option<Instant>,
+    reason: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BanEntry {
+    pub ip: IpAddr,
+    pub reason: String,
+    pub expires_in_secs: u64,
+    pub expires_at_unix: u64,
+}
+
+// fail2ban-style ban list shared by every rule
+pub struct BanList {
+    config: BanConfig,
+    clients: Mutex<HashMap<IpAddr, ClientRecord>>,
+}
+
+impl BanList {
+    pub fn new(config: BanConfig) -> Self {
+        Self {
+            config,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let clients = self.clients.lock().unwrap();
+        clients
+            .get(&ip)
+            .and_then(|record| record.banned_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    // Returns true if the client is banned after this offense
+    pub fn record(&self, ip: IpAddr, offense: Offense) -> bool {
+        let limit = match offense {
+            Offense::Connect => self.config.max_connects,
+            Offense::ShortSession => self.config.max_short_sessions,
+            Offense::Denied | Offense::DeniedDatagram => self.config.max_denials,
+        };
+        let Some(limit) = limit else {
+            return false;
+        };
+
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_seconds());
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(&ip) {
+            return false;
+        }
+        let record = clients.entry(ip).or_default();
+        if record.banned_until.is_some_and(|until| until > now) {
+            return true;
+        }
+        if offense == Offense::DeniedDatagram {
+            if record.datagram_denied.is_some_and(|t| now.duration_since(t) < DATAGRAM_DENIAL_INTERVAL) {
+                return false;
+            }
+            record.datagram_denied = Some(now);
+        }
+
+        let events = match offense {
+            Offense::Connect => &mut record.connects,
+            Offense::ShortSession => &mut record.short_sessions,
+            Offense::Denied | Offense::DeniedDatagram => &mut record.denials,
+        };
+        while events.front().is_some_and(|t| now.duration_since(*t) > window) {
+            events.pop_front();
+        }
+        events.push_back(now);
+
+        if events.len() > limit {
+            let duration = Duration::from_secs(self.config.ban_duration_seconds());
+            warn!("Banning {} for {}s: {}", ip, duration.as_secs(), offense.as_str());
+            *record = ClientRecord {
+                banned_until: Some(now + duration),
+                reason: Some(offense.as_str()),
+                ..Default::default()
+            };
+            return true;
+        }
+        false
+    }
+
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        clients.remove(&ip.to_canonical()).is_some_and(|r| r.banned_until.is_some())
+    }
+
+    pub fn active_bans(&self) -> Vec<BanEntry> {
+        let now = Instant::now();
+        let wall_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let clients = self.clients.lock().unwrap();
+        clients
+            .iter()
+            .filter_map(|(ip, record)| {
+                let remaining = record.banned_until?.checked_duration_since(now)?;
+                Some(BanEntry {
+                    ip: *ip,
+                    reason: record.reason.unwrap_or_default().to_string(),
+                    expires_in_secs: remaining.as_secs(),
+                    expires_at_unix: wall_now + remaining.as_secs(),
+                })
+            })
+            .collect()
+    }
+
+    // Forget expired bans and clients with no recent offenses
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_seconds());
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, record| {
+            if let Some(until) = record.banned_until {
+                return until > now;
+            }
+            [&record.connects, &record.short_sessions, &record.denials]
+                .iter()
+                .any(|events| events.back().is_some_and(|t| now.duration_since(*t) <= window))
+        });
+    }
+}
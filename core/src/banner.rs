@@ -0,0 +1,81 @@
+use crate::config::BannerConfig;
+use crate::filter::{Admission, DatagramFilter, Direction, Flow, FlowFilter, StreamFilter, Verdict};
+use std::sync::Arc;
+
+// Bytes sent at the start of every connection (a rule's banner list), such
+// as a greeting or handshake one side expects but the other doesn't send.
+// TCP rules send them as soon as the target is connected, before anything
+// either side sent; UDP rules put them in front of a session's first
+// datagram in the direction.
+
+struct Banner {
+    upload: Vec<u8>,
+    download: Vec<u8>,
+}
+
+pub struct BannerFilter(Arc<Banner>);
+
+impl BannerFilter {
+    pub fn new(banners: &[BannerConfig]) -> anyhow::Result<Self> {
+        let mut banner = Banner { upload: Vec::new(), download: Vec::new() };
+        for config in banners {
+            let bytes = config.bytes()?;
+            let direction = config.direction();
+            if direction.upload() {
+                banner.upload.extend_from_slice(&bytes);
+            }
+            if direction.download() {
+                banner.download.extend_from_slice(&bytes);
+            }
+        }
+        Ok(Self(Arc::new(banner)))
+    }
+}
+
+impl StreamFilter for BannerFilter {
+    fn on_connect(&self, _flow: &Flow) -> Admission {
+        Admission::Watch(Box::new(StreamBanner(self.0.clone())))
+    }
+}
+
+impl DatagramFilter for BannerFilter {
+    fn on_session(&self, _flow: &Flow) -> Admission {
+        Admission::Watch(Box::new(DatagramBanner { banner: self.0.clone(), uploaded: false, downloaded: false }))
+    }
+}
+
+struct StreamBanner(Arc<Banner>);
+
+impl FlowFilter for StreamBanner {
+    fn on_data(&mut self, _direction: Direction, _data: &mut Vec<u8>) -> Verdict {
+        Verdict::Pass
+    }
+
+    fn prefix(&mut self, direction: Direction) -> Option<Vec<u8>> {
+        let bytes = match direction {
+            Direction::Upload => &self.0.upload,
+            Direction::Download => &self.0.download,
+        };
+        Some(bytes.clone())
+    }
+}
+
+struct DatagramBanner {
+    banner: Arc<Banner>,
+    // Whether the first datagram in each direction went by
+    uploaded: bool,
+    downloaded: bool,
+}
+
+impl FlowFilter for DatagramBanner {
+    fn on_data(&mut self, direction: Direction, data: &mut Vec<u8>) -> Verdict {
+        let (sent, bytes) = match direction {
+            Direction::Upload => (&mut self.uploaded, &self.banner.upload),
+            Direction::Download => (&mut self.downloaded, &self.banner.download),
+        };
+        if !std::mem::replace(sent, true) {
+            data.splice(0..0, bytes.iter().copied());
+        }
+        Verdict::Pass
+    }
+}
@@ -0,0 +1,102 @@
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+
+// Buffers for data on its way through porture, kept for reuse once a
+// connection, session or datagram is done with them instead of freed, so a
+// busy rule doesn't allocate for every connection and datagram. Each thread
+// keeps a pool of its own, so forwarders on different runtime workers never
+// wait for each other; a buffer goes back to the pool of whichever thread
+// drops it. Buffers are handed out at whatever size is asked for, and grow
+// if they have to.
+
+// Idle capacity kept per thread: a few hundred connections' worth at the
+// default buffer_size
+const MAX_POOLED_BYTES: usize = 4 * 1024 * 1024;
+// Larger buffers, e.g. for GRO batches, are freed rather than kept
+const MAX_POOLED_CAPACITY: usize = 256 * 1024;
+
+#[derive(Default)]
+struct Pool {
+    buffers: Vec<Vec<u8>>,
+    // Capacity of `buffers`
+    bytes: usize,
+}
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::default();
+}
+
+// A Vec<u8> that goes back to the pool when dropped. The default is empty,
+// with nothing to give back.
+#[derive(Default)]
+pub struct Buffer(Vec<u8>);
+
+impl Buffer {
+    // `len` zeroed bytes, e.g. to read into
+    pub fn zeroed(len: usize) -> Self {
+        let mut buffer = Self::empty();
+        buffer.resize(len, 0);
+        buffer
+    }
+
+    pub fn copy_of(data: &[u8]) -> Self {
+        let mut buffer = Self::empty();
+        buffer.extend_from_slice(data);
+        buffer
+    }
+
+    // Nothing in it yet, and room for at least `capacity` bytes to read into
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut buffer = Self::empty();
+        buffer.reserve(capacity);
+        buffer
+    }
+
+    fn empty() -> Self {
+        let data = POOL.with_borrow_mut(|pool| {
+            let data = pool.buffers.pop()?;
+            pool.bytes -= data.capacity();
+            Some(data)
+        });
+        Self(data.unwrap_or_default())
+    }
+}
+
+// Data allocated elsewhere, kept in the pool afterwards
+impl From<Vec<u8>> for Buffer {
+    fn from(data: Vec<u8>) -> Self {
+        Self(data)
+    }
+}
+
+impl Deref for Buffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let mut data = std::mem::take(&mut self.0);
+        if data.capacity() == 0 || data.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        data.clear();
+        // Not while the thread is exiting and its pool is gone
+        let _ = POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.bytes + data.capacity() <= MAX_POOLED_BYTES {
+                pool.bytes += data.capacity();
+                pool.buffers.push(data);
+            }
+        });
+    }
+}
@@ -0,0 +1,49 @@
+use crate::config::TcpMode;
+use tokio::io::{self, DuplexStream};
+
+// The test servers of mode = "echo" and mode = "discard", which stand in for
+// a target so a rule, firewall or NAT can be checked without a backend. A
+// TCP connection gets an in-memory stream to a server of its own, so it still
+// passes through the rule's filters, limits and idle_timeout; UDP sessions
+// are answered (or not) by the forwarder directly.
+
+// Room for a typical read in each direction before the server has to catch up
+const PIPE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Server {
+    // Sends back everything it receives
+    Echo,
+    // Reads everything and never answers
+    Discard,
+}
+
+impl Server {
+    pub fn for_mode(mode: TcpMode) -> Option<Self> {
+        match mode {
+            TcpMode::Echo => Some(Server::Echo),
+            TcpMode::Discard => Some(Server::Discard),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Server::Echo => "echo",
+            Server::Discard => "discard",
+        }
+    }
+
+    // A connection to the server, served until it is dropped
+    pub fn connect(self) -> DuplexStream {
+        let (client, server) = io::duplex(PIPE_SIZE);
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = io::split(server);
+            let _ = match self {
+                Server::Echo => io::copy(&mut reader, &mut writer).await,
+                Server::Discard => io::copy(&mut reader, &mut io::sink()).await,
+            };
+        });
+        client
+    }
+}
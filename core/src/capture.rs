@@ -0,0 +1,441 @@
+use crate::config::{TcpRule, UdpRule};
+use crate::filter::{Admission, DatagramFilter, Direction, Flow, FlowFilter, StreamFilter, Verdict};
+use log::{info, warn};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Packet captures of a rule's traffic (capture_file), for debugging without
+// tcpdump on the host. What the rule forwards is written as pcap with
+// made-up IP, TCP and UDP headers between the client and the address it
+// reached: a TCP connection gets a handshake, sequence numbers and a FIN on
+// each side, so Wireshark can follow it. The data is as the client and
+// target see it after the rule's rewrites, without banners. A thread per
+// capture writes the file; packets it can't keep up with are dropped and
+// counted.
+
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+// Data per packet, so the lengths fit the IP headers
+const MAX_SEGMENT: usize = 65000;
+const QUEUE: usize = 4096;
+// How soon the writer notices being stopped, and flushes when idle
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+#[derive(Clone, PartialEq, Eq)]
+struct Settings {
+    path: String,
+    max_bytes: Option<u64>,
+    duration: Option<Duration>,
+    on_start: bool,
+}
+
+impl Settings {
+    fn new(path: &str, max_mb: Option<u64>, seconds: Option<u64>, on_start: Option<bool>) -> Self {
+        Self {
+            path: path.to_string(),
+            max_bytes: max_mb.map(|mb| mb << 20),
+            duration: seconds.map(Duration::from_secs),
+            on_start: on_start.unwrap_or(true),
+        }
+    }
+}
+
+// A rule's capture, running or not
+pub struct Capture {
+    protocol: &'static str,
+    rule_name: String,
+    settings: Settings,
+    recording: Mutex<Option<Arc<Recording>>>,
+    // The thread writing the file, which a new run waits for
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+// One run of a capture, from starting it to its file being closed
+struct Recording {
+    tx: SyncSender<Vec<u8>>,
+    running: AtomicBool,
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Recording {
+    fn record(&self, record: Vec<u8>) {
+        match self.tx.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl Capture {
+    // Starts (again) from an empty file
+    pub fn start(self: &Arc<Self>) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.stop();
+        if let Some(previous) = writer.take() {
+            let _ = previous.join();
+        }
+        let mut file = BufWriter::new(File::create(&self.settings.path)?);
+        file.write_all(&global_header())?;
+        file.flush()?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE);
+        let recording = Arc::new(Recording {
+            tx,
+            running: AtomicBool::new(true),
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(24),
+            dropped: AtomicU64::new(0),
+        });
+        *self.recording.lock().unwrap() = Some(recording.clone());
+        let capture = self.clone();
+        *writer = Some(std::thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || capture.write(file, rx, &recording))?);
+        info!("Capturing {} rule '{}' to {}", self.protocol, self.rule_name, self.settings.path);
+        Ok(())
+    }
+
+    // False if it wasn't running
+    pub fn stop(&self) -> bool {
+        match self.recording.lock().unwrap().as_ref() {
+            Some(recording) => recording.running.swap(false, Ordering::Relaxed),
+            None => false,
+        }
+    }
+
+    // Stops and waits for the file to be closed
+    fn finish(&self) {
+        self.stop();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let _ = writer.join();
+        }
+    }
+
+    fn running(&self) -> Option<Arc<Recording>> {
+        self.recording.lock().unwrap().clone().filter(|recording| recording.running.load(Ordering::Relaxed))
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        let recording = self.recording.lock().unwrap().clone();
+        let running = recording.as_ref().is_some_and(|recording| recording.running.load(Ordering::Relaxed));
+        let count = |counter: fn(&Recording) -> &AtomicU64| {
+            recording.as_deref().map_or(0, |recording| counter(recording).load(Ordering::Relaxed))
+        };
+        serde_json::json!({
+            "protocol": self.protocol,
+            "rule": self.rule_name,
+            "file": self.settings.path,
+            "capturing": running,
+            "packets": count(|recording| &recording.packets),
+            "bytes": count(|recording| &recording.bytes),
+            "dropped": count(|recording| &recording.dropped),
+        })
+    }
+
+    // Until the capture is stopped or restarted, or reaches a limit
+    fn write(&self, mut file: BufWriter<File>, rx: mpsc::Receiver<Vec<u8>>, recording: &Recording) {
+        let deadline = self.settings.duration.map(|duration| Instant::now() + duration);
+        let reason = loop {
+            if !recording.running.load(Ordering::Relaxed) {
+                break "stopped".to_string();
+            }
+            // Wakes up now and then to notice being stopped
+            let wait = deadline.map_or(POLL_INTERVAL, |deadline| {
+                deadline.saturating_duration_since(Instant::now()).min(POLL_INTERVAL)
+            });
+            let record = match rx.recv_timeout(wait) {
+                Ok(record) => record,
+                Err(RecvTimeoutError::Timeout) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                    break "time limit reached".to_string();
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = file.flush();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break "stopped".to_string(),
+            };
+            let size = recording.bytes.load(Ordering::Relaxed) + record.len() as u64;
+            if self.settings.max_bytes.is_some_and(|max| size > max) {
+                break "size limit reached".to_string();
+            }
+            if let Err(e) = file.write_all(&record) {
+                break format!("failed to write: {}", e);
+            }
+            recording.bytes.store(size, Ordering::Relaxed);
+            recording.packets.fetch_add(1, Ordering::Relaxed);
+        };
+        recording.running.store(false, Ordering::Relaxed);
+        let flushed = file.flush();
+        match flushed {
+            Ok(()) => info!("Capture of {} rule '{}' to {} ended: {}", self.protocol, self.rule_name, self.settings.path, reason),
+            Err(e) => warn!("Capture of {} rule '{}' to {} ended: {}", self.protocol, self.rule_name, self.settings.path, e),
+        }
+    }
+
+    fn admit(&self, flow: &Flow, tcp: bool) -> Admission {
+        let Some(recording) = self.running() else {
+            return Admission::Accept;
+        };
+        let (client, local) = same_family(flow.client_addr, flow.local_addr);
+        let mut flow = CaptureFlow { recording, client, local, tcp, upload_seq: 0, download_seq: 0 };
+        if tcp {
+            flow.tcp_packet(Direction::Upload, TCP_SYN, &[]);
+            flow.upload_seq += 1;
+            flow.tcp_packet(Direction::Download, TCP_SYN | TCP_ACK, &[]);
+            flow.download_seq += 1;
+            flow.tcp_packet(Direction::Upload, TCP_ACK, &[]);
+        }
+        Admission::Watch(Box::new(flow))
+    }
+}
+
+impl StreamFilter for Capture {
+    fn on_connect(&self, flow: &Flow) -> Admission {
+        self.admit(flow, true)
+    }
+}
+
+impl DatagramFilter for Capture {
+    fn on_session(&self, flow: &Flow) -> Admission {
+        self.admit(flow, false)
+    }
+}
+
+// The rules' captures, for the admin API to start and stop
+#[derive(Default)]
+pub struct CaptureRegistry {
+    captures: Mutex<Vec<Arc<Capture>>>,
+}
+
+impl CaptureRegistry {
+    // The rule's capture, started if it should be. A rule restarted after
+    // failing keeps its running capture, unless its settings changed.
+    pub fn tcp(&self, rule: &TcpRule) -> Option<Arc<Capture>> {
+        let path = rule.capture_file.as_deref()?;
+        let settings = Settings::new(path, rule.capture_max_mb, rule.capture_seconds, rule.capture_on_start);
+        Some(self.open("tcp", rule.rule_name(), settings))
+    }
+
+    pub fn udp(&self, rule: &UdpRule) -> Option<Arc<Capture>> {
+        let path = rule.capture_file.as_deref()?;
+        let settings = Settings::new(path, rule.capture_max_mb, rule.capture_seconds, rule.capture_on_start);
+        Some(self.open("udp", rule.rule_name(), settings))
+    }
+
+    fn open(&self, protocol: &'static str, rule_name: String, settings: Settings) -> Arc<Capture> {
+        let mut captures = self.captures.lock().unwrap();
+        if let Some(capture) = captures.iter()
+            .find(|c| c.protocol == protocol && c.rule_name == rule_name && c.settings == settings)
+        {
+            return capture.clone();
+        }
+        captures.retain(|c| {
+            let replaced = c.protocol == protocol && c.rule_name == rule_name;
+            if replaced {
+                c.finish();
+            }
+            !replaced
+        });
+        let capture = Arc::new(Capture {
+            protocol,
+            rule_name,
+            settings,
+            recording: Mutex::new(None),
+            writer: Mutex::new(None),
+        });
+        if capture.settings.on_start
+            && let Err(e) = capture.start()
+        {
+            warn!("Failed to start capture of {} rule '{}' to {}: {}", protocol, capture.rule_name, capture.settings.path, e);
+        }
+        captures.push(capture.clone());
+        capture
+    }
+
+    pub fn unregister(&self, protocol: &str, rule_name: &str) {
+        self.captures.lock().unwrap().retain(|c| {
+            let removed = c.protocol == protocol && c.rule_name == rule_name;
+            if removed {
+                c.finish();
+            }
+            !removed
+        });
+    }
+
+    pub fn get(&self, protocol: &str, rule_name: &str) -> Option<Arc<Capture>> {
+        self.captures.lock().unwrap().iter().find(|c| c.protocol == protocol && c.rule_name == rule_name).cloned()
+    }
+
+    pub fn snapshot(&self) -> Vec<serde_json::Value> {
+        self.captures.lock().unwrap().iter().map(|capture| capture.status()).collect()
+    }
+}
+
+// One connection's or session's packets
+struct CaptureFlow {
+    recording: Arc<Recording>,
+    client: SocketAddr,
+    local: SocketAddr,
+    tcp: bool,
+    upload_seq: u32,
+    download_seq: u32,
+}
+
+impl CaptureFlow {
+    fn endpoints(&self, direction: Direction) -> (SocketAddr, SocketAddr) {
+        match direction {
+            Direction::Upload => (self.client, self.local),
+            Direction::Download => (self.local, self.client),
+        }
+    }
+
+    fn tcp_packet(&mut self, direction: Direction, flags: u8, payload: &[u8]) {
+        let (seq, ack) = match direction {
+            Direction::Upload => (self.upload_seq, self.download_seq),
+            Direction::Download => (self.download_seq, self.upload_seq),
+        };
+        let mut segment = Vec::with_capacity(20 + payload.len());
+        let (source, destination) = self.endpoints(direction);
+        segment.extend_from_slice(&source.port().to_be_bytes());
+        segment.extend_from_slice(&destination.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        // The first SYN acknowledges nothing
+        let ack = if flags & TCP_ACK != 0 { ack } else { 0 };
+        segment.extend_from_slice(&ack.to_be_bytes());
+        segment.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        segment.extend_from_slice(payload);
+        self.record(direction, 6, &segment);
+        let sent = payload.len() as u32;
+        match direction {
+            Direction::Upload => self.upload_seq = self.upload_seq.wrapping_add(sent),
+            Direction::Download => self.download_seq = self.download_seq.wrapping_add(sent),
+        }
+    }
+
+    fn udp_packet(&self, direction: Direction, payload: &[u8]) {
+        let (source, destination) = self.endpoints(direction);
+        let mut datagram = Vec::with_capacity(8 + payload.len());
+        datagram.extend_from_slice(&source.port().to_be_bytes());
+        datagram.extend_from_slice(&destination.port().to_be_bytes());
+        datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(payload);
+        self.record(direction, 17, &datagram);
+    }
+
+    // Wraps a TCP segment or UDP datagram in an IP header and a pcap record
+    fn record(&self, direction: Direction, protocol: u8, transport: &[u8]) {
+        let (source, destination) = self.endpoints(direction);
+        let packet = ip_packet(source.ip(), destination.ip(), protocol, transport);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+        self.recording.record(record);
+    }
+}
+
+impl FlowFilter for CaptureFlow {
+    fn on_data(&mut self, direction: Direction, data: &mut Vec<u8>) -> Verdict {
+        if self.recording.running.load(Ordering::Relaxed) {
+            for segment in data.chunks(MAX_SEGMENT) {
+                match self.tcp {
+                    true => self.tcp_packet(direction, TCP_PSH | TCP_ACK, segment),
+                    false => self.udp_packet(direction, segment),
+                }
+            }
+        }
+        Verdict::Pass
+    }
+}
+
+impl Drop for CaptureFlow {
+    fn drop(&mut self) {
+        if self.tcp && self.recording.running.load(Ordering::Relaxed) {
+            self.tcp_packet(Direction::Upload, TCP_FIN | TCP_ACK, &[]);
+            self.upload_seq = self.upload_seq.wrapping_add(1);
+            self.tcp_packet(Direction::Download, TCP_FIN | TCP_ACK, &[]);
+            self.download_seq = self.download_seq.wrapping_add(1);
+            self.tcp_packet(Direction::Upload, TCP_ACK, &[]);
+        }
+    }
+}
+
+// Microsecond timestamps, raw IP packets
+fn global_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+// Both as IPv4, or both as IPv6 if either can't be
+fn same_family(client: SocketAddr, local: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let (client_ip, local_ip) = (client.ip().to_canonical(), local.ip().to_canonical());
+    let (client_ip, local_ip) = match (client_ip, local_ip) {
+        (IpAddr::V4(client_ip), IpAddr::V6(local_ip)) => (IpAddr::V6(client_ip.to_ipv6_mapped()), IpAddr::V6(local_ip)),
+        (IpAddr::V6(client_ip), IpAddr::V4(local_ip)) => (IpAddr::V6(client_ip), IpAddr::V6(local_ip.to_ipv6_mapped())),
+        ips => ips,
+    };
+    (SocketAddr::new(client_ip, client.port()), SocketAddr::new(local_ip, local.port()))
+}
+
+// Transport checksums are left at zero, which Wireshark doesn't check by
+// default
+fn ip_packet(source: IpAddr, destination: IpAddr, protocol: u8, transport: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(40 + transport.len());
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let total = (20 + transport.len()) as u16;
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+            let checksum = ipv4_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (source, destination) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(transport.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[protocol, 64]);
+            packet.extend_from_slice(&to_v6(source).octets());
+            packet.extend_from_slice(&to_v6(destination).octets());
+        }
+    }
+    packet.extend_from_slice(transport);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2).map(|pair| u32::from(u16::from_be_bytes([pair[0], pair[1]]))).sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
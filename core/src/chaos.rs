@@ -0,0 +1,260 @@
+use crate::buffer::Buffer;
+use crate::config::UdpRule;
+use crate::discovery::random_below;
+use crate::rule_log;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep, sleep, sleep_until, timeout_at};
+
+// Latency added to forwarded data (a rule's inject_delay_ms and
+// inject_jitter_ms), to try clients and servers out on a slow network.
+// Every chunk or datagram is held for the delay, give or take up to the
+// jitter, without holding up the ones behind it. A TCP connection's data
+// stays in order, so jitter only spreads it out; UDP datagrams can overtake
+// each other, as they would on a real network.
+
+// UDP rules can lose, duplicate and reorder datagrams as well
+// (inject_drop_percent, inject_duplicate_percent and
+// inject_reorder_window). Each direction of a session then has a task its
+// datagrams go through one by one, coalesced ones split up, and which sends
+// them on.
+
+// Chunks a TCP connection reads ahead per direction while they are held
+const QUEUE: usize = 32;
+// Datagrams waiting for a session's task; more are dropped
+const PIPE_QUEUE: usize = 1024;
+// The longest a datagram waits for its reorder window to fill
+const REORDER_HOLD: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Latency {
+    delay_ms: u64,
+    jitter_ms: u64,
+}
+
+impl Latency {
+    // None if the rule adds no delay
+    pub fn new(delay_ms: Option<u64>, jitter_ms: Option<u64>) -> Option<Self> {
+        let delay_ms = delay_ms.filter(|ms| *ms > 0)?;
+        Some(Self { delay_ms, jitter_ms: jitter_ms.unwrap_or(0).min(delay_ms) })
+    }
+
+    // How long to hold the next chunk or datagram
+    pub fn sample(&self) -> Duration {
+        let spread = match self.jitter_ms {
+            0 => 0,
+            jitter => random_below((jitter * 2 + 1).min(u32::MAX as u64) as u32) as u64,
+        };
+        Duration::from_millis(self.delay_ms - self.jitter_ms + spread)
+    }
+
+    // A TCP connection's reader, with what it reads held for the delay.
+    // The end of the stream is held too, so the close arrives late as well.
+    pub fn delay<R>(self, reader: R, buffer_size: usize) -> DelayedReader
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        tokio::spawn(rule_log::inherit(read_ahead(reader, self, tx, buffer_size)));
+        DelayedReader { rx, held: None, chunk: Buffer::zeroed(0), offset: 0 }
+    }
+}
+
+// A chunk as read, and when it is due
+type Chunk = (Instant, io::Result<Buffer>);
+
+async fn read_ahead<R: AsyncRead + Unpin>(mut reader: R, latency: Latency, tx: mpsc::Sender<Chunk>, buffer_size: usize) {
+    // Never before the chunk ahead of it
+    let mut due = Instant::now();
+    loop {
+        let mut buffer = Buffer::zeroed(buffer_size);
+        let read = tokio::select! {
+            read = reader.read(&mut buffer) => read,
+            // The connection closed; dropping the reader lets it go
+            _ = tx.closed() => return,
+        };
+        due = due.max(Instant::now() + latency.sample());
+        let end = !matches!(read, Ok(n) if n > 0);
+        let data = read.map(|n| {
+            buffer.truncate(n);
+            buffer
+        });
+        if tx.send((due, data)).await.is_err() || end {
+            return;
+        }
+    }
+}
+
+type Held = (Pin<Box<Sleep>>, io::Result<Buffer>);
+
+pub struct DelayedReader {
+    rx: mpsc::Receiver<Chunk>,
+    // The next chunk, until it is due
+    held: Option<Held>,
+    // The due chunk being read, and how much of it was
+    chunk: Buffer,
+    offset: usize,
+}
+
+impl AsyncRead for DelayedReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.offset < this.chunk.len() {
+                let n = buf.remaining().min(this.chunk.len() - this.offset);
+                buf.put_slice(&this.chunk[this.offset..this.offset + n]);
+                this.offset += n;
+                return Poll::Ready(Ok(()));
+            }
+            if let Some((due, _)) = &mut this.held {
+                ready!(due.as_mut().poll(cx));
+                let (_, data) = this.held.take().unwrap();
+                // An empty chunk is the end of the stream
+                this.chunk = data?;
+                this.offset = 0;
+                if this.chunk.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+            match ready!(this.rx.poll_recv(cx)) {
+                Some((due, data)) => this.held = Some((Box::pin(sleep_until(due)), data)),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+// What a UDP rule does to the datagrams it forwards, in both directions
+#[derive(Debug, Clone, Copy)]
+pub struct Impairment {
+    latency: Option<Latency>,
+    // Chances in hundredths of a percent
+    drop: u32,
+    duplicate: u32,
+    reorder_window: usize,
+}
+
+impl Impairment {
+    // None if the rule forwards datagrams as they come
+    pub fn new(rule: &UdpRule) -> Option<Self> {
+        let impairment = Self {
+            latency: Latency::new(rule.inject_delay_ms, rule.inject_jitter_ms),
+            drop: hundredths(rule.inject_drop_percent),
+            duplicate: hundredths(rule.inject_duplicate_percent),
+            reorder_window: rule.inject_reorder_window.unwrap_or(0),
+        };
+        let impaired = impairment.latency.is_some()
+            || impairment.drop > 0
+            || impairment.duplicate > 0
+            || impairment.reorder_window > 1;
+        impaired.then_some(impairment)
+    }
+
+    // A session's datagrams in one direction, which `send` gets once
+    // through
+    pub fn pipe<F, Fut>(self, send: F) -> Pipe
+    where
+        F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(PIPE_QUEUE);
+        tokio::spawn(rule_log::inherit(self.run(rx, Arc::new(send))));
+        Pipe(tx)
+    }
+
+    // Until the session ends
+    async fn run<F, Fut>(self, mut rx: mpsc::Receiver<Vec<u8>>, send: Arc<F>)
+    where
+        F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut held = Vec::new();
+        let mut deadline = Instant::now();
+        loop {
+            let received = match held.is_empty() {
+                true => rx.recv().await,
+                false => match timeout_at(deadline, rx.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        self.release(&mut held, &send).await;
+                        continue;
+                    }
+                },
+            };
+            let Some(datagram) = received else {
+                self.release(&mut held, &send).await;
+                return;
+            };
+            if chance(self.drop) {
+                continue;
+            }
+            if held.is_empty() {
+                deadline = Instant::now() + REORDER_HOLD;
+            }
+            if chance(self.duplicate) {
+                held.push(datagram.clone());
+            }
+            held.push(datagram);
+            if held.len() >= self.reorder_window {
+                self.release(&mut held, &send).await;
+            }
+        }
+    }
+
+    // Sends the held datagrams in a random order
+    async fn release<F, Fut>(&self, held: &mut Vec<Vec<u8>>, send: &Arc<F>)
+    where
+        F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        for i in (1..held.len()).rev() {
+            held.swap(i, random_below(i as u32 + 1) as usize);
+        }
+        for datagram in held.drain(..) {
+            match self.latency {
+                Some(latency) => {
+                    let sent = send(datagram);
+                    let delay = latency.sample();
+                    tokio::spawn(rule_log::inherit(async move {
+                        sleep(delay).await;
+                        sent.await;
+                    }));
+                }
+                None => send(datagram).await,
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Pipe(mpsc::Sender<Vec<u8>>);
+
+impl Pipe {
+    // Datagrams of segment_size coalesced into `data` go one by one
+    pub fn send(&self, data: &[u8], segment_size: Option<usize>) {
+        let datagrams = match data.is_empty() {
+            true => vec![data],
+            false => data.chunks(segment_size.unwrap_or(data.len()).max(1)).collect(),
+        };
+        for datagram in datagrams {
+            // Lost like on a congested link if the task fell behind
+            let _ = self.0.try_send(datagram.to_vec());
+        }
+    }
+}
+
+fn hundredths(percent: Option<f64>) -> u32 {
+    (percent.unwrap_or(0.0) * 100.0).round() as u32
+}
+
+// True `hundredths` times out of 10000
+fn chance(hundredths: u32) -> bool {
+    hundredths > 0 && random_below(10_000) < hundredths
+}
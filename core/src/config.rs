@@ -0,0 +1,3647 @@
+use crate::acl::Acl;
+use crate::confine;
+use crate::connector::{Source, TargetConnector};
+use crate::geoip::CountryFilter;
+use crate::privileges;
+use crate::proxy_protocol::ProxyProtocolVersion;
+use crate::seccomp;
+use crate::sockopt::{self, BindOptions};
+use crate::systemd;
+use crate::tls;
+use crate::unix_socket;
+use crate::upstream::UpstreamProxy;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Config {
+    // Files with more [[tcp]] and [[udp]] rules, as glob patterns relative
+    // to this file's directory (conf.d drop-ins)
+    #[serde(default, deserialize_with = "string_or_list", skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    pub global: Option<GlobalConfig>,
+    pub tcp: Option<Vec<TcpRule>>,
+    pub udp: Option<Vec<UdpRule>>,
+    pub tunnel: Option<TunnelConfig>,
+    pub discovery: Option<DiscoveryConfig>,
+    pub etcd: Option<EtcdConfig>,
+    pub docker: Option<DockerConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct GlobalConfig {
+    pub log_level: Option<String>,
+    pub buffer_size: Option<usize>,
+    pub max_total_connections: Option<usize>,
+    pub geoip_db: Option<String>,
+    pub admin_addr: Option<String>,
+    pub ban: Option<BanConfig>,
+    pub dns: Option<DnsConfig>,
+    // Unix socket a newer process connects to in order to take over the
+    // listening sockets of this one
+    pub upgrade_socket: Option<String>,
+    // Seconds a process that handed over waits for its connections to close
+    pub drain_timeout: Option<u64>,
+    // Seconds a shutdown waits for connections and UDP sessions to finish
+    pub shutdown_timeout: Option<u64>,
+    // Seconds a starting rule keeps retrying an address that is in use
+    pub bind_retry: Option<u64>,
+    // Account to switch to once the listeners are bound, when started as root
+    pub user: Option<String>,
+    // Defaults to the user's primary group
+    pub group: Option<String>,
+    // System call filter installed once the listeners are bound
+    pub seccomp: Option<SeccompMode>,
+    // Confine filesystem access to the paths the configuration names
+    pub landlock: Option<bool>,
+    // Further directories to allow reading, and writing
+    pub landlock_read: Option<Vec<String>>,
+    pub landlock_write: Option<Vec<String>>,
+    // How forwarders do their socket I/O
+    pub io_backend: Option<IoBackend>,
+    // Relay plain TCP connections in the kernel with an eBPF sockmap
+    pub sockmap: Option<bool>,
+    // Hand the record layer of TLS connections to the kernel
+    pub ktls: Option<bool>,
+}
+
+// Site-to-site tunnel between two porture instances. The client dials out
+// to the server, so it can sit behind NAT.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TunnelConfig {
+    pub role: TunnelRole,
+    // Server: address tunnel clients connect to
+    pub listen: Option<String>,
+    // Client: "host:port" of the tunnel server
+    pub server: Option<String>,
+    // Shared secret the client presents after the TLS handshake
+    pub token: String,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    // Client: CA used to verify the server instead of the system roots
+    pub ca: Option<String>,
+    pub server_name: Option<String>,
+    // Client: idle connections kept open for the server to use (TLS only)
+    pub connections: Option<usize>,
+    pub transport: Option<TunnelTransport>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelTransport {
+    // One TLS-over-TCP connection per tunneled connection
+    #[default]
+    Tls,
+    // A single QUIC connection with a stream per tunneled connection
+    Quic,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelRole {
+    // Accepts tunnel clients and sends `tunnel = true` rules through them
+    #[default]
+    Server,
+    // Connects to the server and opens the targets it asks for
+    Client,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BanConfig {
+    pub max_connects: Option<usize>,
+    pub max_short_sessions: Option<usize>,
+    pub max_denials: Option<usize>,
+    pub window: Option<u64>,
+    pub ban_duration: Option<u64>,
+}
+
+// The shared resolver for hostname targets. Without `servers`, the system's
+// resolver configuration (/etc/resolv.conf) is used.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DnsConfig {
+    pub servers: Option<Vec<String>>,
+    pub cache_size: Option<usize>,
+    pub min_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
+}
+
+// Additional rules kept under a key prefix in etcd, each key holding
+// [[tcp]] / [[udp]] tables like this file. Read through etcd's JSON gateway.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct EtcdConfig {
+    // "http://host:port" or "https://host:port", tried in order
+    pub endpoints: Vec<String>,
+    pub prefix: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // CA bundle for https endpoints, instead of the system roots
+    pub ca: Option<String>,
+}
+
+// Rules from the labels of running containers, e.g. porture.tcp.8080=80
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DockerConfig {
+    // Docker API socket, default /var/run/docker.sock
+    pub socket: Option<String>,
+    // Address the rules listen on, default 0.0.0.0
+    pub bind_addr: Option<String>,
+    // Network whose container address is the target, instead of the first
+    pub network: Option<String>,
+}
+
+// Service registries that rules can take their targets from
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DiscoveryConfig {
+    pub consul: Option<ConsulConfig>,
+    pub kubernetes: Option<KubernetesConfig>,
+}
+
+// The Consul agent queried for target_consul services
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ConsulConfig {
+    // "host:port" of the agent's HTTP API, default 127.0.0.1:8500
+    pub addr: Option<String>,
+    pub token: Option<String>,
+    pub datacenter: Option<String>,
+}
+
+// The Kubernetes API for target_kubernetes services. Empty, it is reached
+// from inside the cluster with the pod's service account.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct KubernetesConfig {
+    // "https://host:port", or e.g. "http://127.0.0.1:8001" for kubectl proxy
+    pub api_server: Option<String>,
+    pub token_file: Option<String>,
+    pub ca: Option<String>,
+    // For services given without one
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TcpRule {
+    // "addr:port" shorthands for the split fields below, expanded into them
+    // when the config is loaded
+    pub bind: Option<String>,
+    pub target: Option<String>,
+    // One address or a list of them; the rule listens on each
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub bind_addr: Vec<String>,
+    // Unused when bind_addr is a "unix:" socket
+    #[serde(default)]
+    pub bind_port: u16,
+    // Unused in proxy modes, where clients name their own targets
+    #[serde(default)]
+    pub target_addr: String,
+    #[serde(default)]
+    pub target_port: u16,
+    // SRV name to take the target from instead of target_addr/target_port
+    pub target_srv: Option<String>,
+    // Consul service whose healthy instances are the targets
+    pub target_consul: Option<String>,
+    // Kubernetes Service ("name" or "namespace/name") whose ready endpoints
+    // are the targets, and the name of its port when it has several
+    pub target_kubernetes: Option<String>,
+    pub target_kubernetes_port: Option<String>,
+    pub name: Option<String>,
+    // false keeps the rule in the file without starting it
+    pub enabled: Option<bool>,
+    // Labels --only-tag and --skip-tag select rules by
+    pub tags: Option<Vec<String>>,
+    // Log level for what this rule logs, instead of [global] log_level
+    pub log_level: Option<String>,
+    pub connect_timeout: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub overflow: Option<OverflowPolicy>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_new_connections_per_sec: Option<u32>,
+    pub rate_limit_kbps: Option<u64>,
+    pub client_rate_limit_kbps: Option<u64>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub allow_countries: Option<Vec<String>>,
+    pub deny_countries: Option<Vec<String>>,
+    // WebAssembly module that filters the rule's connections or sessions
+    pub wasm_filter: Option<String>,
+    // Lua script with on_connect, on_data and on_close hooks
+    pub lua_script: Option<String>,
+    pub rewrite: Option<Vec<RewriteConfig>>,
+    pub banner: Option<Vec<BannerConfig>>,
+    pub mirror_target: Option<String>,
+    pub capture_file: Option<String>,
+    pub capture_max_mb: Option<u64>,
+    pub capture_seconds: Option<u64>,
+    pub capture_on_start: Option<bool>,
+    pub record_dir: Option<String>,
+    // Latency added to forwarded data, for testing
+    pub inject_delay_ms: Option<u64>,
+    pub inject_jitter_ms: Option<u64>,
+    pub knock: Option<KnockConfig>,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    pub accept_proxy_protocol: Option<bool>,
+    // Peers allowed to send that header
+    pub trusted_proxies: Option<Vec<String>>,
+    pub tls: Option<TlsConfig>,
+    pub target_tls: Option<bool>,
+    pub target_tls_ca: Option<String>,
+    pub target_tls_sni: Option<String>,
+    pub target_tls_skip_verify: Option<bool>,
+    pub mode: Option<TcpMode>,
+    pub sni_routes: Option<BTreeMap<String, String>>,
+    pub sniff_routes: Option<SniffRoutes>,
+    pub host_routes: Option<BTreeMap<String, String>>,
+    pub rewrite_host: Option<String>,
+    pub forwarded_headers: Option<bool>,
+    pub upstream_proxy: Option<String>,
+    pub socks5_users: Option<BTreeMap<String, String>>,
+    pub tunnel: Option<bool>,
+    pub websocket_path: Option<String>,
+    pub websocket_host: Option<String>,
+    pub transparent: Option<bool>,
+    pub redirect_allow: Option<Vec<String>>,
+    pub redirect_deny: Option<Vec<String>>,
+    pub redirect_ports: Option<Vec<u16>>,
+    pub source_addr: Option<String>,
+    pub bind_device: Option<String>,
+    pub source_device: Option<String>,
+    pub fwmark: Option<u32>,
+    pub dscp: Option<u8>,
+    pub keepalive: Option<KeepaliveConfig>,
+    pub socket: Option<SocketOptions>,
+    pub reuse_port: Option<bool>,
+    pub freebind: Option<bool>,
+    pub ipv6_only: Option<bool>,
+}
+
+// Targets by detected protocol for mode = "sniff"; anything else goes to target_addr
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SniffRoutes {
+    pub tls: Option<String>,
+    pub http: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TlsConfig {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub acme: Option<AcmeConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub email: Option<String>,
+    pub cache_dir: String,
+    pub directory: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct KnockConfig {
+    pub ports: Vec<u16>,
+    pub protocol: Option<KnockProtocol>,
+    pub window: Option<u64>,
+    pub open_for: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KnockProtocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+// A find/replace on a rule's data, each side given either as a string or
+// as hex bytes ("0d 0a" or "0d0a")
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RewriteConfig {
+    pub find: Option<String>,
+    pub find_hex: Option<String>,
+    pub replace: Option<String>,
+    pub replace_hex: Option<String>,
+    pub direction: Option<DataDirection>,
+}
+
+// Bytes sent at the start of each connection, given as a string or as hex
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BannerConfig {
+    pub data: Option<String>,
+    pub data_hex: Option<String>,
+    // Upload unless set
+    pub direction: Option<DataDirection>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataDirection {
+    #[default]
+    Both,
+    // Client to target
+    Upload,
+    // Target to client
+    Download,
+}
+
+// TCP keepalive probing, in seconds: the first probe after `idle`, then one
+// every `interval` until `count` have gone unanswered
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct KeepaliveConfig {
+    pub idle: Option<u64>,
+    pub interval: Option<u64>,
+    pub count: Option<u32>,
+}
+
+// Tuning for client and target TCP sockets; unset options keep the system
+// defaults
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SocketOptions {
+    // TCP_NODELAY: send small writes immediately instead of coalescing them
+    pub nodelay: Option<bool>,
+    // SO_SNDBUF / SO_RCVBUF in bytes; the kernel may round or double them
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    // SO_LINGER in seconds; 0 resets connections on close instead of
+    // draining them
+    pub linger: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeccompMode {
+    // Other system calls fail with EPERM
+    Enforce,
+    // Other system calls are allowed, and logged by the kernel (audit log or
+    // dmesg), to find what a configuration needs beyond the allowed set
+    Log,
+}
+
+impl SeccompMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SeccompMode::Enforce => "enforce",
+            SeccompMode::Log => "log",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoBackend {
+    // Wait for readiness with epoll (kqueue, IOCP) through tokio
+    #[default]
+    Tokio,
+    // Submit reads and writes to an io_uring (Linux 5.6 or later)
+    IoUring,
+}
+
+impl IoBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IoBackend::Tokio => "tokio",
+            IoBackend::IoUring => "io_uring",
+        }
+    }
+}
+
+// The rules to run by their tags, from --only-tag and --skip-tag
+#[derive(Debug, Clone, Default)]
+pub struct TagFilter {
+    pub only: Vec<String>,
+    pub skip: Vec<String>,
+}
+
+impl TagFilter {
+    // With --only-tag, a rule needs one of those tags; with --skip-tag, it
+    // must have none of those. Untagged rules only pass --skip-tag.
+    pub fn selects(&self, tags: Option<&[String]>) -> bool {
+        let tags = tags.unwrap_or_default();
+        (self.only.is_empty() || tags.iter().any(|tag| self.only.contains(tag)))
+            && !tags.iter().any(|tag| self.skip.contains(tag))
+    }
+}
+
+// The language the config file is written in. All three deserialize into
+// the same Config, with the same keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    pub const NAMES: [&str; 3] = ["toml", "yaml", "json"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "toml" => Some(ConfigFormat::Toml),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "json" => Some(ConfigFormat::Json),
+            _ => None,
+        }
+    }
+
+    // By the file's extension; anything but .yaml, .yml and .json is TOML
+    pub fn from_path(path: &str) -> Self {
+        std::path::Path::new(path).extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| Self::from_name(&extension.to_ascii_lowercase()))
+            .unwrap_or(ConfigFormat::Toml)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    // Close new connections as soon as they are accepted
+    #[default]
+    Reject,
+    // Stop accepting until a slot frees up, leaving clients in the listen backlog
+    Queue,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpMode {
+    // Plain forwarding to target_addr:target_port
+    #[default]
+    Forward,
+    // Pick the target from the TLS ClientHello's server name, without terminating TLS
+    Sni,
+    // Pick the target by whether the client opens with TLS, HTTP or something else
+    Sniff,
+    // Pick the target from the Host header of the first HTTP request
+    Http,
+    // Act as a SOCKS5 proxy; clients choose the target
+    Socks5,
+    // Act as an HTTP CONNECT proxy; clients choose the target
+    HttpConnect,
+    // Unwrap length-prefixed datagrams from a udp_in_tcp_client and relay
+    // them to target_addr over UDP
+    UdpInTcpServer,
+    // Accept WebSocket connections from a websocket_client and forward the
+    // unwrapped stream to target_addr
+    WebsocketServer,
+    // Carry each connection in WebSocket frames to a websocket_server,
+    // possibly through an HTTP reverse proxy or CDN
+    WebsocketClient,
+    // Forward each connection to where it was headed before an iptables
+    // REDIRECT sent it here
+    Redirect,
+    // Answer with everything the client sends, instead of forwarding
+    Echo,
+    // Read and drop everything the client sends, instead of forwarding
+    Discard,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UdpMode {
+    // Plain forwarding to target_addr:target_port
+    #[default]
+    Forward,
+    // Carry each session's datagrams over a TCP connection to a porture
+    // rule in mode = "udp_in_tcp_server"
+    UdpInTcpClient,
+    // Repeat mDNS between `interfaces`; no bind or target addresses
+    MdnsReflector,
+    // Send every datagram back to the client; no target address
+    Echo,
+    // Drop every datagram; no target address
+    Discard,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UdpRule {
+    // "addr:port" shorthands, as for TCP rules
+    pub bind: Option<String>,
+    pub target: Option<String>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub bind_addr: Vec<String>,
+    #[serde(default)]
+    pub bind_port: u16,
+    #[serde(default)]
+    pub target_addr: String,
+    #[serde(default)]
+    pub target_port: u16,
+    pub name: Option<String>,
+    // false keeps the rule in the file without starting it
+    pub enabled: Option<bool>,
+    // Labels --only-tag and --skip-tag select rules by
+    pub tags: Option<Vec<String>>,
+    // Log level for what this rule logs, instead of [global] log_level
+    pub log_level: Option<String>,
+    pub timeout: Option<u64>,
+    pub max_sessions: Option<usize>,
+    pub rate_limit_kbps: Option<u64>,
+    pub client_rate_limit_kbps: Option<u64>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub allow_countries: Option<Vec<String>>,
+    pub deny_countries: Option<Vec<String>>,
+    // WebAssembly module that filters the rule's connections or sessions
+    pub wasm_filter: Option<String>,
+    // Lua script with on_connect, on_data and on_close hooks
+    pub lua_script: Option<String>,
+    pub rewrite: Option<Vec<RewriteConfig>>,
+    pub banner: Option<Vec<BannerConfig>>,
+    pub mirror_target: Option<String>,
+    pub capture_file: Option<String>,
+    pub capture_max_mb: Option<u64>,
+    pub capture_seconds: Option<u64>,
+    pub capture_on_start: Option<bool>,
+    // Latency added to forwarded datagrams, for testing
+    pub inject_delay_ms: Option<u64>,
+    pub inject_jitter_ms: Option<u64>,
+    // Chances of losing or duplicating a datagram, and how many are
+    // shuffled at a time
+    pub inject_drop_percent: Option<f64>,
+    pub inject_duplicate_percent: Option<f64>,
+    pub inject_reorder_window: Option<usize>,
+    pub knock: Option<KnockConfig>,
+    pub mode: Option<UdpMode>,
+    pub transparent: Option<bool>,
+    pub source_addr: Option<String>,
+    pub bind_device: Option<String>,
+    pub source_device: Option<String>,
+    pub fwmark: Option<u32>,
+    pub dscp: Option<u8>,
+    pub reuse_port: Option<bool>,
+    pub freebind: Option<bool>,
+    pub ipv6_only: Option<bool>,
+    pub broadcast: Option<bool>,
+    pub gso: Option<bool>,
+    pub interfaces: Option<Vec<String>>,
+}
+
+impl Config {
+    pub fn from_file(path: &str, format: ConfigFormat) -> anyhow::Result<Self> {
+        let mut config = Self::read_file(path, format)?;
+        for file in config.include_files(path)? {
+            let fragment = Self::read_file(&file, ConfigFormat::from_path(&file))
+                .map_err(|e| anyhow::anyhow!("included file {}: {}", file, e))?;
+            if !fragment.only_rules() {
+                anyhow::bail!("included file {}: only [[tcp]] and [[udp]] rules can be set there", file);
+            }
+            config.tcp.get_or_insert_default().extend(fragment.tcp.into_iter().flatten());
+            config.udp.get_or_insert_default().extend(fragment.udp.into_iter().flatten());
+        }
+        config.remove_disabled();
+        config.expand_endpoints()?;
+        Ok(config)
+    }
+
+    fn read_file(path: &str, format: ConfigFormat) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        // Without placeholders, parsing straight into Config keeps the
+        // line numbers in its errors
+        if !content.contains("${") {
+            return Ok(match format {
+                ConfigFormat::Toml => toml::from_str(&content)?,
+                ConfigFormat::Yaml => serde_yaml::from_str(&content)?,
+                ConfigFormat::Json => serde_json::from_str(&content)?,
+            });
+        }
+        let substitute = |value: &mut String| -> anyhow::Result<()> {
+            *value = substitute_env(value, &content)?;
+            Ok(())
+        };
+        Ok(match format {
+            ConfigFormat::Toml => {
+                let mut value: toml::Value = toml::from_str(&content)?;
+                substitute_toml(&mut value, &substitute)?;
+                value.try_into()?
+            }
+            ConfigFormat::Yaml => {
+                let mut value: serde_yaml::Value = serde_yaml::from_str(&content)?;
+                substitute_yaml(&mut value, &substitute)?;
+                serde_yaml::from_value(value)?
+            }
+            ConfigFormat::Json => {
+                let mut value: serde_json::Value = serde_json::from_str(&content)?;
+                substitute_json(&mut value, &substitute)?;
+                serde_json::from_value(value)?
+            }
+        })
+    }
+
+    // The include patterns, relative to the directory of the file at
+    // `config_path`
+    pub fn include_patterns(&self, config_path: &str) -> Vec<PathBuf> {
+        let directory = Path::new(config_path).parent().unwrap_or(Path::new(""));
+        self.include.iter().map(|pattern| directory.join(pattern)).collect()
+    }
+
+    // The files the include patterns match, each pattern's in alphabetical
+    // order. A pattern matching nothing is fine, as for an empty conf.d.
+    // Hidden files (editor backups, locks) are left out.
+    pub fn include_files(&self, config_path: &str) -> anyhow::Result<Vec<String>> {
+        let options = glob::MatchOptions { require_literal_leading_dot: true, ..Default::default() };
+        let mut files = Vec::new();
+        for pattern in self.include_patterns(config_path) {
+            let pattern = pattern.to_string_lossy();
+            let paths = glob::glob_with(&pattern, options)
+                .map_err(|e| anyhow::anyhow!("invalid include pattern '{}': {}", pattern, e))?;
+            for path in paths {
+                let path = path.map_err(|e| anyhow::anyhow!("include '{}': {}", pattern, e))?;
+                let file = path.to_string_lossy().into_owned();
+                if path.is_file() && !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    // Rules from the command line (-L/-T) instead of a file: each listen
+    // address, optionally prefixed with tcp:// or udp://, forwards to the
+    // target at the same position
+    pub fn from_forwards(listens: &[&str], targets: &[&str]) -> anyhow::Result<Self> {
+        if listens.len() != targets.len() {
+            anyhow::bail!("each -L needs a -T to forward to, got {} -L and {} -T", listens.len(), targets.len());
+        }
+        let (mut tcp, mut udp) = (Vec::new(), Vec::new());
+        for (listen, target) in listens.iter().zip(targets) {
+            let target = Some(target.to_string());
+            match listen.split_once("://") {
+                Some(("tcp", bind)) => tcp.push(TcpRule { bind: Some(bind.to_string()), target, ..Default::default() }),
+                Some(("udp", bind)) => udp.push(UdpRule { bind: Some(bind.to_string()), target, ..Default::default() }),
+                Some((scheme, _)) => anyhow::bail!("-L {}: unknown protocol '{}', expected tcp:// or udp://", listen, scheme),
+                None => tcp.push(TcpRule { bind: Some(listen.to_string()), target, ..Default::default() }),
+            }
+        }
+        let mut config = Config {
+            global: None,
+            tcp: Some(tcp),
+            udp: Some(udp),
+            tunnel: None,
+            discovery: None,
+            etcd: None,
+            docker: None,
+            include: Vec::new(),
+        };
+        config.expand_endpoints()?;
+        Ok(config)
+    }
+
+    // Rules set as PORTURE_TCP_<n> and PORTURE_UDP_<n> environment variables,
+    // each LISTEN->TARGET, in the order of n; None if there are none. For
+    // containers, where passing variables is easier than mounting a file.
+    pub fn from_env() -> Option<anyhow::Result<Self>> {
+        let mut forwards = Vec::new();
+        for (name, value) in std::env::vars_os() {
+            let Some(name) = name.to_str() else { continue };
+            let (protocol, index) = if let Some(index) = name.strip_prefix("PORTURE_TCP_") {
+                ("tcp", index)
+            } else if let Some(index) = name.strip_prefix("PORTURE_UDP_") {
+                ("udp", index)
+            } else {
+                continue;
+            };
+            forwards.push((protocol, index.parse::<u32>().ok(), name.to_string(), value));
+        }
+        if forwards.is_empty() {
+            return None;
+        }
+        forwards.sort_by(|a, b| (a.0, a.1, &a.2).cmp(&(b.0, b.1, &b.2)));
+
+        let parse = || {
+            let mut config = Config {
+                global: None,
+                tcp: Some(Vec::new()),
+                udp: Some(Vec::new()),
+                tunnel: None,
+                discovery: None,
+                etcd: None,
+                docker: None,
+                include: Vec::new(),
+            };
+            for (protocol, index, name, value) in forwards {
+                if index.is_none() {
+                    anyhow::bail!("{}: expected a number after PORTURE_{}_", name, protocol.to_uppercase());
+                }
+                let value = value.into_string().map_err(|_| anyhow::anyhow!("{} is not valid UTF-8", name))?;
+                let (listen, target) = value.split_once("->").ok_or_else(|| {
+                    anyhow::anyhow!("{}: expected LISTEN->TARGET, like 0.0.0.0:8080->web:80, got '{}'", name, value)
+                })?;
+                let listen = format!("{}://{}", protocol, listen.trim());
+                let rules = Self::from_forwards(&[&listen], &[target.trim()]).map_err(|e| anyhow::anyhow!("{}: {}", name, e))?;
+                config.tcp.get_or_insert_default().extend(rules.tcp.into_iter().flatten());
+                config.udp.get_or_insert_default().extend(rules.udp.into_iter().flatten());
+            }
+            Ok(config)
+        };
+        Some(parse())
+    }
+
+    // Rules with enabled = false are dropped as soon as they are read, so
+    // they are neither validated nor started
+    fn remove_disabled(&mut self) {
+        if let Some(rules) = &mut self.tcp {
+            rules.retain(|rule| rule.enabled.unwrap_or(true));
+        }
+        if let Some(rules) = &mut self.udp {
+            rules.retain(|rule| rule.enabled.unwrap_or(true));
+        }
+    }
+
+    // Drops the rules the tag filter doesn't select
+    pub fn select_tags(&mut self, filter: &TagFilter) {
+        if let Some(rules) = &mut self.tcp {
+            rules.retain(|rule| filter.selects(rule.tags.as_deref()));
+        }
+        if let Some(rules) = &mut self.udp {
+            rules.retain(|rule| filter.selects(rule.tags.as_deref()));
+        }
+    }
+
+    // Splits `bind` / `target` into the addr and port fields the rest of the
+    // code works with
+    fn expand_endpoints(&mut self) -> anyhow::Result<()> {
+        for rule in self.tcp.iter_mut().flatten() {
+            rule.expand_endpoints()?;
+        }
+        for rule in self.udp.iter_mut().flatten() {
+            rule.expand_endpoints()?;
+        }
+        Ok(())
+    }
+
+    // Rules stored outside the file (an etcd value): only [[tcp]] and [[udp]]
+    pub fn parse_rules(content: &str) -> anyhow::Result<Self> {
+        let mut rules: Config = toml::from_str(content)?;
+        if !rules.only_rules() {
+            anyhow::bail!("only [[tcp]] and [[udp]] rules can be set here");
+        }
+        rules.remove_disabled();
+        rules.expand_endpoints()?;
+        Ok(rules)
+    }
+
+    fn only_rules(&self) -> bool {
+        self.include.is_empty()
+            && self.global.is_none()
+            && self.tunnel.is_none()
+            && self.discovery.is_none()
+            && self.etcd.is_none()
+            && self.docker.is_none()
+    }
+
+    pub fn create_default_config() -> Self {
+        Config {
+            global: Some(GlobalConfig {
+                log_level: Some("info".to_string()),
+                buffer_size: Some(8192),
+                ..Default::default()
+            }),
+            tcp: Some(vec![
+                TcpRule {
+                    bind_addr: vec!["127.0.0.1".to_string()],
+                    bind_port: 8080,
+                    target_addr: "127.0.0.1".to_string(),
+                    target_port: 80,
+                    name: Some("web_proxy_example".to_string()),
+                    ..Default::default()
+                },
+                TcpRule {
+                    bind_addr: vec!["127.0.0.1".to_string()],
+                    bind_port: 2222,
+                    target_addr: "127.0.0.1".to_string(),
+                    target_port: 22,
+                    name: Some("ssh_proxy_example".to_string()),
+                    ..Default::default()
+                },
+            ]),
+            udp: Some(vec![
+                UdpRule {
+                    bind_addr: vec!["127.0.0.1".to_string()],
+                    bind_port: 5353,
+                    target_addr: "8.8.8.8".to_string(),
+                    target_port: 53,
+                    name: Some("dns_proxy_example".to_string()),
+                    timeout: Some(30),
+                    ..Default::default()
+                },
+            ]),
+            tunnel: None,
+            discovery: None,
+            etcd: None,
+            docker: None,
+            include: Vec::new(),
+        }
+    }
+
+    // YAML and JSON are written without the comments TOML gets, and without
+    // the fields that aren't set
+    pub fn save_to_file(&self, path: &str, format: ConfigFormat) -> anyhow::Result<()> {
+        let content = match format {
+            ConfigFormat::Toml => self.to_toml_with_comments(),
+            // Going through TOML, which has no null, leaves out unset fields
+            ConfigFormat::Yaml => serde_yaml::to_string(&toml::Value::try_from(self)?)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(&toml::Value::try_from(self)?)? + "\n",
+        };
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    pub fn to_toml_with_comments(&self) -> String {
+        let mut content = String::new();
+        
+        content.push_str("# Porture Configuration File\n");
+        content.push_str("# TCP/UDP Port Forwarding Tool\n");
+        content.push_str("# \n");
+        content.push_str("# This configuration was automatically generated.\n");
+        content.push_str("# Please edit it to suit your needs.\n");
+        content.push_str("# \n");
+        content.push_str("# For more examples and documentation, visit:\n");
+        content.push_str("# https://github.com/AptS-1547/porture\n\n");
+
+        if !self.include.is_empty() {
+            content.push_str("# Files with more [[tcp]] and [[udp]] rules, relative to this file\n");
+            content.push_str(&format!("include = {}\n\n", toml_string_array(&self.include)));
+        }
+
+        content.push_str("# Global settings\n");
+        content.push_str("[global]\n");
+        content.push_str("# Log level: error, warn, info, debug, trace\n");
+        if let Some(ref global) = self.global {
+            if let Some(ref log_level) = global.log_level {
+                content.push_str(&format!("log_level = \"{}\"\n", log_level));
+            }
+            content.push_str("# Buffer size for data transfer (in bytes)\n");
+            if let Some(buffer_size) = global.buffer_size {
+                content.push_str(&format!("buffer_size = {}\n", buffer_size));
+            }
+            if let Some(max_total_connections) = global.max_total_connections {
+                content.push_str("# Maximum concurrent TCP connections across all rules\n");
+                content.push_str(&format!("max_total_connections = {}\n", max_total_connections));
+            }
+            if let Some(ref geoip_db) = global.geoip_db {
+                content.push_str("# MaxMind country database used by allow_countries/deny_countries\n");
+                content.push_str(&format!("geoip_db = \"{}\"\n", geoip_db));
+            }
+            if let Some(ref admin_addr) = global.admin_addr {
+                content.push_str("# Admin API address (keep it on localhost, there is no authentication)\n");
+                content.push_str(&format!("admin_addr = \"{}\"\n", admin_addr));
+            }
+            if let Some(ref upgrade_socket) = global.upgrade_socket {
+                content.push_str("# Unix socket through which a new porture process takes over this one's listeners\n");
+                content.push_str(&format!("upgrade_socket = \"{}\"\n", upgrade_socket));
+            }
+            if let Some(drain_timeout) = global.drain_timeout {
+                content.push_str("# Seconds to wait for open connections to close after handing over\n");
+                content.push_str(&format!("drain_timeout = {}\n", drain_timeout));
+            }
+            if let Some(shutdown_timeout) = global.shutdown_timeout {
+                content.push_str("# Seconds to wait on shutdown for connections and UDP sessions to finish\n");
+                content.push_str(&format!("shutdown_timeout = {}\n", shutdown_timeout));
+            }
+            if let Some(bind_retry) = global.bind_retry {
+                content.push_str("# Seconds a starting rule retries a listen address that is in use\n");
+                content.push_str(&format!("bind_retry = {}\n", bind_retry));
+            }
+            if let Some(ref user) = global.user {
+                content.push_str("# User to run as once the listeners are bound (requires starting as root)\n");
+                content.push_str(&format!("user = \"{}\"\n", user));
+            }
+            if let Some(ref group) = global.group {
+                content.push_str("# Group to run as; defaults to the user's primary group\n");
+                content.push_str(&format!("group = \"{}\"\n", group));
+            }
+            if let Some(seccomp) = global.seccomp {
+                content.push_str("# Restrict system calls once listening: enforce or log\n");
+                content.push_str(&format!("seccomp = \"{}\"\n", seccomp.as_str()));
+            }
+            if let Some(landlock) = global.landlock {
+                content.push_str("# Restrict filesystem access to the paths this file names (Linux)\n");
+                content.push_str(&format!("landlock = {}\n", landlock));
+            }
+            if let Some(ref paths) = global.landlock_read {
+                content.push_str("# Further directories porture may read\n");
+                content.push_str(&format!("landlock_read = {}\n", toml_string_array(paths)));
+            }
+            if let Some(ref paths) = global.landlock_write {
+                content.push_str("# Further directories porture may write to\n");
+                content.push_str(&format!("landlock_write = {}\n", toml_string_array(paths)));
+            }
+            if let Some(io_backend) = global.io_backend {
+                content.push_str("# Socket I/O: tokio or io_uring (Linux)\n");
+                content.push_str(&format!("io_backend = \"{}\"\n", io_backend.as_str()));
+            }
+            if let Some(sockmap) = global.sockmap {
+                content.push_str("# Relay plain TCP connections in the kernel with eBPF (Linux)\n");
+                content.push_str(&format!("sockmap = {}\n", sockmap));
+            }
+            if let Some(ktls) = global.ktls {
+                content.push_str("# Encrypt and decrypt TLS records in the kernel (Linux)\n");
+                content.push_str(&format!("ktls = {}\n", ktls));
+            }
+            if let Some(ref ban) = global.ban {
+                content.push_str("\n# Automatic banning of abusive clients\n");
+                content.push_str("[global.ban]\n");
+                if let Some(max_connects) = ban.max_connects {
+                    content.push_str("# Ban clients opening more connections than this per window\n");
+                    content.push_str(&format!("max_connects = {}\n", max_connects));
+                }
+                if let Some(max_short_sessions) = ban.max_short_sessions {
+                    content.push_str("# Ban clients that repeatedly connect and disconnect without sending data\n");
+                    content.push_str(&format!("max_short_sessions = {}\n", max_short_sessions));
+                }
+                if let Some(max_denials) = ban.max_denials {
+                    content.push_str("# Ban clients repeatedly rejected by allow/deny or country rules\n");
+                    content.push_str(&format!("max_denials = {}\n", max_denials));
+                }
+                if let Some(window) = ban.window {
+                    content.push_str("# Window in seconds over which offenses are counted\n");
+                    content.push_str(&format!("window = {}\n", window));
+                }
+                if let Some(ban_duration) = ban.ban_duration {
+                    content.push_str("# How long a ban lasts in seconds\n");
+                    content.push_str(&format!("ban_duration = {}\n", ban_duration));
+                }
+            }
+            if let Some(ref dns) = global.dns {
+                content.push_str("\n# Caching resolver for hostname targets\n");
+                content.push_str("[global.dns]\n");
+                if let Some(ref servers) = dns.servers {
+                    content.push_str("# Name servers to query instead of the system's (ip or ip:port)\n");
+                    content.push_str(&format!("servers = {}\n", toml_string_array(servers)));
+                }
+                if let Some(cache_size) = dns.cache_size {
+                    content.push_str("# Number of answers to keep cached\n");
+                    content.push_str(&format!("cache_size = {}\n", cache_size));
+                }
+                if let Some(min_ttl) = dns.min_ttl {
+                    content.push_str("# Cache answers for at least this many seconds, whatever their TTL\n");
+                    content.push_str(&format!("min_ttl = {}\n", min_ttl));
+                }
+                if let Some(max_ttl) = dns.max_ttl {
+                    content.push_str("# Cache answers for at most this many seconds\n");
+                    content.push_str(&format!("max_ttl = {}\n", max_ttl));
+                }
+            }
+        }
+        content.push('\n');
+
+        if let Some(ref tunnel) = self.tunnel {
+            content.push_str("# Site-to-site tunnel\n");
+            content.push_str("[tunnel]\n");
+            content.push_str("# server accepts tunnel clients, client connects to a server\n");
+            content.push_str(&format!("role = \"{}\"\n", tunnel.role.as_str()));
+            if let Some(ref listen) = tunnel.listen {
+                content.push_str("# Address tunnel clients connect to\n");
+                content.push_str(&format!("listen = \"{}\"\n", listen));
+            }
+            if let Some(ref server) = tunnel.server {
+                content.push_str("# Tunnel server to connect to\n");
+                content.push_str(&format!("server = \"{}\"\n", server));
+            }
+            content.push_str("# Shared secret authenticating the client\n");
+            content.push_str(&format!("token = \"{}\"\n", tunnel.token));
+            if let Some(ref cert) = tunnel.cert {
+                content.push_str("# Server certificate chain (PEM)\n");
+                content.push_str(&format!("cert = \"{}\"\n", cert));
+            }
+            if let Some(ref key) = tunnel.key {
+                content.push_str("# Server private key (PEM)\n");
+                content.push_str(&format!("key = \"{}\"\n", key));
+            }
+            if let Some(ref ca) = tunnel.ca {
+                content.push_str("# CA bundle used to verify the server\n");
+                content.push_str(&format!("ca = \"{}\"\n", ca));
+            }
+            if let Some(ref server_name) = tunnel.server_name {
+                content.push_str("# Name to verify in the server certificate\n");
+                content.push_str(&format!("server_name = \"{}\"\n", server_name));
+            }
+            if let Some(connections) = tunnel.connections {
+                content.push_str("# Idle connections the client keeps open for the server\n");
+                content.push_str(&format!("connections = {}\n", connections));
+            }
+            if let Some(transport) = tunnel.transport {
+                content.push_str("# Tunnel transport: tls (TCP) or quic (UDP); both ends must match\n");
+                content.push_str(&format!("transport = \"{}\"\n", transport.as_str()));
+            }
+            content.push('\n');
+        }
+
+        if let Some(consul) = self.discovery.as_ref().and_then(|d| d.consul.as_ref()) {
+            content.push_str("# Consul agent for target_consul rules\n");
+            content.push_str("[discovery.consul]\n");
+            if let Some(ref addr) = consul.addr {
+                content.push_str("# Agent HTTP API address\n");
+                content.push_str(&format!("addr = \"{}\"\n", addr));
+            }
+            if let Some(ref token) = consul.token {
+                content.push_str("# ACL token sent with every request\n");
+                content.push_str(&format!("token = \"{}\"\n", token));
+            }
+            if let Some(ref datacenter) = consul.datacenter {
+                content.push_str("# Datacenter to look services up in, instead of the agent's own\n");
+                content.push_str(&format!("datacenter = \"{}\"\n", datacenter));
+            }
+            content.push('\n');
+        }
+
+        if let Some(kubernetes) = self.discovery.as_ref().and_then(|d| d.kubernetes.as_ref()) {
+            content.push_str("# Kubernetes API for target_kubernetes rules (defaults to the in-cluster service account)\n");
+            content.push_str("[discovery.kubernetes]\n");
+            if let Some(ref api_server) = kubernetes.api_server {
+                content.push_str("# API server URL\n");
+                content.push_str(&format!("api_server = \"{}\"\n", api_server));
+            }
+            if let Some(ref token_file) = kubernetes.token_file {
+                content.push_str("# File holding the bearer token, read again for every request\n");
+                content.push_str(&format!("token_file = \"{}\"\n", token_file));
+            }
+            if let Some(ref ca) = kubernetes.ca {
+                content.push_str("# CA bundle used to verify the API server\n");
+                content.push_str(&format!("ca = \"{}\"\n", ca));
+            }
+            if let Some(ref namespace) = kubernetes.namespace {
+                content.push_str("# Namespace of services given without one\n");
+                content.push_str(&format!("namespace = \"{}\"\n", namespace));
+            }
+            content.push('\n');
+        }
+
+        if let Some(ref etcd) = self.etcd {
+            content.push_str("# More rules from etcd, watched for changes\n");
+            content.push_str("[etcd]\n");
+            content.push_str("# etcd endpoints (http:// or https://), tried in order\n");
+            content.push_str(&format!("endpoints = {}\n", toml_string_array(&etcd.endpoints)));
+            content.push_str("# Every key under this prefix holds [[tcp]]/[[udp]] rules\n");
+            content.push_str(&format!("prefix = \"{}\"\n", etcd.prefix));
+            if let Some(ref username) = etcd.username {
+                content.push_str("# etcd user, when authentication is enabled\n");
+                content.push_str(&format!("username = \"{}\"\n", username));
+            }
+            if let Some(ref password) = etcd.password {
+                content.push_str("# Password for username\n");
+                content.push_str(&format!("password = \"{}\"\n", password));
+            }
+            if let Some(ref ca) = etcd.ca {
+                content.push_str("# CA bundle used to verify https endpoints\n");
+                content.push_str(&format!("ca = \"{}\"\n", ca));
+            }
+            content.push('\n');
+        }
+
+        if let Some(ref docker) = self.docker {
+            content.push_str("# Rules from container labels such as porture.tcp.8080=80\n");
+            content.push_str("[docker]\n");
+            if let Some(ref socket) = docker.socket {
+                content.push_str("# Docker API socket\n");
+                content.push_str(&format!("socket = \"{}\"\n", socket));
+            }
+            if let Some(ref bind_addr) = docker.bind_addr {
+                content.push_str("# Address the container rules listen on\n");
+                content.push_str(&format!("bind_addr = \"{}\"\n", bind_addr));
+            }
+            if let Some(ref network) = docker.network {
+                content.push_str("# Network whose container address is forwarded to\n");
+                content.push_str(&format!("network = \"{}\"\n", network));
+            }
+            content.push('\n');
+        }
+
+        if let Some(ref tcp_rules) = self.tcp {
+            content.push_str("# TCP forwarding rules\n");
+            for rule in tcp_rules {
+                content.push_str("[[tcp]]\n");
+                content.push_str("# Local address, or list of addresses, to bind to (use \"0.0.0.0\" for all interfaces)\n");
+                content.push_str(&format!("bind_addr = {}\n", bind_addr_toml(&rule.bind_addr)));
+                content.push_str("# Local port to bind to\n");
+                content.push_str(&format!("bind_port = {}\n", rule.bind_port));
+                if let Some(ref srv) = rule.target_srv {
+                    content.push_str("# SRV record naming the targets (priority, weight, host and port)\n");
+                    content.push_str(&format!("target_srv = \"{}\"\n", srv));
+                } else if let Some(ref service) = rule.target_consul {
+                    content.push_str("# Consul service whose passing instances are the targets\n");
+                    content.push_str(&format!("target_consul = \"{}\"\n", service));
+                } else if let Some(ref service) = rule.target_kubernetes {
+                    content.push_str("# Kubernetes service (name or namespace/name) whose ready endpoints are the targets\n");
+                    content.push_str(&format!("target_kubernetes = \"{}\"\n", service));
+                    if let Some(ref port) = rule.target_kubernetes_port {
+                        content.push_str("# Name of the service port to forward to\n");
+                        content.push_str(&format!("target_kubernetes_port = \"{}\"\n", port));
+                    }
+                } else if !rule.mode().is_proxy() && !rule.mode().is_builtin() {
+                    content.push_str("# Target address to forward to\n");
+                    content.push_str(&format!("target_addr = \"{}\"\n", rule.target_addr));
+                    content.push_str("# Target port to forward to\n");
+                    content.push_str(&format!("target_port = {}\n", rule.target_port));
+                }
+                if let Some(ref name) = rule.name {
+                    content.push_str("# Optional: rule name for logging\n");
+                    content.push_str(&format!("name = \"{}\"\n", name));
+                }
+                if let Some(enabled) = rule.enabled {
+                    content.push_str("# Optional: false skips the rule without removing it\n");
+                    content.push_str(&format!("enabled = {}\n", enabled));
+                }
+                if let Some(ref tags) = rule.tags {
+                    content.push_str("# Optional: labels to select the rule by with --only-tag/--skip-tag\n");
+                    content.push_str(&format!("tags = {}\n", toml_string_array(tags)));
+                }
+                if let Some(ref log_level) = rule.log_level {
+                    content.push_str("# Optional: log level for this rule only\n");
+                    content.push_str(&format!("log_level = \"{}\"\n", log_level));
+                }
+                if let Some(connect_timeout) = rule.connect_timeout {
+                    content.push_str("# Seconds to wait for the target connection to be established\n");
+                    content.push_str(&format!("connect_timeout = {}\n", connect_timeout));
+                }
+                if let Some(idle_timeout) = rule.idle_timeout {
+                    content.push_str("# Close connections idle in both directions for this many seconds\n");
+                    content.push_str(&format!("idle_timeout = {}\n", idle_timeout));
+                }
+                if let Some(max_connections) = rule.max_connections {
+                    content.push_str("# Maximum concurrent connections for this rule\n");
+                    content.push_str(&format!("max_connections = {}\n", max_connections));
+                }
+                if let Some(overflow) = rule.overflow {
+                    content.push_str("# What to do when max_connections is reached: reject, queue\n");
+                    content.push_str(&format!("overflow = \"{}\"\n", overflow.as_str()));
+                }
+                if let Some(max_connections_per_ip) = rule.max_connections_per_ip {
+                    content.push_str("# Maximum concurrent connections from a single client IP\n");
+                    content.push_str(&format!("max_connections_per_ip = {}\n", max_connections_per_ip));
+                }
+                if let Some(rate) = rule.max_new_connections_per_sec {
+                    content.push_str("# Maximum new connections accepted per second\n");
+                    content.push_str(&format!("max_new_connections_per_sec = {}\n", rate));
+                }
+                if let Some(kbps) = rule.rate_limit_kbps {
+                    content.push_str("# Bandwidth limit per direction in kilobits per second\n");
+                    content.push_str(&format!("rate_limit_kbps = {}\n", kbps));
+                }
+                if let Some(kbps) = rule.client_rate_limit_kbps {
+                    content.push_str("# Bandwidth limit per direction for each client IP in kilobits per second\n");
+                    content.push_str(&format!("client_rate_limit_kbps = {}\n", kbps));
+                }
+                if let Some(ref allow) = rule.allow {
+                    content.push_str("# Only accept clients from these networks\n");
+                    content.push_str(&format!("allow = {}\n", toml_string_array(allow)));
+                }
+                if let Some(ref deny) = rule.deny {
+                    content.push_str("# Reject clients from these networks\n");
+                    content.push_str(&format!("deny = {}\n", toml_string_array(deny)));
+                }
+                if let Some(ref countries) = rule.allow_countries {
+                    content.push_str("# Only accept clients from these countries (requires geoip_db)\n");
+                    content.push_str(&format!("allow_countries = {}\n", toml_string_array(countries)));
+                }
+                if let Some(ref countries) = rule.deny_countries {
+                    content.push_str("# Reject clients from these countries (requires geoip_db)\n");
+                    content.push_str(&format!("deny_countries = {}\n", toml_string_array(countries)));
+                }
+                if let Some(ref path) = rule.wasm_filter {
+                    content.push_str("# WebAssembly module filtering connections and data\n");
+                    content.push_str(&format!("wasm_filter = \"{}\"\n", path));
+                }
+                if let Some(ref path) = rule.lua_script {
+                    content.push_str("# Lua script with on_connect, on_data and on_close hooks\n");
+                    content.push_str(&format!("lua_script = \"{}\"\n", path));
+                }
+                if let Some(ref rewrites) = rule.rewrite {
+                    content.push_str("# Find/replace on the forwarded data\n");
+                    let rewrites: Vec<String> = rewrites.iter().map(RewriteConfig::to_inline_toml).collect();
+                    content.push_str(&format!("rewrite = [{}]\n", rewrites.join(", ")));
+                }
+                if let Some(ref banners) = rule.banner {
+                    content.push_str("# Bytes sent at the start of each connection\n");
+                    let banners: Vec<String> = banners.iter().map(BannerConfig::to_inline_toml).collect();
+                    content.push_str(&format!("banner = [{}]\n", banners.join(", ")));
+                }
+                if let Some(ref mirror) = rule.mirror_target {
+                    content.push_str("# Where to send a copy of what clients send\n");
+                    content.push_str(&format!("mirror_target = \"{}\"\n", mirror));
+                }
+                if let Some(ref path) = rule.capture_file {
+                    content.push_str("# Write forwarded traffic to this pcap file\n");
+                    content.push_str(&format!("capture_file = \"{}\"\n", path));
+                }
+                if let Some(mb) = rule.capture_max_mb {
+                    content.push_str("# Stop capturing once the file is this large\n");
+                    content.push_str(&format!("capture_max_mb = {}\n", mb));
+                }
+                if let Some(seconds) = rule.capture_seconds {
+                    content.push_str("# Stop capturing after this many seconds\n");
+                    content.push_str(&format!("capture_seconds = {}\n", seconds));
+                }
+                if let Some(on_start) = rule.capture_on_start {
+                    content.push_str("# Capture from the start, or only once started through the admin API\n");
+                    content.push_str(&format!("capture_on_start = {}\n", on_start));
+                }
+                if let Some(ref dir) = rule.record_dir {
+                    content.push_str("# Record each connection to a file in this directory, for porture replay\n");
+                    content.push_str(&format!("record_dir = \"{}\"\n", dir));
+                }
+                if let Some(ms) = rule.inject_delay_ms {
+                    content.push_str("# Hold forwarded data for this many milliseconds, to simulate a slow network\n");
+                    content.push_str(&format!("inject_delay_ms = {}\n", ms));
+                }
+                if let Some(ms) = rule.inject_jitter_ms {
+                    content.push_str("# Vary the delay by up to this many milliseconds either way\n");
+                    content.push_str(&format!("inject_jitter_ms = {}\n", ms));
+                }
+                if let Some(version) = rule.proxy_protocol {
+                    content.push_str("# Send a PROXY protocol header (v1 or v2) to the target\n");
+                    content.push_str(&format!("proxy_protocol = \"{}\"\n", version.as_str()));
+                }
+                if let Some(accept_proxy_protocol) = rule.accept_proxy_protocol {
+                    content.push_str("# Expect a PROXY protocol header from an upstream load balancer\n");
+                    content.push_str(&format!("accept_proxy_protocol = {}\n", accept_proxy_protocol));
+                }
+                if let Some(ref proxies) = rule.trusted_proxies {
+                    content.push_str("# Addresses of the load balancers allowed to send that header\n");
+                    content.push_str(&format!("trusted_proxies = {}\n", toml_string_array(proxies)));
+                }
+                if let Some(ref tls) = rule.tls {
+                    content.push_str("# Terminate TLS from clients with this certificate and key\n");
+                    content.push_str(&format!("tls = {}\n", tls.to_inline_toml()));
+                }
+                if let Some(target_tls) = rule.target_tls {
+                    content.push_str("# Connect to the target over TLS\n");
+                    content.push_str(&format!("target_tls = {}\n", target_tls));
+                }
+                if let Some(ref ca) = rule.target_tls_ca {
+                    content.push_str("# CA bundle used to verify the target instead of the built-in roots\n");
+                    content.push_str(&format!("target_tls_ca = \"{}\"\n", ca));
+                }
+                if let Some(ref sni) = rule.target_tls_sni {
+                    content.push_str("# Server name sent to and verified against the target\n");
+                    content.push_str(&format!("target_tls_sni = \"{}\"\n", sni));
+                }
+                if let Some(skip_verify) = rule.target_tls_skip_verify {
+                    content.push_str("# Skip certificate verification for the target (insecure)\n");
+                    content.push_str(&format!("target_tls_skip_verify = {}\n", skip_verify));
+                }
+                if let Some(ref proxy) = rule.upstream_proxy {
+                    content.push_str("# Reach the target through this proxy\n");
+                    content.push_str(&format!("upstream_proxy = \"{}\"\n", proxy));
+                }
+                if let Some(ref source) = rule.source_addr {
+                    content.push_str("# Local address to connect to the target from\n");
+                    content.push_str(&format!("source_addr = \"{}\"\n", source));
+                }
+                if let Some(ref device) = rule.bind_device {
+                    content.push_str("# Only accept connections through this network interface (Linux)\n");
+                    content.push_str(&format!("bind_device = \"{}\"\n", device));
+                }
+                if let Some(ref device) = rule.source_device {
+                    content.push_str("# Connect to the target through this network interface (Linux)\n");
+                    content.push_str(&format!("source_device = \"{}\"\n", device));
+                }
+                if let Some(mark) = rule.fwmark {
+                    content.push_str("# Mark outgoing packets for policy routing (Linux)\n");
+                    content.push_str(&format!("fwmark = {}\n", mark));
+                }
+                if let Some(dscp) = rule.dscp {
+                    content.push_str("# DSCP value (0-63) for QoS marking of forwarded traffic (Linux)\n");
+                    content.push_str(&format!("dscp = {}\n", dscp));
+                }
+                if let Some(ref keepalive) = rule.keepalive {
+                    content.push_str("# TCP keepalive on client and target connections\n");
+                    content.push_str(&format!("keepalive = {}\n", keepalive.to_inline_toml()));
+                }
+                if let Some(ref socket) = rule.socket {
+                    content.push_str("# Socket options for client and target connections\n");
+                    content.push_str(&format!("socket = {}\n", socket.to_inline_toml()));
+                }
+                if let Some(reuse_port) = rule.reuse_port {
+                    content.push_str("# Share the listening address with other processes (SO_REUSEPORT)\n");
+                    content.push_str(&format!("reuse_port = {}\n", reuse_port));
+                }
+                if let Some(freebind) = rule.freebind {
+                    content.push_str("# Bind even if bind_addr is not configured on this host yet (Linux)\n");
+                    content.push_str(&format!("freebind = {}\n", freebind));
+                }
+                if let Some(ipv6_only) = rule.ipv6_only {
+                    content.push_str("# With an IPv6 bind_addr, refuse IPv4 clients instead of serving both\n");
+                    content.push_str(&format!("ipv6_only = {}\n", ipv6_only));
+                }
+                if let Some(tunnel) = rule.tunnel {
+                    content.push_str("# Open the target from the far end of the [tunnel]\n");
+                    content.push_str(&format!("tunnel = {}\n", tunnel));
+                }
+                if let Some(transparent) = rule.transparent {
+                    content.push_str("# Accept TPROXY-redirected connections and connect from the client's address (Linux)\n");
+                    content.push_str(&format!("transparent = {}\n", transparent));
+                }
+                if let Some(mode) = rule.mode {
+                    content.push_str("# Listener mode: forward, sni, sniff, http, socks5, http_connect, udp_in_tcp_server,\n");
+                    content.push_str("# websocket_server, websocket_client, redirect, echo or discard\n");
+                    content.push_str(&format!("mode = \"{}\"\n", mode.as_str()));
+                }
+                if let Some(ref routes) = rule.sni_routes {
+                    content.push_str("# Server name -> target for mode = \"sni\"; unmatched names use target_addr\n");
+                    content.push_str(&format!("sni_routes = {}\n", toml_string_table(routes)));
+                }
+                if let Some(ref routes) = rule.host_routes {
+                    content.push_str("# Host header -> target for mode = \"http\"; unmatched hosts use target_addr\n");
+                    content.push_str(&format!("host_routes = {}\n", toml_string_table(routes)));
+                }
+                if let Some(ref users) = rule.socks5_users {
+                    content.push_str("# Username -> password required by mode = \"socks5\"\n");
+                    content.push_str(&format!("socks5_users = {}\n", toml_string_table(users)));
+                }
+                if let Some(ref path) = rule.websocket_path {
+                    content.push_str("# Request path of the WebSocket upgrade in websocket modes\n");
+                    content.push_str(&format!("websocket_path = \"{}\"\n", path));
+                }
+                if let Some(ref host) = rule.websocket_host {
+                    content.push_str("# Host header sent by mode = \"websocket_client\"\n");
+                    content.push_str(&format!("websocket_host = \"{}\"\n", host));
+                }
+                if let Some(ref allow) = rule.redirect_allow {
+                    content.push_str("# Original destinations mode = \"redirect\" may forward to\n");
+                    content.push_str(&format!("redirect_allow = {}\n", toml_string_array(allow)));
+                }
+                if let Some(ref deny) = rule.redirect_deny {
+                    content.push_str("# Original destinations mode = \"redirect\" refuses\n");
+                    content.push_str(&format!("redirect_deny = {}\n", toml_string_array(deny)));
+                }
+                if let Some(ref ports) = rule.redirect_ports {
+                    content.push_str("# Original destination ports mode = \"redirect\" may forward to\n");
+                    content.push_str(&format!("redirect_ports = {:?}\n", ports));
+                }
+                if let Some(ref host) = rule.rewrite_host {
+                    content.push_str("# Host header sent to the target in mode = \"http\"\n");
+                    content.push_str(&format!("rewrite_host = \"{}\"\n", host));
+                }
+                if let Some(forwarded_headers) = rule.forwarded_headers {
+                    content.push_str("# Add X-Forwarded-For / X-Real-IP / X-Forwarded-Proto in mode = \"http\"\n");
+                    content.push_str(&format!("forwarded_headers = {}\n", forwarded_headers));
+                }
+                if let Some(ref routes) = rule.sniff_routes {
+                    content.push_str("# Targets for TLS and HTTP clients in mode = \"sniff\"; others use target_addr\n");
+                    content.push_str(&format!("sniff_routes = {}\n", routes.to_inline_toml()));
+                }
+                if let Some(ref knock) = rule.knock {
+                    content.push_str("# Port knocking sequence that unlocks this rule per client\n");
+                    content.push_str(&format!("knock = {}\n", knock.to_inline_toml()));
+                }
+                content.push('\n');
+            }
+        }
+
+        if let Some(ref udp_rules) = self.udp {
+            content.push_str("# UDP forwarding rules\n");
+            for rule in udp_rules {
+                content.push_str("[[udp]]\n");
+                if rule.mode() != UdpMode::MdnsReflector {
+                    content.push_str("# Local address, or list of addresses, to bind to (use \"0.0.0.0\" for all interfaces)\n");
+                    content.push_str(&format!("bind_addr = {}\n", bind_addr_toml(&rule.bind_addr)));
+                    content.push_str("# Local port to bind to\n");
+                    content.push_str(&format!("bind_port = {}\n", rule.bind_port));
+                }
+                if rule.mode() != UdpMode::MdnsReflector && !rule.mode().is_builtin() {
+                    content.push_str("# Target address to forward to\n");
+                    content.push_str(&format!("target_addr = \"{}\"\n", rule.target_addr));
+                    content.push_str("# Target port to forward to\n");
+                    content.push_str(&format!("target_port = {}\n", rule.target_port));
+                }
+                if let Some(ref name) = rule.name {
+                    content.push_str("# Optional: rule name for logging\n");
+                    content.push_str(&format!("name = \"{}\"\n", name));
+                }
+                if let Some(enabled) = rule.enabled {
+                    content.push_str("# Optional: false skips the rule without removing it\n");
+                    content.push_str(&format!("enabled = {}\n", enabled));
+                }
+                if let Some(ref tags) = rule.tags {
+                    content.push_str("# Optional: labels to select the rule by with --only-tag/--skip-tag\n");
+                    content.push_str(&format!("tags = {}\n", toml_string_array(tags)));
+                }
+                if let Some(ref log_level) = rule.log_level {
+                    content.push_str("# Optional: log level for this rule only\n");
+                    content.push_str(&format!("log_level = \"{}\"\n", log_level));
+                }
+                content.push_str("# UDP session timeout in seconds\n");
+                if let Some(timeout) = rule.timeout {
+                    content.push_str(&format!("timeout = {}\n", timeout));
+                }
+                if let Some(max) = rule.max_sessions {
+                    content.push_str("# Most concurrent client sessions; the least recently active is evicted beyond this\n");
+                    content.push_str(&format!("max_sessions = {}\n", max));
+                }
+                if let Some(kbps) = rule.rate_limit_kbps {
+                    content.push_str("# Bandwidth limit per direction in kilobits per second\n");
+                    content.push_str(&format!("rate_limit_kbps = {}\n", kbps));
+                }
+                if let Some(kbps) = rule.client_rate_limit_kbps {
+                    content.push_str("# Bandwidth limit per direction for each client IP in kilobits per second\n");
+                    content.push_str(&format!("client_rate_limit_kbps = {}\n", kbps));
+                }
+                if let Some(ref allow) = rule.allow {
+                    content.push_str("# Only accept clients from these networks\n");
+                    content.push_str(&format!("allow = {}\n", toml_string_array(allow)));
+                }
+                if let Some(ref deny) = rule.deny {
+                    content.push_str("# Reject clients from these networks\n");
+                    content.push_str(&format!("deny = {}\n", toml_string_array(deny)));
+                }
+                if let Some(ref countries) = rule.allow_countries {
+                    content.push_str("# Only accept clients from these countries (requires geoip_db)\n");
+                    content.push_str(&format!("allow_countries = {}\n", toml_string_array(countries)));
+                }
+                if let Some(ref countries) = rule.deny_countries {
+                    content.push_str("# Reject clients from these countries (requires geoip_db)\n");
+                    content.push_str(&format!("deny_countries = {}\n", toml_string_array(countries)));
+                }
+                if let Some(ref path) = rule.wasm_filter {
+                    content.push_str("# WebAssembly module filtering connections and data\n");
+                    content.push_str(&format!("wasm_filter = \"{}\"\n", path));
+                }
+                if let Some(ref path) = rule.lua_script {
+                    content.push_str("# Lua script with on_connect, on_data and on_close hooks\n");
+                    content.push_str(&format!("lua_script = \"{}\"\n", path));
+                }
+                if let Some(ref rewrites) = rule.rewrite {
+                    content.push_str("# Find/replace on the forwarded data\n");
+                    let rewrites: Vec<String> = rewrites.iter().map(RewriteConfig::to_inline_toml).collect();
+                    content.push_str(&format!("rewrite = [{}]\n", rewrites.join(", ")));
+                }
+                if let Some(ref banners) = rule.banner {
+                    content.push_str("# Bytes sent at the start of each connection\n");
+                    let banners: Vec<String> = banners.iter().map(BannerConfig::to_inline_toml).collect();
+                    content.push_str(&format!("banner = [{}]\n", banners.join(", ")));
+                }
+                if let Some(ref mirror) = rule.mirror_target {
+                    content.push_str("# Where to send a copy of what clients send\n");
+                    content.push_str(&format!("mirror_target = \"{}\"\n", mirror));
+                }
+                if let Some(ref path) = rule.capture_file {
+                    content.push_str("# Write forwarded traffic to this pcap file\n");
+                    content.push_str(&format!("capture_file = \"{}\"\n", path));
+                }
+                if let Some(mb) = rule.capture_max_mb {
+                    content.push_str("# Stop capturing once the file is this large\n");
+                    content.push_str(&format!("capture_max_mb = {}\n", mb));
+                }
+                if let Some(seconds) = rule.capture_seconds {
+                    content.push_str("# Stop capturing after this many seconds\n");
+                    content.push_str(&format!("capture_seconds = {}\n", seconds));
+                }
+                if let Some(on_start) = rule.capture_on_start {
+                    content.push_str("# Capture from the start, or only once started through the admin API\n");
+                    content.push_str(&format!("capture_on_start = {}\n", on_start));
+                }
+                if let Some(ms) = rule.inject_delay_ms {
+                    content.push_str("# Hold forwarded data for this many milliseconds, to simulate a slow network\n");
+                    content.push_str(&format!("inject_delay_ms = {}\n", ms));
+                }
+                if let Some(ms) = rule.inject_jitter_ms {
+                    content.push_str("# Vary the delay by up to this many milliseconds either way\n");
+                    content.push_str(&format!("inject_jitter_ms = {}\n", ms));
+                }
+                if let Some(percent) = rule.inject_drop_percent {
+                    content.push_str("# Lose this percentage of datagrams, to simulate a bad network\n");
+                    content.push_str(&format!("inject_drop_percent = {}\n", percent));
+                }
+                if let Some(percent) = rule.inject_duplicate_percent {
+                    content.push_str("# Send this percentage of datagrams twice\n");
+                    content.push_str(&format!("inject_duplicate_percent = {}\n", percent));
+                }
+                if let Some(window) = rule.inject_reorder_window {
+                    content.push_str("# Shuffle datagrams in groups of up to this many\n");
+                    content.push_str(&format!("inject_reorder_window = {}\n", window));
+                }
+                if let Some(ref knock) = rule.knock {
+                    content.push_str("# Port knocking sequence that unlocks this rule per client\n");
+                    content.push_str(&format!("knock = {}\n", knock.to_inline_toml()));
+                }
+                if let Some(mode) = rule.mode {
+                    content.push_str("# Rule mode: forward, udp_in_tcp_client, mdns_reflector, echo or discard\n");
+                    content.push_str(&format!("mode = \"{}\"\n", mode.as_str()));
+                }
+                if let Some(ref interfaces) = rule.interfaces {
+                    content.push_str("# Interfaces to reflect mDNS between in mode = \"mdns_reflector\"\n");
+                    content.push_str(&format!("interfaces = {}\n", toml_string_array(interfaces)));
+                }
+                if let Some(transparent) = rule.transparent {
+                    content.push_str("# Accept TPROXY-redirected datagrams and send from the client's address (Linux)\n");
+                    content.push_str(&format!("transparent = {}\n", transparent));
+                }
+                if let Some(ref source) = rule.source_addr {
+                    content.push_str("# Local address to send to the target from\n");
+                    content.push_str(&format!("source_addr = \"{}\"\n", source));
+                }
+                if let Some(ref device) = rule.bind_device {
+                    content.push_str("# Only accept datagrams through this network interface (Linux)\n");
+                    content.push_str(&format!("bind_device = \"{}\"\n", device));
+                }
+                if let Some(ref device) = rule.source_device {
+                    content.push_str("# Send to the target through this network interface (Linux)\n");
+                    content.push_str(&format!("source_device = \"{}\"\n", device));
+                }
+                if let Some(mark) = rule.fwmark {
+                    content.push_str("# Mark outgoing packets for policy routing (Linux)\n");
+                    content.push_str(&format!("fwmark = {}\n", mark));
+                }
+                if let Some(dscp) = rule.dscp {
+                    content.push_str("# DSCP value (0-63) for QoS marking of forwarded traffic (Linux)\n");
+                    content.push_str(&format!("dscp = {}\n", dscp));
+                }
+                if let Some(reuse_port) = rule.reuse_port {
+                    content.push_str("# Share the listening address with other processes (SO_REUSEPORT)\n");
+                    content.push_str(&format!("reuse_port = {}\n", reuse_port));
+                }
+                if let Some(freebind) = rule.freebind {
+                    content.push_str("# Bind even if bind_addr is not configured on this host yet (Linux)\n");
+                    content.push_str(&format!("freebind = {}\n", freebind));
+                }
+                if let Some(ipv6_only) = rule.ipv6_only {
+                    content.push_str("# With an IPv6 bind_addr, refuse IPv4 clients instead of serving both\n");
+                    content.push_str(&format!("ipv6_only = {}\n", ipv6_only));
+                }
+                if let Some(broadcast) = rule.broadcast {
+                    content.push_str("# Allow target_addr to be a broadcast address (SO_BROADCAST)\n");
+                    content.push_str(&format!("broadcast = {}\n", broadcast));
+                }
+                if let Some(gso) = rule.gso {
+                    content.push_str("# Batch datagrams with UDP segmentation offload (GRO/GSO, Linux)\n");
+                    content.push_str(&format!("gso = {}\n", gso));
+                }
+                content.push('\n');
+            }
+        }
+
+        content
+    }
+
+    pub fn from_file_or_create_default(path: &str, format: ConfigFormat) -> anyhow::Result<Self> {
+        match std::fs::metadata(path) {
+            Ok(_) => {
+                // 文件存在，直接读取
+                Self::from_file(path, format)
+            }
+            Err(_) => {
+                // 文件不存在，创建默认配置
+                let default_config = Self::create_default_config();
+                default_config.save_to_file(path, format)?;
+                Ok(default_config)
+            }
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.global.as_ref().and_then(|g| g.max_total_connections) == Some(0) {
+            anyhow::bail!("max_total_connections must be greater than 0");
+        }
+
+        if let Some(global) = &self.global {
+            if let Some(admin_addr) = &global.admin_addr {
+                SocketAddr::from_str(admin_addr)
+                    .map_err(|_| anyhow::anyhow!("invalid admin_addr '{}', expected ip:port", admin_addr))?;
+            }
+            if let Some(ban) = &global.ban {
+                ban.validate()?;
+            }
+            if let Some(dns) = &global.dns {
+                dns.validate()?;
+            }
+            if let Some(upgrade_socket) = &global.upgrade_socket {
+                unix_socket::validate(upgrade_socket)?;
+                // QUIC connections can't share their socket with another process
+                if self.tunnel.as_ref().is_some_and(|t| t.role == TunnelRole::Server && t.transport() == TunnelTransport::Quic) {
+                    anyhow::bail!("upgrade_socket can't hand over a QUIC tunnel server");
+                }
+            }
+            if global.group.is_some() && global.user.is_none() {
+                anyhow::bail!("[global] group requires user");
+            }
+            if let Some(user) = &global.user {
+                privileges::validate(user, global.group.as_deref())?;
+            }
+            if global.seccomp.is_some() {
+                seccomp::validate()?;
+            }
+            if global.io_backend == Some(IoBackend::IoUring) && !cfg!(target_os = "linux") {
+                anyhow::bail!("[global] io_backend = \"io_uring\" requires Linux");
+            }
+            if global.sockmap.unwrap_or(false) && !cfg!(target_os = "linux") {
+                anyhow::bail!("[global] sockmap requires Linux");
+            }
+            if global.ktls.unwrap_or(false) && !cfg!(target_os = "linux") {
+                anyhow::bail!("[global] ktls requires Linux");
+            }
+            if global.landlock.unwrap_or(false) {
+                confine::validate()?;
+            } else if global.landlock_read.is_some() || global.landlock_write.is_some() {
+                anyhow::bail!("[global] landlock_read and landlock_write require landlock = true");
+            }
+        }
+
+        if let Some(tunnel) = &self.tunnel {
+            tunnel.validate()?;
+        }
+        if let Some(etcd) = &self.etcd {
+            etcd.validate()?;
+        }
+        if let Some(docker) = &self.docker {
+            docker.validate()?;
+        }
+        let consul = self.discovery.as_ref().and_then(|d| d.consul.as_ref());
+        if let Some(consul) = consul {
+            consul.validate()?;
+        }
+        let kubernetes = self.discovery.as_ref().and_then(|d| d.kubernetes.as_ref());
+        if let Some(kubernetes) = kubernetes {
+            kubernetes.validate()?;
+        }
+        let tunnel_server = self.tunnel.as_ref().is_some_and(|t| t.role == TunnelRole::Server);
+
+        let has_geoip_db = self.global.as_ref().is_some_and(|g| g.geoip_db.is_some());
+        // fwmark and transparent are set on every outbound socket, which
+        // takes CAP_NET_ADMIN
+        let drops_privileges = self.global.as_ref().is_some_and(|g| g.user.is_some());
+
+        if let Some(tcp_rules) = &self.tcp {
+            for rule in tcp_rules {
+                rule.validate()?;
+                if !has_geoip_db && rule.country_filter()?.is_some() {
+                    anyhow::bail!("TCP rule '{}': country filters require [global] geoip_db", rule.rule_name());
+                }
+                if drops_privileges && (rule.fwmark.is_some() || rule.transparent.unwrap_or(false)) {
+                    anyhow::bail!("TCP rule '{}': fwmark and transparent need root, which [global] user gives up", rule.rule_name());
+                }
+                if rule.tunnel.unwrap_or(false) && !tunnel_server {
+                    anyhow::bail!("TCP rule '{}': tunnel = true requires a [tunnel] with role = \"server\"", rule.rule_name());
+                }
+                if rule.target_consul.is_some() && consul.is_none() {
+                    anyhow::bail!("TCP rule '{}': target_consul requires [discovery.consul]", rule.rule_name());
+                }
+                if rule.target_kubernetes.is_some() && kubernetes.is_none() {
+                    anyhow::bail!("TCP rule '{}': target_kubernetes requires [discovery.kubernetes]", rule.rule_name());
+                }
+            }
+        }
+
+        if let Some(udp_rules) = &self.udp {
+            for rule in udp_rules {
+                rule.validate()?;
+                if !has_geoip_db && rule.country_filter()?.is_some() {
+                    anyhow::bail!("UDP rule '{}': country filters require [global] geoip_db", rule.rule_name());
+                }
+                if drops_privileges && (rule.fwmark.is_some() || rule.transparent.unwrap_or(false)) {
+                    anyhow::bail!("UDP rule '{}': fwmark and transparent need root, which [global] user gives up", rule.rule_name());
+                }
+            }
+        }
+
+        self.validate_rule_names()?;
+        self.validate_listeners()
+    }
+
+    // Statistics, certificates and logs tell a protocol's rules apart by
+    // name; a TCP and a UDP rule may share one
+    fn validate_rule_names(&self) -> anyhow::Result<()> {
+        let check = |protocol: &str, names: Vec<String>| {
+            let mut seen = HashSet::new();
+            match names.into_iter().find(|name| !seen.insert(name.clone())) {
+                Some(name) => anyhow::bail!("two {} rules are named '{}'; give them different names", protocol, name),
+                None => Ok(()),
+            }
+        };
+        check("TCP", self.tcp.iter().flatten().map(TcpRule::rule_name).collect())?;
+        check("UDP", self.udp.iter().flatten().map(UdpRule::rule_name).collect())
+    }
+
+    // Rules that would fail to bind because another rule holds the address
+    fn validate_listeners(&self) -> anyhow::Result<()> {
+        let mut unix_paths: HashMap<&str, String> = HashMap::new();
+        let mut tcp = Vec::new();
+        // Rules may share a socket systemd passed in, each accepting on a copy
+        for rule in self.tcp.iter().flatten().filter(|rule| rule.bind_systemd_socket().is_none()) {
+            if let Some(path) = rule.bind_unix_path() {
+                if let Some(other) = unix_paths.insert(path, rule.rule_name()) {
+                    anyhow::bail!("TCP rules '{}' and '{}' both listen on unix:{}; give them different paths", other, rule.rule_name(), path);
+                }
+                continue;
+            }
+            for addr in rule.bind_socket_addrs()? {
+                tcp.push(Listener {
+                    rule: rule.rule_name(),
+                    addr,
+                    ipv6_only: rule.ipv6_only.unwrap_or(false),
+                    reuse_port: rule.reuse_port.unwrap_or(false),
+                    device: rule.bind_device.as_deref(),
+                });
+            }
+        }
+        check_listeners("TCP", &tcp)?;
+
+        let mut udp = Vec::new();
+        let udp_rules = self.udp.iter().flatten()
+            .filter(|rule| rule.mode() != UdpMode::MdnsReflector && rule.bind_systemd_socket().is_none());
+        for rule in udp_rules {
+            for addr in rule.bind_socket_addrs()? {
+                udp.push(Listener {
+                    rule: rule.rule_name(),
+                    addr,
+                    ipv6_only: rule.ipv6_only.unwrap_or(false),
+                    reuse_port: rule.reuse_port.unwrap_or(false),
+                    device: rule.bind_device.as_deref(),
+                });
+            }
+        }
+        check_listeners("UDP", &udp)
+    }
+}
+
+impl TunnelConfig {
+    pub fn connections(&self) -> usize {
+        self.connections.unwrap_or(4)
+    }
+
+    pub fn transport(&self) -> TunnelTransport {
+        self.transport.unwrap_or_default()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.token.is_empty() {
+            anyhow::bail!("[tunnel] token must not be empty");
+        }
+        match self.role {
+            TunnelRole::Server => {
+                let listen = self.listen.as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("[tunnel] role = \"server\" needs listen"))?;
+                SocketAddr::from_str(listen)
+                    .map_err(|_| anyhow::anyhow!("invalid [tunnel] listen '{}', expected ip:port", listen))?;
+                match (&self.cert, &self.key) {
+                    (Some(cert), Some(key)) => {
+                        tls::load_certified_key(cert, key)?;
+                    }
+                    _ => anyhow::bail!("[tunnel] role = \"server\" needs cert and key"),
+                }
+            }
+            TunnelRole::Client => {
+                let server = self.server.as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("[tunnel] role = \"client\" needs server"))?;
+                let (host, _) = parse_endpoint(server)
+                    .map_err(|e| anyhow::anyhow!("[tunnel] server: {}", e))?;
+                tls::server_name(self.server_name.as_deref().unwrap_or(&host))?;
+                if let Some(ca) = &self.ca {
+                    tls::load_certs(ca)?;
+                }
+                if self.connections == Some(0) {
+                    anyhow::bail!("[tunnel] connections must be greater than 0");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TunnelTransport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TunnelTransport::Tls => "tls",
+            TunnelTransport::Quic => "quic",
+        }
+    }
+}
+
+impl TunnelRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TunnelRole::Server => "server",
+            TunnelRole::Client => "client",
+        }
+    }
+}
+
+impl BanConfig {
+    pub fn window_seconds(&self) -> u64 {
+        self.window.unwrap_or(60)
+    }
+
+    pub fn ban_duration_seconds(&self) -> u64 {
+        self.ban_duration.unwrap_or(600)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.window == Some(0) || self.ban_duration == Some(0) {
+            anyhow::bail!("ban window and ban_duration must be greater than 0");
+        }
+        if self.max_connects.is_none() && self.max_short_sessions.is_none() && self.max_denials.is_none() {
+            anyhow::bail!("[global.ban] needs at least one of max_connects, max_short_sessions, max_denials");
+        }
+        Ok(())
+    }
+}
+
+impl DnsConfig {
+    pub fn server_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        self.servers.iter().flatten()
+            .map(|server| match IpAddr::from_str(server) {
+                Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+                Err(_) => SocketAddr::from_str(server)
+                    .map_err(|_| anyhow::anyhow!("[global.dns]: invalid server '{}', expected ip or ip:port", server)),
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.servers.as_ref().is_some_and(|servers| servers.is_empty()) {
+            anyhow::bail!("[global.dns]: servers must not be empty");
+        }
+        self.server_addrs()?;
+        if self.cache_size == Some(0) {
+            anyhow::bail!("[global.dns]: cache_size must be greater than 0");
+        }
+        if let (Some(min), Some(max)) = (self.min_ttl, self.max_ttl)
+            && min > max
+        {
+            anyhow::bail!("[global.dns]: min_ttl must not exceed max_ttl");
+        }
+        Ok(())
+    }
+}
+
+impl EtcdConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.endpoints.is_empty() {
+            anyhow::bail!("[etcd] endpoints must not be empty");
+        }
+        for endpoint in &self.endpoints {
+            let authority = endpoint.strip_prefix("http://")
+                .or_else(|| endpoint.strip_prefix("https://"))
+                .ok_or_else(|| anyhow::anyhow!("[etcd] endpoint '{}' must start with http:// or https://", endpoint))?;
+            parse_endpoint(authority.trim_end_matches('/'))
+                .map_err(|e| anyhow::anyhow!("[etcd] endpoint: {}", e))?;
+        }
+        if self.prefix.is_empty() {
+            anyhow::bail!("[etcd] prefix must not be empty");
+        }
+        if self.username.is_some() != self.password.is_some() {
+            anyhow::bail!("[etcd] username and password must be set together");
+        }
+        if let Some(ca) = &self.ca {
+            tls::load_certs(ca)?;
+        }
+        Ok(())
+    }
+}
+
+impl DockerConfig {
+    pub fn socket(&self) -> &str {
+        self.socket.as_deref().unwrap_or("/var/run/docker.sock")
+    }
+
+    pub fn bind_addr(&self) -> &str {
+        self.bind_addr.as_deref().unwrap_or("0.0.0.0")
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !cfg!(unix) {
+            anyhow::bail!("[docker] requires a Unix platform, where the Docker API is on a Unix socket");
+        }
+        IpAddr::from_str(self.bind_addr())
+            .map_err(|_| anyhow::anyhow!("[docker] invalid bind_addr '{}'", self.bind_addr()))?;
+        Ok(())
+    }
+}
+
+impl KubernetesConfig {
+    pub const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+    // The configured server, or the in-cluster one from the environment
+    pub fn api_server(&self) -> anyhow::Result<String> {
+        if let Some(api_server) = &self.api_server {
+            return Ok(api_server.trim_end_matches('/').to_string());
+        }
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| anyhow::anyhow!("[discovery.kubernetes] has no api_server and KUBERNETES_SERVICE_HOST is not set"))?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        match host.contains(':') {
+            true => Ok(format!("https://[{}]:{}", host, port)),
+            false => Ok(format!("https://{}:{}", host, port)),
+        }
+    }
+
+    pub fn token_file(&self) -> String {
+        self.token_file.clone().unwrap_or_else(|| format!("{}/token", Self::SERVICE_ACCOUNT))
+    }
+
+    // The service account's CA when running in a pod, else the system roots
+    pub fn ca(&self) -> Option<String> {
+        self.ca.clone().or_else(|| {
+            let ca = format!("{}/ca.crt", Self::SERVICE_ACCOUNT);
+            std::path::Path::new(&ca).exists().then_some(ca)
+        })
+    }
+
+    pub fn namespace(&self) -> String {
+        self.namespace.clone()
+            .or_else(|| std::fs::read_to_string(format!("{}/namespace", Self::SERVICE_ACCOUNT)).ok())
+            .map(|namespace| namespace.trim().to_string())
+            .unwrap_or_else(|| "default".to_string())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(api_server) = &self.api_server {
+            let authority = api_server.strip_prefix("http://")
+                .or_else(|| api_server.strip_prefix("https://"))
+                .ok_or_else(|| anyhow::anyhow!("[discovery.kubernetes] api_server '{}' must start with http:// or https://", api_server))?;
+            parse_endpoint(authority.trim_end_matches('/'))
+                .map_err(|e| anyhow::anyhow!("[discovery.kubernetes] api_server: {}", e))?;
+        }
+        if let Some(ca) = &self.ca {
+            tls::load_certs(ca)?;
+        }
+        if self.namespace.as_deref().is_some_and(|namespace| !is_dns_label(namespace)) {
+            anyhow::bail!("[discovery.kubernetes] invalid namespace");
+        }
+        Ok(())
+    }
+}
+
+impl ConsulConfig {
+    pub fn addr(&self) -> &str {
+        self.addr.as_deref().unwrap_or("127.0.0.1:8500")
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        parse_endpoint(self.addr())
+            .map_err(|e| anyhow::anyhow!("[discovery.consul] addr: {}", e))?;
+        Ok(())
+    }
+}
+
+impl KnockConfig {
+    pub fn window_seconds(&self) -> u64 {
+        self.window.unwrap_or(10)
+    }
+
+    pub fn open_for_seconds(&self) -> u64 {
+        self.open_for.unwrap_or(300)
+    }
+
+    pub fn validate(&self, bind_port: u16) -> anyhow::Result<()> {
+        if self.ports.is_empty() {
+            anyhow::bail!("knock.ports must not be empty");
+        }
+        if self.ports.contains(&bind_port) {
+            anyhow::bail!("knock.ports must not include the rule's own bind_port");
+        }
+        if self.window == Some(0) || self.open_for == Some(0) {
+            anyhow::bail!("knock window and open_for must be greater than 0");
+        }
+        Ok(())
+    }
+
+    pub fn to_inline_toml(&self) -> String {
+        let ports: Vec<String> = self.ports.iter().map(|p| p.to_string()).collect();
+        let mut fields = vec![format!("ports = [{}]", ports.join(", "))];
+        if let Some(protocol) = self.protocol {
+            let protocol = match protocol {
+                KnockProtocol::Udp => "udp",
+                KnockProtocol::Tcp => "tcp",
+            };
+            fields.push(format!("protocol = \"{}\"", protocol));
+        }
+        if let Some(window) = self.window {
+            fields.push(format!("window = {}", window));
+        }
+        if let Some(open_for) = self.open_for {
+            fields.push(format!("open_for = {}", open_for));
+        }
+        format!("{{ {} }}", fields.join(", "))
+    }
+}
+
+impl KeepaliveConfig {
+    pub fn idle_seconds(&self) -> u64 {
+        self.idle.unwrap_or(60)
+    }
+
+    pub fn interval_seconds(&self) -> u64 {
+        self.interval.unwrap_or(10)
+    }
+
+    pub fn probe_count(&self) -> u32 {
+        self.count.unwrap_or(5)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.idle == Some(0) || self.interval == Some(0) || self.count == Some(0) {
+            anyhow::bail!("keepalive idle, interval and count must be greater than 0");
+        }
+        Ok(())
+    }
+
+    pub fn to_inline_toml(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(idle) = self.idle {
+            fields.push(format!("idle = {}", idle));
+        }
+        if let Some(interval) = self.interval {
+            fields.push(format!("interval = {}", interval));
+        }
+        if let Some(count) = self.count {
+            fields.push(format!("count = {}", count));
+        }
+        format!("{{ {} }}", fields.join(", "))
+    }
+}
+
+impl SocketOptions {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.send_buffer == Some(0) || self.recv_buffer == Some(0) {
+            anyhow::bail!("socket send_buffer and recv_buffer must be greater than 0");
+        }
+        Ok(())
+    }
+
+    pub fn to_inline_toml(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(nodelay) = self.nodelay {
+            fields.push(format!("nodelay = {}", nodelay));
+        }
+        if let Some(send_buffer) = self.send_buffer {
+            fields.push(format!("send_buffer = {}", send_buffer));
+        }
+        if let Some(recv_buffer) = self.recv_buffer {
+            fields.push(format!("recv_buffer = {}", recv_buffer));
+        }
+        if let Some(linger) = self.linger {
+            fields.push(format!("linger = {}", linger));
+        }
+        format!("{{ {} }}", fields.join(", "))
+    }
+}
+
+impl TlsConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match (&self.cert, &self.key, &self.acme) {
+            (Some(cert), Some(key), None) => {
+                tls::load_certified_key(cert, key)?;
+            }
+            (None, None, Some(acme)) => acme.validate()?,
+            (_, _, Some(_)) => anyhow::bail!("tls.acme cannot be combined with tls.cert/tls.key"),
+            _ => anyhow::bail!("tls needs both cert and key, or acme"),
+        }
+        Ok(())
+    }
+
+    pub fn to_inline_toml(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(ref cert) = self.cert {
+            fields.push(format!("cert = \"{}\"", cert));
+        }
+        if let Some(ref key) = self.key {
+            fields.push(format!("key = \"{}\"", key));
+        }
+        if let Some(ref acme) = self.acme {
+            fields.push(format!("acme = {}", acme.to_inline_toml()));
+        }
+        format!("{{ {} }}", fields.join(", "))
+    }
+}
+
+impl AcmeConfig {
+    pub fn directory_url(&self) -> &str {
+        self.directory.as_deref()
+            .unwrap_or("https://acme-v02.api.letsencrypt.org/directory")
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.domains.is_empty() {
+            anyhow::bail!("acme.domains must not be empty");
+        }
+        // Wildcards need DNS-01, which isn't supported
+        if let Some(domain) = self.domains.iter().find(|d| !is_valid_hostname(d)) {
+            anyhow::bail!("invalid acme domain '{}'", domain);
+        }
+        if self.cache_dir.is_empty() {
+            anyhow::bail!("acme.cache_dir must not be empty");
+        }
+        if !self.directory_url().starts_with("https://") {
+            anyhow::bail!("acme.directory must be an https:// URL");
+        }
+        Ok(())
+    }
+
+    pub fn to_inline_toml(&self) -> String {
+        let mut fields = vec![format!("domains = {}", toml_string_array(&self.domains))];
+        if let Some(ref email) = self.email {
+            fields.push(format!("email = \"{}\"", email));
+        }
+        fields.push(format!("cache_dir = \"{}\"", self.cache_dir));
+        if let Some(ref directory) = self.directory {
+            fields.push(format!("directory = \"{}\"", directory));
+        }
+        format!("{{ {} }}", fields.join(", "))
+    }
+}
+
+impl SniffRoutes {
+    pub fn to_inline_toml(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(ref tls) = self.tls {
+            fields.push(format!("tls = \"{}\"", tls));
+        }
+        if let Some(ref http) = self.http {
+            fields.push(format!("http = \"{}\"", http));
+        }
+        format!("{{ {} }}", fields.join(", "))
+    }
+}
+
+impl TcpMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TcpMode::Forward => "forward",
+            TcpMode::Sni => "sni",
+            TcpMode::Sniff => "sniff",
+            TcpMode::Http => "http",
+            TcpMode::Socks5 => "socks5",
+            TcpMode::HttpConnect => "http_connect",
+            TcpMode::UdpInTcpServer => "udp_in_tcp_server",
+            TcpMode::WebsocketServer => "websocket_server",
+            TcpMode::WebsocketClient => "websocket_client",
+            TcpMode::Redirect => "redirect",
+            TcpMode::Echo => "echo",
+            TcpMode::Discard => "discard",
+        }
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        matches!(self, TcpMode::WebsocketServer | TcpMode::WebsocketClient)
+    }
+
+    // Proxy modes take the target from each client instead of the rule
+    pub fn is_proxy(&self) -> bool {
+        matches!(self, TcpMode::Socks5 | TcpMode::HttpConnect | TcpMode::Redirect)
+    }
+
+    // Built-in test servers answer clients themselves, without a target
+    pub fn is_builtin(&self) -> bool {
+        matches!(self, TcpMode::Echo | TcpMode::Discard)
+    }
+}
+
+impl UdpMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UdpMode::Forward => "forward",
+            UdpMode::UdpInTcpClient => "udp_in_tcp_client",
+            UdpMode::MdnsReflector => "mdns_reflector",
+            UdpMode::Echo => "echo",
+            UdpMode::Discard => "discard",
+        }
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        matches!(self, UdpMode::Echo | UdpMode::Discard)
+    }
+}
+
+impl OverflowPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverflowPolicy::Reject => "reject",
+            OverflowPolicy::Queue => "queue",
+        }
+    }
+}
+
+impl DataDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DataDirection::Both => "both",
+            DataDirection::Upload => "upload",
+            DataDirection::Download => "download",
+        }
+    }
+
+    pub fn upload(&self) -> bool {
+        *self != DataDirection::Download
+    }
+
+    pub fn download(&self) -> bool {
+        *self != DataDirection::Upload
+    }
+}
+
+impl RewriteConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.find.is_some() == self.find_hex.is_some() {
+            anyhow::bail!("rewrite needs one of find and find_hex");
+        }
+        if self.replace.is_some() == self.replace_hex.is_some() {
+            anyhow::bail!("rewrite needs one of replace and replace_hex");
+        }
+        if self.find_bytes()?.is_empty() {
+            anyhow::bail!("rewrite find must not be empty");
+        }
+        self.replace_bytes()?;
+        Ok(())
+    }
+
+    pub fn find_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        text_or_hex(&self.find, &self.find_hex).map_err(|e| anyhow::anyhow!("rewrite find_hex: {}", e))
+    }
+
+    pub fn replace_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        text_or_hex(&self.replace, &self.replace_hex).map_err(|e| anyhow::anyhow!("rewrite replace_hex: {}", e))
+    }
+
+    pub fn direction(&self) -> DataDirection {
+        self.direction.unwrap_or_default()
+    }
+
+    pub fn to_inline_toml(&self) -> String {
+        let sides = [
+            ("find", &self.find),
+            ("find_hex", &self.find_hex),
+            ("replace", &self.replace),
+            ("replace_hex", &self.replace_hex),
+        ];
+        inline_data_toml(&sides, self.direction)
+    }
+}
+
+impl BannerConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.data.is_some() == self.data_hex.is_some() {
+            anyhow::bail!("banner needs one of data and data_hex");
+        }
+        if self.bytes()?.is_empty() {
+            anyhow::bail!("banner data must not be empty");
+        }
+        Ok(())
+    }
+
+    pub fn bytes(&self) -> anyhow::Result<Vec<u8>> {
+        text_or_hex(&self.data, &self.data_hex).map_err(|e| anyhow::anyhow!("banner data_hex: {}", e))
+    }
+
+    pub fn direction(&self) -> DataDirection {
+        self.direction.unwrap_or(DataDirection::Upload)
+    }
+
+    pub fn to_inline_toml(&self) -> String {
+        inline_data_toml(&[("data", &self.data), ("data_hex", &self.data_hex)], self.direction)
+    }
+}
+
+// The fields of a rewrite or banner entry. Data is arbitrary text, so unlike
+// other strings it is escaped.
+fn inline_data_toml(sides: &[(&str, &Option<String>)], direction: Option<DataDirection>) -> String {
+    let mut fields: Vec<String> = sides.iter()
+        .filter_map(|(key, value)| value.as_ref().map(|value| format!("{} = {}", key, toml::Value::from(value.as_str()))))
+        .collect();
+    if let Some(direction) = direction {
+        fields.push(format!("direction = \"{}\"", direction.as_str()));
+    }
+    format!("{{ {} }}", fields.join(", "))
+}
+
+// The bytes of a string, or of hex if that is given instead
+fn text_or_hex(text: &Option<String>, hex: &Option<String>) -> anyhow::Result<Vec<u8>> {
+    match (text, hex) {
+        (Some(text), _) => Ok(text.as_bytes().to_vec()),
+        (None, Some(hex)) => parse_hex(hex),
+        (None, None) => Ok(Vec::new()),
+    }
+}
+
+impl TcpRule {
+    pub fn expand_endpoints(&mut self) -> anyhow::Result<()> {
+        let label = self.name.clone().or_else(|| self.bind.clone()).unwrap_or_else(|| self.bind_addr.join(", "));
+        let context = |e: anyhow::Error| anyhow::anyhow!("TCP rule '{}': {}", label, e);
+        let bind_set = !self.bind_addr.is_empty() || self.bind_port != 0;
+        if let Some((addr, port)) = split_endpoint("bind", self.bind.take(), bind_set).map_err(context)? {
+            (self.bind_addr, self.bind_port) = (vec![addr], port);
+        }
+        let target_set = !self.target_addr.is_empty() || self.target_port != 0;
+        if let Some((addr, port)) = split_endpoint("target", self.target.take(), target_set).map_err(context)? {
+            (self.target_addr, self.target_port) = (addr, port);
+        }
+        if self.bind_addr.is_empty() {
+            anyhow::bail!("TCP rule '{}': bind or bind_addr is required", label);
+        }
+        Ok(())
+    }
+
+    pub fn bind_socket_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        bind_socket_addrs(&self.bind_addr, self.bind_port)
+            .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))
+    }
+
+    // A "unix:" bind_addr can't be listed with others, so only a single
+    // address is considered
+    pub fn bind_unix_path(&self) -> Option<&str> {
+        match self.bind_addr.as_slice() {
+            [addr] => unix_socket::parse(addr),
+            _ => None,
+        }
+    }
+
+    pub fn target_unix_path(&self) -> Option<&str> {
+        unix_socket::parse(&self.target_addr)
+    }
+
+    // Like "unix:", a "systemd:" bind_addr stands alone
+    pub fn bind_systemd_socket(&self) -> Option<&str> {
+        match self.bind_addr.as_slice() {
+            [addr] => systemd::parse(addr),
+            _ => None,
+        }
+    }
+
+    // Where the listeners are bound, for logs
+    pub fn bind_endpoint(&self) -> String {
+        if let Some(name) = self.bind_systemd_socket() {
+            return format!("systemd:{}", name);
+        }
+        match self.bind_unix_path() {
+            Some(path) => format!("unix:{}", path),
+            None => bind_endpoint(&self.bind_addr, self.bind_port),
+        }
+    }
+
+    // Targets may be IP literals, hostnames or "unix:" sockets; hostnames are
+    // resolved per connection
+    pub fn target_endpoint(&self) -> String {
+        if self.mode().is_builtin() {
+            format!("built-in {} server", self.mode().as_str())
+        } else if let Some(srv) = &self.target_srv {
+            format!("SRV {}", srv)
+        } else if let Some(service) = &self.target_consul {
+            format!("Consul service {}", service)
+        } else if let Some(service) = &self.target_kubernetes {
+            format!("Kubernetes service {}", service)
+        } else if self.target_unix_path().is_some() {
+            self.target_addr.clone()
+        } else if self.target_addr.contains(':') {
+            format!("[{}]:{}", self.target_addr, self.target_port)
+        } else {
+            format!("{}:{}", self.target_addr, self.target_port)
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(level) = &self.log_level {
+            rule_log_level(level).map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if self.wasm_filter.is_some() && !cfg!(feature = "wasm") {
+            anyhow::bail!("TCP rule '{}': wasm_filter requires porture built with the \"wasm\" feature", self.rule_name());
+        }
+        if self.lua_script.is_some() && !cfg!(feature = "lua") {
+            anyhow::bail!("TCP rule '{}': lua_script requires porture built with the \"lua\" feature", self.rule_name());
+        }
+        match (self.bind_systemd_socket(), self.bind_unix_path()) {
+            (Some(name), _) => {
+                systemd::validate(name).map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+                // systemd binds the socket, so these belong in the .socket unit
+                if self.transparent.is_some() || self.reuse_port.is_some() || self.freebind.is_some() || self.ipv6_only.is_some() {
+                    anyhow::bail!(
+                        "TCP rule '{}': set transparent, reuse_port, freebind and ipv6_only in the systemd socket unit instead",
+                        self.rule_name()
+                    );
+                }
+                if self.knock.is_some() {
+                    anyhow::bail!("TCP rule '{}': knock requires a single IP bind_addr", self.rule_name());
+                }
+            }
+            (None, Some(path)) => unix_socket::validate(path)
+                .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?,
+            (None, None) => {
+                if self.bind_addr.iter().any(|addr| unix_socket::parse(addr).is_some() || systemd::parse(addr).is_some()) {
+                    anyhow::bail!("TCP rule '{}': a unix: or systemd: bind_addr cannot be listed with other addresses", self.rule_name());
+                }
+                self.bind_socket_addrs()?;
+            }
+        }
+        if let Some(path) = self.target_unix_path() {
+            unix_socket::validate(path)
+                .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+            // The socket only exists on this host
+            if self.upstream_proxy.is_some() || self.tunnel.unwrap_or(false) || self.mode() == TcpMode::UdpInTcpServer {
+                anyhow::bail!(
+                    "TCP rule '{}': a unix: target cannot be combined with upstream_proxy, tunnel or mode = \"udp_in_tcp_server\"",
+                    self.rule_name()
+                );
+            }
+        }
+        if self.transparent.unwrap_or(false) {
+            if !cfg!(target_os = "linux") {
+                anyhow::bail!("TCP rule '{}': transparent requires Linux", self.rule_name());
+            }
+            if self.bind_unix_path().is_some() || self.target_unix_path().is_some() {
+                anyhow::bail!("TCP rule '{}': transparent cannot be used with unix: addresses", self.rule_name());
+            }
+            // The spoofed source only works for connections made from this host
+            if self.upstream_proxy.is_some() || self.tunnel.unwrap_or(false) || self.mode() == TcpMode::UdpInTcpServer {
+                anyhow::bail!(
+                    "TCP rule '{}': transparent cannot be combined with upstream_proxy, tunnel or mode = \"udp_in_tcp_server\"",
+                    self.rule_name()
+                );
+            }
+        }
+        if self.knock.is_some() && (self.bind_unix_path().is_some() || self.bind_addr.len() > 1) {
+            anyhow::bail!("TCP rule '{}': knock requires a single IP bind_addr", self.rule_name());
+        }
+        if self.mode().is_proxy() {
+            if self.target_tls.is_some() || self.proxy_protocol.is_some() {
+                anyhow::bail!(
+                    "TCP rule '{}': target_tls and proxy_protocol are not supported in mode = \"{}\"",
+                    self.rule_name(), self.mode().as_str()
+                );
+            }
+        } else if self.mode().is_builtin() {
+            self.validate_builtin()?;
+        } else if let Some(srv) = &self.target_srv {
+            if !is_valid_srv_name(srv) {
+                anyhow::bail!("TCP rule '{}': invalid target_srv '{}', expected e.g. \"_service._tcp.example.com\"", self.rule_name(), srv);
+            }
+            if self.target_consul.is_some() || self.target_kubernetes.is_some() {
+                anyhow::bail!("TCP rule '{}': target_srv cannot be combined with target_consul or target_kubernetes", self.rule_name());
+            }
+            self.validate_discovered_target("target_srv")?;
+        } else if let Some(service) = &self.target_consul {
+            if service.is_empty() || service.contains(['/', '?', '#', '%', ' ']) {
+                anyhow::bail!("TCP rule '{}': invalid target_consul service name '{}'", self.rule_name(), service);
+            }
+            if self.target_kubernetes.is_some() {
+                anyhow::bail!("TCP rule '{}': target_consul and target_kubernetes cannot be combined", self.rule_name());
+            }
+            self.validate_discovered_target("target_consul")?;
+        } else if let Some(service) = &self.target_kubernetes {
+            let valid = match service.split_once('/') {
+                Some((namespace, name)) => is_dns_label(namespace) && is_dns_label(name),
+                None => is_dns_label(service),
+            };
+            if !valid {
+                anyhow::bail!("TCP rule '{}': invalid target_kubernetes '{}', expected \"name\" or \"namespace/name\"", self.rule_name(), service);
+            }
+            self.validate_discovered_target("target_kubernetes")?;
+        } else if self.target_unix_path().is_none()
+            && IpAddr::from_str(&self.target_addr).is_err()
+            && !is_valid_hostname(&self.target_addr)
+        {
+            anyhow::bail!("TCP rule '{}': invalid target address '{}'", self.rule_name(), self.target_addr);
+        }
+        if self.target_kubernetes_port.is_some() && self.target_kubernetes.is_none() {
+            anyhow::bail!("TCP rule '{}': target_kubernetes_port requires target_kubernetes", self.rule_name());
+        }
+        if self.mode() == TcpMode::UdpInTcpServer
+            && (self.target_tls.is_some() || self.proxy_protocol.is_some() || self.upstream_proxy.is_some())
+        {
+            anyhow::bail!(
+                "TCP rule '{}': target_tls, proxy_protocol and upstream_proxy are not supported in mode = \"udp_in_tcp_server\"",
+                self.rule_name()
+            );
+        }
+        if self.tunnel.unwrap_or(false) && (self.upstream_proxy.is_some() || self.mode() == TcpMode::UdpInTcpServer) {
+            anyhow::bail!("TCP rule '{}': tunnel cannot be combined with upstream_proxy or mode = \"udp_in_tcp_server\"", self.rule_name());
+        }
+        if let Some(source) = self.source_ip()? {
+            if self.transparent.unwrap_or(false) || self.tunnel.unwrap_or(false) || self.target_unix_path().is_some() {
+                anyhow::bail!(
+                    "TCP rule '{}': source_addr cannot be combined with transparent, tunnel or a unix: target",
+                    self.rule_name()
+                );
+            }
+            if let Ok(target) = IpAddr::from_str(&self.target_addr)
+                && target.is_ipv6() != source.is_ipv6()
+            {
+                anyhow::bail!("TCP rule '{}': source_addr {} cannot reach target {}", self.rule_name(), source, target);
+            }
+        }
+        for device in [&self.bind_device, &self.source_device].into_iter().flatten() {
+            sockopt::validate_device(device).map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if self.bind_device.is_some() && self.bind_unix_path().is_some() {
+            anyhow::bail!("TCP rule '{}': bind_device requires an IP bind_addr", self.rule_name());
+        }
+        if self.reuse_port.unwrap_or(false) && self.bind_unix_path().is_some() {
+            anyhow::bail!("TCP rule '{}': reuse_port requires an IP bind_addr", self.rule_name());
+        }
+        if self.reuse_port.unwrap_or(false) && !cfg!(unix) {
+            anyhow::bail!("TCP rule '{}': reuse_port requires a Unix platform", self.rule_name());
+        }
+        if self.freebind.unwrap_or(false) {
+            if !cfg!(target_os = "linux") {
+                anyhow::bail!("TCP rule '{}': freebind requires Linux", self.rule_name());
+            }
+            if self.bind_unix_path().is_some() {
+                anyhow::bail!("TCP rule '{}': freebind requires an IP bind_addr", self.rule_name());
+            }
+        }
+        if self.ipv6_only.is_some() && !self.bind_socket_addrs().is_ok_and(|addrs| addrs.iter().all(|addr| addr.is_ipv6())) {
+            anyhow::bail!("TCP rule '{}': ipv6_only requires IPv6 bind_addr addresses", self.rule_name());
+        }
+        if self.source_device.is_some()
+            && (self.transparent.unwrap_or(false) || self.tunnel.unwrap_or(false) || self.target_unix_path().is_some())
+        {
+            anyhow::bail!(
+                "TCP rule '{}': source_device cannot be combined with transparent, tunnel or a unix: target",
+                self.rule_name()
+            );
+        }
+        if self.fwmark.is_some() {
+            sockopt::validate_mark().map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+            if self.tunnel.unwrap_or(false) || self.target_unix_path().is_some() {
+                anyhow::bail!("TCP rule '{}': fwmark cannot be combined with tunnel or a unix: target", self.rule_name());
+            }
+        }
+        if let Some(dscp) = self.dscp {
+            sockopt::validate_dscp(dscp).map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+            if self.tunnel.unwrap_or(false) || self.bind_unix_path().is_some() || self.target_unix_path().is_some() {
+                anyhow::bail!("TCP rule '{}': dscp requires IP sockets on both sides, without tunnel", self.rule_name());
+            }
+        }
+        if self.mode() == TcpMode::Redirect {
+            if !cfg!(target_os = "linux") {
+                anyhow::bail!("TCP rule '{}': mode = \"redirect\" requires Linux", self.rule_name());
+            }
+            if self.bind_unix_path().is_some() {
+                anyhow::bail!("TCP rule '{}': mode = \"redirect\" requires an IP bind_addr", self.rule_name());
+            }
+            self.redirect_filter()
+                .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        } else if self.redirect_allow.is_some() || self.redirect_deny.is_some() || self.redirect_ports.is_some() {
+            anyhow::bail!(
+                "TCP rule '{}': redirect_allow, redirect_deny and redirect_ports require mode = \"redirect\"",
+                self.rule_name()
+            );
+        }
+        // Anyone who can send the header can claim any address, and so get
+        // past allow/deny lists, bans and per-IP limits
+        if self.accept_proxy_protocol.unwrap_or(false) {
+            if self.trusted_proxies.as_ref().is_none_or(Vec::is_empty) {
+                anyhow::bail!(
+                    "TCP rule '{}': accept_proxy_protocol needs trusted_proxies, the addresses of the proxies that send the header",
+                    self.rule_name()
+                );
+            }
+            self.trusted_proxies()
+                .map_err(|e| anyhow::anyhow!("TCP rule '{}': trusted_proxies: {}", self.rule_name(), e))?;
+        } else if self.trusted_proxies.is_some() {
+            anyhow::bail!("TCP rule '{}': trusted_proxies requires accept_proxy_protocol = true", self.rule_name());
+        }
+        if self.mode() != TcpMode::Socks5 && self.socks5_users.is_some() {
+            anyhow::bail!("TCP rule '{}': socks5_users requires mode = \"socks5\"", self.rule_name());
+        }
+        for (user, password) in self.socks5_users.iter().flatten() {
+            if user.is_empty() || user.len() > 255 || password.len() > 255 {
+                anyhow::bail!("TCP rule '{}': SOCKS5 usernames must be 1-255 bytes and passwords at most 255", self.rule_name());
+            }
+        }
+        if !self.mode().is_websocket() && self.websocket_path.is_some() {
+            anyhow::bail!("TCP rule '{}': websocket_path requires a websocket mode", self.rule_name());
+        }
+        if self.mode() != TcpMode::WebsocketClient && self.websocket_host.is_some() {
+            anyhow::bail!("TCP rule '{}': websocket_host requires mode = \"websocket_client\"", self.rule_name());
+        }
+        if let Some(path) = &self.websocket_path
+            && (!path.starts_with('/') || path.chars().any(|c| c.is_control() || c.is_whitespace()))
+        {
+            anyhow::bail!("TCP rule '{}': websocket_path must be an absolute path, got '{}'", self.rule_name(), path);
+        }
+        if let Some(host) = &self.websocket_host
+            && (host.is_empty() || host.chars().any(|c| c.is_control() || c.is_whitespace()))
+        {
+            anyhow::bail!("TCP rule '{}': invalid websocket_host '{}'", self.rule_name(), host);
+        }
+        if self.connect_timeout == Some(0) {
+            anyhow::bail!("TCP rule '{}': connect_timeout must be greater than 0", self.rule_name());
+        }
+        if self.idle_timeout == Some(0) {
+            anyhow::bail!("TCP rule '{}': idle_timeout must be greater than 0", self.rule_name());
+        }
+        if self.max_connections == Some(0) {
+            anyhow::bail!("TCP rule '{}': max_connections must be greater than 0", self.rule_name());
+        }
+        if self.max_connections_per_ip == Some(0) {
+            anyhow::bail!("TCP rule '{}': max_connections_per_ip must be greater than 0", self.rule_name());
+        }
+        if self.max_new_connections_per_sec == Some(0) {
+            anyhow::bail!("TCP rule '{}': max_new_connections_per_sec must be greater than 0", self.rule_name());
+        }
+        if self.rate_limit_kbps == Some(0) {
+            anyhow::bail!("TCP rule '{}': rate_limit_kbps must be greater than 0", self.rule_name());
+        }
+        if self.client_rate_limit_kbps == Some(0) {
+            anyhow::bail!("TCP rule '{}': client_rate_limit_kbps must be greater than 0", self.rule_name());
+        }
+        self.acl()
+            .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        self.country_filter()
+            .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        if let Some(knock) = &self.knock {
+            knock.validate(self.bind_port)
+                .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        }
+        for rewrite in self.rewrite.iter().flatten() {
+            rewrite.validate().map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        }
+        for banner in self.banner.iter().flatten() {
+            banner.validate().map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if let Some(mirror) = &self.mirror_target {
+            parse_endpoint(mirror).map_err(|e| anyhow::anyhow!("TCP rule '{}': mirror_target: {}", self.rule_name(), e))?;
+        }
+        validate_capture(&self.capture_file, self.capture_max_mb, self.capture_seconds, self.capture_on_start)
+            .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        validate_latency(self.inject_delay_ms, self.inject_jitter_ms)
+            .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.validate().map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if let Some(socket) = &self.socket {
+            socket.validate().map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if let Some(tls) = &self.tls {
+            tls.validate()
+                .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if let Some(proxy) = &self.upstream_proxy {
+            UpstreamProxy::parse(proxy)
+                .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if self.target_tls.unwrap_or(false) {
+            TargetConnector::from_rule(self)
+                .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+        } else if self.target_tls_ca.is_some()
+            || self.target_tls_sni.is_some()
+            || self.target_tls_skip_verify.is_some()
+        {
+            anyhow::bail!("TCP rule '{}': target_tls_* options require target_tls = true", self.rule_name());
+        }
+        if self.mode() == TcpMode::Sni {
+            // The ClientHello has to reach the backend untouched
+            if self.tls.is_some() || self.target_tls.unwrap_or(false) {
+                anyhow::bail!("TCP rule '{}': mode = \"sni\" cannot be combined with tls or target_tls", self.rule_name());
+            }
+        } else if self.sni_routes.is_some() {
+            anyhow::bail!("TCP rule '{}': sni_routes requires mode = \"sni\"", self.rule_name());
+        }
+        if self.mode() != TcpMode::Sniff && self.sniff_routes.is_some() {
+            anyhow::bail!("TCP rule '{}': sniff_routes requires mode = \"sniff\"", self.rule_name());
+        }
+        if let Some(routes) = &self.sniff_routes {
+            // Routed connections go in plaintext, as TLS is set up for
+            // target_addr only
+            if self.target_tls.unwrap_or(false) {
+                anyhow::bail!("TCP rule '{}': sniff_routes cannot be combined with target_tls", self.rule_name());
+            }
+            for (protocol, target) in [("tls", &routes.tls), ("http", &routes.http)] {
+                if let Some(target) = target {
+                    parse_endpoint(target).map_err(|e| {
+                        anyhow::anyhow!("TCP rule '{}': sniff_routes.{}: {}", self.rule_name(), protocol, e)
+                    })?;
+                }
+            }
+        }
+        if self.mode() != TcpMode::Http
+            && (self.host_routes.is_some() || self.rewrite_host.is_some() || self.forwarded_headers.is_some())
+        {
+            anyhow::bail!(
+                "TCP rule '{}': host_routes, rewrite_host and forwarded_headers require mode = \"http\"",
+                self.rule_name()
+            );
+        }
+        if let Some(host) = &self.rewrite_host
+            && (host.is_empty() || host.chars().any(|c| c.is_control() || c.is_whitespace()))
+        {
+            anyhow::bail!("TCP rule '{}': invalid rewrite_host '{}'", self.rule_name(), host);
+        }
+        for (field, routes) in [("sni_routes", &self.sni_routes), ("host_routes", &self.host_routes)] {
+            if let Some(routes) = routes {
+                validate_host_routes(field, routes)
+                    .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))?;
+            }
+        }
+        // Plain forwarding to itself; the other modes pick targets per
+        // connection or reach them some other way
+        if self.mode() == TcpMode::Forward && self.upstream_proxy.is_none() && !self.tunnel.unwrap_or(false)
+            && let Ok(bind) = self.bind_socket_addrs()
+            && let Some(addr) = own_listener(&bind, self.ipv6_only.unwrap_or(false), &self.target_addr, self.target_port)
+        {
+            anyhow::bail!("TCP rule '{}': target {} is the rule's own listener {}, which would forward in a loop",
+                self.rule_name(), self.target_endpoint(), addr);
+        }
+        Ok(())
+    }
+
+    // Nothing is connected to in mode = "echo" or "discard", so what says
+    // where or how to connect doesn't belong
+    fn validate_builtin(&self) -> anyhow::Result<()> {
+        if !self.target_addr.is_empty() || self.target_port != 0
+            || self.target_srv.is_some() || self.target_consul.is_some() || self.target_kubernetes.is_some()
+        {
+            anyhow::bail!("TCP rule '{}': mode = \"{}\" takes no target", self.rule_name(), self.mode().as_str());
+        }
+        let outgoing = [
+            ("target_tls", self.target_tls.is_some()),
+            ("proxy_protocol", self.proxy_protocol.is_some()),
+            ("upstream_proxy", self.upstream_proxy.is_some()),
+            ("tunnel", self.tunnel.is_some()),
+            ("transparent", self.transparent.is_some()),
+            ("source_addr", self.source_addr.is_some()),
+            ("source_device", self.source_device.is_some()),
+            ("fwmark", self.fwmark.is_some()),
+        ];
+        if let Some((field, _)) = outgoing.iter().find(|(_, set)| *set) {
+            anyhow::bail!("TCP rule '{}': {} is not supported in mode = \"{}\"", self.rule_name(), field, self.mode().as_str());
+        }
+        Ok(())
+    }
+
+    // Checks shared by target_srv and target_consul, whose targets are only
+    // known at runtime
+    fn validate_discovered_target(&self, field: &str) -> anyhow::Result<()> {
+        if !self.target_addr.is_empty() || self.target_port != 0 {
+            anyhow::bail!("TCP rule '{}': {} cannot be combined with target or target_addr/target_port", self.rule_name(), field);
+        }
+        if matches!(self.mode(), TcpMode::UdpInTcpServer | TcpMode::Redirect) {
+            anyhow::bail!("TCP rule '{}': {} is not supported in mode = \"{}\"", self.rule_name(), field, self.mode().as_str());
+        }
+        // Names for TLS and WebSocket otherwise default to target_addr
+        if self.target_tls.unwrap_or(false) && self.target_tls_sni.is_none() {
+            anyhow::bail!("TCP rule '{}': {} with target_tls requires target_tls_sni", self.rule_name(), field);
+        }
+        if self.mode() == TcpMode::WebsocketClient && self.websocket_host.is_none() && self.target_tls_sni.is_none() {
+            anyhow::bail!("TCP rule '{}': {} in mode = \"websocket_client\" requires websocket_host", self.rule_name(), field);
+        }
+        Ok(())
+    }
+
+    pub fn log_level_filter(&self) -> Option<LevelFilter> {
+        self.log_level.as_deref().and_then(|level| rule_log_level(level).ok())
+    }
+
+    pub fn rule_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            if self.mode().is_proxy() || self.mode().is_builtin() {
+                return format!("tcp_{}_{}", self.bind_endpoint(), self.mode().as_str());
+            }
+            if self.target_unix_path().is_some() {
+                return format!("tcp_{}_to_{}", self.bind_endpoint(), self.target_addr);
+            }
+            if let Some(srv) = &self.target_srv {
+                return format!("tcp_{}_to_{}", self.bind_endpoint(), srv);
+            }
+            if let Some(service) = &self.target_consul {
+                return format!("tcp_{}_to_consul_{}", self.bind_endpoint(), service);
+            }
+            if let Some(service) = &self.target_kubernetes {
+                return format!("tcp_{}_to_kubernetes_{}", self.bind_endpoint(), service);
+            }
+            format!("tcp_{}_to_{}:{}", 
+                self.bind_endpoint(),
+                self.target_addr, self.target_port)
+        })
+    }
+
+    pub fn connect_timeout_seconds(&self) -> u64 {
+        self.connect_timeout.unwrap_or(10)
+    }
+
+    pub fn source_ip(&self) -> anyhow::Result<Option<IpAddr>> {
+        parse_source_addr(self.source_addr.as_deref())
+            .map_err(|e| anyhow::anyhow!("TCP rule '{}': {}", self.rule_name(), e))
+    }
+
+    pub fn source(&self) -> anyhow::Result<Source> {
+        Ok(Source {
+            addr: self.source_ip()?,
+            device: self.source_device.clone(),
+            mark: self.fwmark,
+            dscp: self.dscp,
+            keepalive: self.keepalive.clone(),
+            socket: self.socket.clone(),
+        })
+    }
+
+    pub fn bind_options(&self) -> BindOptions {
+        BindOptions {
+            transparent: self.transparent.unwrap_or(false),
+            reuse_port: self.reuse_port.unwrap_or(false),
+            freebind: self.freebind.unwrap_or(false),
+            ipv6_only: self.ipv6_only.unwrap_or(false),
+        }
+    }
+
+    pub fn acl(&self) -> anyhow::Result<Acl> {
+        Acl::from_lists(
+            self.allow.as_deref().unwrap_or_default(),
+            self.deny.as_deref().unwrap_or_default(),
+        )
+    }
+
+    // Peers whose PROXY headers are believed
+    pub fn trusted_proxies(&self) -> anyhow::Result<Acl> {
+        Acl::from_lists(self.trusted_proxies.as_deref().unwrap_or_default(), &[])
+    }
+
+    // Original destinations mode = "redirect" may forward to
+    pub fn redirect_filter(&self) -> anyhow::Result<Acl> {
+        Acl::from_lists(
+            self.redirect_allow.as_deref().unwrap_or_default(),
+            self.redirect_deny.as_deref().unwrap_or_default(),
+        )
+    }
+
+    pub fn country_filter(&self) -> anyhow::Result<Option<CountryFilter>> {
+        CountryFilter::from_lists(
+            self.allow_countries.as_deref().unwrap_or_default(),
+            self.deny_countries.as_deref().unwrap_or_default(),
+        )
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow.unwrap_or_default()
+    }
+
+    pub fn mode(&self) -> TcpMode {
+        self.mode.unwrap_or_default()
+    }
+}
+
+impl UdpRule {
+    pub fn expand_endpoints(&mut self) -> anyhow::Result<()> {
+        let label = self.name.clone().or_else(|| self.bind.clone()).unwrap_or_else(|| self.bind_addr.join(", "));
+        let context = |e: anyhow::Error| anyhow::anyhow!("UDP rule '{}': {}", label, e);
+        let bind_set = !self.bind_addr.is_empty() || self.bind_port != 0;
+        if let Some((addr, port)) = split_endpoint("bind", self.bind.take(), bind_set).map_err(context)? {
+            (self.bind_addr, self.bind_port) = (vec![addr], port);
+        }
+        let target_set = !self.target_addr.is_empty() || self.target_port != 0;
+        if let Some((addr, port)) = split_endpoint("target", self.target.take(), target_set).map_err(context)? {
+            (self.target_addr, self.target_port) = (addr, port);
+        }
+        match self.mode() {
+            UdpMode::MdnsReflector => {}
+            mode if mode.is_builtin() && self.bind_addr.is_empty() => {
+                anyhow::bail!("UDP rule '{}': bind or bind_addr is required", label);
+            }
+            mode if !mode.is_builtin() && (self.bind_addr.is_empty() || self.target_addr.is_empty()) => {
+                anyhow::bail!("UDP rule '{}': bind and target (or bind_addr and target_addr) are required", label);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn bind_socket_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        bind_socket_addrs(&self.bind_addr, self.bind_port)
+            .map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))
+    }
+
+    pub fn bind_systemd_socket(&self) -> Option<&str> {
+        match self.bind_addr.as_slice() {
+            [addr] => systemd::parse(addr),
+            _ => None,
+        }
+    }
+
+    pub fn bind_endpoint(&self) -> String {
+        match self.bind_systemd_socket() {
+            Some(name) => format!("systemd:{}", name),
+            None => bind_endpoint(&self.bind_addr, self.bind_port),
+        }
+    }
+
+    pub fn target_endpoint(&self) -> String {
+        if self.mode().is_builtin() {
+            return format!("built-in {} server", self.mode().as_str());
+        }
+        match self.target_addr.contains(':') {
+            true => format!("[{}]:{}", self.target_addr, self.target_port),
+            false => format!("{}:{}", self.target_addr, self.target_port),
+        }
+    }
+
+    pub fn target_socket_addr(&self) -> anyhow::Result<SocketAddr> {
+        let ip = IpAddr::from_str(&self.target_addr)?;
+        Ok(SocketAddr::new(ip, self.target_port))
+    }
+
+    pub fn source_ip(&self) -> anyhow::Result<Option<IpAddr>> {
+        parse_source_addr(self.source_addr.as_deref())
+            .map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))
+    }
+
+    pub fn source(&self) -> anyhow::Result<Source> {
+        Ok(Source {
+            addr: self.source_ip()?,
+            device: self.source_device.clone(),
+            mark: self.fwmark,
+            dscp: self.dscp,
+            keepalive: None,
+            socket: None,
+        })
+    }
+
+    pub fn bind_options(&self) -> BindOptions {
+        BindOptions {
+            transparent: self.transparent.unwrap_or(false),
+            reuse_port: self.reuse_port.unwrap_or(false),
+            freebind: self.freebind.unwrap_or(false),
+            ipv6_only: self.ipv6_only.unwrap_or(false),
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(level) = &self.log_level {
+            rule_log_level(level).map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if self.wasm_filter.is_some() && !cfg!(feature = "wasm") {
+            anyhow::bail!("UDP rule '{}': wasm_filter requires porture built with the \"wasm\" feature", self.rule_name());
+        }
+        if self.lua_script.is_some() && !cfg!(feature = "lua") {
+            anyhow::bail!("UDP rule '{}': lua_script requires porture built with the \"lua\" feature", self.rule_name());
+        }
+        if self.mode() == UdpMode::MdnsReflector {
+            return self.validate_mdns_reflector();
+        }
+        if self.interfaces.is_some() {
+            anyhow::bail!("UDP rule '{}': interfaces requires mode = \"mdns_reflector\"", self.rule_name());
+        }
+        match self.bind_systemd_socket() {
+            Some(name) => {
+                systemd::validate(name).map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+                if self.transparent.is_some() || self.reuse_port.is_some() || self.freebind.is_some() || self.ipv6_only.is_some() {
+                    anyhow::bail!(
+                        "UDP rule '{}': set transparent, reuse_port, freebind and ipv6_only in the systemd socket unit instead",
+                        self.rule_name()
+                    );
+                }
+                if self.knock.is_some() || self.broadcast.is_some() {
+                    anyhow::bail!("UDP rule '{}': knock and broadcast require an IP bind_addr", self.rule_name());
+                }
+            }
+            None => {
+                self.bind_socket_addrs()?;
+            }
+        }
+        if self.knock.is_some() && self.bind_addr.len() > 1 {
+            anyhow::bail!("UDP rule '{}': knock requires a single bind_addr", self.rule_name());
+        }
+        // The tunnel client connects over TCP, so its target may be a hostname
+        if self.mode() == UdpMode::UdpInTcpClient {
+            if IpAddr::from_str(&self.target_addr).is_err() && !is_valid_hostname(&self.target_addr) {
+                anyhow::bail!("UDP rule '{}': invalid target address '{}'", self.rule_name(), self.target_addr);
+            }
+        } else if self.mode().is_builtin() {
+            if !self.target_addr.is_empty() || self.target_port != 0 {
+                anyhow::bail!("UDP rule '{}': mode = \"{}\" takes no target", self.rule_name(), self.mode().as_str());
+            }
+            if self.source_addr.is_some() || self.source_device.is_some() || self.fwmark.is_some() {
+                anyhow::bail!(
+                    "UDP rule '{}': source_addr, source_device and fwmark are not supported in mode = \"{}\"",
+                    self.rule_name(), self.mode().as_str()
+                );
+            }
+        } else {
+            self.target_socket_addr()?;
+        }
+        if self.transparent.unwrap_or(false) {
+            if !cfg!(target_os = "linux") {
+                anyhow::bail!("UDP rule '{}': transparent requires Linux", self.rule_name());
+            }
+            if self.mode() != UdpMode::Forward {
+                anyhow::bail!("UDP rule '{}': transparent requires mode = \"forward\"", self.rule_name());
+            }
+        }
+        if self.broadcast.unwrap_or(false) {
+            if self.mode() != UdpMode::Forward || self.transparent.unwrap_or(false) {
+                anyhow::bail!("UDP rule '{}': broadcast requires mode = \"forward\" without transparent", self.rule_name());
+            }
+            // A wildcard listener would pick up the broadcasts it relays
+            // itself if they go out on the same interface
+            if self.bind_device.is_none() && self.bind_socket_addrs()?.iter().any(|addr| addr.ip().is_unspecified()) {
+                anyhow::bail!(
+                    "UDP rule '{}': broadcast with a wildcard bind_addr requires bind_device, so relayed broadcasts aren't received again",
+                    self.rule_name()
+                );
+            }
+        }
+        if let Some(source) = self.source_ip()? {
+            if self.transparent.unwrap_or(false) {
+                anyhow::bail!("UDP rule '{}': source_addr cannot be combined with transparent", self.rule_name());
+            }
+            if let Ok(target) = IpAddr::from_str(&self.target_addr)
+                && target.is_ipv6() != source.is_ipv6()
+            {
+                anyhow::bail!("UDP rule '{}': source_addr {} cannot reach target {}", self.rule_name(), source, target);
+            }
+        }
+        for device in [&self.bind_device, &self.source_device].into_iter().flatten() {
+            sockopt::validate_device(device).map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if self.source_device.is_some() && self.transparent.unwrap_or(false) {
+            anyhow::bail!("UDP rule '{}': source_device cannot be combined with transparent", self.rule_name());
+        }
+        if self.fwmark.is_some() {
+            sockopt::validate_mark().map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if let Some(dscp) = self.dscp {
+            sockopt::validate_dscp(dscp).map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if self.reuse_port.unwrap_or(false) && !cfg!(unix) {
+            anyhow::bail!("UDP rule '{}': reuse_port requires a Unix platform", self.rule_name());
+        }
+        if self.freebind.unwrap_or(false) && !cfg!(target_os = "linux") {
+            anyhow::bail!("UDP rule '{}': freebind requires Linux", self.rule_name());
+        }
+        if self.gso.unwrap_or(false) {
+            if !cfg!(target_os = "linux") {
+                anyhow::bail!("UDP rule '{}': gso requires Linux", self.rule_name());
+            }
+            // Tunnelled and transparent sessions read datagrams their own way
+            if self.mode() != UdpMode::Forward || self.transparent.unwrap_or(false) {
+                anyhow::bail!("UDP rule '{}': gso requires mode = \"forward\" without transparent", self.rule_name());
+            }
+        }
+        if self.ipv6_only.is_some() && !self.bind_socket_addrs()?.iter().all(|addr| addr.is_ipv6()) {
+            anyhow::bail!("UDP rule '{}': ipv6_only requires IPv6 bind_addr addresses", self.rule_name());
+        }
+        if self.max_sessions == Some(0) {
+            anyhow::bail!("UDP rule '{}': max_sessions must be greater than 0", self.rule_name());
+        }
+        if self.rate_limit_kbps == Some(0) {
+            anyhow::bail!("UDP rule '{}': rate_limit_kbps must be greater than 0", self.rule_name());
+        }
+        if self.client_rate_limit_kbps == Some(0) {
+            anyhow::bail!("UDP rule '{}': client_rate_limit_kbps must be greater than 0", self.rule_name());
+        }
+        self.acl()
+            .map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        self.country_filter()
+            .map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        if let Some(knock) = &self.knock {
+            knock.validate(self.bind_port)
+                .map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        }
+        for rewrite in self.rewrite.iter().flatten() {
+            rewrite.validate().map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        }
+        for banner in self.banner.iter().flatten() {
+            banner.validate().map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        }
+        if let Some(mirror) = &self.mirror_target {
+            parse_endpoint(mirror).map_err(|e| anyhow::anyhow!("UDP rule '{}': mirror_target: {}", self.rule_name(), e))?;
+        }
+        validate_capture(&self.capture_file, self.capture_max_mb, self.capture_seconds, self.capture_on_start)
+            .map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        validate_latency(self.inject_delay_ms, self.inject_jitter_ms)
+            .map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+        for (field, percent) in [("inject_drop_percent", self.inject_drop_percent), ("inject_duplicate_percent", self.inject_duplicate_percent)] {
+            if let Some(percent) = percent
+                && !(0.0..=100.0).contains(&percent)
+            {
+                anyhow::bail!("UDP rule '{}': {} must be between 0 and 100", self.rule_name(), field);
+            }
+        }
+        if self.mode() == UdpMode::Forward
+            && let Ok(bind) = self.bind_socket_addrs()
+            && let Some(addr) = own_listener(&bind, self.ipv6_only.unwrap_or(false), &self.target_addr, self.target_port)
+        {
+            anyhow::bail!("UDP rule '{}': target {}:{} is the rule's own listener {}, which would forward in a loop",
+                self.rule_name(), self.target_addr, self.target_port, addr);
+        }
+        Ok(())
+    }
+
+    pub fn log_level_filter(&self) -> Option<LevelFilter> {
+        self.log_level.as_deref().and_then(|level| rule_log_level(level).ok())
+    }
+
+    pub fn rule_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            if self.mode() == UdpMode::MdnsReflector {
+                return format!("mdns_{}", self.interfaces.as_deref().unwrap_or_default().join("_"));
+            }
+            if self.mode().is_builtin() {
+                return format!("udp_{}_{}", self.bind_endpoint(), self.mode().as_str());
+            }
+            format!("udp_{}_to_{}:{}", 
+                self.bind_endpoint(),
+                self.target_addr, self.target_port)
+        })
+    }
+
+    pub fn acl(&self) -> anyhow::Result<Acl> {
+        Acl::from_lists(
+            self.allow.as_deref().unwrap_or_default(),
+            self.deny.as_deref().unwrap_or_default(),
+        )
+    }
+
+    pub fn country_filter(&self) -> anyhow::Result<Option<CountryFilter>> {
+        CountryFilter::from_lists(
+            self.allow_countries.as_deref().unwrap_or_default(),
+            self.deny_countries.as_deref().unwrap_or_default(),
+        )
+    }
+
+    pub fn timeout_seconds(&self) -> u64 {
+        self.timeout.unwrap_or(30)
+    }
+
+    // Each session holds a socket or TCP connection, so this also bounds
+    // the rule's file descriptors
+    pub fn session_limit(&self) -> usize {
+        self.max_sessions.unwrap_or(10_000)
+    }
+
+    // The reflector only listens on the mDNS group, so the addressing and
+    // forwarding options don't apply
+    fn validate_mdns_reflector(&self) -> anyhow::Result<()> {
+        if !cfg!(target_os = "linux") {
+            anyhow::bail!("UDP rule '{}': mode = \"mdns_reflector\" requires Linux", self.rule_name());
+        }
+        let interfaces = self.interfaces.as_deref().unwrap_or_default();
+        if interfaces.len() < 2 {
+            anyhow::bail!("UDP rule '{}': mode = \"mdns_reflector\" needs at least two interfaces", self.rule_name());
+        }
+        for (i, name) in interfaces.iter().enumerate() {
+            sockopt::validate_device(name).map_err(|e| anyhow::anyhow!("UDP rule '{}': {}", self.rule_name(), e))?;
+            if interfaces[..i].contains(name) {
+                anyhow::bail!("UDP rule '{}': interface '{}' is listed twice", self.rule_name(), name);
+            }
+        }
+        if !self.bind_addr.is_empty() || !self.target_addr.is_empty() || self.bind_port != 0 || self.target_port != 0 {
+            anyhow::bail!(
+                "UDP rule '{}': mode = \"mdns_reflector\" takes interfaces instead of bind and target addresses",
+                self.rule_name()
+            );
+        }
+        Ok(())
+    }
+
+    pub fn mode(&self) -> UdpMode {
+        self.mode.unwrap_or_default()
+    }
+}
+
+// Environment variables go into the string values of the parsed file, so
+// comments are left alone and values need no escaping
+fn substitute_toml(value: &mut toml::Value, substitute: &impl Fn(&mut String) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    match value {
+        toml::Value::String(string) => substitute(string),
+        toml::Value::Array(values) => values.iter_mut().try_for_each(|value| substitute_toml(value, substitute)),
+        toml::Value::Table(table) => table.iter_mut().try_for_each(|(_, value)| substitute_toml(value, substitute)),
+        _ => Ok(()),
+    }
+}
+
+fn substitute_yaml(value: &mut serde_yaml::Value, substitute: &impl Fn(&mut String) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    match value {
+        serde_yaml::Value::String(string) => substitute(string),
+        serde_yaml::Value::Sequence(values) => values.iter_mut().try_for_each(|value| substitute_yaml(value, substitute)),
+        serde_yaml::Value::Mapping(mapping) => mapping.values_mut().try_for_each(|value| substitute_yaml(value, substitute)),
+        serde_yaml::Value::Tagged(tagged) => substitute_yaml(&mut tagged.value, substitute),
+        _ => Ok(()),
+    }
+}
+
+fn substitute_json(value: &mut serde_json::Value, substitute: &impl Fn(&mut String) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    match value {
+        serde_json::Value::String(string) => substitute(string),
+        serde_json::Value::Array(values) => values.iter_mut().try_for_each(|value| substitute_json(value, substitute)),
+        serde_json::Value::Object(object) => object.values_mut().try_for_each(|value| substitute_json(value, substitute)),
+        _ => Ok(()),
+    }
+}
+
+// Replaces ${NAME} in a string value with the environment variable NAME,
+// and ${NAME:-default} with `default` if NAME is unset or empty. $${ is a
+// literal ${. An unset variable without a default is an error rather than
+// an empty value; errors give the line of `content`, the whole file, that
+// the placeholder is on.
+fn substitute_env(value: &str, content: &str) -> anyhow::Result<String> {
+    let mut substituted = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let line = line_of(content, &rest[start..]);
+        if rest[..start].ends_with('$') {
+            substituted.push_str(&rest[..start - 1]);
+            substituted.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        substituted.push_str(&rest[..start]);
+        let end = rest[start..].find('}')
+            .ok_or_else(|| anyhow::anyhow!("line {}: ${{ without a closing }}", line))?;
+        let placeholder = &rest[start + 2..start + end];
+        let (name, default) = match placeholder.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (placeholder, None),
+        };
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            anyhow::bail!("line {}: invalid environment variable name in ${{{}}}", line, placeholder);
+        }
+        let value = match (std::env::var(name), default) {
+            (Ok(value), Some(default)) if value.is_empty() => default.to_string(),
+            (Ok(value), _) => value,
+            (Err(std::env::VarError::NotUnicode(_)), _) => {
+                anyhow::bail!("line {}: environment variable {} is not valid UTF-8", line, name)
+            }
+            (Err(_), Some(default)) => default.to_string(),
+            (Err(_), None) => anyhow::bail!("line {}: environment variable {} is not set", line, name),
+        };
+        substituted.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    substituted.push_str(rest);
+    Ok(substituted)
+}
+
+// The line `content` first has `placeholder` on, up to its closing }
+fn line_of(content: &str, placeholder: &str) -> usize {
+    let placeholder = placeholder.find('}').map_or(placeholder, |end| &placeholder[..=end]);
+    content.find(placeholder).map_or(0, |offset| content[..offset].matches('\n').count() + 1)
+}
+
+fn parse_source_addr(source: Option<&str>) -> anyhow::Result<Option<IpAddr>> {
+    source
+        .map(|source| IpAddr::from_str(source).map_err(|_| anyhow::anyhow!("invalid source_addr '{}'", source)))
+        .transpose()
+}
+
+// A single level: unlike [global] log_level, which takes env_logger's
+// module filters
+fn rule_log_level(level: &str) -> anyhow::Result<LevelFilter> {
+    level.parse().map_err(|_| anyhow::anyhow!("invalid log_level '{}', expected off, error, warn, info, debug or trace", level))
+}
+
+fn toml_string_array(values: &[String]) -> String {
+    let quoted: Vec<String> = values.iter().map(|v| format!("\"{}\"", v)).collect();
+    format!("[{}]", quoted.join(", "))
+}
+
+fn toml_string_table(entries: &BTreeMap<String, String>) -> String {
+    let quoted: Vec<String> = entries.iter().map(|(k, v)| format!("\"{}\" = \"{}\"", k, v)).collect();
+    format!("{{ {} }}", quoted.join(", "))
+}
+
+// Keys are hostnames, optionally "*.example.com"; values are "host:port" targets
+fn validate_host_routes(field: &str, routes: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    for (name, target) in routes {
+        let host = name.strip_prefix("*.").unwrap_or(name);
+        if !is_valid_hostname(host) {
+            anyhow::bail!("invalid hostname '{}' in {}", name, field);
+        }
+        parse_endpoint(target).map_err(|e| anyhow::anyhow!("{} '{}': {}", field, name, e))?;
+    }
+    Ok(())
+}
+
+fn bind_socket_addrs(addrs: &[String], port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    addrs.iter()
+        .map(|addr| {
+            let ip = IpAddr::from_str(addr).map_err(|_| anyhow::anyhow!("invalid bind_addr '{}'", addr))?;
+            Ok(SocketAddr::new(ip, port))
+        })
+        .collect()
+}
+
+// The listener among `bind` that connecting to the target would reach. Only
+// addresses and "localhost" are checked, since names resolve at runtime, and
+// a wildcard listener is only known to be reached through loopback.
+fn own_listener(bind: &[SocketAddr], ipv6_only: bool, target_addr: &str, target_port: u16) -> Option<SocketAddr> {
+    let targets: Vec<IpAddr> = match target_addr {
+        "localhost" => vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()],
+        addr => IpAddr::from_str(addr).into_iter().collect(),
+    };
+    bind.iter()
+        .filter(|bind| bind.port() == target_port && target_port != 0)
+        .find(|bind| {
+            targets.iter().any(|&target| {
+                bind.ip() == target || (bind.ip().is_unspecified() && target.is_loopback() && covers_family(**bind, ipv6_only, target))
+            })
+        })
+        .copied()
+}
+
+// Whether a listener on `addr` takes connections to `ip`'s address family.
+// IPv6 listeners are dual-stack unless ipv6_only is set.
+fn covers_family(addr: SocketAddr, ipv6_only: bool, ip: IpAddr) -> bool {
+    addr.is_ipv4() == ip.is_ipv4() || (addr.is_ipv6() && !ipv6_only)
+}
+
+// One address a rule listens on, to find rules that can't be bound together
+struct Listener<'a> {
+    rule: String,
+    addr: SocketAddr,
+    ipv6_only: bool,
+    reuse_port: bool,
+    device: Option<&'a str>,
+}
+
+impl Listener<'_> {
+    fn clashes(&self, other: &Listener) -> bool {
+        if self.addr.port() != other.addr.port() || self.addr.port() == 0 || (self.reuse_port && other.reuse_port) {
+            return false;
+        }
+        // Sockets bound to different devices may share an address
+        if let (Some(device), Some(other_device)) = (self.device, other.device)
+            && device != other_device
+        {
+            return false;
+        }
+        let (ip, other_ip) = (self.addr.ip(), other.addr.ip());
+        if ip.is_ipv4() == other_ip.is_ipv4() {
+            return ip == other_ip || ip.is_unspecified() || other_ip.is_unspecified();
+        }
+        (ip.is_unspecified() && covers_family(self.addr, self.ipv6_only, other_ip))
+            || (other_ip.is_unspecified() && covers_family(other.addr, other.ipv6_only, ip))
+    }
+}
+
+fn check_listeners(protocol: &str, listeners: &[Listener]) -> anyhow::Result<()> {
+    for (i, listener) in listeners.iter().enumerate() {
+        let Some(other) = listeners[..i].iter().find(|other| other.clashes(listener)) else {
+            continue;
+        };
+        match (listener.rule == other.rule, listener.addr == other.addr) {
+            (true, true) => anyhow::bail!("{} rule '{}' lists {} twice", protocol, listener.rule, listener.addr),
+            (true, false) => anyhow::bail!(
+                "{} rule '{}' lists overlapping addresses {} and {}; keep only one of them",
+                protocol, listener.rule, other.addr, listener.addr
+            ),
+            (false, true) => anyhow::bail!(
+                "{} rules '{}' and '{}' both listen on {}; give them different ports or addresses",
+                protocol, other.rule, listener.rule, listener.addr
+            ),
+            (false, false) => anyhow::bail!(
+                "{} rules '{}' and '{}' listen on overlapping addresses {} and {}; give them different ports or addresses",
+                protocol, other.rule, listener.rule, other.addr, listener.addr
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn bind_endpoint(addrs: &[String], port: u16) -> String {
+    let endpoints: Vec<String> = addrs.iter().map(|addr| format!("{}:{}", addr, port)).collect();
+    endpoints.join(", ")
+}
+
+fn bind_addr_toml(addrs: &[String]) -> String {
+    match addrs {
+        [addr] => format!("\"{}\"", addr),
+        _ => {
+            let quoted: Vec<String> = addrs.iter().map(|addr| format!("\"{}\"", addr)).collect();
+            format!("[{}]", quoted.join(", "))
+        }
+    }
+}
+
+// bind_addr may be a single address or a list
+fn string_or_list<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::One(addr) => vec![addr],
+        StringOrList::Many(addrs) => addrs,
+    })
+}
+
+// Parses a combined "host:port", "[v6]:port", "unix:" or "systemd:" endpoint, which
+// can't be mixed with the split addr/port fields
+fn split_endpoint(field: &str, endpoint: Option<String>, split_fields_set: bool) -> anyhow::Result<Option<(String, u16)>> {
+    let Some(endpoint) = endpoint else {
+        return Ok(None);
+    };
+    if split_fields_set {
+        anyhow::bail!("{} cannot be combined with {}_addr or {}_port", field, field, field);
+    }
+    if unix_socket::parse(&endpoint).is_some() || systemd::parse(&endpoint).is_some() {
+        return Ok(Some((endpoint, 0)));
+    }
+    parse_endpoint(&endpoint).map(Some)
+}
+
+fn validate_capture(file: &Option<String>, max_mb: Option<u64>, seconds: Option<u64>, on_start: Option<bool>) -> anyhow::Result<()> {
+    if file.is_none() && (max_mb.is_some() || seconds.is_some() || on_start.is_some()) {
+        anyhow::bail!("capture_max_mb, capture_seconds and capture_on_start require capture_file");
+    }
+    if max_mb == Some(0) {
+        anyhow::bail!("capture_max_mb must be greater than 0");
+    }
+    if seconds == Some(0) {
+        anyhow::bail!("capture_seconds must be greater than 0");
+    }
+    Ok(())
+}
+
+fn validate_latency(delay_ms: Option<u64>, jitter_ms: Option<u64>) -> anyhow::Result<()> {
+    if let Some(jitter) = jitter_ms
+        && jitter > delay_ms.unwrap_or(0)
+    {
+        anyhow::bail!("inject_jitter_ms can't be more than inject_delay_ms");
+    }
+    Ok(())
+}
+
+// Bytes written as hex digit pairs, optionally separated by whitespace
+pub fn parse_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    let digits: Vec<char> = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        anyhow::bail!("'{}' has an odd number of hex digits", hex);
+    }
+    digits.chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| anyhow::anyhow!("'{}' is not a hex byte", pair))
+        })
+        .collect()
+}
+
+// Splits "host:port" or "[v6]:port" into its parts
+pub fn parse_endpoint(endpoint: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = endpoint.rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not of the form host:port", endpoint))?;
+    let port: u16 = port.parse()
+        .map_err(|_| anyhow::anyhow!("invalid port in '{}'", endpoint))?;
+    let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(v6) if v6.parse::<std::net::Ipv6Addr>().is_ok() => v6,
+        Some(_) => anyhow::bail!("invalid IPv6 address in '{}'", endpoint),
+        None if host.contains(':') => anyhow::bail!("IPv6 address in '{}' must be in brackets, e.g. [::1]:80", endpoint),
+        None if IpAddr::from_str(host).is_ok() || is_valid_hostname(host) => host,
+        None => anyhow::bail!("invalid host in '{}'", endpoint),
+    };
+    Ok((host.to_string(), port))
+}
+
+// RFC 1123 label, as Kubernetes names namespaces and services
+fn is_dns_label(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+// Like a hostname, but the service and protocol labels start with '_'
+fn is_valid_srv_name(name: &str) -> bool {
+    let mut labels = name.trim_end_matches('.').splitn(3, '.');
+    let (Some(service), Some(protocol), Some(domain)) = (labels.next(), labels.next(), labels.next()) else {
+        return false;
+    };
+    [service, protocol].iter().all(|label| {
+        label.len() > 1
+            && label.len() <= 63
+            && label.starts_with('_')
+            && label[1..].chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    }) && is_valid_hostname(domain)
+}
+
+fn is_valid_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test uses variables of its own, as tests run in parallel
+    fn set_var(name: &str, value: &str) {
+        // SAFETY: no test reads or writes another test's variables
+        unsafe { std::env::set_var(name, value) };
+    }
+
+    #[test]
+    fn substitutes_set_variables() {
+        set_var("PORTURE_TEST_TARGET_HOST", "10.0.0.5");
+        let value = substitute_env("${PORTURE_TEST_TARGET_HOST}:80", "").unwrap();
+        assert_eq!(value, "10.0.0.5:80");
+    }
+
+    #[test]
+    fn escaped_placeholders_stay() {
+        set_var("PORTURE_TEST_ESCAPED", "replaced");
+        let value = substitute_env("$${PORTURE_TEST_ESCAPED} ${PORTURE_TEST_ESCAPED}", "").unwrap();
+        assert_eq!(value, "${PORTURE_TEST_ESCAPED} replaced");
+    }
+
+    #[test]
+    fn defaults() {
+        set_var("PORTURE_TEST_EMPTY", "");
+        set_var("PORTURE_TEST_DEFAULT_SET", "9000");
+        assert_eq!(substitute_env("${PORTURE_TEST_UNSET:-8080}", "").unwrap(), "8080");
+        assert_eq!(substitute_env("${PORTURE_TEST_EMPTY:-8080}", "").unwrap(), "8080");
+        assert_eq!(substitute_env("${PORTURE_TEST_DEFAULT_SET:-8080}", "").unwrap(), "9000");
+        assert_eq!(substitute_env("${PORTURE_TEST_UNSET:-}", "").unwrap(), "");
+        // Without a default, only unset variables are errors
+        assert_eq!(substitute_env("${PORTURE_TEST_EMPTY}", "").unwrap(), "");
+        let error = substitute_env("${PORTURE_TEST_UNSET}", "").unwrap_err();
+        assert!(error.to_string().contains("PORTURE_TEST_UNSET is not set"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_names() {
+        for value in ["${}", "${1ABC}", "${A-B}", "${A B}", "${:-default}"] {
+            let error = substitute_env(value, "").unwrap_err();
+            assert!(error.to_string().contains("invalid environment variable name"), "{}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn missing_closing_brace() {
+        let error = substitute_env("${PORTURE_TEST_UNCLOSED", "").unwrap_err();
+        assert!(error.to_string().contains("without a closing }"), "{}", error);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let content = "[[tcp]]\nbind = \"0.0.0.0:8080\"\ntarget = \"${PORTURE_TEST_UNSET_TARGET}\"\n";
+        let error = substitute_env("${PORTURE_TEST_UNSET_TARGET}", content).unwrap_err();
+        assert!(error.to_string().starts_with("line 3: "), "{}", error);
+    }
+
+    #[test]
+    fn only_string_values_are_substituted() {
+        set_var("PORTURE_TEST_FILE_TARGET", "10.0.0.5:80");
+        let path = std::env::temp_dir().join(format!("porture-test-{}.toml", std::process::id()));
+        let content = "# ${PORTURE_TEST_NOT_IN_COMMENTS}\n[[tcp]]\nbind = \"0.0.0.0:8080\"\ntarget = \"${PORTURE_TEST_FILE_TARGET}\"\n";
+        std::fs::write(&path, content).unwrap();
+        let config = Config::read_file(path.to_str().unwrap(), ConfigFormat::Toml);
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.tcp.unwrap()[0].target.as_deref(), Some("10.0.0.5:80"));
+    }
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(parse_endpoint("[::1]:80").unwrap(), ("::1".to_string(), 80));
+        assert_eq!(parse_endpoint("[2001:db8::1]:443").unwrap(), ("2001:db8::1".to_string(), 443));
+        assert_eq!(parse_endpoint("10.0.0.5:80").unwrap(), ("10.0.0.5".to_string(), 80));
+        assert_eq!(parse_endpoint("example.com:8443").unwrap(), ("example.com".to_string(), 8443));
+    }
+
+    #[test]
+    fn bare_ipv6_endpoints() {
+        // The last group would otherwise be taken for the port
+        for endpoint in ["::1", "2001:db8::1", "2001:db8::1:80"] {
+            let error = parse_endpoint(endpoint).unwrap_err();
+            assert!(error.to_string().contains("must be in brackets"), "{}: {}", endpoint, error);
+        }
+        assert!(parse_endpoint("[not-an-ip]:80").is_err());
+    }
+
+    #[test]
+    fn endpoints_without_ports() {
+        for endpoint in ["example.com", "10.0.0.5", "example.com:", "[::1]", "example.com:http", "example.com:65536"] {
+            assert!(parse_endpoint(endpoint).is_err(), "{}", endpoint);
+        }
+    }
+
+    #[test]
+    fn legacy_split_fields() {
+        let mut rule = TcpRule {
+            bind_addr: vec!["::".to_string()],
+            bind_port: 8080,
+            target_addr: "2001:db8::5".to_string(),
+            target_port: 80,
+            ..Default::default()
+        };
+        rule.expand_endpoints().unwrap();
+        assert_eq!((rule.bind_addr.as_slice(), rule.bind_port), (&["::".to_string()][..], 8080));
+        assert_eq!((rule.target_addr.as_str(), rule.target_port), ("2001:db8::5", 80));
+
+        let mut rule = TcpRule {
+            bind: Some("[::]:8080".to_string()),
+            target: Some("[2001:db8::5]:80".to_string()),
+            ..Default::default()
+        };
+        rule.expand_endpoints().unwrap();
+        assert_eq!((rule.bind_addr.as_slice(), rule.bind_port), (&["::".to_string()][..], 8080));
+        assert_eq!((rule.target_addr.as_str(), rule.target_port), ("2001:db8::5", 80));
+
+        let mut rule = TcpRule {
+            bind: Some("[::]:8080".to_string()),
+            bind_port: 8080,
+            ..Default::default()
+        };
+        assert!(rule.expand_endpoints().is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::config::Config;
+use anyhow::Result;
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+#[cfg(target_os = "linux")]
+use landlock::{path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus, ABI};
+#[cfg(target_os = "linux")]
+use log::{info, warn};
+#[cfg(target_os = "linux")]
+use std::path::{Path, PathBuf};
+
+// Filesystem confinement with Landlock ([global] landlock). A forwarder only
+// needs the files its configuration names, so the whole process is limited
+// to reading those and to writing where it keeps state (pid file, Unix
+// sockets, ACME cache). Directories are allowed rather than single files, so
+// files replaced by renaming (editors, certbot, geoipupdate) stay readable.
+// Landlock only confines the thread that enables it and the threads started
+// after, so this runs before the runtime starts.
+
+// What the C library reads to resolve hostnames, services and accounts
+#[cfg(target_os = "linux")]
+const SYSTEM_FILES: &[&str] = &[
+    "/etc/resolv.conf", "/etc/hosts", "/etc/nsswitch.conf", "/etc/host.conf", "/etc/gai.conf",
+    "/etc/services", "/etc/protocols", "/etc/passwd", "/etc/group",
+];
+// Name service modules it may load for those lookups
+#[cfg(target_os = "linux")]
+const SYSTEM_LIBRARIES: &[&str] = &["/lib", "/lib64", "/usr/lib", "/usr/lib64"];
+
+pub fn validate() -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        anyhow::bail!("[global] landlock requires Linux");
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+struct Paths {
+    // Directories read from
+    read: Vec<PathBuf>,
+    // Directories files are created in, written and removed
+    write: Vec<PathBuf>,
+    // Directories Unix sockets are bound in
+    sockets: Vec<PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Paths {
+    fn from_config(config: &Config, config_path: &str, pid_file: Option<&str>) -> Result<Self> {
+        let mut paths = Paths::default();
+        let global = config.global.as_ref();
+        paths.read_file(config_path);
+        // Included files, and the directories files added later are read from
+        // on a reload
+        for file in config.include_files(config_path)? {
+            paths.read_file(&file);
+        }
+        for pattern in config.include_patterns(config_path) {
+            if let Some(parent) = pattern.parent().filter(|parent| parent.is_dir()) {
+                paths.read.push(parent.to_path_buf());
+            }
+        }
+        for path in SYSTEM_FILES {
+            paths.read_file(path);
+        }
+        paths.read.extend(SYSTEM_LIBRARIES.iter().map(PathBuf::from));
+        if let Some(path) = global.and_then(|g| g.geoip_db.as_deref()) {
+            paths.read_file(path);
+        }
+        if let Some(tunnel) = &config.tunnel {
+            for path in [&tunnel.cert, &tunnel.key, &tunnel.ca].into_iter().flatten() {
+                paths.read_file(path);
+            }
+        }
+        if let Some(path) = config.etcd.as_ref().and_then(|etcd| etcd.ca.as_deref()) {
+            paths.read_file(path);
+        }
+        if let Some(kubernetes) = config.discovery.as_ref().and_then(|d| d.kubernetes.as_ref()) {
+            paths.read_file(&kubernetes.token_file());
+            if let Some(ca) = kubernetes.ca() {
+                paths.read_file(&ca);
+            }
+        }
+        for rule in config.tcp.iter().flatten() {
+            if let Some(tls) = &rule.tls {
+                for path in [&tls.cert, &tls.key].into_iter().flatten() {
+                    paths.read_file(path);
+                }
+                if let Some(acme) = &tls.acme {
+                    // Must exist to be allowed
+                    std::fs::create_dir_all(&acme.cache_dir)
+                        .with_context(|| format!("failed to create ACME cache directory {}", acme.cache_dir))?;
+                    paths.write.push(PathBuf::from(&acme.cache_dir));
+                }
+            }
+            if let Some(path) = &rule.target_tls_ca {
+                paths.read_file(path);
+            }
+            if let Some(path) = rule.bind_unix_path().filter(|path| !path.starts_with('@')) {
+                paths.sockets.push(directory(path));
+            }
+            if let Some(dir) = &rule.record_dir {
+                // Must exist to be allowed
+                std::fs::create_dir_all(dir).with_context(|| format!("failed to create record_dir {}", dir))?;
+                paths.write.push(PathBuf::from(dir));
+            }
+        }
+        let tcp_filters = config.tcp.iter().flatten().flat_map(|rule| [&rule.wasm_filter, &rule.lua_script]);
+        let udp_filters = config.udp.iter().flatten().flat_map(|rule| [&rule.wasm_filter, &rule.lua_script]);
+        for path in tcp_filters.chain(udp_filters).flatten() {
+            paths.read_file(path);
+        }
+        let tcp_captures = config.tcp.iter().flatten().map(|rule| &rule.capture_file);
+        for path in tcp_captures.chain(config.udp.iter().flatten().map(|rule| &rule.capture_file)).flatten() {
+            paths.write.push(directory(path));
+        }
+        if let Some(path) = global.and_then(|g| g.upgrade_socket.as_deref()) {
+            paths.sockets.push(directory(path));
+        }
+        if let Some(path) = pid_file {
+            paths.write.push(directory(path));
+        }
+        for path in global.and_then(|g| g.landlock_read.as_ref()).into_iter().flatten() {
+            paths.read.push(PathBuf::from(path));
+        }
+        for path in global.and_then(|g| g.landlock_write.as_ref()).into_iter().flatten() {
+            paths.write.push(PathBuf::from(path));
+        }
+        Ok(paths)
+    }
+
+    // The directory a file is in, and the one it really is in if it's a
+    // symlink
+    fn read_file(&mut self, path: &str) {
+        self.read.push(directory(path));
+        if let Ok(target) = std::fs::canonicalize(path)
+            && let Some(parent) = target.parent()
+        {
+            self.read.push(parent.to_path_buf());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn directory(path: &str) -> PathBuf {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+// Confines the calling thread, and every thread it starts from now on, to
+// the paths the configuration needs
+#[cfg(target_os = "linux")]
+pub fn restrict(config: &Config, config_path: &str, pid_file: Option<&str>) -> Result<()> {
+    let paths = Paths::from_config(config, config_path, pid_file)?;
+    let abi = ABI::V5;
+    let read = AccessFs::ReadFile | AccessFs::ReadDir;
+    let write = read | AccessFs::from_write(abi);
+    let sockets = AccessFs::MakeSock | AccessFs::RemoveFile;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(&paths.read, read))?
+        .add_rules(path_beneath_rules(&paths.write, write))?
+        .add_rules(path_beneath_rules(&paths.sockets, sockets))?
+        // Output of the daemon once it has detached
+        .add_rules(path_beneath_rules(["/dev/null"], AccessFs::ReadFile | AccessFs::WriteFile))?
+        .restrict_self()
+        .context("failed to enable Landlock")?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => info!("Landlock restricts filesystem access to the configured paths"),
+        RulesetStatus::PartiallyEnforced => {
+            info!("Landlock restricts filesystem access to the configured paths, partially on this kernel")
+        }
+        RulesetStatus::NotEnforced => warn!("Landlock is not supported by this kernel, filesystem access is not restricted"),
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict(_config: &Config, _config_path: &str, _pid_file: Option<&str>) -> Result<()> {
+    anyhow::bail!("[global] landlock requires Linux")
+}
@@ -0,0 +1,493 @@
+use crate::builtin::Server;
+use crate::config::{parse_endpoint, KeepaliveConfig, SocketOptions, TcpMode, TcpRule};
+use crate::resolver;
+use crate::sockopt;
+use crate::discovery::TargetPool;
+use crate::ktls::RecordStream;
+use crate::tls;
+use crate::transparent;
+use crate::tunnel::TunnelServer;
+use crate::unix_socket;
+use crate::upstream::UpstreamProxy;
+use crate::websocket;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::debug;
+use rustls::pki_types::ServerName;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsConnector;
+
+// RFC 8305 recommends 250ms between connection attempts
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Any, so the forwarder can tell a plain TCP connection it may splice
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + Any {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Any> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+// Where and how outgoing connections are made (source_addr / source_device /
+// fwmark / dscp / keepalive / socket)
+#[derive(Debug, Clone, Default)]
+pub struct Source {
+    pub addr: Option<IpAddr>,
+    pub device: Option<String>,
+    pub mark: Option<u32>,
+    pub dscp: Option<u8>,
+    // TCP only
+    pub keepalive: Option<KeepaliveConfig>,
+    pub socket: Option<SocketOptions>,
+}
+
+impl Source {
+    // Sets the socket options; binding to `addr` is left to the caller
+    pub fn apply<S: sockopt::AsSocket>(&self, socket: &S, ipv6: bool) -> io::Result<()> {
+        if let Some(name) = &self.device {
+            sockopt::bind_device(socket, name)?;
+        }
+        if let Some(mark) = self.mark {
+            sockopt::set_mark(socket, mark)?;
+        }
+        if let Some(dscp) = self.dscp {
+            sockopt::set_dscp(socket, dscp, ipv6)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            sockopt::set_keepalive(socket, keepalive)?;
+        }
+        if let Some(options) = &self.socket {
+            sockopt::set_options(socket, options)?;
+        }
+        Ok(())
+    }
+}
+
+struct TargetTls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    // Read a record at a time so kTLS can take over after the handshake
+    ktls: bool,
+}
+
+// Upgrade request sent by mode = "websocket_client"
+struct WebSocketTarget {
+    host: String,
+    path: String,
+}
+
+// Everything needed to open a connection to a rule's target
+pub struct TargetConnector {
+    host: String,
+    port: u16,
+    connect_timeout: Duration,
+    upstream: Option<Arc<UpstreamProxy>>,
+    tunnel: Option<Arc<TunnelServer>>,
+    source: Source,
+    tls: Option<TargetTls>,
+    websocket: Option<WebSocketTarget>,
+    // Connect from the client's address (transparent = true)
+    transparent: bool,
+    // Picks host and port per connection instead (target_srv, target_consul, target_kubernetes)
+    pool: Option<Arc<dyn TargetPool>>,
+    // Connects to a server in porture instead (mode = "echo" or "discard")
+    builtin: Option<Server>,
+}
+
+impl TargetConnector {
+    pub fn new(host: String, port: u16, connect_timeout: Duration) -> Self {
+        Self {
+            host,
+            port,
+            connect_timeout,
+            upstream: None,
+            tunnel: None,
+            source: Source::default(),
+            tls: None,
+            websocket: None,
+            transparent: false,
+            pool: None,
+            builtin: None,
+        }
+    }
+
+    // A plaintext connector for another "host:port" that otherwise connects
+    // the same way: through the same upstream proxy or tunnel, from the same
+    // source with the same socket options, and as the client if transparent.
+    // TLS, the WebSocket upgrade and a discovered pool are for the rule's
+    // own target, so they don't carry over.
+    pub fn retarget(&self, endpoint: &str) -> anyhow::Result<Self> {
+        let (host, port) = parse_endpoint(endpoint)?;
+        Ok(self.retarget_to(host, port))
+    }
+
+    pub fn retarget_to(&self, host: String, port: u16) -> Self {
+        Self {
+            upstream: self.upstream.clone(),
+            tunnel: self.tunnel.clone(),
+            source: self.source.clone(),
+            transparent: self.transparent,
+            ..Self::new(host, port, self.connect_timeout)
+        }
+    }
+
+    pub fn from_rule(rule: &TcpRule) -> anyhow::Result<Self> {
+        let tls = if rule.target_tls.unwrap_or(false) {
+            let connector = tls::connector(
+                rule.target_tls_ca.as_deref(),
+                rule.target_tls_skip_verify.unwrap_or(false),
+            )?;
+            let name = rule.target_tls_sni.as_deref().unwrap_or(&rule.target_addr);
+            Some(TargetTls {
+                connector,
+                server_name: tls::server_name(name)?,
+                ktls: false,
+            })
+        } else {
+            None
+        };
+
+        let upstream = match &rule.upstream_proxy {
+            Some(url) => Some(Arc::new(UpstreamProxy::parse(url)?)),
+            None => None,
+        };
+
+        let websocket = (rule.mode() == TcpMode::WebsocketClient).then(|| WebSocketTarget {
+            host: rule.websocket_host.clone()
+                .or_else(|| rule.target_tls_sni.clone())
+                .unwrap_or_else(|| rule.target_addr.clone()),
+            path: rule.websocket_path.clone().unwrap_or_else(|| "/".to_string()),
+        });
+
+        let connect_timeout = Duration::from_secs(rule.connect_timeout_seconds());
+        Ok(Self {
+            upstream,
+            source: rule.source()?,
+            tls,
+            websocket,
+            transparent: rule.transparent.unwrap_or(false),
+            builtin: Server::for_mode(rule.mode()),
+            ..Self::new(rule.target_addr.clone(), rule.target_port, connect_timeout)
+        })
+    }
+
+    // Connect from the far end of the site-to-site tunnel instead of locally
+    pub fn via_tunnel(self, tunnel: Arc<TunnelServer>) -> Self {
+        Self {
+            tunnel: Some(tunnel),
+            ..self
+        }
+    }
+
+    // Let kTLS take over the connections to a TLS target
+    pub fn with_ktls(mut self) -> Self {
+        if let Some(tls) = &mut self.tls {
+            tls.ktls = true;
+        }
+        self
+    }
+
+    // Take the target from a discovered pool
+    pub fn via_pool(self, pool: Arc<dyn TargetPool>) -> Self {
+        Self {
+            pool: Some(pool),
+            ..self
+        }
+    }
+
+    pub fn endpoint(&self) -> String {
+        if let Some(server) = self.builtin {
+            format!("built-in {} server", server.name())
+        } else if let Some(pool) = &self.pool {
+            pool.describe()
+        } else if unix_socket::parse(&self.host).is_some() {
+            self.host.clone()
+        } else if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    // `preamble` (e.g. a PROXY header) is written before any TLS handshake.
+    // `client` is only used by transparent connectors.
+    pub async fn connect(&self, preamble: &[u8], client: SocketAddr) -> anyhow::Result<BoxedStream> {
+        match timeout(self.connect_timeout, self.establish(preamble, client)).await {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!(
+                "connect to {} timed out after {}s",
+                self.endpoint(),
+                self.connect_timeout.as_secs()
+            ),
+        }
+    }
+
+    async fn establish(&self, preamble: &[u8], client: SocketAddr) -> anyhow::Result<BoxedStream> {
+        if let Some(server) = self.builtin {
+            return Ok(Box::new(server.connect()));
+        }
+        let picked;
+        let (host, port) = match &self.pool {
+            Some(pool) => {
+                picked = pool.pick()?;
+                debug!("{} picked {}:{}", pool.describe(), picked.0, picked.1);
+                (picked.0.as_str(), picked.1)
+            }
+            None => (self.host.as_str(), self.port),
+        };
+        let mut stream: BoxedStream = match (&self.tunnel, &self.upstream) {
+            (Some(tunnel), _) => tunnel.open(host, port).await?,
+            (None, Some(proxy)) => Box::new(proxy.connect(host, port, &self.source).await?),
+            #[cfg(unix)]
+            (None, None) if let Some(path) = unix_socket::parse(host) => {
+                Box::new(unix_socket::connect(path).await?)
+            }
+            (None, None) if self.transparent => Box::new(transparent::connect(host, port, client, &self.source).await?),
+            // Race IPv6/IPv4 if the target is a dual-stack hostname
+            (None, None) => Box::new(connect_happy_eyeballs_from(host, port, &self.source).await?),
+        };
+        if !preamble.is_empty() {
+            stream.write_all(preamble).await?;
+        }
+
+        if let Some(tls) = &self.tls {
+            if tls.ktls {
+                stream = Box::new(RecordStream::new(stream));
+            }
+            stream = Box::new(tls.connector.connect(tls.server_name.clone(), stream).await?);
+        }
+        if let Some(target) = &self.websocket {
+            stream = Box::new(websocket::connect(stream, &target.host, &target.path).await?);
+        }
+        Ok(stream)
+    }
+}
+
+// Picks a target by hostname (TLS server name or HTTP Host), falling back to
+// the rule's own target
+pub struct HostRouter {
+    routes: HashMap<String, Arc<TargetConnector>>,
+    default: Arc<TargetConnector>,
+}
+
+impl HostRouter {
+    pub fn new(routes: &BTreeMap<String, String>, default: Arc<TargetConnector>) -> anyhow::Result<Self> {
+        let mut connectors = HashMap::new();
+        for (name, target) in routes {
+            let connector = default.retarget(target)?;
+            connectors.insert(name.to_ascii_lowercase(), Arc::new(connector));
+        }
+        Ok(Self { routes: connectors, default })
+    }
+
+    // Exact names win over "*.example.com" wildcards, which match one label
+    pub fn route(&self, host: Option<&str>) -> &Arc<TargetConnector> {
+        let Some(name) = host.map(|n| n.trim_end_matches('.').to_ascii_lowercase()) else {
+            return &self.default;
+        };
+        if let Some(connector) = self.routes.get(&name) {
+            return connector;
+        }
+        name.split_once('.')
+            .and_then(|(_, parent)| self.routes.get(&format!("*.{}", parent)))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Resolve `host` and connect to it, racing IPv6 and IPv4 addresses
+/// Happy Eyeballs style (RFC 8305).
+pub async fn connect_happy_eyeballs(host: &str, port: u16) -> io::Result<TcpStream> {
+    connect_happy_eyeballs_from(host, port, &Source::default()).await
+}
+
+/// Like `connect_happy_eyeballs`, but from `source`. Only addresses of the
+/// source address's family can be reached if one is set.
+pub async fn connect_happy_eyeballs_from(host: &str, port: u16, source: &Source) -> io::Result<TcpStream> {
+    let addrs = interleave_families(
+        resolver::lookup(host, port).await?
+            .into_iter()
+            .filter(|addr| source.addr.is_none_or(|source| source.is_ipv6() == addr.is_ipv6()))
+            .collect(),
+    );
+    if addrs.is_empty() {
+        let message = match source.addr {
+            Some(source) => format!("no addresses found for {} in the family of source_addr {}", host, source),
+            None => format!("no addresses found for {}", host),
+        };
+        return Err(io::Error::new(io::ErrorKind::NotFound, message));
+    }
+    race(addrs, source).await
+}
+
+// Connects to the first of `addrs` that answers, starting an attempt every
+// CONNECTION_ATTEMPT_DELAY, or as soon as the one before fails
+async fn race(addrs: Vec<SocketAddr>, source: &Source) -> io::Result<TcpStream> {
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    if let Some(addr) = pending.next() {
+        attempts.push(attempt(addr, source));
+    }
+
+    loop {
+        let delay = sleep(CONNECTION_ATTEMPT_DELAY);
+        tokio::pin!(delay);
+
+        tokio::select! {
+            result = attempts.next(), if !attempts.is_empty() => {
+                match result {
+                    Some((_, Ok(stream))) => return Ok(stream),
+                    Some((addr, Err(e))) => {
+                        debug!("Connection attempt to {} failed: {}", addr, e);
+                        last_error = Some(e);
+                        // A failed attempt starts the next one right away
+                        match pending.next() {
+                            Some(addr) => attempts.push(attempt(addr, source)),
+                            None if attempts.is_empty() => break,
+                            None => {}
+                        }
+                    }
+                    None => break,
+                }
+            }
+            _ = &mut delay, if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(attempt(addr, source));
+                }
+            }
+            else => break,
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::NotConnected, "all connection attempts failed")
+    }))
+}
+
+async fn attempt(addr: SocketAddr, source: &Source) -> (SocketAddr, io::Result<TcpStream>) {
+    debug!("Attempting connection to {}", addr);
+    (addr, connect_from(addr, source).await)
+}
+
+async fn connect_from(addr: SocketAddr, source: &Source) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv6() { TcpSocket::new_v6()? } else { TcpSocket::new_v4()? };
+    source.apply(&socket, addr.is_ipv6())?;
+    if let Some(ip) = source.addr {
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+    socket.connect(addr).await
+}
+
+// Alternate address families, starting with the family the resolver
+// returned first
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let prefer_v6 = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == prefer_v6);
+
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn retarget_keeps_how_to_connect() {
+        let mut rule = TcpRule {
+            bind: Some("127.0.0.1:0".into()),
+            target: Some("10.0.0.5:443".into()),
+            target_tls: Some(true),
+            transparent: Some(true),
+            fwmark: Some(7),
+            connect_timeout: Some(3),
+            ..Default::default()
+        };
+        rule.expand_endpoints().unwrap();
+        let retargeted = TargetConnector::from_rule(&rule).unwrap().retarget("10.0.0.6:80").unwrap();
+        assert_eq!(retargeted.endpoint(), "10.0.0.6:80");
+        assert!(retargeted.transparent);
+        assert_eq!(retargeted.source.mark, Some(7));
+        assert_eq!(retargeted.connect_timeout, Duration::from_secs(3));
+        assert!(retargeted.tls.is_none());
+    }
+
+    #[test]
+    fn interleaves_ipv6_first() {
+        let resolved = addrs(&["[2001:db8::1]:80", "[2001:db8::2]:80", "[2001:db8::3]:80", "192.0.2.1:80", "192.0.2.2:80"]);
+        let expected = addrs(&["[2001:db8::1]:80", "192.0.2.1:80", "[2001:db8::2]:80", "192.0.2.2:80", "[2001:db8::3]:80"]);
+        assert_eq!(interleave_families(resolved), expected);
+    }
+
+    #[test]
+    fn interleaves_ipv4_first() {
+        let resolved = addrs(&["192.0.2.1:80", "[2001:db8::1]:80", "192.0.2.2:80", "192.0.2.3:80"]);
+        let expected = addrs(&["192.0.2.1:80", "[2001:db8::1]:80", "192.0.2.2:80", "192.0.2.3:80"]);
+        assert_eq!(interleave_families(resolved), expected);
+    }
+
+    #[test]
+    fn interleaves_one_family() {
+        let resolved = addrs(&["192.0.2.1:80", "192.0.2.2:80"]);
+        assert_eq!(interleave_families(resolved.clone()), resolved);
+        assert_eq!(interleave_families(Vec::new()), Vec::new());
+    }
+
+    // A loopback port with nothing listening on it
+    async fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn falls_through_to_the_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let closed = closed_port().await;
+
+        let started = tokio::time::Instant::now();
+        let stream = race(vec![closed, open], &Source::default()).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        // Refused, so the next attempt didn't wait out the delay
+        assert!(started.elapsed() < CONNECTION_ATTEMPT_DELAY);
+    }
+
+    #[tokio::test]
+    async fn reports_the_last_failure() {
+        let closed = vec![closed_port().await, closed_port().await];
+        let error = race(closed, &Source::default()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn connects_to_literal_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let stream = connect_happy_eyeballs("127.0.0.1", open.port()).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+    }
+}
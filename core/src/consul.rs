@@ -0,0 +1,169 @@
+use crate::config::{parse_endpoint, ConsulConfig};
+use crate::connector::connect_happy_eyeballs;
+use crate::discovery::{pick_weighted, TargetPool};
+use crate::http::read_response;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::io;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{sleep, timeout};
+
+// Consul catalog discovery (target_consul). The passing instances of a
+// service are the rule's targets, picked per connection by their passing
+// weight. A blocking query to the agent's health endpoint returns as soon as
+// the set changes, so instances that register, deregister or fail their
+// checks are followed within moments. If the agent can't be reached, the
+// last known instances stay in use.
+
+// How long the agent may hold a blocking query open
+const WAIT: Duration = Duration::from_secs(60);
+// Consul adds up to WAIT/16 of jitter before answering
+const HTTP_TIMEOUT: Duration = Duration::from_secs(80);
+const RETRY: Duration = Duration::from_secs(5);
+const MAX_RESPONSE: usize = 4 * 1024 * 1024;
+
+struct Instance {
+    host: String,
+    port: u16,
+    weight: u32,
+}
+
+pub struct ConsulService {
+    config: ConsulConfig,
+    service: String,
+    rule_name: String,
+    instances: RwLock<Vec<Instance>>,
+}
+
+impl ConsulService {
+    // Fetches the instances once before returning, then watches for changes
+    pub async fn start(config: ConsulConfig, service: String, rule_name: String) -> Arc<Self> {
+        let consul = Arc::new(Self { config, service, rule_name, instances: RwLock::new(Vec::new()) });
+        let index = match consul.refresh(0).await {
+            Ok(index) => index,
+            Err(e) => {
+                warn!("TCP forwarder '{}': Consul service {}: {:#}", consul.rule_name, consul.service, e);
+                0
+            }
+        };
+        tokio::spawn(watch(Arc::downgrade(&consul), index));
+        consul
+    }
+
+    // Blocks until the instances differ from those at `index` (0 returns at
+    // once), stores them and returns the new index
+    async fn refresh(&self, index: u64) -> Result<u64> {
+        let (instances, new_index) = timeout(HTTP_TIMEOUT, self.query(index))
+            .await
+            .context("request to the Consul agent timed out")??;
+        if new_index != index {
+            if instances.is_empty() {
+                warn!("TCP forwarder '{}': Consul service {} has no passing instances", self.rule_name, self.service);
+            } else {
+                info!("TCP forwarder '{}': Consul service {} has {} passing instances", self.rule_name, self.service, instances.len());
+            }
+            *self.instances.write().unwrap() = instances;
+        }
+        Ok(new_index)
+    }
+
+    async fn query(&self, index: u64) -> Result<(Vec<Instance>, u64)> {
+        let (host, port) = parse_endpoint(self.config.addr())?;
+        let mut stream = connect_happy_eyeballs(&host, port).await
+            .with_context(|| format!("failed to connect to the Consul agent at {}", self.config.addr()))?;
+
+        let mut path = format!("/v1/health/service/{}?passing=true&index={}&wait={}s", self.service, index, WAIT.as_secs());
+        if let Some(datacenter) = &self.config.datacenter {
+            path.push_str(&format!("&dc={}", datacenter));
+        }
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: porture/{}\r\nAccept: application/json\r\nConnection: close\r\n",
+            path, self.config.addr(), env!("CARGO_PKG_VERSION")
+        );
+        if let Some(token) = &self.config.token {
+            request.push_str(&format!("X-Consul-Token: {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        let response = read_response(&mut stream, MAX_RESPONSE).await?;
+        if response.status != 200 {
+            anyhow::bail!(
+                "Consul agent answered {}: {}",
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            );
+        }
+        let new_index = response.header("x-consul-index")
+            .and_then(|index| index.parse().ok())
+            .context("Consul response has no X-Consul-Index")?;
+        Ok((parse_instances(&response.json()?), new_index))
+    }
+}
+
+impl TargetPool for ConsulService {
+    fn describe(&self) -> String {
+        format!("Consul service {}", self.service)
+    }
+
+    fn pick(&self) -> io::Result<(String, u16)> {
+        let instances = self.instances.read().unwrap();
+        if instances.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no passing instances of Consul service {}", self.service),
+            ));
+        }
+        let chosen = pick_weighted(&instances, |instance| instance.weight);
+        Ok((chosen.host.clone(), chosen.port))
+    }
+}
+
+// Ends once the rule's connector is gone
+async fn watch(consul: Weak<ConsulService>, mut index: u64) {
+    loop {
+        let Some(consul) = consul.upgrade() else {
+            return;
+        };
+        match consul.refresh(index).await {
+            // The index may go backwards, e.g. after a Consul snapshot
+            // restore; start over rather than wait on an index never reached
+            Ok(new_index) if new_index < index => index = 0,
+            Ok(new_index) => {
+                debug!("TCP forwarder '{}': Consul service {} at index {}", consul.rule_name, consul.service, new_index);
+                index = new_index;
+            }
+            Err(e) => {
+                warn!(
+                    "TCP forwarder '{}': Consul service {}: {:#}, keeping the previous instances",
+                    consul.rule_name, consul.service, e
+                );
+                drop(consul);
+                sleep(RETRY).await;
+            }
+        }
+    }
+}
+
+// Entries of /v1/health/service/<name>. An instance without its own address
+// is reached at its node's.
+fn parse_instances(entries: &Value) -> Vec<Instance> {
+    entries.as_array().into_iter().flatten()
+        .filter_map(|entry| {
+            let service = &entry["Service"];
+            let host = service["Address"].as_str()
+                .filter(|address| !address.is_empty())
+                .or_else(|| entry["Node"]["Address"].as_str())?;
+            let port = u16::try_from(service["Port"].as_u64()?).ok().filter(|&port| port != 0)?;
+            let weight = service["Weights"]["Passing"].as_u64().unwrap_or(1);
+            Some(Instance {
+                host: host.to_string(),
+                port,
+                weight: u32::try_from(weight).unwrap_or(u32::MAX),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,150 @@
+use crate::buffer::Buffer;
+use crate::sockopt::{self, AsSocket};
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::Poll;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// One direction of a TCP connection that porture copies itself. Reading and
+// writing are driven by readiness together: while earlier chunks wait for
+// the writer, the reader keeps going until MAX_QUEUED chunks are queued, and
+// queued chunks go out in one vectored write. Written chunks are kept for
+// the next reads, so a connection only takes buffers from the pool until it
+// has enough. At EOF, once everything is written, the writer is shut down,
+// so the peer sees the half-close and the other direction carries on.
+
+// Chunks read but not yet written
+const MAX_QUEUED: usize = 4;
+
+// How one direction of a connection ended
+pub enum End {
+    // The reader reached EOF, and the writer was shut down
+    Eof,
+    // on_data turned the data down
+    Rejected,
+    Read(io::Error),
+    Write(io::Error),
+}
+
+impl End {
+    // For directions the kernel moves: `from` reached EOF, so `to` is
+    // half-closed the way forward does it
+    pub fn eof<S: AsSocket>(to: &S) -> Self {
+        match sockopt::shutdown_write(to) {
+            Ok(()) => End::Eof,
+            Err(e) => End::Write(e),
+        }
+    }
+}
+
+// What one wakeup brought
+enum Event {
+    Read(io::Result<usize>),
+    Written(io::Result<usize>),
+    Flushed(io::Result<()>),
+}
+
+// Copies from `reader` to `writer` in chunks of up to buffer_size, handing
+// each chunk to `on_data` first, which gives back what to write, or None to
+// stop
+pub async fn forward<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    buffer_size: usize,
+    mut on_data: impl FnMut(Buffer) -> F,
+) -> End
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+    F: Future<Output = Option<Buffer>>,
+{
+    let mut queue: VecDeque<Buffer> = VecDeque::with_capacity(MAX_QUEUED);
+    // Already written of the first queued chunk
+    let mut written = 0;
+    // Read into; always empty between reads
+    let mut buffer = Buffer::with_capacity(buffer_size);
+    // Written chunks, for reading into again
+    let mut free: Vec<Buffer> = Vec::with_capacity(MAX_QUEUED);
+    let mut eof = false;
+    let mut flushed = true;
+    loop {
+        if eof && queue.is_empty() {
+            return match writer.shutdown().await {
+                Ok(()) => End::Eof,
+                Err(e) => End::Write(e),
+            };
+        }
+        // Writing goes first, so the queue drains before it grows
+        let event = poll_fn(|cx| {
+            if !queue.is_empty() {
+                let mut slices = [IoSlice::new(&[]); MAX_QUEUED];
+                for (slice, (i, chunk)) in slices.iter_mut().zip(queue.iter().enumerate()) {
+                    *slice = IoSlice::new(if i == 0 { &chunk[written..] } else { chunk });
+                }
+                if let Poll::Ready(result) = Pin::new(&mut *writer).poll_write_vectored(cx, &slices[..queue.len()]) {
+                    return Poll::Ready(Event::Written(result));
+                }
+            } else if !flushed
+                && let Poll::Ready(result) = Pin::new(&mut *writer).poll_flush(cx)
+            {
+                return Poll::Ready(Event::Flushed(result));
+            }
+            if !eof && queue.len() < MAX_QUEUED {
+                // The spare capacity, so it isn't zeroed just to be overwritten
+                let start = buffer.as_ptr();
+                let mut read_buf = ReadBuf::uninit(&mut buffer.spare_capacity_mut()[..buffer_size]);
+                if let Poll::Ready(result) = Pin::new(&mut *reader).poll_read(cx, &mut read_buf) {
+                    let filled = read_buf.filled();
+                    // As tokio's own read_buf checks: the reader must not have
+                    // swapped in another buffer
+                    assert_eq!(filled.as_ptr(), start, "reader replaced the read buffer");
+                    return Poll::Ready(Event::Read(result.map(|()| filled.len())));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        match event {
+            Event::Read(Ok(0)) => eof = true,
+            Event::Read(Ok(n)) => {
+                // SAFETY: the reader initialized the first n bytes
+                unsafe { buffer.set_len(n) };
+                match on_data(std::mem::take(&mut buffer)).await {
+                    // Filtered away: read into it again
+                    Some(mut chunk) if chunk.is_empty() => {
+                        chunk.reserve(buffer_size);
+                        buffer = chunk;
+                    }
+                    Some(chunk) => {
+                        queue.push_back(chunk);
+                        buffer = free.pop().unwrap_or_else(|| Buffer::with_capacity(buffer_size));
+                    }
+                    None => return End::Rejected,
+                }
+            }
+            Event::Read(Err(e)) => return End::Read(e),
+            Event::Written(Ok(0)) => return End::Write(io::ErrorKind::WriteZero.into()),
+            Event::Written(Ok(mut n)) => {
+                flushed = false;
+                while let Some(chunk) = queue.front() {
+                    let left = chunk.len() - written;
+                    if n < left {
+                        written += n;
+                        break;
+                    }
+                    n -= left;
+                    written = 0;
+                    if let Some(mut chunk) = queue.pop_front() {
+                        chunk.clear();
+                        chunk.reserve(buffer_size);
+                        free.push(chunk);
+                    }
+                }
+            }
+            Event::Written(Err(e)) | Event::Flushed(Err(e)) => return End::Write(e),
+            Event::Flushed(Ok(())) => flushed = true,
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+
+// Targets discovered at runtime (target_srv, target_consul,
+// target_kubernetes). The connector asks the pool for a host and port on
+// every connection, so a rule follows its backends as they come and go.
+pub trait TargetPool: Send + Sync {
+    // For logs, e.g. "SRV _minecraft._tcp.example.com"
+    fn describe(&self) -> String;
+
+    fn pick(&self) -> io::Result<(String, u16)>;
+}
+
+// At random in proportion to weight. If every weight is 0, uniformly.
+// `items` must not be empty.
+pub fn pick_weighted<T>(items: &[T], weight: impl Fn(&T) -> u32) -> &T {
+    let total: u32 = items.iter().map(&weight).fold(0, u32::saturating_add);
+    if total == 0 {
+        return &items[random_below(items.len() as u32) as usize];
+    }
+    let mut point = random_below(total);
+    for item in items {
+        let weight = weight(item);
+        if point < weight {
+            return item;
+        }
+        point -= weight;
+    }
+    &items[0]
+}
+
+// Below bound, which must not be 0
+pub fn random_below(bound: u32) -> u32 {
+    let mut bytes = [0u8; 4];
+    // All zeroes if the system RNG fails, which just picks the first target
+    SystemRandom::new().fill(&mut bytes).ok();
+    u32::from_le_bytes(bytes) % bound
+}
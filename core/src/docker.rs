@@ -0,0 +1,187 @@
+use crate::config::{DockerConfig, TcpRule, UdpRule};
+use crate::connector::BoxedStream;
+use crate::http::{read_response, StreamingBody};
+#[cfg(unix)]
+use crate::unix_socket;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+
+// Rules from container labels ([docker]), Traefik style. A running container
+// labelled `porture.tcp.8080=80` gets a rule listening on port 8080 that
+// forwards to port 80 at the container's address; `porture.udp.<port>` does
+// the same for UDP. The rules go away when the container stops. Containers
+// are listed once, then Docker's event stream triggers a fresh listing
+// whenever one starts or stops.
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY: Duration = Duration::from_secs(5);
+const MAX_RESPONSE: usize = 16 * 1024 * 1024;
+const LABEL_PREFIX: &str = "porture.";
+
+#[derive(Default)]
+pub struct DockerRules {
+    pub tcp: Vec<TcpRule>,
+    pub udp: Vec<UdpRule>,
+}
+
+// Sends the rules for the running containers after every change, for as
+// long as the receiver lives
+pub fn spawn(config: DockerConfig) -> mpsc::Receiver<DockerRules> {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            if let Err(e) = sync(&config, &tx).await {
+                warn!("Docker {}: {:#}", config.socket(), e);
+                sleep(RETRY).await;
+            }
+        }
+    });
+    rx
+}
+
+// Only returns on failure
+async fn sync(config: &DockerConfig, tx: &mpsc::Sender<DockerRules>) -> Result<()> {
+    // Subscribe first so no start or stop between the two goes unseen
+    let filters = r#"{"type":["container"],"event":["start","die","destroy"]}"#;
+    let events = send(config, &format!("/events?filters={}", percent_encode(filters))).await?;
+    let mut events = timeout(REQUEST_TIMEOUT, StreamingBody::start(events)).await
+        .context("event subscription timed out")??;
+
+    let rules = container_rules(config).await?;
+    info!(
+        "Docker {}: {} rules from container labels",
+        config.socket(),
+        rules.tcp.len() + rules.udp.len()
+    );
+    tx.send(rules).await?;
+
+    while let Some(line) = events.next_line().await? {
+        let event: Value = serde_json::from_slice(&line).context("invalid event from Docker")?;
+        debug!(
+            "Docker: container {} {}",
+            event["Actor"]["Attributes"]["name"].as_str().unwrap_or_default(),
+            event["Action"].as_str().unwrap_or_default()
+        );
+        tx.send(container_rules(config).await?).await?;
+    }
+    anyhow::bail!("event stream closed by Docker")
+}
+
+async fn container_rules(config: &DockerConfig) -> Result<DockerRules> {
+    let containers = timeout(REQUEST_TIMEOUT, async {
+        let mut stream = send(config, "/containers/json").await?;
+        let response = read_response(&mut stream, MAX_RESPONSE).await?;
+        if response.status != 200 {
+            anyhow::bail!("Docker answered {}: {}", response.status, String::from_utf8_lossy(&response.body).trim());
+        }
+        response.json()
+    })
+    .await
+    .context("container listing timed out")??;
+
+    let mut rules = DockerRules::default();
+    for container in containers.as_array().into_iter().flatten() {
+        let name = container["Names"][0].as_str().unwrap_or_default().trim_start_matches('/');
+        let labels = container["Labels"].as_object().into_iter().flatten()
+            .filter_map(|(key, value)| Some((key.strip_prefix(LABEL_PREFIX)?, value.as_str()?)))
+            .filter_map(|(key, value)| Some((key.split_once('.')?, value)))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            continue;
+        }
+        let Some(address) = container_address(config, container) else {
+            warn!("Docker: container {} has porture labels but no address to forward to", name);
+            continue;
+        };
+        for ((protocol, bind_port), target_port) in labels {
+            let added = match protocol {
+                "tcp" => tcp_rule(config, name, bind_port, &address, target_port).map(|rule| {
+                    rules.tcp.push(rule);
+                }),
+                "udp" => udp_rule(config, name, bind_port, &address, target_port).map(|rule| {
+                    rules.udp.push(rule);
+                }),
+                _ => Err(anyhow::anyhow!("expected porture.tcp.<port> or porture.udp.<port>")),
+            };
+            if let Err(e) = added {
+                warn!("Docker: container {}: label porture.{}.{}: {}", name, protocol, bind_port, e);
+            }
+        }
+    }
+    Ok(rules)
+}
+
+fn tcp_rule(config: &DockerConfig, container: &str, bind_port: &str, address: &str, target_port: &str) -> Result<TcpRule> {
+    let rule = TcpRule {
+        bind_addr: vec![config.bind_addr().to_string()],
+        bind_port: bind_port.parse().context("invalid listening port")?,
+        target_addr: address.to_string(),
+        target_port: target_port.parse().context("invalid container port")?,
+        name: Some(format!("docker_{}_tcp_{}", container, bind_port)),
+        ..Default::default()
+    };
+    rule.validate()?;
+    Ok(rule)
+}
+
+fn udp_rule(config: &DockerConfig, container: &str, bind_port: &str, address: &str, target_port: &str) -> Result<UdpRule> {
+    let rule = UdpRule {
+        bind_addr: vec![config.bind_addr().to_string()],
+        bind_port: bind_port.parse().context("invalid listening port")?,
+        target_addr: address.to_string(),
+        target_port: target_port.parse().context("invalid container port")?,
+        name: Some(format!("docker_{}_udp_{}", container, bind_port)),
+        ..Default::default()
+    };
+    rule.validate()?;
+    Ok(rule)
+}
+
+// The container's address on the configured network, or on any network
+fn container_address(config: &DockerConfig, container: &Value) -> Option<String> {
+    let networks = container["NetworkSettings"]["Networks"].as_object()?;
+    let address = |network: &Value| {
+        network["IPAddress"].as_str()
+            .filter(|address| !address.is_empty())
+            .or_else(|| network["GlobalIPv6Address"].as_str().filter(|address| !address.is_empty()))
+            .map(str::to_string)
+    };
+    match &config.network {
+        Some(name) => address(networks.get(name)?),
+        None => networks.values().find_map(address),
+    }
+}
+
+#[cfg(unix)]
+async fn send(config: &DockerConfig, path: &str) -> Result<BoxedStream> {
+    let mut stream = unix_socket::connect(config.socket()).await
+        .with_context(|| format!("failed to connect to {}", config.socket()))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: docker\r\nUser-Agent: porture/{}\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+        path, env!("CARGO_PKG_VERSION")
+    );
+    stream.write_all(request.as_bytes()).await?;
+    Ok(Box::new(stream))
+}
+
+// Docker Desktop on Windows listens on a named pipe; config validation
+// rejects [docker] there
+#[cfg(not(unix))]
+async fn send(config: &DockerConfig, _path: &str) -> Result<BoxedStream> {
+    anyhow::bail!("failed to connect to {}: Unix sockets require a Unix platform", config.socket())
+}
+
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,238 @@
+use crate::config::{parse_endpoint, Config, EtcdConfig, TcpRule, UdpRule};
+use crate::connector::{connect_happy_eyeballs, BoxedStream};
+use crate::http::{read_response, StreamingBody};
+use crate::tls;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsConnector;
+
+// Rules from etcd ([etcd]). Every key under the prefix holds [[tcp]] and
+// [[udp]] tables in the config file's syntax. The prefix is read once, then
+// watched through etcd's JSON gateway; whenever a key changes the whole
+// prefix is read again and handed to the same reload path as SIGHUP.
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Watches ask for progress notifications, which etcd sends every 10 minutes
+// by default; a watch silent for longer than this is assumed dead
+const WATCH_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+const RETRY: Duration = Duration::from_secs(5);
+const MAX_RESPONSE: usize = 16 * 1024 * 1024;
+
+// Key and value of every key under the prefix
+pub type Snapshot = Vec<(String, String)>;
+
+// Reads the prefix and sends a snapshot after every change, retrying across
+// the endpoints for as long as the receiver lives
+pub fn spawn(config: EtcdConfig) -> mpsc::Receiver<Snapshot> {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let tls = match config.endpoints.iter().any(|e| e.starts_with("https://")) {
+            true => match tls::connector(config.ca.as_deref(), false) {
+                Ok(tls) => Some(tls),
+                Err(e) => {
+                    warn!("etcd: {:#}", e);
+                    return;
+                }
+            },
+            false => None,
+        };
+        let mut next = 0;
+        while !tx.is_closed() {
+            let client = Client { config: &config, endpoint: &config.endpoints[next], tls: tls.as_ref() };
+            if let Err(e) = client.sync(&tx).await {
+                warn!("etcd {}: {:#}", client.endpoint, e);
+                next = (next + 1) % config.endpoints.len();
+                sleep(RETRY).await;
+            }
+        }
+    });
+    rx
+}
+
+struct Client<'a> {
+    config: &'a EtcdConfig,
+    endpoint: &'a str,
+    tls: Option<&'a TlsConnector>,
+}
+
+impl Client<'_> {
+    // Only returns on failure
+    async fn sync(&self, tx: &mpsc::Sender<Snapshot>) -> Result<()> {
+        let token = match (&self.config.username, &self.config.password) {
+            (Some(name), Some(password)) => {
+                let response = self.call("/v3/auth/authenticate", json!({ "name": name, "password": password })).await?;
+                Some(response["token"].as_str().context("etcd returned no auth token")?.to_string())
+            }
+            _ => None,
+        };
+        let (snapshot, revision) = self.range(token.as_deref()).await?;
+        info!("etcd {}: {} keys under {}", self.endpoint, snapshot.len(), self.config.prefix);
+        tx.send(snapshot).await?;
+        self.watch(token.as_deref(), revision + 1, tx).await
+    }
+
+    async fn range(&self, token: Option<&str>) -> Result<(Snapshot, i64)> {
+        let (key, range_end) = prefix_range(&self.config.prefix);
+        let request = json!({ "key": STANDARD.encode(key), "range_end": STANDARD.encode(range_end) });
+        let response = self.call_with_token("/v3/kv/range", request, token).await?;
+        let mut snapshot = Vec::new();
+        for kv in response["kvs"].as_array().into_iter().flatten() {
+            let key = decode(&kv["key"]).context("invalid key from etcd")?;
+            let value = decode(&kv["value"]).with_context(|| format!("invalid value for key '{}'", key))?;
+            snapshot.push((key, value));
+        }
+        Ok((snapshot, revision(&response)?))
+    }
+
+    // Returns when the watch ends; every change sends a fresh snapshot
+    async fn watch(&self, token: Option<&str>, start_revision: i64, tx: &mpsc::Sender<Snapshot>) -> Result<()> {
+        let (key, range_end) = prefix_range(&self.config.prefix);
+        let request = json!({ "create_request": {
+            "key": STANDARD.encode(key),
+            "range_end": STANDARD.encode(range_end),
+            "start_revision": start_revision.to_string(),
+            "progress_notify": true,
+        }});
+        let stream = timeout(REQUEST_TIMEOUT, self.send("/v3/watch", &request, token)).await
+            .context("watch request timed out")??;
+        let mut body = StreamingBody::start(stream).await?;
+        loop {
+            let line = timeout(WATCH_IDLE_TIMEOUT, body.next_line()).await
+                .context("watch went silent")??
+                .context("watch closed by etcd")?;
+            let message: Value = serde_json::from_slice(&line).context("invalid watch message from etcd")?;
+            if let Some(error) = message.get("error") {
+                anyhow::bail!("watch failed: {}", error);
+            }
+            let result = &message["result"];
+            if result["canceled"].as_bool().unwrap_or(false) {
+                anyhow::bail!("watch canceled: {}", result["cancel_reason"].as_str().unwrap_or("no reason given"));
+            }
+            if result["events"].as_array().is_some_and(|events| !events.is_empty()) {
+                debug!("etcd {}: keys under {} changed", self.endpoint, self.config.prefix);
+                let (snapshot, _) = self.range(token).await?;
+                tx.send(snapshot).await?;
+            }
+        }
+    }
+
+    async fn call(&self, path: &str, request: Value) -> Result<Value> {
+        self.call_with_token(path, request, None).await
+    }
+
+    async fn call_with_token(&self, path: &str, request: Value, token: Option<&str>) -> Result<Value> {
+        timeout(REQUEST_TIMEOUT, async {
+            let mut stream = self.send(path, &request, token).await?;
+            let response = read_response(&mut stream, MAX_RESPONSE).await?;
+            if response.status != 200 {
+                anyhow::bail!("{} answered {}: {}", path, response.status, String::from_utf8_lossy(&response.body).trim());
+            }
+            response.json()
+        })
+        .await
+        .with_context(|| format!("{} timed out", path))?
+    }
+
+    async fn send(&self, path: &str, request: &Value, token: Option<&str>) -> Result<BoxedStream> {
+        let (authority, secure) = match self.endpoint.strip_prefix("https://") {
+            Some(authority) => (authority, true),
+            None => (self.endpoint.trim_start_matches("http://"), false),
+        };
+        let authority = authority.trim_end_matches('/');
+        let (host, port) = parse_endpoint(authority)?;
+        let stream = connect_happy_eyeballs(&host, port).await?;
+        let mut stream: BoxedStream = match (secure, self.tls) {
+            (true, Some(tls)) => Box::new(tls.connect(tls::server_name(&host)?, stream).await?),
+            _ => Box::new(stream),
+        };
+
+        let body = request.to_string();
+        let mut head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: porture/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            path, authority, env!("CARGO_PKG_VERSION"), body.len()
+        );
+        if let Some(token) = token {
+            head.push_str(&format!("Authorization: {}\r\n", token));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        Ok(stream)
+    }
+}
+
+// Rules from every key that last parsed and validated. A key whose new value
+// is broken keeps its previous rules.
+#[derive(Default)]
+pub struct EtcdRules {
+    keys: BTreeMap<String, Config>,
+}
+
+impl EtcdRules {
+    // `base` is the file config the rules run alongside, which supplies the
+    // [global] settings they are validated against
+    pub fn update(&mut self, snapshot: Snapshot, base: &Config) {
+        let mut keys = BTreeMap::new();
+        for (key, value) in snapshot {
+            let parsed = Config::parse_rules(&value).and_then(|rules| {
+                Config { tcp: rules.tcp.clone(), udp: rules.udp.clone(), ..base.clone() }.validate()?;
+                Ok(rules)
+            });
+            match parsed {
+                Ok(rules) => {
+                    keys.insert(key, rules);
+                }
+                Err(e) => match self.keys.remove(&key) {
+                    Some(previous) => {
+                        warn!("etcd key '{}': {}, keeping its previous rules", key, e);
+                        keys.insert(key, previous);
+                    }
+                    None => warn!("etcd key '{}': {}, skipping it", key, e),
+                },
+            }
+        }
+        self.keys = keys;
+    }
+
+    pub fn tcp(&self) -> impl Iterator<Item = &TcpRule> {
+        self.keys.values().flat_map(|rules| rules.tcp.iter().flatten())
+    }
+
+    pub fn udp(&self) -> impl Iterator<Item = &UdpRule> {
+        self.keys.values().flat_map(|rules| rules.udp.iter().flatten())
+    }
+}
+
+// The range covering every key that starts with `prefix`
+fn prefix_range(prefix: &str) -> (Vec<u8>, Vec<u8>) {
+    let key = prefix.as_bytes().to_vec();
+    let mut end = key.clone();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return (key, end);
+        }
+    }
+    // All 0xff: to the end of the keyspace
+    (key, vec![0])
+}
+
+fn decode(field: &Value) -> Result<String> {
+    let bytes = STANDARD.decode(field.as_str().unwrap_or_default())?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+// int64 fields come as JSON strings
+fn revision(response: &Value) -> Result<i64> {
+    response["header"]["revision"].as_str()
+        .and_then(|revision| revision.parse().ok())
+        .context("etcd response has no revision")
+}
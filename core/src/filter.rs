@@ -0,0 +1,207 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Filters a rule runs on its connections or UDP sessions: allow/deny lists
+// and rate limits are built in, and embedders can add their own after them.
+// A rule's filter decides once per connection, or per session for UDP,
+// whether to let the client in, and may return a per-flow filter that then
+// sees every chunk or datagram in both directions.
+
+/// A TCP connection or UDP session, as a filter is told about it
+#[derive(Debug, Clone, Copy)]
+pub struct Flow {
+    /// The client's address, or the one its PROXY protocol header gave
+    pub client_addr: SocketAddr,
+    /// The address the client connected or sent to. For a transparent rule,
+    /// its original destination.
+    pub local_addr: SocketAddr,
+}
+
+/// Which way data is going through the forwarder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to target
+    Upload,
+    /// Target to client
+    Download,
+}
+
+/// What a [`StreamFilter`] or [`DatagramFilter`] decides about a new client
+pub enum Admission {
+    /// Close the TCP connection, or drop the datagram that would have started
+    /// the UDP session. Later filters aren't asked.
+    Reject,
+    /// Let the client in without looking at its data
+    Accept,
+    /// Let the client in and run the [`FlowFilter`] on its data until the
+    /// connection or session ends
+    Watch(Box<dyn FlowFilter>),
+}
+
+/// What a [`FlowFilter`] decides about one chunk of TCP data or one datagram
+pub enum Verdict {
+    /// Send the data on
+    Pass,
+    /// Send the data on after waiting this long, to limit the rate. When
+    /// several filters delay the same data, the longest delay is waited, not
+    /// their sum. A TCP connection's later data in that direction waits
+    /// behind it; a UDP session's later datagrams queue behind it, and are
+    /// dropped once the queue is full.
+    Delay(Duration),
+    /// Close the TCP connection, in both directions. For UDP, drop just this
+    /// datagram and keep the session.
+    Reject,
+}
+
+/// Decides about each TCP connection a rule accepts. Add one to a rule with
+/// [`Filters::stream`].
+pub trait StreamFilter: Send + Sync {
+    /// Called once for each accepted connection, after the rule's allow/deny
+    /// lists and before anything is read from the client or the target is
+    /// connected to. Runs on the rule's accept loop, so it should return
+    /// quickly.
+    fn on_connect(&self, flow: &Flow) -> Admission;
+}
+
+/// Decides about each new UDP session of a rule. Add one to a rule with
+/// [`Filters::datagram`].
+pub trait DatagramFilter: Send + Sync {
+    /// Called once, for the first datagram from a client without a session,
+    /// before the session's target socket is opened. Runs on the rule's
+    /// receive loop, which every client's datagrams go through, so it should
+    /// return quickly.
+    fn on_session(&self, flow: &Flow) -> Admission;
+}
+
+/// One connection's or session's filter, returned with [`Admission::Watch`]
+/// and dropped when the connection or session ends
+pub trait FlowFilter: Send {
+    /// Called for every chunk of TCP data as it is read, or every datagram,
+    /// in either direction, before it is sent on; the data may be rewritten
+    /// in place. TCP data comes in chunks as read, not as the peer wrote it,
+    /// and with `gso` a UDP rule's datagrams can come coalesced.
+    ///
+    /// This is the forwarding hot path: it runs synchronously for every
+    /// chunk, holding a lock both directions share, so it must not block.
+    /// Return [`Verdict::Delay`] rather than sleeping.
+    fn on_data(&mut self, direction: Direction, data: &mut Vec<u8>) -> Verdict;
+
+    /// Where to connect a TCP connection instead of the target the rule
+    /// picks, as host:port. Asked once, just before connecting; the
+    /// connection then goes there in plaintext, through the rule's upstream
+    /// proxy or tunnel if it has one. Not asked for UDP.
+    fn target(&self) -> Option<String> {
+        None
+    }
+
+    /// Bytes a TCP connection sends in the direction before anything else,
+    /// once connected to the target. Asked once for each direction; they
+    /// don't go through [`on_data`](FlowFilter::on_data). Not asked for UDP.
+    fn prefix(&mut self, _direction: Direction) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Filters to add to a rule, after its built-in ones, for
+/// [`Forwarder::spawn_with_filters`](crate::Forwarder::spawn_with_filters)
+#[derive(Clone, Default)]
+pub struct Filters {
+    /// Run on a TCP rule's connections
+    pub stream: Vec<Arc<dyn StreamFilter>>,
+    /// Run on a UDP rule's sessions
+    pub datagram: Vec<Arc<dyn DatagramFilter>>,
+}
+
+impl Filters {
+    /// Adds a filter for a TCP rule, after those added before it
+    pub fn stream(mut self, filter: impl StreamFilter + 'static) -> Self {
+        self.stream.push(Arc::new(filter));
+        self
+    }
+
+    /// Adds a filter for a UDP rule, after those added before it
+    pub fn datagram(mut self, filter: impl DatagramFilter + 'static) -> Self {
+        self.datagram.push(Arc::new(filter));
+        self
+    }
+}
+
+// A rule's filters in the order they run. The first to reject wins, and
+// later ones aren't asked.
+pub struct Chain<F: ?Sized> {
+    filters: Vec<Arc<F>>,
+}
+
+impl<F: ?Sized> Chain<F> {
+    pub fn new(filters: Vec<Arc<F>>) -> Self {
+        Self { filters }
+    }
+
+    fn admit(&self, admit: impl Fn(&F) -> Admission) -> Option<FlowFilters> {
+        let mut watching = Vec::new();
+        for filter in &self.filters {
+            match admit(filter) {
+                Admission::Reject => return None,
+                Admission::Accept => {}
+                Admission::Watch(flow_filter) => watching.push(flow_filter),
+            }
+        }
+        Some(FlowFilters(Mutex::new(watching)))
+    }
+}
+
+impl Chain<dyn StreamFilter> {
+    // None if the connection is rejected
+    pub fn connect(&self, flow: &Flow) -> Option<FlowFilters> {
+        self.admit(|filter| filter.on_connect(flow))
+    }
+}
+
+impl Chain<dyn DatagramFilter> {
+    // None if the session is rejected
+    pub fn session(&self, flow: &Flow) -> Option<FlowFilters> {
+        self.admit(|filter| filter.on_session(flow))
+    }
+}
+
+// The flow filters of one connection or session. Both directions share
+// them, so each call holds the lock only while the filters run.
+pub struct FlowFilters(Mutex<Vec<Box<dyn FlowFilter>>>);
+
+impl FlowFilters {
+    // Runs the data through every filter, waiting out their delays. Returns
+    // false if one rejected it.
+    pub async fn run(&self, direction: Direction, data: &mut Vec<u8>) -> bool {
+        let mut delay = Duration::ZERO;
+        {
+            let mut filters = self.0.lock().unwrap();
+            for filter in filters.iter_mut() {
+                match filter.on_data(direction, data) {
+                    Verdict::Pass => {}
+                    Verdict::Delay(wait) => delay = delay.max(wait),
+                    Verdict::Reject => return false,
+                }
+            }
+        }
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        true
+    }
+
+    // True if no filter looks at this flow's data
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    // The first target a filter picked
+    pub fn target(&self) -> Option<String> {
+        self.0.lock().unwrap().iter().find_map(|filter| filter.target())
+    }
+
+    // The prefixes of all the filters, in order
+    pub fn prefix(&self, direction: Direction) -> Vec<u8> {
+        self.0.lock().unwrap().iter_mut().filter_map(|filter| filter.prefix(direction)).flatten().collect()
+    }
+}
@@ -0,0 +1,67 @@
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+
+// MaxMind GeoLite2/GeoIP2 country database, loaded once at startup
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| anyhow::anyhow!("failed to open GeoIP database '{}': {}", path, e))?;
+        Ok(Self { reader })
+    }
+
+    // ISO 3166-1 alpha-2 code of the client's country, if the database knows it
+    pub fn country_code(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip.to_canonical()).ok()?;
+        record
+            .country
+            .or(record.registered_country)
+            .and_then(|c| c.iso_code)
+            .map(|code| code.to_ascii_uppercase())
+    }
+}
+
+// A rule's `allow_countries` / `deny_countries` lists
+#[derive(Debug, Clone, Default)]
+pub struct CountryFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl CountryFilter {
+    pub fn from_lists(allow: &[String], deny: &[String]) -> anyhow::Result<Option<Self>> {
+        if allow.is_empty() && deny.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            allow: normalize_codes(allow)?,
+            deny: normalize_codes(deny)?,
+        }))
+    }
+
+    // Clients whose country can't be determined only pass when there is no allow list
+    pub fn permits(&self, geoip: &GeoIp, ip: IpAddr) -> bool {
+        match geoip.country_code(ip) {
+            Some(code) => {
+                !self.deny.contains(&code) && (self.allow.is_empty() || self.allow.contains(&code))
+            }
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+fn normalize_codes(codes: &[String]) -> anyhow::Result<Vec<String>> {
+    codes
+        .iter()
+        .map(|code| {
+            if code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+                Ok(code.to_ascii_uppercase())
+            } else {
+                Err(anyhow::anyhow!("invalid country code '{}', expected ISO 3166-1 alpha-2", code))
+            }
+        })
+        .collect()
+}
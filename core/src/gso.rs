@@ -0,0 +1,185 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+// UDP segmentation offload (Linux). With UDP_GRO, the kernel hands a socket
+// several same-sized datagrams from one sender in a single read, along with
+// the size of each. UDP_SEGMENT does the reverse on send: one buffer goes
+// out as a train of datagrams of the given size. Bulk flows such as
+// WireGuard or QUIC then cost one syscall per batch instead of per datagram.
+
+// Largest read GRO can produce (a full IP payload)
+pub const MAX_COALESCED: usize = 65536;
+
+#[cfg(target_os = "linux")]
+pub fn enable_gro<S: std::os::fd::AsRawFd>(socket: &S) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    // SAFETY: `enable` outlives the call and its size is passed with it
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            (&enable as *const libc::c_int).cast(),
+            std::mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_gro<S>(_socket: &S) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "gso requires Linux"))
+}
+
+// Returns the length and sender of the next read, and the size of the
+// datagrams in it if the kernel coalesced several. The last datagram may be
+// shorter than the rest.
+pub async fn recv(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<usize>)> {
+    socket.async_io(Interest::READABLE, || recvmsg(socket, buffer)).await
+}
+
+// Sends `data` as datagrams of `segment_size` bytes, to `destination` or,
+// if None, to the socket's connected peer. Routes whose device can't take
+// segmented sends get the datagrams one by one instead.
+pub async fn send(
+    socket: &UdpSocket,
+    data: &[u8],
+    segment_size: usize,
+    destination: Option<SocketAddr>,
+) -> io::Result<()> {
+    if segment_size < data.len() {
+        match socket.async_io(Interest::WRITABLE, || sendmsg(socket, data, segment_size, destination)).await {
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => {}
+            result => return result,
+        }
+    }
+    for datagram in data.chunks(segment_size.max(1)) {
+        match destination {
+            Some(destination) => socket.send_to(datagram, destination).await?,
+            None => socket.send(datagram).await?,
+        };
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn recvmsg(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<usize>)> {
+    use std::os::fd::AsRawFd;
+
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    // SAFETY: all-zero is a valid sockaddr_storage and msghdr
+    let mut source: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_name = (&mut source as *mut libc::sockaddr_storage).cast();
+    message.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = std::mem::size_of_val(&control);
+
+    // SAFETY: every pointer in `message` refers to a live buffer of the
+    // length given next to it
+    let length = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, 0) };
+    if length < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: recvmsg filled in `source` and set msg_namelen
+    let source = unsafe { crate::transparent::read_sockaddr((&source as *const libc::sockaddr_storage).cast()) }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram from a non-IP address"))?;
+
+    // SAFETY: recvmsg just filled in the control data
+    let segment_size = unsafe { segment_size(&message, length as usize) };
+    Ok((length as usize, source, segment_size))
+}
+
+// The size of the datagrams in a read of `length` bytes, from the UDP_GRO
+// control message
+// SAFETY: `message` must have been filled in by recvmsg
+#[cfg(target_os = "linux")]
+pub unsafe fn segment_size(message: &libc::msghdr, length: usize) -> Option<usize> {
+    let mut segment_size = None;
+    // SAFETY: the CMSG_* macros walk the control data recvmsg wrote, within
+    // msg_controllen
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_UDP && (*header).cmsg_type == libc::UDP_GRO {
+                let size = std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<libc::c_int>());
+                segment_size = Some(size as usize).filter(|&size| size > 0 && size < length);
+            }
+            header = libc::CMSG_NXTHDR(message, header);
+        }
+    }
+    segment_size
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recvmsg(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<usize>)> {
+    socket.try_recv_from(buffer).map(|(len, addr)| (len, addr, None))
+}
+
+#[cfg(target_os = "linux")]
+fn sendmsg(socket: &UdpSocket, data: &[u8], segment_size: usize, destination: Option<SocketAddr>) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let segment_size = u16::try_from(segment_size)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "segment size exceeds 65535 bytes"))?;
+    let destination = destination.map(socket2::SockAddr::from);
+    let mut control = [0u64; 4];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr().cast_mut().cast(),
+        iov_len: data.len(),
+    };
+    // SAFETY: all-zero is a valid msghdr
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    if let Some(destination) = &destination {
+        message.msg_name = destination.as_ptr().cast_mut().cast();
+        message.msg_namelen = destination.len();
+    }
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    // SAFETY: `control` is aligned for cmsghdr and has room for a u16
+    unsafe { set_segment_size(&mut message, segment_size) };
+
+    // SAFETY: every pointer in `message` refers to a live buffer of the
+    // length given next to it
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &message, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Adds the UDP_SEGMENT control message that has the kernel split a send
+// into datagrams of `segment_size` bytes
+// SAFETY: msg_control must point to a buffer aligned for cmsghdr with room
+// for a control message of one u16
+#[cfg(target_os = "linux")]
+pub unsafe fn set_segment_size(message: &mut libc::msghdr, segment_size: u16) {
+    // SAFETY: the CMSG_* macros stay within the buffer the caller provides,
+    // so CMSG_FIRSTHDR is non-null and the data fits
+    unsafe {
+        message.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<u16>() as u32) as usize;
+        let header = libc::CMSG_FIRSTHDR(message);
+        (*header).cmsg_level = libc::SOL_UDP;
+        (*header).cmsg_type = libc::UDP_SEGMENT;
+        (*header).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u16>() as u32) as usize;
+        std::ptr::write_unaligned(libc::CMSG_DATA(header).cast::<u16>(), segment_size);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sendmsg(_socket: &UdpSocket, _data: &[u8], _segment_size: usize, _destination: Option<SocketAddr>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "gso requires Linux"))
+}
@@ -0,0 +1,386 @@
+use crate::sockopt::AsSocket;
+#[cfg(unix)]
+use crate::unix_socket;
+use anyhow::Result;
+#[cfg(unix)]
+use anyhow::Context;
+use log::debug;
+#[cfg(unix)]
+use log::{info, warn};
+#[cfg(unix)]
+use serde_json::{json, Value};
+use socket2::Socket;
+use std::io;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use tokio::time::sleep;
+
+// Zero-downtime upgrades ([global] upgrade_socket). The running process
+// listens on a Unix socket; a new process started with the same setting
+// connects to it and receives every listening socket (SCM_RIGHTS), keyed by
+// what it is bound to. The new process starts its rules on those sockets
+// instead of binding afresh, then says it is ready. Only then does the old
+// process stop accepting and wait for its open connections to finish. Both
+// processes share the same kernel sockets throughout, so connections queue
+// up rather than being refused while the handover is under way. Handing
+// over needs Unix sockets, so other platforms bind afresh on every start.
+
+// Sockets per message, below the kernel's SCM_MAX_FD of 253
+#[cfg(unix)]
+const MAX_FDS: usize = 200;
+#[cfg(unix)]
+const MAX_MESSAGE: usize = 1024 * 1024;
+// How long the new process waits for its rules to claim the inherited
+// sockets; whatever is left over belongs to rules it no longer has
+#[cfg(unix)]
+const CLAIM_GRACE: Duration = Duration::from_secs(10);
+// How long the old process waits for the new one to be ready
+#[cfg(unix)]
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+#[cfg(unix)]
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Listening sockets of this process, offered to its successor for as long
+// as their rules keep them open
+static OFFERED: Mutex<Vec<(String, Weak<dyn AsSocket + Send + Sync>)>> = Mutex::new(Vec::new());
+// Sockets handed over by the previous process that no rule has claimed yet
+static INHERITED: Mutex<Vec<(String, Socket)>> = Mutex::new(Vec::new());
+
+// Registers a listening socket bound to `key` (e.g. "tcp 0.0.0.0:80") for
+// the next upgrade
+pub fn offer<T: AsSocket + Send + Sync + 'static>(key: String, socket: T) -> Arc<T> {
+    let socket = Arc::new(socket);
+    let weak: Weak<T> = Arc::downgrade(&socket);
+    let mut offered = OFFERED.lock().unwrap();
+    offered.retain(|(_, socket)| socket.strong_count() > 0);
+    offered.push((key, weak));
+    socket
+}
+
+// The socket the previous process had bound to `key`, if any
+pub fn inherited(key: &str) -> Option<Socket> {
+    let mut inherited = INHERITED.lock().unwrap();
+    let index = inherited.iter().position(|(inherited_key, _)| inherited_key == key)?;
+    debug!("Taking over {} from the previous process", key);
+    Some(inherited.remove(index).1)
+}
+
+// The running process's end: waits for a successor to connect
+#[cfg(unix)]
+pub struct Upgrades {
+    listener: UnixListener,
+    path: String,
+}
+
+#[cfg(unix)]
+impl Upgrades {
+    pub fn bind(path: &str) -> Result<Self> {
+        let listener = unix_socket::bind(path).with_context(|| format!("failed to listen on upgrade socket {}", path))?;
+        info!("Accepting upgrades on {}", path);
+        Ok(Self { listener, path: path.to_string() })
+    }
+
+    pub async fn accept(&self) -> io::Result<Successor> {
+        let (stream, _) = self.listener.accept().await?;
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        Ok(Successor(stream))
+    }
+
+    // Stops accepting upgrades and frees the path for the successor
+    pub fn close(self) {
+        if !self.path.starts_with('@') {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// A new process taking over from this one
+#[cfg(unix)]
+pub struct Successor(UnixStream);
+
+#[cfg(unix)]
+impl Successor {
+    // Sends every listening socket, then waits until the new process is
+    // serving on them. On error this process carries on as before.
+    pub async fn hand_over(self) -> Result<Self> {
+        blocking(move || {
+            let sockets: Vec<(String, Arc<dyn AsFd + Send + Sync>)> = OFFERED.lock().unwrap()
+                .iter()
+                .filter_map(|(key, socket)| Some((key.clone(), socket.upgrade()?)))
+                .collect();
+            info!("Handing {} listening sockets over to a new process", sockets.len());
+            let mut batches = sockets.chunks(MAX_FDS).peekable();
+            if batches.peek().is_none() {
+                send(&self.0, &json!({ "listeners": [], "last": true }), &[])?;
+            }
+            while let Some(batch) = batches.next() {
+                let keys: Vec<&str> = batch.iter().map(|(key, _)| key.as_str()).collect();
+                let fds: Vec<RawFd> = batch.iter().map(|(_, socket)| socket.as_fd().as_raw_fd()).collect();
+                send(&self.0, &json!({ "listeners": keys, "last": batches.peek().is_none() }), &fds)?;
+            }
+
+            self.0.set_read_timeout(Some(READY_TIMEOUT))?;
+            let (message, _) = receive(&self.0).context("the new process did not get ready")?;
+            if message["ready"].as_bool() != Some(true) {
+                anyhow::bail!("unexpected message from the new process: {}", message);
+            }
+            Ok(self)
+        })
+        .await
+    }
+
+    // Tells the new process this one has stopped accepting
+    pub async fn release(self) -> Result<()> {
+        blocking(move || send(&self.0, &json!({ "released": true }), &[])).await
+    }
+}
+
+// The previous process, while this one takes over from it
+#[cfg(unix)]
+pub struct Predecessor(UnixStream);
+
+// Connects to the process serving on `path` and takes its listening
+// sockets. None if no process is serving there.
+#[cfg(unix)]
+pub async fn take_over(path: &str) -> Option<Predecessor> {
+    let stream = unix_socket::connect(path).await.ok()?.into_std().ok()?;
+    let result = blocking(move || {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(READY_TIMEOUT))?;
+        let mut inherited = Vec::new();
+        loop {
+            let (message, fds) = receive(&stream)?;
+            let keys = message["listeners"].as_array().context("unexpected message from the previous process")?;
+            if keys.len() != fds.len() {
+                anyhow::bail!("the previous process sent {} sockets for {} listeners", fds.len(), keys.len());
+            }
+            for (key, fd) in keys.iter().zip(fds) {
+                inherited.push((key.as_str().unwrap_or_default().to_string(), Socket::from(fd)));
+            }
+            if message["last"].as_bool().unwrap_or(true) {
+                break;
+            }
+        }
+        Ok((Predecessor(stream), inherited))
+    })
+    .await;
+    match result {
+        Ok((predecessor, inherited)) => {
+            info!("Taking over {} listening sockets from the process on {}", inherited.len(), path);
+            *INHERITED.lock().unwrap() = inherited;
+            Some(predecessor)
+        }
+        Err(e) => {
+            warn!("Upgrade through {} failed: {:#}, binding afresh", path, e);
+            None
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Predecessor {
+    // Once the rules have claimed their sockets, lets the previous process
+    // stop and waits for it to do so
+    pub async fn finish(self) -> Result<()> {
+        let deadline = Instant::now() + CLAIM_GRACE;
+        while !INHERITED.lock().unwrap().is_empty() && Instant::now() < deadline {
+            sleep(Duration::from_millis(100)).await;
+        }
+        for (key, _) in INHERITED.lock().unwrap().drain(..) {
+            warn!("No rule took over {} from the previous process, closing it", key);
+        }
+
+        blocking(move || {
+            send(&self.0, &json!({ "ready": true }), &[])?;
+            self.0.set_read_timeout(Some(RELEASE_TIMEOUT))?;
+            let (message, _) = receive(&self.0).context("the previous process did not stop")?;
+            if message["released"].as_bool() != Some(true) {
+                anyhow::bail!("unexpected message from the previous process: {}", message);
+            }
+            Ok(())
+        })
+        .await?;
+        info!("Took over from the previous process");
+        Ok(())
+    }
+}
+
+// Connections accepted by TCP rules, and UDP sessions. A process that is
+// shutting down or handed its sockets over waits for these to end before
+// exiting.
+#[derive(Default)]
+pub struct OpenConnections(Arc<AtomicUsize>);
+
+pub struct OpenConnection(Arc<AtomicUsize>);
+
+impl OpenConnections {
+    pub fn track(&self) -> OpenConnection {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self.0.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(unix)]
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f).await?
+}
+
+// A length-prefixed JSON message, with `fds` attached to its first byte
+#[cfg(unix)]
+fn send(stream: &UnixStream, message: &Value, fds: &[RawFd]) -> Result<()> {
+    let body = message.to_string();
+    let mut data = (body.len() as u32).to_be_bytes().to_vec();
+    data.extend_from_slice(body.as_bytes());
+
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+    // u64s keep the buffer aligned for cmsghdr
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of_val(fds) as u32) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of_val(fds) as u32) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast::<RawFd>(), fds.len());
+        }
+    }
+    let sent = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    (&*stream).write_all(&data[sent as usize..])?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn receive(stream: &UnixStream) -> Result<(Value, Vec<OwnedFd>)> {
+    // Read the length alone, so the sockets that came with it are not
+    // mixed up with those of the next message
+    let mut length = [0u8; 4];
+    let mut iov = libc::iovec { iov_base: length.as_mut_ptr().cast(), iov_len: length.len() };
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<RawFd>()) as u32) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+    let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, RECEIVE_FLAGS) };
+    if received < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / std::mem::size_of::<RawFd>();
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        anyhow::bail!("too many sockets in one message");
+    }
+    if received == 0 {
+        anyhow::bail!("connection closed");
+    }
+    (&*stream).read_exact(&mut length[received as usize..])?;
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE {
+        anyhow::bail!("message of {} bytes is too large", length);
+    }
+    let mut body = vec![0u8; length];
+    (&*stream).read_exact(&mut body)?;
+    Ok((serde_json::from_slice(&body)?, fds))
+}
+
+// Received sockets must not leak into processes this one starts
+#[cfg(target_os = "linux")]
+const RECEIVE_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+
+#[cfg(all(unix, not(target_os = "linux")))]
+const RECEIVE_FLAGS: libc::c_int = 0;
+
+// Config validation rejects upgrade_socket where there are no Unix sockets,
+// so none of these is ever reached there
+#[cfg(not(unix))]
+pub enum Upgrades {}
+
+#[cfg(not(unix))]
+impl Upgrades {
+    pub fn bind(path: &str) -> Result<Self> {
+        anyhow::bail!("upgrade socket {} requires a Unix platform", path)
+    }
+
+    pub async fn accept(&self) -> io::Result<Successor> {
+        match *self {}
+    }
+
+    pub fn close(self) {
+        match self {}
+    }
+}
+
+#[cfg(not(unix))]
+pub enum Successor {}
+
+#[cfg(not(unix))]
+impl Successor {
+    pub async fn hand_over(self) -> Result<Self> {
+        match self {}
+    }
+
+    pub async fn release(self) -> Result<()> {
+        match self {}
+    }
+}
+
+#[cfg(not(unix))]
+pub enum Predecessor {}
+
+#[cfg(not(unix))]
+pub async fn take_over(_path: &str) -> Option<Predecessor> {
+    None
+}
+
+#[cfg(not(unix))]
+impl Predecessor {
+    pub async fn finish(self) -> Result<()> {
+        match self {}
+    }
+}
@@ -0,0 +1,350 @@
+use crate::config::parse_endpoint;
+use crate::connector::BoxedStream;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+const MAX_STREAMED_MESSAGE: usize = 16 * 1024 * 1024;
+
+// The first request's line and headers, plus whatever followed them in the
+// same reads
+pub struct RequestHead {
+    head: Vec<u8>,
+    rest: Vec<u8>,
+}
+
+impl RequestHead {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let head = std::str::from_utf8(&self.head).ok()?;
+        head.split("\r\n")
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+
+    // Host header without the port
+    pub fn host(&self) -> Option<&str> {
+        let host = self.header("host")?;
+        if let Some(v6) = host.strip_prefix('[') {
+            return v6.split(']').next();
+        }
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => Some(name),
+            _ => Some(host),
+        }
+    }
+
+    // Rewrites the head for the backend. Non-upgrade requests get
+    // `Connection: close` so each later request arrives on a fresh connection
+    // and is rewritten too.
+    pub fn rewrite(&mut self, host: Option<&str>, forwarded_for: Option<(IpAddr, &str)>) {
+        let Ok(head) = std::str::from_utf8(&self.head) else {
+            return;
+        };
+        let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+        let mut rewritten = vec![lines.next().unwrap_or_default().to_string()];
+        let mut prior_forwarded_for = None;
+        let mut upgrade = false;
+
+        for line in lines {
+            let name = line.split(':').next().unwrap_or_default().trim().to_ascii_lowercase();
+            let value = line.split_once(':').map(|(_, v)| v.trim()).unwrap_or_default();
+            match name.as_str() {
+                "host" if host.is_some() => continue,
+                "connection" => {
+                    upgrade |= value.to_ascii_lowercase().contains("upgrade");
+                    if !upgrade {
+                        continue;
+                    }
+                }
+                "x-forwarded-for" if forwarded_for.is_some() => {
+                    prior_forwarded_for = Some(value.to_string());
+                    continue;
+                }
+                // Never pass on client-supplied values for these
+                "x-real-ip" | "x-forwarded-proto" if forwarded_for.is_some() => continue,
+                _ => {}
+            }
+            rewritten.push(line.to_string());
+        }
+
+        if let Some(host) = host {
+            rewritten.insert(1, format!("Host: {}", host));
+        }
+        if let Some((client_ip, proto)) = forwarded_for {
+            let chain = match prior_forwarded_for {
+                Some(prior) => format!("{}, {}", prior, client_ip),
+                None => client_ip.to_string(),
+            };
+            rewritten.push(format!("X-Forwarded-For: {}", chain));
+            rewritten.push(format!("X-Real-IP: {}", client_ip));
+            rewritten.push(format!("X-Forwarded-Proto: {}", proto));
+        }
+        if !upgrade {
+            rewritten.push("Connection: close".to_string());
+        }
+
+        self.head = format!("{}\r\n\r\n", rewritten.join("\r\n")).into_bytes();
+    }
+
+    // Request line, or status line when reading a response
+    pub fn start_line(&self) -> &str {
+        let head = std::str::from_utf8(&self.head).unwrap_or_default();
+        head.split("\r\n").next().unwrap_or_default()
+    }
+
+    // Method and request target from the request line
+    pub fn request_line(&self) -> Option<(&str, &str)> {
+        let mut parts = self.start_line().split_whitespace();
+        Some((parts.next()?, parts.next()?))
+    }
+
+    // Bytes that followed the head
+    pub fn into_rest(self) -> Vec<u8> {
+        self.rest
+    }
+
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.head.extend_from_slice(&self.rest);
+        self.head
+    }
+}
+
+pub const CONNECT_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+pub const CONNECT_FAILED: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const METHOD_NOT_ALLOWED: &[u8] = b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Reads a CONNECT request for mode = "http_connect". Returns the requested
+// host and port, plus any bytes the client sent after the request head.
+pub async fn read_connect_request<S>(stream: &mut S) -> Result<(String, u16, Vec<u8>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let head = read_request_head(stream).await?;
+    let Some((method, authority)) = head.request_line() else {
+        stream.write_all(BAD_REQUEST).await?;
+        anyhow::bail!("malformed HTTP request line");
+    };
+    if !method.eq_ignore_ascii_case("CONNECT") {
+        stream.write_all(METHOD_NOT_ALLOWED).await?;
+        anyhow::bail!("unsupported proxy method {}", method);
+    }
+    let (host, port) = match parse_endpoint(authority) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            stream.write_all(BAD_REQUEST).await?;
+            return Err(e);
+        }
+    };
+    Ok((host, port, head.rest))
+}
+
+pub async fn read_request_head<S>(stream: &mut S) -> Result<RequestHead>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = data.split_off(end + 4);
+            return Ok(RequestHead { head: data, rest });
+        }
+        if data.len() > MAX_REQUEST_HEAD {
+            anyhow::bail!("HTTP request head larger than {} bytes", MAX_REQUEST_HEAD);
+        }
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before the end of the HTTP request head");
+        }
+        data.extend_from_slice(&buffer[..n]);
+    }
+}
+
+// A response read to the end of the connection
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> Result<Value> {
+        serde_json::from_slice(&self.body).context("invalid JSON in HTTP response")
+    }
+}
+
+// Reads a `Connection: close` response to its end
+pub async fn read_response<S>(stream: &mut S, limit: usize) -> Result<HttpResponse>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut raw = Vec::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        // Servers often skip close_notify; treat that as a normal end of body
+        let n = match stream.read(&mut buffer).await {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            break;
+        }
+        raw.extend_from_slice(&buffer[..n]);
+        if raw.len() > limit {
+            anyhow::bail!("HTTP response too large");
+        }
+    }
+    parse_response(&raw, false)
+}
+
+pub fn parse_response(raw: &[u8], head_only: bool) -> Result<HttpResponse> {
+    let split = raw.windows(4).position(|w| w == b"\r\n\r\n").context("truncated HTTP response")?;
+    let head = String::from_utf8_lossy(&raw[..split]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .context("malformed HTTP status line")?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut body = if head_only { Vec::new() } else { raw[split + 4..].to_vec() };
+    let chunked = headers.iter()
+        .any(|(n, v)| n.eq_ignore_ascii_case("transfer-encoding") && v.eq_ignore_ascii_case("chunked"));
+    if chunked && !head_only {
+        body = decode_chunked(&body)?;
+    }
+    Ok(HttpResponse { status, headers, body })
+}
+
+fn decode_chunked(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n").context("truncated chunk")?;
+        let size_field = String::from_utf8_lossy(&data[..line_end]);
+        let size_field = size_field.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_field, 16).context("invalid chunk size")?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size + 2 {
+            anyhow::bail!("truncated chunk");
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+// A response body read line by line as it arrives, for watch streams that
+// send one JSON message per line
+pub struct StreamingBody {
+    stream: BoxedStream,
+    // Undecoded bytes read from the stream
+    raw: Vec<u8>,
+    chunked: bool,
+    // Bytes left in the current chunk
+    chunk_left: usize,
+    // Decoded body not yet returned
+    body: Vec<u8>,
+}
+
+impl StreamingBody {
+    // Reads the head; fails unless the status is 200
+    pub async fn start(stream: BoxedStream) -> Result<Self> {
+        let mut body = Self { stream, raw: Vec::new(), chunked: false, chunk_left: 0, body: Vec::new() };
+        let end = loop {
+            if let Some(end) = body.raw.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end;
+            }
+            body.read_more().await?;
+        };
+        let head = parse_response(&body.raw[..end + 4], true)?;
+        if head.status != 200 {
+            anyhow::bail!("watch answered {}", head.status);
+        }
+        body.chunked = head.header("transfer-encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+        body.raw.drain(..end + 4);
+        Ok(body)
+    }
+
+    // None once the body has ended
+    pub async fn next_line(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(end) = self.body.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.body.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return Ok(Some(line));
+            }
+            if !self.decode_more().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Moves more of the body from `raw` to `body`; false at its end
+    async fn decode_more(&mut self) -> Result<bool> {
+        if !self.chunked {
+            if self.raw.is_empty() && !self.read_more().await? {
+                return Ok(false);
+            }
+            self.body.append(&mut self.raw);
+            return Ok(true);
+        }
+        while self.chunk_left == 0 {
+            let line_end = loop {
+                if let Some(end) = self.raw.windows(2).position(|w| w == b"\r\n") {
+                    break end;
+                }
+                if !self.read_more().await? {
+                    return Ok(false);
+                }
+            };
+            let size_field: String = String::from_utf8_lossy(&self.raw[..line_end]).into();
+            self.raw.drain(..line_end + 2);
+            // The empty line is the CRLF that ends the previous chunk's data
+            if size_field.is_empty() {
+                continue;
+            }
+            let size_field = size_field.split(';').next().unwrap_or_default().trim();
+            self.chunk_left = usize::from_str_radix(size_field, 16).context("invalid chunk size")?;
+            if self.chunk_left == 0 {
+                return Ok(false);
+            }
+        }
+        if self.raw.is_empty() && !self.read_more().await? {
+            return Ok(false);
+        }
+        let n = self.chunk_left.min(self.raw.len());
+        let data: Vec<u8> = self.raw.drain(..n).collect();
+        self.chunk_left -= n;
+        self.body.extend_from_slice(&data);
+        Ok(true)
+    }
+
+    async fn read_more(&mut self) -> Result<bool> {
+        let mut buffer = [0u8; 8192];
+        let n = self.stream.read(&mut buffer).await?;
+        self.raw.extend_from_slice(&buffer[..n]);
+        if self.raw.len() > MAX_STREAMED_MESSAGE {
+            anyhow::bail!("watch message too large");
+        }
+        Ok(n > 0)
+    }
+}
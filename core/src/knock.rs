@@ -0,0 +1,131 @@
+use crate::config::{KnockConfig, KnockProtocol};
+use crate::sockopt::{self, BindOptions};
+use crate::task::AbortOnDrop;
+use anyhow::Result;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+
+struct Progress {
+    next_index: usize,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct KnockState {
+    progress: HashMap<IpAddr, Progress>,
+    unlocked: HashMap<IpAddr, Instant>,
+}
+
+// Port knocking gate: a client must hit `ports` in order within `window`
+// before the rule will forward its traffic
+pub struct KnockGate {
+    rule_name: String,
+    ports: Vec<u16>,
+    window: Duration,
+    open_for: Duration,
+    state: Mutex<KnockState>,
+    _tasks: Vec<AbortOnDrop>,
+}
+
+enum KnockListener {
+    Udp(Arc<UdpSocket>),
+    Tcp(Arc<TcpListener>),
+}
+
+impl KnockListener {
+    async fn run(self, gate: Weak<KnockGate>, port: u16) {
+        match self {
+            KnockListener::Udp(socket) => {
+                let mut buffer = [0u8; 64];
+                while let Ok((_, from)) = socket.recv_from(&mut buffer).await {
+                    let Some(gate) = gate.upgrade() else { return };
+                    gate.knock(from.ip(), port);
+                }
+            }
+            // The connection is dropped immediately; only the SYN matters
+            KnockListener::Tcp(listener) => {
+                while let Ok((_, from)) = listener.accept().await {
+                    let Some(gate) = gate.upgrade() else { return };
+                    gate.knock(from.ip(), port);
+                }
+            }
+        }
+    }
+}
+
+impl KnockGate {
+    pub async fn start(bind_ip: IpAddr, config: &KnockConfig, rule_name: String) -> Result<Arc<Self>> {
+        // Bind every knock port up front so configuration errors surface at startup
+        let mut listeners = Vec::new();
+        for &port in &config.ports {
+            let addr = SocketAddr::new(bind_ip, port);
+            listeners.push(match config.protocol.unwrap_or_default() {
+                KnockProtocol::Udp => KnockListener::Udp(sockopt::udp_listener(addr, BindOptions::default())?),
+                KnockProtocol::Tcp => KnockListener::Tcp(sockopt::tcp_listener(addr, BindOptions::default())?),
+            });
+        }
+
+        // The listeners only hold the gate weakly and are aborted with it, so
+        // the knock ports are released once the rule stops
+        let gate = Arc::new_cyclic(|gate: &Weak<Self>| {
+            let tasks = config.ports.iter().zip(listeners)
+                .map(|(&port, listener)| AbortOnDrop::spawn(listener.run(gate.clone(), port)))
+                .collect();
+            Self {
+                rule_name,
+                ports: config.ports.clone(),
+                window: Duration::from_secs(config.window_seconds()),
+                open_for: Duration::from_secs(config.open_for_seconds()),
+                state: Mutex::new(KnockState::default()),
+                _tasks: tasks,
+            }
+        });
+
+        info!("Port knocking enabled for '{}' on ports {:?}", gate.rule_name, gate.ports);
+        Ok(gate)
+    }
+
+    fn knock(&self, ip: IpAddr, port: u16) {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        let expected = state
+            .progress
+            .get(&ip)
+            .filter(|p| now.duration_since(p.started) <= self.window)
+            .map(|p| p.next_index)
+            .unwrap_or(0);
+
+        if self.ports[expected] == port {
+            if expected + 1 == self.ports.len() {
+                state.progress.remove(&ip);
+                state.unlocked.insert(ip, now + self.open_for);
+                info!("Client {} unlocked '{}' for {}s", ip, self.rule_name, self.open_for.as_secs());
+            } else {
+                let started = if expected == 0 { now } else { state.progress[&ip].started };
+                state.progress.insert(ip, Progress { next_index: expected + 1, started });
+            }
+        } else if self.ports[0] == port {
+            // A wrong knock that happens to be the first port restarts the sequence
+            state.progress.insert(ip, Progress { next_index: 1, started: now });
+        } else {
+            debug!("Out-of-sequence knock from {} on port {} for '{}'", ip, port, self.rule_name);
+            state.progress.remove(&ip);
+        }
+
+        // Keep the maps from growing without bound under scans
+        state.progress.retain(|_, p| now.duration_since(p.started) <= self.window);
+        state.unlocked.retain(|_, until| *until > now);
+    }
+
+    pub fn is_unlocked(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let state = self.state.lock().unwrap();
+        state.unlocked.get(&ip).is_some_and(|until| *until > Instant::now())
+    }
+}
@@ -0,0 +1,455 @@
+use crate::connector::BoxedStream;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Kernel TLS offload ([global] ktls = true, Linux). Once rustls has done a
+// handshake, the connection's keys and sequence numbers are handed to the
+// kernel, which from then on encrypts what porture writes to the socket and
+// decrypts what it reads. The socket carries plaintext like any plain TCP
+// connection, so a TLS rule can be spliced or go through io_uring.
+//
+// The kernel has to take over at a record boundary, so until then TLS
+// connections are read one record at a time (RecordStream). Records that
+// aren't application data, such as TLS 1.3 session tickets and close_notify,
+// still reach porture after the handover and are dealt with here.
+
+// TLS record content types
+const ALERT: u8 = 21;
+const APPLICATION_DATA: u8 = 23;
+// Record header: type, version, length
+const HEADER_LEN: usize = 5;
+
+// Reads no further than the end of the TLS record it is in, so whatever
+// rustls has read when the handshake ends is whole records
+pub struct RecordStream {
+    inner: BoxedStream,
+    // The part of the next record's header read so far
+    header: [u8; HEADER_LEN],
+    header_read: usize,
+    // Bytes left of the current record
+    remaining: usize,
+    // Cleared for connections that stay with rustls
+    aligned: bool,
+}
+
+impl RecordStream {
+    pub fn new(inner: BoxedStream) -> Self {
+        Self { inner, header: [0; HEADER_LEN], header_read: 0, remaining: 0, aligned: true }
+    }
+
+    fn at_boundary(&self) -> bool {
+        self.aligned && self.header_read == 0 && self.remaining == 0
+    }
+}
+
+impl AsyncRead for RecordStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.aligned {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let limit = match this.remaining {
+            0 => HEADER_LEN - this.header_read,
+            remaining => remaining,
+        };
+        let mut limited = buf.take(limit);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled();
+        let n = read.len();
+        if this.remaining > 0 {
+            this.remaining -= n;
+        } else {
+            this.header[this.header_read..this.header_read + n].copy_from_slice(read);
+            this.header_read += n;
+            if this.header_read == HEADER_LEN {
+                this.remaining = u16::from_be_bytes([this.header[3], this.header[4]]).into();
+                this.header_read = 0;
+            }
+        }
+        // SAFETY: the inner read initialized these bytes
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RecordStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Whether close_notify ended the connection, for any other alert an error
+fn alert(payload: &[u8]) -> io::Result<()> {
+    match payload {
+        [_, 0] => Ok(()),
+        [_, description] => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            format!("TLS alert {} from peer", description),
+        )),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "truncated TLS alert")),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::{is_offloaded, offload, probe, skip_control, KtlsStream};
+
+#[cfg(not(target_os = "linux"))]
+pub fn probe() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "kTLS requires Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn offload(stream: BoxedStream, _plain: bool) -> io::Result<(BoxedStream, Vec<u8>)> {
+    Ok((stream, Vec::new()))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_offloaded(_stream: &BoxedStream) -> bool {
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn skip_control(_fd: i32, error: io::Error) -> io::Result<bool> {
+    Err(error)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{alert, RecordStream, TaskContext, ALERT, APPLICATION_DATA};
+    use crate::connector::BoxedStream;
+    use crate::splice;
+    use log::debug;
+    use rustls::{ConnectionTrafficSecrets, ProtocolVersion};
+    use std::any::Any;
+    use std::io::{self, Read};
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::pin::Pin;
+    use std::task::{ready, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, Interest, ReadBuf};
+    use tokio::net::TcpStream;
+    use tokio_rustls::{client, server};
+
+    // A TCP connection whose TLS records the kernel encrypts and decrypts
+    pub struct KtlsStream {
+        inner: TcpStream,
+        close_notify_sent: bool,
+    }
+
+    impl KtlsStream {
+        pub fn get_ref(&self) -> &TcpStream {
+            &self.inner
+        }
+
+        pub fn into_inner(self) -> TcpStream {
+            self.inner
+        }
+    }
+
+    impl AsyncRead for KtlsStream {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            loop {
+                ready!(this.inner.poll_read_ready(cx))?;
+                let unfilled = buf.initialize_unfilled();
+                let fd = this.inner.as_raw_fd();
+                match this.inner.try_io(Interest::READABLE, || recv_record(fd, unfilled, 0)) {
+                    Ok((APPLICATION_DATA, n)) => {
+                        buf.advance(n);
+                        return Poll::Ready(Ok(()));
+                    }
+                    // Nothing filled in reads as the end of the stream
+                    Ok((ALERT, n)) => return Poll::Ready(alert(&unfilled[..n])),
+                    // Post-handshake messages such as session tickets
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for KtlsStream {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            cx: &mut TaskContext<'_>,
+            bufs: &[io::IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            self.inner.is_write_vectored()
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            while !this.close_notify_sent {
+                ready!(this.inner.poll_write_ready(cx))?;
+                let fd = this.inner.as_raw_fd();
+                match this.inner.try_io(Interest::WRITABLE, || send_close_notify(fd)) {
+                    Ok(()) => this.close_notify_sent = true,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+            Pin::new(&mut this.inner).poll_shutdown(cx)
+        }
+    }
+
+    // Checks that the kernel has TLS support, loading the module if it's
+    // built as one (which takes root)
+    pub fn probe() -> io::Result<()> {
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
+        match set_ulp(socket.as_raw_fd()) {
+            // The module is there; only connected sockets can use it
+            Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                Err(io::Error::new(io::ErrorKind::Unsupported, "the kernel has no TLS support (the tls module)"))
+            }
+            Err(e) => Err(e),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    // Hands a TLS connection's record layer to the kernel if `plain` and
+    // possible, returning the plaintext rustls had already decrypted. Other
+    // connections come back as they were, and stay with rustls.
+    pub async fn offload(mut stream: BoxedStream, plain: bool) -> io::Result<(BoxedStream, Vec<u8>)> {
+        // What rustls wrote has to be on the wire before the kernel writes
+        stream.flush().await?;
+        let any: &mut dyn Any = stream.as_mut();
+        let (inner, flushed) = if let Some(tls) = any.downcast_mut::<server::TlsStream<BoxedStream>>() {
+            let (inner, connection) = tls.get_mut();
+            (inner, !connection.wants_write())
+        } else if let Some(tls) = any.downcast_mut::<client::TlsStream<BoxedStream>>() {
+            let (inner, connection) = tls.get_mut();
+            (inner, !connection.wants_write())
+        } else {
+            return Ok((stream, Vec::new()));
+        };
+        let any: &mut dyn Any = inner.as_mut();
+        let Some(records) = any.downcast_mut::<RecordStream>() else {
+            return Ok((stream, Vec::new()));
+        };
+        if !prepare(records, flushed, plain) {
+            return Ok((stream, Vec::new()));
+        }
+        let any: Box<dyn Any> = stream;
+        let (inner, connection): (BoxedStream, rustls::Connection) = match any.downcast::<server::TlsStream<BoxedStream>>() {
+            Ok(tls) => {
+                let (inner, connection) = tls.into_inner();
+                (inner, connection.into())
+            }
+            Err(any) => {
+                let (inner, connection) = any.downcast::<client::TlsStream<BoxedStream>>()
+                    .expect("stream checked above")
+                    .into_inner();
+                (inner, connection.into())
+            }
+        };
+        let inner: Box<dyn Any> = inner;
+        let records = inner.downcast::<RecordStream>().expect("stream checked above");
+        let (stream, plaintext) = take_over(*records, connection)?;
+        Ok((Box::new(stream), plaintext))
+    }
+
+    pub fn is_offloaded(stream: &BoxedStream) -> bool {
+        let stream: &dyn Any = stream.as_ref();
+        stream.is::<KtlsStream>()
+    }
+
+    // Whether the connection can be handed over. Checked before anything is
+    // taken from rustls, so a connection that can't carries on as it was.
+    fn prepare(records: &mut RecordStream, flushed: bool, plain: bool) -> bool {
+        let ready = plain
+            && flushed
+            && records.at_boundary()
+            && splice::tcp_stream(records.inner.as_ref()).is_some_and(|tcp| {
+                set_ulp(tcp.as_raw_fd())
+                    .inspect_err(|e| debug!("Leaving TLS connection to rustls: {}", e))
+                    .is_ok()
+            });
+        if !ready {
+            records.aligned = false;
+        }
+        ready
+    }
+
+    fn take_over(records: RecordStream, mut connection: rustls::Connection) -> io::Result<(KtlsStream, Vec<u8>)> {
+        let mut plaintext = Vec::new();
+        match connection.reader().read_to_end(&mut plaintext) {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+            _ => {}
+        }
+        let version = match connection.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => libc::TLS_1_2_VERSION,
+            _ => libc::TLS_1_3_VERSION,
+        };
+        let secrets = connection.dangerous_extract_secrets().map_err(io::Error::other)?;
+        let inner = splice::into_tcp(records.inner);
+        set_keys(inner.as_raw_fd(), libc::TLS_TX, version, secrets.tx)?;
+        set_keys(inner.as_raw_fd(), libc::TLS_RX, version, secrets.rx)?;
+        Ok((KtlsStream { inner, close_notify_sent: false }, plaintext))
+    }
+
+    // After splice(2) or recv stopped at a record that isn't application
+    // data: reads it, and returns whether it closed the connection. Any
+    // other error is returned as it was.
+    pub fn skip_control(fd: RawFd, error: io::Error) -> io::Result<bool> {
+        let mut payload = [0u8; 512];
+        match recv_record(fd, &mut payload, libc::MSG_PEEK) {
+            Ok((APPLICATION_DATA, _)) | Err(_) => return Err(error),
+            Ok(_) => {}
+        }
+        match recv_record(fd, &mut payload, 0)? {
+            (ALERT, n) => alert(&payload[..n]).map(|()| true),
+            _ => Ok(false),
+        }
+    }
+
+    fn set_ulp(fd: RawFd) -> io::Result<()> {
+        setsockopt(fd, libc::SOL_TCP, libc::TCP_ULP, b"tls")
+    }
+
+    // rustls only negotiates AES-GCM and ChaCha20-Poly1305, which the kernel
+    // supports from 5.1 and 5.11 on
+    fn set_keys(fd: RawFd, direction: libc::c_int, version: u16, (seq, secrets): (u64, ConnectionTrafficSecrets)) -> io::Result<()> {
+        let rec_seq = seq.to_be_bytes();
+        match secrets {
+            ConnectionTrafficSecrets::Aes128Gcm { key, iv } => {
+                let mut info = libc::tls12_crypto_info_aes_gcm_128 {
+                    info: libc::tls_crypto_info { version, cipher_type: libc::TLS_CIPHER_AES_GCM_128 },
+                    iv: [0; libc::TLS_CIPHER_AES_GCM_128_IV_SIZE],
+                    key: [0; libc::TLS_CIPHER_AES_GCM_128_KEY_SIZE],
+                    salt: [0; libc::TLS_CIPHER_AES_GCM_128_SALT_SIZE],
+                    rec_seq,
+                };
+                info.key.copy_from_slice(key.as_ref());
+                // The 12-byte nonce is the salt followed by the IV
+                info.salt.copy_from_slice(&iv.as_ref()[..4]);
+                info.iv.copy_from_slice(&iv.as_ref()[4..]);
+                setsockopt(fd, libc::SOL_TLS, direction, &info)
+            }
+            ConnectionTrafficSecrets::Aes256Gcm { key, iv } => {
+                let mut info = libc::tls12_crypto_info_aes_gcm_256 {
+                    info: libc::tls_crypto_info { version, cipher_type: libc::TLS_CIPHER_AES_GCM_256 },
+                    iv: [0; libc::TLS_CIPHER_AES_GCM_256_IV_SIZE],
+                    key: [0; libc::TLS_CIPHER_AES_GCM_256_KEY_SIZE],
+                    salt: [0; libc::TLS_CIPHER_AES_GCM_256_SALT_SIZE],
+                    rec_seq,
+                };
+                info.key.copy_from_slice(key.as_ref());
+                info.salt.copy_from_slice(&iv.as_ref()[..4]);
+                info.iv.copy_from_slice(&iv.as_ref()[4..]);
+                setsockopt(fd, libc::SOL_TLS, direction, &info)
+            }
+            ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+                let mut info = libc::tls12_crypto_info_chacha20_poly1305 {
+                    info: libc::tls_crypto_info { version, cipher_type: libc::TLS_CIPHER_CHACHA20_POLY1305 },
+                    iv: [0; libc::TLS_CIPHER_CHACHA20_POLY1305_IV_SIZE],
+                    key: [0; libc::TLS_CIPHER_CHACHA20_POLY1305_KEY_SIZE],
+                    salt: [],
+                    rec_seq,
+                };
+                info.key.copy_from_slice(key.as_ref());
+                info.iv.copy_from_slice(iv.as_ref());
+                setsockopt(fd, libc::SOL_TLS, direction, &info)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "cipher not supported by kTLS")),
+        }
+    }
+
+    fn setsockopt<T: ?Sized>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+        // SAFETY: `value` outlives the call and its size is passed with it
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                (value as *const T).cast(),
+                std::mem::size_of_val(value) as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // One read from a kTLS socket, with the type of the record it came from
+    fn recv_record(fd: RawFd, buf: &mut [u8], flags: libc::c_int) -> io::Result<(u8, usize)> {
+        // u64 keeps the control buffer aligned for cmsghdr
+        let mut control = [0u64; 4];
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+        // SAFETY: all-zero is a valid msghdr
+        let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+        header.msg_iov = &mut iov;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr().cast();
+        header.msg_controllen = std::mem::size_of_val(&control);
+        // SAFETY: the buffers `header` points to outlive the call
+        let n = unsafe { libc::recvmsg(fd, &mut header, flags) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Data comes without a record type
+        let mut record_type = APPLICATION_DATA;
+        // SAFETY: the kernel filled in the control data
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_TLS && (*cmsg).cmsg_type == libc::TLS_GET_RECORD_TYPE {
+                    record_type = *libc::CMSG_DATA(cmsg);
+                }
+                cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+            }
+        }
+        Ok((record_type, n as usize))
+    }
+
+    // A warning-level close_notify alert record
+    fn send_close_notify(fd: RawFd) -> io::Result<()> {
+        let mut payload = [1u8, 0];
+        let mut control = [0u64; 4];
+        let mut iov = libc::iovec { iov_base: payload.as_mut_ptr().cast(), iov_len: payload.len() };
+        // SAFETY: all-zero is a valid msghdr
+        let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+        header.msg_iov = &mut iov;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr().cast();
+        // SAFETY: CMSG_SPACE only computes a size
+        header.msg_controllen = unsafe { libc::CMSG_SPACE(1) } as usize;
+        // SAFETY: the control buffer has room for one header and a byte
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_TLS;
+            (*cmsg).cmsg_type = libc::TLS_SET_RECORD_TYPE;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(1) as usize;
+            *libc::CMSG_DATA(cmsg) = ALERT;
+        }
+        // SAFETY: the buffers `header` points to outlive the call
+        if unsafe { libc::sendmsg(fd, &header, libc::MSG_NOSIGNAL) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
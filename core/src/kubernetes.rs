@@ -0,0 +1,251 @@
+use crate::config::{parse_endpoint, KubernetesConfig};
+use crate::connector::{connect_happy_eyeballs, BoxedStream};
+use crate::discovery::{pick_weighted, TargetPool};
+use crate::http::{read_response, StreamingBody};
+use crate::tls;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsConnector;
+
+// Kubernetes EndpointSlice discovery (target_kubernetes). The ready
+// endpoints of a Service are the rule's targets, picked at random per
+// connection. The Service's EndpointSlices are listed once, then watched, so
+// pods that become ready, fail their readiness probe or go away are followed
+// as the API server reports them. If the API server can't be reached, the
+// last known endpoints stay in use.
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Watches are ended by the server after this long and started again, which
+// also notices a dead connection
+const WATCH_SECONDS: u64 = 300;
+const RETRY: Duration = Duration::from_secs(5);
+const MAX_RESPONSE: usize = 16 * 1024 * 1024;
+
+pub struct ServiceEndpoints {
+    api: ApiClient,
+    namespace: String,
+    service: String,
+    port_name: Option<String>,
+    rule_name: String,
+    // Ready endpoints by EndpointSlice name
+    slices: RwLock<HashMap<String, Vec<(String, u16)>>>,
+}
+
+impl ServiceEndpoints {
+    // Lists the endpoints once before returning, then watches for changes
+    pub async fn start(
+        config: &KubernetesConfig,
+        service: &str,
+        port_name: Option<String>,
+        rule_name: String,
+    ) -> Result<Arc<Self>> {
+        let (namespace, service) = match service.split_once('/') {
+            Some((namespace, service)) => (namespace.to_string(), service.to_string()),
+            None => (config.namespace(), service.to_string()),
+        };
+        let endpoints = Arc::new(Self {
+            api: ApiClient::new(config)?,
+            namespace,
+            service,
+            port_name,
+            rule_name,
+            slices: RwLock::new(HashMap::new()),
+        });
+        let resource_version = match endpoints.list().await {
+            Ok(resource_version) => Some(resource_version),
+            Err(e) => {
+                warn!("TCP forwarder '{}': {}: {:#}", endpoints.rule_name, endpoints.describe(), e);
+                None
+            }
+        };
+        tokio::spawn(watch(Arc::downgrade(&endpoints), resource_version));
+        Ok(endpoints)
+    }
+
+    fn slices_path(&self) -> String {
+        format!(
+            "/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3D{}",
+            self.namespace, self.service
+        )
+    }
+
+    // Replaces every slice; returns the list's resourceVersion to watch from
+    async fn list(&self) -> Result<String> {
+        let list = self.api.get(&self.slices_path()).await?;
+        let slices: HashMap<String, Vec<(String, u16)>> = list["items"].as_array().into_iter().flatten()
+            .filter_map(|slice| Some((slice["metadata"]["name"].as_str()?.to_string(), self.ready_endpoints(slice))))
+            .collect();
+        *self.slices.write().unwrap() = slices;
+        self.log_count();
+        list["metadata"]["resourceVersion"].as_str()
+            .map(str::to_string)
+            .context("EndpointSlice list has no resourceVersion")
+    }
+
+    // Applies events until the server ends the watch; returns the
+    // resourceVersion to resume from
+    async fn watch(&self, mut resource_version: String) -> Result<String> {
+        let path = format!(
+            "{}&watch=true&allowWatchBookmarks=true&resourceVersion={}&timeoutSeconds={}",
+            self.slices_path(), resource_version, WATCH_SECONDS
+        );
+        let stream = timeout(REQUEST_TIMEOUT, self.api.send(&path)).await
+            .context("watch request timed out")??;
+        let mut body = StreamingBody::start(stream).await?;
+        while let Some(line) = body.next_line().await? {
+            let event: Value = serde_json::from_slice(&line).context("invalid watch event")?;
+            let object = &event["object"];
+            if let Some(version) = object["metadata"]["resourceVersion"].as_str() {
+                resource_version = version.to_string();
+            }
+            let name = object["metadata"]["name"].as_str().unwrap_or_default().to_string();
+            match event["type"].as_str().unwrap_or_default() {
+                "ADDED" | "MODIFIED" => {
+                    let endpoints = self.ready_endpoints(object);
+                    self.slices.write().unwrap().insert(name, endpoints);
+                    self.log_count();
+                }
+                "DELETED" => {
+                    self.slices.write().unwrap().remove(&name);
+                    self.log_count();
+                }
+                "BOOKMARK" => {}
+                // Usually 410 Gone: the resourceVersion is too old to resume from
+                _ => anyhow::bail!("watch error: {}", object["message"].as_str().unwrap_or("unknown")),
+            }
+        }
+        Ok(resource_version)
+    }
+
+    // Endpoints not marked unready, at the service port named by the rule,
+    // or at the slice's only port
+    fn ready_endpoints(&self, slice: &Value) -> Vec<(String, u16)> {
+        let ports: Vec<&Value> = slice["ports"].as_array().into_iter().flatten().collect();
+        let port = match &self.port_name {
+            Some(name) => ports.iter().find(|port| port["name"].as_str() == Some(name.as_str())),
+            None if ports.len() == 1 => ports.first(),
+            None => None,
+        };
+        let Some(port) = port.and_then(|port| port["port"].as_u64()).and_then(|port| u16::try_from(port).ok()) else {
+            return Vec::new();
+        };
+        slice["endpoints"].as_array().into_iter().flatten()
+            .filter(|endpoint| endpoint["conditions"]["ready"].as_bool() != Some(false))
+            .filter_map(|endpoint| endpoint["addresses"][0].as_str())
+            .map(|address| (address.to_string(), port))
+            .collect()
+    }
+
+    fn log_count(&self) {
+        let count: usize = self.slices.read().unwrap().values().map(Vec::len).sum();
+        if count == 0 {
+            warn!("TCP forwarder '{}': {} has no ready endpoints", self.rule_name, self.describe());
+        } else {
+            info!("TCP forwarder '{}': {} has {} ready endpoints", self.rule_name, self.describe(), count);
+        }
+    }
+}
+
+impl TargetPool for ServiceEndpoints {
+    fn describe(&self) -> String {
+        format!("Kubernetes service {}/{}", self.namespace, self.service)
+    }
+
+    fn pick(&self) -> io::Result<(String, u16)> {
+        let slices = self.slices.read().unwrap();
+        let endpoints: Vec<&(String, u16)> = slices.values().flatten().collect();
+        if endpoints.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no ready endpoints for {}", self.describe())));
+        }
+        let (host, port) = pick_weighted(&endpoints, |_| 1);
+        Ok((host.clone(), *port))
+    }
+}
+
+// Ends once the rule's connector is gone
+async fn watch(endpoints: Weak<ServiceEndpoints>, mut resource_version: Option<String>) {
+    loop {
+        let Some(endpoints) = endpoints.upgrade() else {
+            return;
+        };
+        let result = match resource_version.take() {
+            Some(version) => endpoints.watch(version).await,
+            None => endpoints.list().await,
+        };
+        match result {
+            Ok(version) => {
+                debug!("TCP forwarder '{}': {} at resourceVersion {}", endpoints.rule_name, endpoints.describe(), version);
+                resource_version = Some(version);
+            }
+            Err(e) => {
+                warn!(
+                    "TCP forwarder '{}': {}: {:#}, keeping the previous endpoints",
+                    endpoints.rule_name, endpoints.describe(), e
+                );
+                drop(endpoints);
+                sleep(RETRY).await;
+            }
+        }
+    }
+}
+
+struct ApiClient {
+    api_server: String,
+    token_file: String,
+    tls: Option<TlsConnector>,
+}
+
+impl ApiClient {
+    fn new(config: &KubernetesConfig) -> Result<Self> {
+        let api_server = config.api_server()?;
+        let tls = match api_server.starts_with("https://") {
+            true => Some(tls::connector(config.ca().as_deref(), false)?),
+            false => None,
+        };
+        Ok(Self { api_server, token_file: config.token_file(), tls })
+    }
+
+    async fn get(&self, path: &str) -> Result<Value> {
+        timeout(REQUEST_TIMEOUT, async {
+            let mut stream = self.send(path).await?;
+            let response = read_response(&mut stream, MAX_RESPONSE).await?;
+            if response.status != 200 {
+                anyhow::bail!("API server answered {}: {}", response.status, String::from_utf8_lossy(&response.body).trim());
+            }
+            response.json()
+        })
+        .await
+        .context("request to the API server timed out")?
+    }
+
+    async fn send(&self, path: &str) -> Result<BoxedStream> {
+        let authority = self.api_server.trim_start_matches("https://").trim_start_matches("http://");
+        let (host, port) = parse_endpoint(authority)?;
+        let stream = connect_happy_eyeballs(&host, port).await
+            .with_context(|| format!("failed to connect to the API server at {}", self.api_server))?;
+        let mut stream: BoxedStream = match &self.tls {
+            Some(tls) => Box::new(tls.connect(tls::server_name(&host)?, stream).await?),
+            None => Box::new(stream),
+        };
+
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: porture/{}\r\nAccept: application/json\r\nConnection: close\r\n",
+            path, authority, env!("CARGO_PKG_VERSION")
+        );
+        // Projected service account tokens are rotated, so read it each time.
+        // Without one (e.g. behind kubectl proxy) requests go unauthenticated.
+        if let Ok(token) = std::fs::read_to_string(&self.token_file) {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token.trim()));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        Ok(stream)
+    }
+}
@@ -0,0 +1,246 @@
+//! porture's forwarding engine, for embedding in other programs. The porture
+//! binary adds the command line, reloads, upgrades and process management on
+//! top of it.
+//!
+//! [`Forwarder::spawn`] starts one rule and returns once it is listening:
+//!
+//! ```
+//! use porture_core::{Forwarder, TcpRule};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! let rule = TcpRule {
+//!     bind: Some("127.0.0.1:0".into()),
+//!     target: Some("10.0.0.5:80".into()),
+//!     ..Default::default()
+//! };
+//! let forwarder = Forwarder::spawn(rule).await?;
+//! println!("listening on {:?}", forwarder.local_addrs());
+//! forwarder.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Config`], the rules, [`Forwarder`] and the filter traits are the API for
+//! embedding. The modules hidden from these docs are public only because the
+//! binary is built from them, and change with its needs.
+
+mod acl;
+mod acme;
+#[doc(hidden)]
+pub mod admin;
+#[doc(hidden)]
+pub mod ban;
+mod banner;
+mod buffer;
+mod builtin;
+#[doc(hidden)]
+pub mod capture;
+mod chaos;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod confine;
+mod connector;
+mod consul;
+mod copy;
+mod discovery;
+#[doc(hidden)]
+pub mod docker;
+#[doc(hidden)]
+pub mod etcd;
+mod filter;
+#[doc(hidden)]
+pub mod geoip;
+mod gso;
+#[doc(hidden)]
+pub mod handoff;
+mod http;
+mod knock;
+#[doc(hidden)]
+pub mod ktls;
+mod kubernetes;
+mod limits;
+#[cfg(feature = "lua")]
+mod lua;
+mod mdns;
+mod mirror;
+#[doc(hidden)]
+pub mod privileges;
+mod proxy_protocol;
+#[doc(hidden)]
+pub mod record;
+#[doc(hidden)]
+pub mod resolver;
+mod rewrite;
+#[doc(hidden)]
+pub mod rule_log;
+#[doc(hidden)]
+pub mod seccomp;
+mod sni;
+mod sniff;
+#[doc(hidden)]
+pub mod sockmap;
+#[doc(hidden)]
+pub mod sockopt;
+mod socks5;
+mod splice;
+mod srv;
+#[doc(hidden)]
+pub mod state;
+#[doc(hidden)]
+pub mod supervisor;
+#[doc(hidden)]
+pub mod systemd;
+#[doc(hidden)]
+pub mod task;
+#[doc(hidden)]
+pub mod tcp_forwarder;
+#[doc(hidden)]
+pub mod tls;
+mod transparent;
+#[doc(hidden)]
+pub mod tunnel;
+#[doc(hidden)]
+pub mod udp_forwarder;
+mod udp_tunnel;
+#[doc(hidden)]
+pub mod unix_socket;
+mod upstream;
+#[doc(hidden)]
+pub mod uring;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod websocket;
+
+pub use config::{Config, TcpMode, TcpRule, UdpMode, UdpRule};
+pub use filter::{Admission, DatagramFilter, Direction, Filters, Flow, FlowFilter, StreamFilter, Verdict};
+
+use state::SharedState;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use supervisor::Bound;
+use tcp_forwarder::TcpForwarder;
+use tokio::sync::oneshot;
+use tokio::task::{AbortHandle, JoinHandle};
+use udp_forwarder::UdpForwarder;
+
+/// A rule of either protocol, as the `[[tcp]]` and `[[udp]]` tables of a
+/// config file hold them
+#[derive(Debug, Clone)]
+pub enum Rule {
+    Tcp(Box<TcpRule>),
+    Udp(Box<UdpRule>),
+}
+
+impl From<TcpRule> for Rule {
+    fn from(rule: TcpRule) -> Self {
+        Rule::Tcp(Box::new(rule))
+    }
+}
+
+impl From<UdpRule> for Rule {
+    fn from(rule: UdpRule) -> Self {
+        Rule::Udp(Box::new(rule))
+    }
+}
+
+/// One running rule. Unlike the porture binary, which restarts failed rules,
+/// a forwarder that fails stays stopped and [`wait`](Forwarder::wait) returns
+/// the error. Each forwarder has its own limits and state; `[global]`
+/// settings don't apply. Dropping it leaves it running, like dropping a
+/// task's `JoinHandle`.
+pub struct Forwarder {
+    task: JoinHandle<anyhow::Result<()>>,
+    local_addrs: Vec<SocketAddr>,
+}
+
+impl Forwarder {
+    /// Checks the rule as a config file's would be, starts it and waits until
+    /// it is listening. Fails if the rule is invalid or can't bind.
+    pub async fn spawn(rule: impl Into<Rule>) -> anyhow::Result<Self> {
+        Self::spawn_with_filters(rule, Filters::default()).await
+    }
+
+    /// Like [`spawn`](Forwarder::spawn), running the stream filters on a TCP
+    /// rule's connections or the datagram filters on a UDP rule's sessions,
+    /// after its own. See [`StreamFilter`] and [`DatagramFilter`] for writing
+    /// them.
+    pub async fn spawn_with_filters(rule: impl Into<Rule>, filters: Filters) -> anyhow::Result<Self> {
+        let rule = match rule.into() {
+            Rule::Tcp(mut rule) => {
+                rule.expand_endpoints()?;
+                rule.validate()?;
+                Rule::Tcp(rule)
+            }
+            Rule::Udp(mut rule) => {
+                rule.expand_endpoints()?;
+                rule.validate()?;
+                Rule::Udp(rule)
+            }
+        };
+        let shared = Arc::new(SharedState::default());
+        let (bound_tx, bound_rx) = oneshot::channel();
+        let log_level = match &rule {
+            Rule::Tcp(rule) => rule.log_level_filter(),
+            Rule::Udp(rule) => rule.log_level_filter(),
+        };
+        let task = tokio::spawn(rule_log::scope(log_level, async move {
+            let mut bound = Bound::new(bound_tx);
+            match rule {
+                Rule::Tcp(rule) => TcpForwarder::new(*rule, shared).with_filters(filters.stream).start(&mut bound).await,
+                Rule::Udp(rule) => UdpForwarder::new(*rule, shared).with_filters(filters.datagram).start(&mut bound).await,
+            }
+        }));
+        match bound_rx.await {
+            Ok(local_addrs) => Ok(Self { task, local_addrs }),
+            // Dropped unsignalled: the forwarder failed to start
+            Err(_) => {
+                let forwarder = Self { task, local_addrs: Vec::new() };
+                Err(forwarder.wait().await.err().unwrap_or_else(|| anyhow::anyhow!("forwarder stopped while starting")))
+            }
+        }
+    }
+
+    /// The addresses the rule listens on, with the ports the system picked
+    /// for any bound to port 0. Empty for a Unix socket.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// A handle that stops the forwarder from elsewhere, e.g. another task
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.task.abort_handle())
+    }
+
+    /// Stops listening and returns once the listeners are closed. TCP
+    /// connections already accepted carry on until they close, as when
+    /// porture reloads a rule away.
+    pub async fn shutdown(self) {
+        self.task.abort();
+        let _ = self.task.await;
+    }
+
+    /// Runs until the forwarder fails, with its error, or is stopped through
+    /// a [`ShutdownHandle`]
+    pub async fn wait(self) -> anyhow::Result<()> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) if e.is_cancelled() => Ok(()),
+            Err(e) => Err(anyhow::anyhow!("forwarder panicked: {}", e)),
+        }
+    }
+}
+
+/// Stops a [`Forwarder`] without owning it, from
+/// [`Forwarder::shutdown_handle`]
+#[derive(Clone)]
+pub struct ShutdownHandle(AbortHandle);
+
+impl ShutdownHandle {
+    /// Stops listening, like [`Forwarder::shutdown`], without waiting for the
+    /// listeners to close
+    pub fn shutdown(&self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,221 @@
+use crate::filter::{Admission, DatagramFilter, Direction, Flow, FlowFilter, StreamFilter, Verdict};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+// Tracks live connections per client IP for a single rule
+pub struct PerIpLimiter {
+    max_per_ip: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+// Releases the client's slot when the connection ends
+pub struct PerIpGuard {
+    limiter: Arc<PerIpLimiter>,
+    ip: IpAddr,
+}
+
+impl PerIpLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        Self {
+            max_per_ip,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<PerIpGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(PerIpGuard {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for PerIpGuard {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// Classic token bucket: refills at `rate` tokens per second up to `capacity`
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    pub fn try_take(&mut self, amount: f64) -> bool {
+        self.refill();
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Take `amount` unconditionally, going into debt if needed, and return
+    // how long the caller should wait for the balance to recover
+    pub fn reserve(&mut self, amount: f64) -> Duration {
+        self.refill();
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+// Byte-rate shaper for one direction of traffic
+pub struct RateLimiter {
+    bucket: Mutex<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn from_kbps(kbps: u64) -> Self {
+        let bytes_per_sec = (kbps * 1000 / 8).max(1) as f64;
+        Self {
+            bucket: Mutex::new(TokenBucket::new(bytes_per_sec, bytes_per_sec)),
+        }
+    }
+
+    // How long to wait before passing the bytes on
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        self.bucket.lock().unwrap().reserve(bytes as f64)
+    }
+}
+
+// Independent limits for each direction of a forward
+pub struct Bandwidth {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+impl Bandwidth {
+    pub fn from_kbps(kbps: u64) -> Self {
+        Self {
+            upload: RateLimiter::from_kbps(kbps),
+            download: RateLimiter::from_kbps(kbps),
+        }
+    }
+
+    fn direction(&self, direction: Direction) -> &RateLimiter {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+}
+
+// Per-client-IP bandwidth limits. Entries live as long as some connection or
+// session from that client holds the returned Arc.
+pub struct ClientBandwidth {
+    kbps: u64,
+    clients: Mutex<HashMap<IpAddr, Weak<Bandwidth>>>,
+}
+
+impl ClientBandwidth {
+    pub fn new(kbps: u64) -> Self {
+        Self {
+            kbps,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn for_client(&self, ip: IpAddr) -> Arc<Bandwidth> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(bandwidth) = clients.get(&ip).and_then(Weak::upgrade) {
+            return bandwidth;
+        }
+
+        // Drop entries for clients that are gone before adding a new one
+        clients.retain(|_, bandwidth| bandwidth.strong_count() > 0);
+        let bandwidth = Arc::new(Bandwidth::from_kbps(self.kbps));
+        clients.insert(ip, Arc::downgrade(&bandwidth));
+        bandwidth
+    }
+}
+
+// A rule's rate_limit_kbps and client_rate_limit_kbps, as a filter
+pub struct BandwidthFilter {
+    rule: Option<Arc<Bandwidth>>,
+    clients: Option<ClientBandwidth>,
+}
+
+impl BandwidthFilter {
+    // None if the rule sets neither limit
+    pub fn new(rate_limit_kbps: Option<u64>, client_rate_limit_kbps: Option<u64>) -> Option<Self> {
+        if rate_limit_kbps.is_none() && client_rate_limit_kbps.is_none() {
+            return None;
+        }
+        Some(Self {
+            rule: rate_limit_kbps.map(|kbps| Arc::new(Bandwidth::from_kbps(kbps))),
+            clients: client_rate_limit_kbps.map(ClientBandwidth::new),
+        })
+    }
+
+    fn admit(&self, flow: &Flow) -> Admission {
+        let shapers = self.rule.iter().cloned()
+            .chain(self.clients.as_ref().map(|c| c.for_client(flow.client_addr.ip())))
+            .collect();
+        Admission::Watch(Box::new(Shapers(shapers)))
+    }
+}
+
+impl StreamFilter for BandwidthFilter {
+    fn on_connect(&self, flow: &Flow) -> Admission {
+        self.admit(flow)
+    }
+}
+
+impl DatagramFilter for BandwidthFilter {
+    fn on_session(&self, flow: &Flow) -> Admission {
+        self.admit(flow)
+    }
+}
+
+// The limits one connection or session counts against. Holding the
+// client's keeps its entry alive.
+struct Shapers(Vec<Arc<Bandwidth>>);
+
+impl FlowFilter for Shapers {
+    fn on_data(&mut self, direction: Direction, data: &mut Vec<u8>) -> Verdict {
+        let wait = self.0.iter()
+            .map(|shaper| shaper.direction(direction).reserve(data.len()))
+            .max()
+            .unwrap_or_default();
+        Verdict::Delay(wait)
+    }
+}
@@ -0,0 +1,190 @@
+use crate::config::parse_endpoint;
+use crate::filter::{Admission, DatagramFilter, Direction, Flow, FlowFilter, StreamFilter, Verdict};
+use log::{debug, info, warn};
+use mlua::{Function, HookTriggers, IntoLuaMulti, Lua, LuaOptions, RegistryKey, StdLib, Table, Value};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Lua scripts (a rule's lua_script), a lighter way to filter than
+// wasm_filter. Each rule runs its script in a Lua state of its own, without
+// the io, os and package libraries, and calls the hooks it defines:
+//
+//   on_connect(conn)                 false rejects the client; a "host:port" string picks a TCP rule's target
+//   on_data(conn, direction, data)   false rejects the data; a string is passed on instead
+//   on_close(conn)
+//
+// conn is a table per connection or UDP session, with client, local and
+// protocol set, that the script may keep its own fields in. direction is
+// "upload" or "download". A global log(message) logs at info level.
+
+// Hooks count instructions in ticks of this many
+const INSTRUCTIONS_PER_TICK: u32 = 1000;
+// For each call, so a hook stuck in a loop fails quickly
+const TICKS_PER_CALL: u32 = 10_000;
+const MAX_MEMORY: usize = 16 << 20;
+
+struct Script {
+    rule_name: String,
+    path: String,
+    // Hooks of all the rule's connections take turns
+    lua: Mutex<Lua>,
+    ticks_left: Arc<AtomicU32>,
+    has_on_data: bool,
+}
+
+impl Script {
+    // Calls the hook if the script defines it
+    fn call<'lua>(&self, lua: &'lua Lua, hook: &str, args: impl IntoLuaMulti<'lua>) -> mlua::Result<Option<Value<'lua>>> {
+        let Some(function) = lua.globals().get::<_, Option<Function>>(hook)? else {
+            return Ok(None);
+        };
+        self.ticks_left.store(TICKS_PER_CALL, Ordering::Relaxed);
+        function.call(args).map(Some)
+    }
+}
+
+pub struct LuaFilter(Arc<Script>);
+
+impl LuaFilter {
+    pub fn load(path: &str, rule_name: String) -> anyhow::Result<Self> {
+        let source = std::fs::read(path).map_err(|e| anyhow::anyhow!("failed to read lua_script {}: {}", path, e))?;
+        let ticks_left = Arc::new(AtomicU32::new(TICKS_PER_CALL));
+        let lua = new_state(&rule_name, ticks_left.clone())
+            .and_then(|lua| lua.load(&source).set_name(path).exec().map(|()| lua))
+            .map_err(|e| anyhow::anyhow!("lua_script {}: {}", path, e))?;
+        let has_on_data = lua.globals().contains_key("on_data").unwrap_or(false);
+        Ok(Self(Arc::new(Script {
+            rule_name,
+            path: path.to_string(),
+            lua: Mutex::new(lua),
+            ticks_left,
+            has_on_data,
+        })))
+    }
+
+    fn admit(&self, flow: &Flow, protocol: &str) -> Admission {
+        match self.connect(flow, protocol) {
+            Ok(Some(flow)) => Admission::Watch(Box::new(flow)),
+            Ok(None) => {
+                debug!("lua_script of rule '{}' rejected {}", self.0.rule_name, flow.client_addr);
+                Admission::Reject
+            }
+            Err(e) => {
+                warn!("lua_script {} of rule '{}' failed, rejecting {}: {}", self.0.path, self.0.rule_name, flow.client_addr, e);
+                Admission::Reject
+            }
+        }
+    }
+
+    // None if on_connect rejected the client
+    fn connect(&self, flow: &Flow, protocol: &str) -> mlua::Result<Option<LuaFlow>> {
+        let lua = self.0.lua.lock().unwrap();
+        let conn = lua.create_table()?;
+        conn.set("client", flow.client_addr.to_string())?;
+        conn.set("local", flow.local_addr.to_string())?;
+        conn.set("protocol", protocol)?;
+        let target = match self.0.call(&lua, "on_connect", conn.clone())? {
+            Some(Value::Boolean(false)) => return Ok(None),
+            Some(Value::String(target)) if protocol == "tcp" => {
+                let target = target.to_str()?.to_string();
+                parse_endpoint(&target).map_err(|e| mlua::Error::runtime(format!("on_connect returned {}", e)))?;
+                Some(target)
+            }
+            _ => None,
+        };
+        Ok(Some(LuaFlow {
+            script: self.0.clone(),
+            client_addr: flow.client_addr,
+            conn: lua.create_registry_value(conn)?,
+            target,
+        }))
+    }
+}
+
+impl StreamFilter for LuaFilter {
+    fn on_connect(&self, flow: &Flow) -> Admission {
+        self.admit(flow, "tcp")
+    }
+}
+
+impl DatagramFilter for LuaFilter {
+    fn on_session(&self, flow: &Flow) -> Admission {
+        self.admit(flow, "udp")
+    }
+}
+
+// One connection or session, whose on_close runs when it is dropped
+struct LuaFlow {
+    script: Arc<Script>,
+    client_addr: std::net::SocketAddr,
+    conn: RegistryKey,
+    target: Option<String>,
+}
+
+impl LuaFlow {
+    fn filter(&self, direction: Direction, data: &mut Vec<u8>) -> mlua::Result<bool> {
+        let lua = self.script.lua.lock().unwrap();
+        let conn: Table = lua.registry_value(&self.conn)?;
+        let direction = match direction {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+        };
+        match self.script.call(&lua, "on_data", (conn, direction, lua.create_string(&data[..])?))? {
+            Some(Value::Boolean(false)) => Ok(false),
+            Some(Value::String(replacement)) => {
+                *data = replacement.as_bytes().to_vec();
+                Ok(true)
+            }
+            _ => Ok(true),
+        }
+    }
+}
+
+impl FlowFilter for LuaFlow {
+    fn on_data(&mut self, direction: Direction, data: &mut Vec<u8>) -> Verdict {
+        if !self.script.has_on_data {
+            return Verdict::Pass;
+        }
+        match self.filter(direction, data) {
+            Ok(true) => Verdict::Pass,
+            Ok(false) => Verdict::Reject,
+            Err(e) => {
+                warn!("lua_script of rule '{}' failed on data of {}: {}", self.script.rule_name, self.client_addr, e);
+                Verdict::Reject
+            }
+        }
+    }
+
+    fn target(&self) -> Option<String> {
+        self.target.clone()
+    }
+}
+
+impl Drop for LuaFlow {
+    fn drop(&mut self) {
+        let lua = self.script.lua.lock().unwrap();
+        let closed = lua.registry_value::<Table>(&self.conn)
+            .and_then(|conn| self.script.call(&lua, "on_close", conn));
+        if let Err(e) = closed {
+            warn!("lua_script of rule '{}' failed closing {}: {}", self.script.rule_name, self.client_addr, e);
+        }
+    }
+}
+
+fn new_state(rule_name: &str, ticks_left: Arc<AtomicU32>) -> mlua::Result<Lua> {
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8, LuaOptions::new())?;
+    lua.set_memory_limit(MAX_MEMORY)?;
+    lua.set_hook(HookTriggers::new().every_nth_instruction(INSTRUCTIONS_PER_TICK), move |_, _| {
+        match ticks_left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |ticks| ticks.checked_sub(1)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(mlua::Error::runtime("instruction limit exceeded")),
+        }
+    });
+    let rule_name = rule_name.to_string();
+    let log = lua.create_function(move |_, message: String| {
+        info!("lua_script of rule '{}': {}", rule_name, message);
+        Ok(())
+    })?;
+    lua.globals().set("log", log)?;
+    Ok(lua)
+}
@@ -0,0 +1,169 @@
+use crate::config::UdpRule;
+use crate::sockopt;
+use crate::supervisor::Bound;
+use anyhow::Result;
+use log::{debug, info, warn};
+use socket2::{Domain, InterfaceIndexOrAddress, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+// mDNS reflector (mode = "mdns_reflector"). Joins 224.0.0.251:5353 on each
+// of the rule's interfaces and repeats every packet heard on one interface
+// onto all the others, so service discovery works across networks that
+// multicast doesn't cross. IPv4 only.
+//
+// Loops are cut three ways: our own sends aren't looped back
+// (IP_MULTICAST_LOOP off), packets from this host's addresses are dropped,
+// and a payload reflected in the last second is not reflected again, which
+// stops two reflectors on the same pair of networks bouncing packets
+// between each other.
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+// Large enough for jumbo frames; mDNS messages can exceed the usual 1500
+const MAX_PACKET: usize = 9000;
+// RFC 6762 section 11: link-local traffic goes out with TTL 255
+const MDNS_TTL: u32 = 255;
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(1);
+
+struct Interface {
+    name: String,
+    socket: UdpSocket,
+}
+
+struct Reflector {
+    rule_name: String,
+    interfaces: Vec<Interface>,
+    // This host's addresses, to recognise packets we (or a local responder
+    // such as avahi) sent
+    local_addrs: Vec<IpAddr>,
+    // Payload hash -> when it was last reflected
+    recent: Mutex<HashMap<u64, Instant>>,
+}
+
+pub async fn run(rule: &UdpRule, bound: &mut Bound) -> Result<()> {
+    let names = rule.interfaces.as_deref().unwrap_or_default();
+    let interfaces = names
+        .iter()
+        .map(|name| {
+            let socket = join(name).map_err(|e| anyhow::anyhow!("failed to join mDNS group on {}: {}", name, e))?;
+            Ok(Interface { name: name.clone(), socket })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let reflector = Arc::new(Reflector {
+        rule_name: rule.rule_name(),
+        interfaces,
+        local_addrs: local_addresses()?,
+        recent: Mutex::new(HashMap::new()),
+    });
+
+    bound.signal(reflector.interfaces.iter().filter_map(|interface| interface.socket.local_addr().ok()).collect());
+    info!("UDP forwarder '{}' reflecting mDNS between {}", reflector.rule_name, names.join(", "));
+    futures::future::try_join_all((0..reflector.interfaces.len()).map(|index| reflector.clone().receive(index))).await?;
+    Ok(())
+}
+
+// A socket that only sees (and sends on) `name`. Local responders also bind
+// 5353, so the port is shared.
+fn join(name: &str) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    sockopt::bind_device(&socket, name)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4_n(&MDNS_GROUP, &InterfaceIndexOrAddress::Index(interface_index(name)?))?;
+    socket.set_multicast_loop_v4(false)?;
+    socket.set_multicast_ttl_v4(MDNS_TTL)?;
+    UdpSocket::from_std(socket.into())
+}
+
+impl Reflector {
+    async fn receive(self: Arc<Self>, index: usize) -> Result<()> {
+        let from_interface = &self.interfaces[index];
+        let mut buffer = vec![0u8; MAX_PACKET];
+        loop {
+            let (len, from) = match from_interface.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("UDP forwarder '{}': mDNS receive on {} failed: {}", self.rule_name, from_interface.name, e);
+                    continue;
+                }
+            };
+            if self.local_addrs.contains(&from.ip()) {
+                continue;
+            }
+            let packet = &buffer[..len];
+            if !self.first_sighting(packet) {
+                debug!("UDP forwarder '{}': dropping repeated mDNS packet from {}", self.rule_name, from);
+                continue;
+            }
+            debug!("UDP forwarder '{}': reflecting {} bytes from {} on {}", self.rule_name, len, from, from_interface.name);
+            for (other, interface) in self.interfaces.iter().enumerate() {
+                if other == index {
+                    continue;
+                }
+                if let Err(e) = interface.socket.send_to(packet, (MDNS_GROUP, MDNS_PORT)).await {
+                    warn!("UDP forwarder '{}': mDNS send on {} failed: {}", self.rule_name, interface.name, e);
+                }
+            }
+        }
+    }
+
+    // Whether `packet` hasn't been reflected within DUPLICATE_WINDOW, and
+    // records it if so
+    fn first_sighting(&self, packet: &[u8]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        packet.hash(&mut hasher);
+        let key = hasher.finish();
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, seen| now.duration_since(*seen) < DUPLICATE_WINDOW);
+        recent.insert(key, now).is_none()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn interface_index(name: &str) -> io::Result<u32> {
+    let name = std::ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_index(_name: &str) -> io::Result<u32> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "mdns_reflector requires Linux"))
+}
+
+// Every address configured on this host, read once at startup
+#[cfg(target_os = "linux")]
+fn local_addresses() -> Result<Vec<IpAddr>> {
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        anyhow::bail!("failed to list local addresses: {}", io::Error::last_os_error());
+    }
+    let mut addrs = Vec::new();
+    let mut entry = list;
+    while !entry.is_null() {
+        let ifaddr = unsafe { &*entry };
+        if !ifaddr.ifa_addr.is_null()
+            && let Some(addr) = unsafe { crate::transparent::read_sockaddr(ifaddr.ifa_addr.cast()) }
+        {
+            addrs.push(addr.ip());
+        }
+        entry = ifaddr.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(list) };
+    Ok(addrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn local_addresses() -> Result<Vec<IpAddr>> {
+    anyhow::bail!("mdns_reflector requires Linux")
+}
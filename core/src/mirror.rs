@@ -0,0 +1,106 @@
+use crate::config::parse_endpoint;
+use crate::connector::connect_happy_eyeballs;
+use crate::resolver;
+use crate::rule_log;
+use log::debug;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::timeout;
+
+// Copies of what clients send, for a rule's mirror_target. Each connection
+// or UDP session opens its own connection or socket to the mirror and never
+// waits for it: what the mirror can't take is dropped, and its replies are
+// ignored. A TCP mirror that falls behind is closed rather than sent a
+// stream with holes in it.
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Chunks or datagrams waiting while the mirror connects or catches up
+const QUEUE: usize = 256;
+
+#[derive(Clone)]
+pub struct Mirror {
+    // None once a stream mirror fell behind
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+    stream: bool,
+    client_addr: SocketAddr,
+}
+
+impl Mirror {
+    // For a TCP connection
+    pub fn stream(endpoint: &str, client_addr: SocketAddr) -> Self {
+        Self::start(endpoint, client_addr, true)
+    }
+
+    // For a UDP session; every datagram goes as one
+    pub fn datagram(endpoint: &str, client_addr: SocketAddr) -> Self {
+        Self::start(endpoint, client_addr, false)
+    }
+
+    fn start(endpoint: &str, client_addr: SocketAddr, stream: bool) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        let endpoint = endpoint.to_string();
+        tokio::spawn(rule_log::inherit(async move {
+            let copied = match stream {
+                true => copy_stream(&endpoint, rx).await,
+                false => copy_datagrams(&endpoint, rx).await,
+            };
+            if let Err(e) = copied {
+                debug!("Mirror {} for {} failed: {}", endpoint, client_addr, e);
+            }
+        }));
+        Self { tx: Some(tx), stream, client_addr }
+    }
+
+    pub fn send(&mut self, data: &[u8]) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if data.is_empty() {
+            return;
+        }
+        match tx.try_send(data.to_vec()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) if self.stream => {
+                debug!("Mirror for {} fell behind, closing it", self.client_addr);
+                self.tx = None;
+            }
+            Err(TrySendError::Full(_)) => {}
+            // The mirror failed, which was logged
+            Err(TrySendError::Closed(_)) => self.tx = None,
+        }
+    }
+}
+
+// Until the connection closes and everything queued has been sent
+async fn copy_stream(endpoint: &str, mut rx: mpsc::Receiver<Vec<u8>>) -> anyhow::Result<()> {
+    let (host, port) = parse_endpoint(endpoint)?;
+    let mut stream = timeout(CONNECT_TIMEOUT, connect_happy_eyeballs(&host, port))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out connecting"))??;
+    while let Some(data) = rx.recv().await {
+        stream.write_all(&data).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn copy_datagrams(endpoint: &str, mut rx: mpsc::Receiver<Vec<u8>>) -> anyhow::Result<()> {
+    let (host, port) = parse_endpoint(endpoint)?;
+    let addr = *resolver::lookup(&host, port).await?
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("no addresses found for {}", host))?;
+    let bind: SocketAddr = match addr.is_ipv6() {
+        true => ([0u16; 8], 0).into(),
+        false => ([0u8; 4], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+    while let Some(data) = rx.recv().await {
+        // An unreachable mirror only loses this datagram
+        let _ = socket.send(&data).await;
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(unix)]
+use log::info;
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+#[cfg(unix)]
+use std::io;
+
+// Dropping root ([global] user and group). Started as root, porture binds
+// every rule's listeners first and then switches to the configured account
+// for good, before it forwards any traffic. The account is looked up before
+// anything is bound, so a typo fails the start instead of leaving porture
+// running as root.
+
+// Large enough for any sane passwd or group entry; grown on ERANGE
+#[cfg(unix)]
+const LOOKUP_BUFFER: usize = 4096;
+
+#[cfg(unix)]
+pub struct Account {
+    user: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    // Whether the user has a passwd entry to take supplementary groups from
+    known_user: bool,
+}
+
+// Windows has no such accounts
+#[cfg(not(unix))]
+pub enum Account {}
+
+pub fn validate(user: &str, group: Option<&str>) -> Result<()> {
+    if user.is_empty() {
+        anyhow::bail!("[global] user must not be empty");
+    }
+    if group.is_some_and(str::is_empty) {
+        anyhow::bail!("[global] group must not be empty");
+    }
+    if !cfg!(unix) {
+        anyhow::bail!("[global] user requires a Unix platform");
+    }
+    Ok(())
+}
+
+// The account to switch to, or None if porture already runs as it. Names
+// may also be numeric ids.
+#[cfg(unix)]
+pub fn resolve(user: &str, group: Option<&str>) -> Result<Option<Account>> {
+    let (uid, user_gid, known_user) = match lookup_user(user)? {
+        Some((uid, gid)) => (uid, Some(gid), true),
+        None => match user.parse() {
+            Ok(uid) => (uid, None, false),
+            Err(_) => anyhow::bail!("[global] user '{}' does not exist", user),
+        },
+    };
+    let gid = match group {
+        Some(group) => match lookup_group(group)? {
+            Some(gid) => gid,
+            None => group.parse().map_err(|_| anyhow::anyhow!("[global] group '{}' does not exist", group))?,
+        },
+        None => user_gid
+            .ok_or_else(|| anyhow::anyhow!("[global] user '{}' has no passwd entry, so [global] group is required", user))?,
+    };
+    let account = Account { user: user.to_string(), uid, gid, known_user };
+
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if euid != 0 {
+        if euid == uid && egid == gid {
+            return Ok(None);
+        }
+        anyhow::bail!("[global] user '{}' requires starting porture as root", user);
+    }
+    Ok(Some(account))
+}
+
+#[cfg(not(unix))]
+pub fn resolve(user: &str, _group: Option<&str>) -> Result<Option<Account>> {
+    anyhow::bail!("[global] user '{}' requires a Unix platform", user)
+}
+
+#[cfg(unix)]
+impl Account {
+    // Switches every thread of the process to the account, irrevocably
+    pub fn switch(&self) -> Result<()> {
+        // Groups first: once the uid changes, they can't be changed any more
+        if self.known_user {
+            let name = CString::new(self.user.as_str())?;
+            check(unsafe { libc::initgroups(name.as_ptr(), self.gid as _) })
+                .context("failed to set supplementary groups")?;
+        } else {
+            check(unsafe { libc::setgroups(1, &self.gid) }).context("failed to set supplementary groups")?;
+        }
+        check(unsafe { libc::setgid(self.gid) }).with_context(|| format!("failed to switch to gid {}", self.gid))?;
+        check(unsafe { libc::setuid(self.uid) }).with_context(|| format!("failed to switch to user {}", self.user))?;
+        if self.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            anyhow::bail!("root privileges could be regained after switching to user {}", self.user);
+        }
+        info!("Dropped privileges to user {} (uid {}, gid {})", self.user, self.uid, self.gid);
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+impl Account {
+    pub fn switch(&self) -> Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(unix)]
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// uid and primary gid
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Result<Option<(libc::uid_t, libc::gid_t)>> {
+    let name = CString::new(name)?;
+    let mut buffer = vec![0; LOOKUP_BUFFER];
+    loop {
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let result = unsafe {
+            libc::getpwnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found)
+        };
+        match result {
+            0 if found.is_null() => return Ok(None),
+            0 => return Ok(Some((entry.pw_uid, entry.pw_gid))),
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            e => return Err(io::Error::from_raw_os_error(e)).with_context(|| lookup_failed("user", &name)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Result<Option<libc::gid_t>> {
+    let name = CString::new(name)?;
+    let mut buffer = vec![0; LOOKUP_BUFFER];
+    loop {
+        let mut entry: libc::group = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let result = unsafe {
+            libc::getgrnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found)
+        };
+        match result {
+            0 if found.is_null() => return Ok(None),
+            0 => return Ok(Some(entry.gr_gid)),
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            e => return Err(io::Error::from_raw_os_error(e)).with_context(|| lookup_failed("group", &name)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn lookup_failed(kind: &str, name: &CStr) -> String {
+    format!("failed to look up {} '{}'", kind, name.to_string_lossy())
+}
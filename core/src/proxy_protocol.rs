@@ -0,0 +1,407 @@
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// Longest possible v1 header, including the trailing CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+impl ProxyProtocolVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProxyProtocolVersion::V1 => "v1",
+            ProxyProtocolVersion::V2 => "v2",
+        }
+    }
+}
+
+// Build the PROXY header announcing `source` (the client) connecting to
+// `destination` (the address porture accepted it on)
+pub fn encode_header(version: ProxyProtocolVersion, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source, destination) = same_family(source, destination);
+    match version {
+        ProxyProtocolVersion::V1 => encode_v1(source, destination),
+        ProxyProtocolVersion::V2 => encode_v2(source, destination),
+    }
+}
+
+fn encode_v1(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    // Version 2, PROXY command
+    header.push(0x21);
+
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            // TCP over IPv4
+            header.push(0x11);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            // TCP over IPv6
+            header.push(0x21);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_v6(src).octets());
+            header.extend_from_slice(&to_v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+// Both addresses in a header must share a family; unmap IPv4-mapped IPv6
+// addresses where possible and otherwise fall back to IPv6 for both
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+    let destination = SocketAddr::new(destination.ip().to_canonical(), destination.port());
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (
+            SocketAddr::new(IpAddr::V6(to_v6(source.ip())), source.port()),
+            SocketAddr::new(IpAddr::V6(to_v6(destination.ip())), destination.port()),
+        )
+    }
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+// Read and strip a PROXY v1 or v2 header from the start of `stream`, reading
+// no further than the header itself. Returns the original client address, or
+// None for LOCAL/UNKNOWN headers where the peer address should be used.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if &prefix == b"PROXY " {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                anyhow::bail!("PROXY v1 header too long");
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line[..line.len() - 2]);
+    }
+
+    if prefix != V2_SIGNATURE[..6] {
+        anyhow::bail!("connection did not start with a PROXY protocol header");
+    }
+
+    let mut rest = [0u8; 10];
+    stream.read_exact(&mut rest).await?;
+    if rest[..6] != V2_SIGNATURE[6..] {
+        anyhow::bail!("invalid PROXY v2 signature");
+    }
+    let version_command = rest[6];
+    let family = rest[7];
+    let length = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        anyhow::bail!("unsupported PROXY protocol version");
+    }
+    match version_command & 0x0F {
+        // LOCAL: health checks from the proxy itself
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => anyhow::bail!("unsupported PROXY v2 command"),
+    }
+
+    match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        2 if payload.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        // AF_UNSPEC, AF_UNIX or truncated address block
+        _ => Ok(None),
+    }
+}
+
+fn parse_v1(line: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            // Both addresses must be of the family the header names
+            let parse = |address: &str| -> anyhow::Result<IpAddr> {
+                let ip = match *family {
+                    "TCP4" => address.parse::<Ipv4Addr>().map(IpAddr::V4),
+                    _ => address.parse::<Ipv6Addr>().map(IpAddr::V6),
+                };
+                ip.map_err(|_| anyhow::anyhow!("PROXY v1 {} header with address '{}'", family, address))
+            };
+            let ip = parse(source)?;
+            parse(destination)?;
+            let port: u16 = source_port.parse()?;
+            destination_port.parse::<u16>()?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => anyhow::bail!("malformed PROXY v1 header"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn v2(family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn encode_v1_ipv4() {
+        let header = encode_header(ProxyProtocolVersion::V1, addr("192.0.2.1:56324"), addr("198.51.100.2:443"));
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\n");
+    }
+
+    #[test]
+    fn encode_v1_ipv6() {
+        let header = encode_header(ProxyProtocolVersion::V1, addr("[2001:db8::1]:56324"), addr("[2001:db8::2]:443"));
+        assert_eq!(header, b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n");
+    }
+
+    #[test]
+    fn encode_v1_mixed_families() {
+        // A dual-stack listener sees IPv4 clients as mapped addresses
+        let header = encode_header(ProxyProtocolVersion::V1, addr("[::ffff:192.0.2.1]:56324"), addr("198.51.100.2:443"));
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\n");
+
+        let header = encode_header(ProxyProtocolVersion::V1, addr("192.0.2.1:56324"), addr("[2001:db8::2]:443"));
+        assert_eq!(header, b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 443\r\n");
+    }
+
+    #[test]
+    fn encode_v2_ipv4() {
+        let header = encode_header(ProxyProtocolVersion::V2, addr("192.0.2.1:56324"), addr("198.51.100.2:443"));
+        let expected = v2(0x11, &[192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn encode_v2_ipv6() {
+        let header = encode_header(ProxyProtocolVersion::V2, addr("[2001:db8::1]:56324"), addr("[2001:db8::2]:443"));
+        let mut addresses = Vec::new();
+        addresses.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        addresses.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        addresses.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(header, v2(0x21, &addresses));
+    }
+
+    #[test]
+    fn encode_v2_mixed_families() {
+        let header = encode_header(ProxyProtocolVersion::V2, addr("[::ffff:192.0.2.1]:56324"), addr("198.51.100.2:443"));
+        let expected = v2(0x11, &[192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(header, expected);
+
+        let header = encode_header(ProxyProtocolVersion::V2, addr("192.0.2.1:56324"), addr("[2001:db8::2]:443"));
+        let mut addresses = Vec::new();
+        addresses.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 0, 2, 1]);
+        addresses.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        addresses.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(header, v2(0x21, &addresses));
+    }
+
+    // The result, and what's left in the stream after it
+    async fn read(stream: &[u8]) -> (anyhow::Result<Option<SocketAddr>>, &[u8]) {
+        let mut reader = stream;
+        let result = read_header(&mut reader).await;
+        (result, reader)
+    }
+
+    #[tokio::test]
+    async fn read_v1() {
+        let (result, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(result.unwrap(), Some(addr("192.0.2.1:56324")));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (result, rest) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n\x16\x03").await;
+        assert_eq!(result.unwrap(), Some(addr("[2001:db8::1]:56324")));
+        assert_eq!(rest, b"\x16\x03");
+    }
+
+    #[tokio::test]
+    async fn read_v1_unknown() {
+        let (result, rest) = read(b"PROXY UNKNOWN\r\ndata").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"data");
+
+        let (result, rest) = read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\ndata").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"data");
+    }
+
+    #[tokio::test]
+    async fn read_v1_malformed() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.2 56324\r\n"[..],
+            b"PROXY TCP4 not-an-ip 198.51.100.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 65536\r\n",
+            b"PROXY TCP4 192.0.2.1 not-an-ip 56324 443\r\n",
+        ] {
+            assert!(read(header).await.0.is_err(), "{:?}", String::from_utf8_lossy(header));
+        }
+    }
+
+    #[tokio::test]
+    async fn read_v1_family_mismatch() {
+        for header in [
+            &b"PROXY TCP4 2001:db8::1 198.51.100.2 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 2001:db8::2 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 2001:db8::2 56324 443\r\n",
+            b"PROXY TCP6 2001:db8::1 198.51.100.2 56324 443\r\n",
+        ] {
+            assert!(read(header).await.0.is_err(), "{:?}", String::from_utf8_lossy(header));
+        }
+        // Mapped IPv4 addresses are IPv6 ones
+        let (result, _) = read(b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(result.unwrap(), Some(addr("[::ffff:192.0.2.1]:56324")));
+    }
+
+    #[tokio::test]
+    async fn read_v1_over_long() {
+        let mut stream = b"PROXY TCP4 ".to_vec();
+        stream.resize(200, b'1');
+        stream.extend_from_slice(b"\r\n");
+        let (result, rest) = read(&stream).await;
+        assert!(result.unwrap_err().to_string().contains("too long"));
+        // Gives up at the longest valid header
+        assert_eq!(rest.len(), stream.len() - V1_MAX_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn read_v2() {
+        let mut stream = encode_header(ProxyProtocolVersion::V2, addr("192.0.2.1:56324"), addr("198.51.100.2:443"));
+        stream.extend_from_slice(b"data");
+        let (result, rest) = read(&stream).await;
+        assert_eq!(result.unwrap(), Some(addr("192.0.2.1:56324")));
+        assert_eq!(rest, b"data");
+
+        let mut stream = encode_header(ProxyProtocolVersion::V2, addr("[2001:db8::1]:56324"), addr("[2001:db8::2]:443"));
+        stream.extend_from_slice(b"data");
+        let (result, rest) = read(&stream).await;
+        assert_eq!(result.unwrap(), Some(addr("[2001:db8::1]:56324")));
+        assert_eq!(rest, b"data");
+    }
+
+    #[tokio::test]
+    async fn read_v2_with_tlvs() {
+        // A PP2_TYPE_AUTHORITY TLV after the addresses is skipped
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb];
+        addresses.extend_from_slice(&[0x02, 0x00, 0x0b]);
+        addresses.extend_from_slice(b"example.com");
+        let mut stream = v2(0x11, &addresses);
+        stream.extend_from_slice(b"data");
+        let (result, rest) = read(&stream).await;
+        assert_eq!(result.unwrap(), Some(addr("192.0.2.1:56324")));
+        assert_eq!(rest, b"data");
+    }
+
+    #[tokio::test]
+    async fn read_v2_local() {
+        let mut stream = V2_SIGNATURE.to_vec();
+        stream.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        stream.extend_from_slice(b"data");
+        let (result, rest) = read(&stream).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"data");
+    }
+
+    #[tokio::test]
+    async fn read_v2_unsupported_command() {
+        let mut stream = encode_header(ProxyProtocolVersion::V2, addr("192.0.2.1:56324"), addr("198.51.100.2:443"));
+        stream[12] = 0x22;
+        assert!(read(&stream).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_v2_unspecified_family() {
+        let mut stream = v2(0x00, &[]);
+        stream.extend_from_slice(b"data");
+        let (result, rest) = read(&stream).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"data");
+    }
+
+    #[tokio::test]
+    async fn read_v2_truncated_addresses() {
+        // Address blocks shorter than their family needs are unknown
+        // addresses, and the block is still consumed
+        for (family, length) in [(0x11, 8), (0x21, 12)] {
+            let mut stream = v2(family, &vec![1; length]);
+            stream.extend_from_slice(b"data");
+            let (result, rest) = read(&stream).await;
+            assert_eq!(result.unwrap(), None);
+            assert_eq!(rest, b"data");
+        }
+
+        // The stream ending inside the block
+        let stream = v2(0x11, &[192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb]);
+        assert!(read(&stream[..stream.len() - 1]).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_bad_signature() {
+        let mut stream = V2_SIGNATURE.to_vec();
+        stream[10] = b'X';
+        stream.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        assert!(read(&stream).await.0.unwrap_err().to_string().contains("signature"));
+
+        let (result, _) = read(b"GET / HTTP/1.1\r\n").await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_v2_unsupported_version() {
+        let mut stream = V2_SIGNATURE.to_vec();
+        stream.extend_from_slice(&[0x31, 0x11, 0x00, 0x00]);
+        assert!(read(&stream).await.0.is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::filter::{Admission, Direction, Flow, FlowFilter, StreamFilter, Verdict};
+use crate::rule_log;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+// Recordings of whole TCP connections (a rule's record_dir), for `porture
+// replay` to play back. Each connection is written to a file of its own as
+// JSON lines: a session line, then the data in both directions as it was
+// forwarded, with milliseconds since the connection started, and an end
+// line. A recording the disk can't keep up with is cut short rather than
+// left with gaps, and its end line says so.
+
+// Chunks waiting to be written, per connection
+const QUEUE: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Record {
+    Session { rule: String, client: String, local: String, started_unix_ms: u64 },
+    Upload { ms: u64, data: String },
+    Download { ms: u64, data: String },
+    End { ms: u64, truncated: bool },
+}
+
+impl Record {
+    // The data of an upload or download
+    pub fn data(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Record::Upload { data, .. } | Record::Download { data, .. } => Ok(STANDARD.decode(data)?),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+pub struct Recorder {
+    rule_name: String,
+    dir: PathBuf,
+}
+
+impl Recorder {
+    pub fn new(rule_name: String, dir: &str) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|e| anyhow::anyhow!("failed to create record_dir {}: {}", dir, e))?;
+        Ok(Self { rule_name, dir: PathBuf::from(dir) })
+    }
+}
+
+impl StreamFilter for Recorder {
+    fn on_connect(&self, flow: &Flow) -> Admission {
+        let started_unix_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let name = format!("{}-{}-{}.jsonl", self.rule_name, started_unix_ms, flow.client_addr);
+        let name: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+            .collect();
+        let path = self.dir.join(name);
+        let (tx, rx) = mpsc::channel(QUEUE);
+        let truncated = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+        let session = Record::Session {
+            rule: self.rule_name.clone(),
+            client: flow.client_addr.to_string(),
+            local: flow.local_addr.to_string(),
+            started_unix_ms,
+        };
+        let writer_truncated = truncated.clone();
+        tokio::spawn(rule_log::inherit(async move {
+            if let Err(e) = write(&path, session, rx, started, &writer_truncated).await {
+                warn!("Failed to record session to {}: {}", path.display(), e);
+            }
+        }));
+        Admission::Watch(Box::new(Recording { tx: Some(tx), started, truncated }))
+    }
+}
+
+struct Recording {
+    // None once the recording was cut short
+    tx: Option<mpsc::Sender<Record>>,
+    started: Instant,
+    truncated: Arc<AtomicBool>,
+}
+
+impl FlowFilter for Recording {
+    fn on_data(&mut self, direction: Direction, data: &mut Vec<u8>) -> Verdict {
+        let Some(tx) = &self.tx else {
+            return Verdict::Pass;
+        };
+        let ms = self.started.elapsed().as_millis() as u64;
+        let data = STANDARD.encode(&data[..]);
+        let record = match direction {
+            Direction::Upload => Record::Upload { ms, data },
+            Direction::Download => Record::Download { ms, data },
+        };
+        match tx.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.truncated.store(true, Ordering::Relaxed);
+                self.tx = None;
+            }
+            Err(TrySendError::Closed(_)) => self.tx = None,
+        }
+        Verdict::Pass
+    }
+}
+
+// Until the connection closes, or the recording is cut short
+async fn write(
+    path: &Path,
+    session: Record,
+    mut rx: mpsc::Receiver<Record>,
+    started: Instant,
+    truncated: &AtomicBool,
+) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(tokio::fs::File::create(path).await?);
+    file.write_all(&json_line(&session)?).await?;
+    while let Some(record) = rx.recv().await {
+        file.write_all(&json_line(&record)?).await?;
+    }
+    let end = Record::End { ms: started.elapsed().as_millis() as u64, truncated: truncated.load(Ordering::Relaxed) };
+    file.write_all(&json_line(&end)?).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+fn json_line(record: &Record) -> anyhow::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    Ok(line)
+}
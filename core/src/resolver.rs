@@ -0,0 +1,91 @@
+use crate::config::DnsConfig;
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::TokioResolver;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+
+// Name resolution for hostname targets. One caching resolver serves every
+// rule, so repeated connections to a backend reuse its answer until the
+// record's TTL runs out instead of calling getaddrinfo each time. Lookups
+// made before `init`, or when the system resolver configuration can't be
+// read, go through getaddrinfo as before.
+
+static RESOLVER: OnceLock<TokioResolver> = OnceLock::new();
+
+// One SRV answer; `host` has no trailing dot
+#[derive(Debug, Clone)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub host: String,
+    pub port: u16,
+}
+
+pub fn init(config: Option<&DnsConfig>) -> anyhow::Result<()> {
+    let mut builder = match config.filter(|c| c.servers.is_some()) {
+        Some(config) => {
+            let mut servers = NameServerConfigGroup::new();
+            for addr in config.server_addrs()? {
+                servers.merge(NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true));
+            }
+            TokioResolver::builder_with_config(
+                ResolverConfig::from_parts(None, Vec::new(), servers),
+                TokioConnectionProvider::default(),
+            )
+        }
+        None => TokioResolver::builder_tokio()
+            .map_err(|e| anyhow::anyhow!("failed to read the system resolver configuration: {}", e))?,
+    };
+    let options = builder.options_mut();
+    // Both families, for Happy Eyeballs
+    options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    if let Some(config) = config {
+        if let Some(cache_size) = config.cache_size {
+            options.cache_size = cache_size;
+        }
+        options.positive_min_ttl = config.min_ttl.map(Duration::from_secs);
+        options.positive_max_ttl = config.max_ttl.map(Duration::from_secs);
+    }
+    RESOLVER.set(builder.build()).map_err(|_| anyhow::anyhow!("resolver already initialised"))
+}
+
+// Addresses for `host`, which may also be an IP literal
+pub async fn lookup(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    match RESOLVER.get() {
+        Some(resolver) => {
+            let ips = resolver.lookup_ip(host).await.map_err(|e| {
+                io::Error::new(io::ErrorKind::NotFound, format!("failed to resolve {}: {}", host, e))
+            })?;
+            Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+        }
+        None => Ok(lookup_host((host, port)).await?.collect()),
+    }
+}
+
+// The records for an SRV name such as "_minecraft._tcp.example.com", and
+// when the answer expires. A lone "." target (service not offered) yields
+// no records.
+pub async fn lookup_srv(name: &str) -> io::Result<(Vec<SrvRecord>, Instant)> {
+    let resolver = RESOLVER.get()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "SRV lookups need the built-in resolver"))?;
+    let lookup = resolver.srv_lookup(name).await.map_err(|e| {
+        io::Error::new(io::ErrorKind::NotFound, format!("failed to resolve SRV {}: {}", name, e))
+    })?;
+    let records = lookup.iter()
+        .filter(|srv| !srv.target().is_root())
+        .map(|srv| SrvRecord {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            host: srv.target().to_ascii().trim_end_matches('.').to_string(),
+            port: srv.port(),
+        })
+        .collect();
+    Ok((records, lookup.as_lookup().valid_until()))
+}
@@ -0,0 +1,90 @@
+use crate::config::{DataDirection, RewriteConfig};
+use crate::filter::{Admission, DatagramFilter, Direction, Flow, FlowFilter, StreamFilter, Verdict};
+use std::sync::Arc;
+
+// Find/replace on forwarded data (a rule's rewrite list). Replacements run in
+// the order configured, each on the output of the one before. A TCP rule
+// sees its data in chunks as read, so a pattern that a read splits in two is
+// left as it is; datagrams are always matched whole.
+
+struct Replacement {
+    find: Vec<u8>,
+    replace: Vec<u8>,
+    direction: DataDirection,
+}
+
+impl Replacement {
+    fn applies(&self, direction: Direction) -> bool {
+        match direction {
+            Direction::Upload => self.direction.upload(),
+            Direction::Download => self.direction.download(),
+        }
+    }
+}
+
+pub struct RewriteFilter(Arc<[Replacement]>);
+
+impl RewriteFilter {
+    pub fn new(rewrites: &[RewriteConfig]) -> anyhow::Result<Self> {
+        let replacements = rewrites.iter()
+            .map(|rewrite| Ok(Replacement {
+                find: rewrite.find_bytes()?,
+                replace: rewrite.replace_bytes()?,
+                direction: rewrite.direction(),
+            }))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self(replacements.into()))
+    }
+
+    fn admit(&self) -> Admission {
+        Admission::Watch(Box::new(RewriteFlow(self.0.clone())))
+    }
+}
+
+impl StreamFilter for RewriteFilter {
+    fn on_connect(&self, _flow: &Flow) -> Admission {
+        self.admit()
+    }
+}
+
+impl DatagramFilter for RewriteFilter {
+    fn on_session(&self, _flow: &Flow) -> Admission {
+        self.admit()
+    }
+}
+
+struct RewriteFlow(Arc<[Replacement]>);
+
+impl FlowFilter for RewriteFlow {
+    fn on_data(&mut self, direction: Direction, data: &mut Vec<u8>) -> Verdict {
+        for replacement in self.0.iter().filter(|replacement| replacement.applies(direction)) {
+            if let Some(rewritten) = replace_all(data, &replacement.find, &replacement.replace) {
+                *data = rewritten;
+            }
+        }
+        Verdict::Pass
+    }
+}
+
+// Replaces non-overlapping matches from left to right; None if there are none
+fn replace_all(data: &[u8], find: &[u8], replace: &[u8]) -> Option<Vec<u8>> {
+    let mut rewritten = Vec::new();
+    let mut rest = 0;
+    let mut at = 0;
+    while at + find.len() <= data.len() {
+        if data[at..].starts_with(find) {
+            rewritten.extend_from_slice(&data[rest..at]);
+            rewritten.extend_from_slice(replace);
+            at += find.len();
+            rest = at;
+        } else {
+            at += 1;
+        }
+    }
+    // find is never empty, so any match moves rest
+    if rest == 0 {
+        return None;
+    }
+    rewritten.extend_from_slice(&data[rest..]);
+    Some(rewritten)
+}
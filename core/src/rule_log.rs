@@ -0,0 +1,42 @@
+use log::{LevelFilter, Metadata};
+use tokio::task::futures::TaskLocalFuture;
+
+// Per-rule log levels ([[tcp]] and [[udp]] log_level). The supervisor runs
+// each forwarder with its rule's level in a task-local, and the tasks a
+// forwarder spawns take it along, so everything porture logs while working
+// for the rule is filtered by that level instead of [global] log_level.
+// Other crates' messages keep the global filter, so a rule at trace doesn't
+// bring the TLS and DNS libraries' tracing along.
+
+tokio::task_local! {
+    static LEVEL: Option<LevelFilter>;
+}
+
+// Runs a rule's forwarder with the rule's level
+pub fn scope<F: Future>(level: Option<LevelFilter>, future: F) -> TaskLocalFuture<Option<LevelFilter>, F> {
+    // Messages above the global level are otherwise discarded before the
+    // logger sees them
+    if let Some(level) = level
+        && level > log::max_level()
+    {
+        log::set_max_level(level);
+    }
+    LEVEL.scope(level, future)
+}
+
+// For a task spawned while working for a rule, which doesn't inherit
+// task-locals by itself
+pub fn inherit<F: Future>(future: F) -> TaskLocalFuture<Option<LevelFilter>, F> {
+    LEVEL.scope(current(), future)
+}
+
+fn current() -> Option<LevelFilter> {
+    LEVEL.try_with(|level| *level).ok().flatten()
+}
+
+// The current rule's level for one of porture's messages, if the rule set
+// one. Loggers filter by it instead of their own filter.
+pub fn level(metadata: &Metadata<'_>) -> Option<LevelFilter> {
+    // The binary's modules and the engine's
+    current().filter(|_| metadata.target().starts_with("porture"))
+}
@@ -0,0 +1,104 @@
+use crate::config::SeccompMode;
+use crate::state::SharedState;
+use anyhow::Result;
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+#[cfg(target_os = "linux")]
+use log::info;
+#[cfg(target_os = "linux")]
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+#[cfg(target_os = "linux")]
+use std::collections::BTreeMap;
+
+// seccomp sandbox ([global] seccomp). Once the initial rules are listening
+// (and root is dropped), every thread is confined to the system calls
+// porture itself makes: sockets, files, memory, threads and time. A bug
+// exploited through a parsing path (PROXY protocol, TLS, HTTP) then can't
+// start programs, trace other processes, load kernel modules or mount
+// anything. The filter stays for the life of the process; reloads and
+// upgrades need nothing outside it.
+
+// Every architecture's calls, by their generic names
+#[cfg(target_os = "linux")]
+const ALLOWED: &[libc::c_long] = &[
+    // Files: config, certificates, GeoIP database, logs, pid file, ACME cache
+    libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_pread64, libc::SYS_pwrite64,
+    libc::SYS_openat, libc::SYS_close, libc::SYS_lseek, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx,
+    libc::SYS_statfs, libc::SYS_fstatfs, libc::SYS_getdents64, libc::SYS_readlinkat, libc::SYS_faccessat,
+    libc::SYS_faccessat2, libc::SYS_mkdirat, libc::SYS_unlinkat, libc::SYS_renameat2, libc::SYS_fsync,
+    libc::SYS_fdatasync, libc::SYS_ftruncate, libc::SYS_ioctl, libc::SYS_fcntl, libc::SYS_dup, libc::SYS_dup3,
+    libc::SYS_pipe2, libc::SYS_splice,
+    // Sockets
+    libc::SYS_socket, libc::SYS_socketpair, libc::SYS_bind, libc::SYS_listen, libc::SYS_accept4,
+    libc::SYS_connect, libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_setsockopt, libc::SYS_getsockopt,
+    libc::SYS_sendto, libc::SYS_recvfrom, libc::SYS_sendmsg, libc::SYS_recvmsg, libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg, libc::SYS_shutdown,
+    // Event loop and timers
+    libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2, libc::SYS_ppoll, libc::SYS_futex, libc::SYS_clock_gettime, libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep, libc::SYS_getrandom,
+    // Memory
+    libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mremap, libc::SYS_mprotect, libc::SYS_madvise, libc::SYS_brk,
+    // Threads, signals and exiting
+    libc::SYS_clone, libc::SYS_clone3, libc::SYS_set_robust_list, libc::SYS_rseq, libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_restart_syscall,
+    libc::SYS_sched_getaffinity, libc::SYS_sched_yield, libc::SYS_prctl, libc::SYS_prlimit64, libc::SYS_getpid,
+    libc::SYS_gettid, libc::SYS_tgkill, libc::SYS_exit, libc::SYS_exit_group,
+    // Identity
+    libc::SYS_uname, libc::SYS_getuid, libc::SYS_geteuid, libc::SYS_getgid, libc::SYS_getegid,
+];
+
+// Older calls that only x86_64 has, still made by the C library
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const ALLOWED_LEGACY: &[libc::c_long] = &[
+    libc::SYS_open, libc::SYS_stat, libc::SYS_lstat, libc::SYS_access, libc::SYS_readlink, libc::SYS_mkdir,
+    libc::SYS_unlink, libc::SYS_rename, libc::SYS_renameat, libc::SYS_dup2, libc::SYS_pipe, libc::SYS_poll,
+    libc::SYS_epoll_wait,
+];
+
+#[cfg(all(target_os = "linux", not(target_arch = "x86_64")))]
+const ALLOWED_LEGACY: &[libc::c_long] = &[];
+
+pub fn validate() -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        anyhow::bail!("[global] seccomp requires Linux");
+    }
+    #[cfg(target_os = "linux")]
+    TargetArch::try_from(std::env::consts::ARCH)
+        .map_err(|_| anyhow::anyhow!("[global] seccomp is not supported on {}", std::env::consts::ARCH))?;
+    Ok(())
+}
+
+// Installs the filter on every thread of the process. With io_uring, the
+// ring thread keeps submitting to the ring it set up, and with sockmap,
+// connections keep being added to the map.
+#[cfg(target_os = "linux")]
+pub fn install(mode: SeccompMode, shared: &SharedState) -> Result<()> {
+    let ring: &[libc::c_long] = if shared.ring.is_some() { &[libc::SYS_io_uring_enter] } else { &[] };
+    let sockmap: &[libc::c_long] = if shared.sockmap.is_some() { &[libc::SYS_bpf] } else { &[] };
+    // c_long is only i64 on 64-bit targets
+    #[allow(clippy::useless_conversion)]
+    let rules: BTreeMap<i64, Vec<_>> = ALLOWED.iter().chain(ALLOWED_LEGACY).chain(ring).chain(sockmap)
+        .map(|&call| (i64::from(call), Vec::new()))
+        .collect();
+    let allowed = rules.len();
+    let mismatch = match mode {
+        SeccompMode::Enforce => SeccompAction::Errno(libc::EPERM as u32),
+        SeccompMode::Log => SeccompAction::Log,
+    };
+    let arch = TargetArch::try_from(std::env::consts::ARCH).context("unsupported architecture for seccomp")?;
+    let filter = SeccompFilter::new(rules, mismatch, SeccompAction::Allow, arch)
+        .context("failed to build the seccomp filter")?;
+    let program = BpfProgram::try_from(filter).context("failed to build the seccomp filter")?;
+    seccompiler::apply_filter_all_threads(&program).context("failed to install the seccomp filter")?;
+    match mode {
+        SeccompMode::Enforce => info!("seccomp filter installed, {} system calls allowed", allowed),
+        SeccompMode::Log => info!("seccomp filter installed in log mode; unexpected system calls are logged by the kernel"),
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn install(_mode: SeccompMode, _shared: &SharedState) -> Result<()> {
+    anyhow::bail!("[global] seccomp requires Linux")
+}
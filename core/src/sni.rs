@@ -0,0 +1,235 @@
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+// ClientHellos with large post-quantum key shares can span several records
+const MAX_CLIENT_HELLO: usize = 64 * 1024;
+
+// Reads the records carrying the ClientHello and returns them verbatim, so
+// they can be replayed to the target, along with the SNI if there was one.
+// Anything that isn't a TLS handshake is returned as-is with no name.
+pub async fn read_client_hello<S>(stream: &mut S) -> Result<(Vec<u8>, Option<String>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut raw = Vec::new();
+    let mut handshake = Vec::new();
+    loop {
+        let mut header = [0u8; 5];
+        stream.read_exact(&mut header).await?;
+        raw.extend_from_slice(&header);
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Ok((raw, None));
+        }
+
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let start = raw.len();
+        raw.resize(start + length, 0);
+        stream.read_exact(&mut raw[start..]).await?;
+        handshake.extend_from_slice(&raw[start..]);
+
+        if handshake.len() >= 4 {
+            let message_length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + message_length {
+                break;
+            }
+        }
+        if raw.len() > MAX_CLIENT_HELLO {
+            anyhow::bail!("ClientHello larger than {} bytes", MAX_CLIENT_HELLO);
+        }
+    }
+
+    if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+        return Ok((raw, None));
+    }
+    let server_name = parse_server_name(&handshake[4..]);
+    Ok((raw, server_name))
+}
+
+fn parse_server_name(client_hello: &[u8]) -> Option<String> {
+    let mut reader = Reader::new(client_hello);
+    reader.skip(2 + 32)?; // legacy_version, random
+    let session_id = reader.u8()? as usize;
+    reader.skip(session_id)?;
+    let cipher_suites = reader.u16()? as usize;
+    reader.skip(cipher_suites)?;
+    let compression_methods = reader.u8()? as usize;
+    reader.skip(compression_methods)?;
+
+    let extensions_length = reader.u16()? as usize;
+    let mut extensions = Reader::new(reader.take(extensions_length)?);
+    while !extensions.is_empty() {
+        let kind = extensions.u16()?;
+        let length = extensions.u16()? as usize;
+        let data = extensions.take(length)?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut list = Reader::new(data);
+        let list_length = list.u16()? as usize;
+        let mut names = Reader::new(list.take(list_length)?);
+        while !names.is_empty() {
+            let name_type = names.u8()?;
+            let name_length = names.u16()? as usize;
+            let name = names.take(name_length)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_string);
+            }
+        }
+    }
+    None
+}
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buffer.len() < n {
+            return None;
+        }
+        let (head, rest) = self.buffer.split_at(n);
+        self.buffer = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use std::sync::Arc;
+
+    // A ClientHello as rustls sends it, in one record
+    fn client_hello(sni: bool) -> Vec<u8> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        config.enable_sni = sni;
+        let name = ServerName::try_from("example.com").unwrap();
+        let mut connection = rustls::ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut hello = Vec::new();
+        connection.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    fn record(fragment: &[u8]) -> Vec<u8> {
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+        record.extend_from_slice(fragment);
+        record
+    }
+
+    #[tokio::test]
+    async fn reads_server_name() {
+        let hello = client_hello(true);
+        let (raw, server_name) = read_client_hello(&mut hello.as_slice()).await.unwrap();
+        assert_eq!(raw, hello);
+        assert_eq!(server_name.as_deref(), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn client_hello_without_sni() {
+        let hello = client_hello(false);
+        let (raw, server_name) = read_client_hello(&mut hello.as_slice()).await.unwrap();
+        assert_eq!(raw, hello);
+        assert_eq!(server_name, None);
+    }
+
+    #[tokio::test]
+    async fn client_hello_split_across_records() {
+        let handshake = client_hello(true)[5..].to_vec();
+        let mut split = record(&handshake[..100]);
+        split.extend(record(&handshake[100..]));
+        // Data after the ClientHello stays in the stream
+        let stream = [split.as_slice(), b"early data"].concat();
+        let mut reader = stream.as_slice();
+        let (raw, server_name) = read_client_hello(&mut reader).await.unwrap();
+        assert_eq!(raw, split);
+        assert_eq!(server_name.as_deref(), Some("example.com"));
+        assert_eq!(reader, b"early data");
+    }
+
+    #[tokio::test]
+    async fn not_a_handshake() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let mut reader = &request[..];
+        let (raw, server_name) = read_client_hello(&mut reader).await.unwrap();
+        assert_eq!(raw, &request[..5]);
+        assert_eq!(server_name, None);
+        assert_eq!(reader, &request[5..]);
+    }
+
+    #[tokio::test]
+    async fn truncated_record() {
+        let hello = client_hello(true);
+        assert!(read_client_hello(&mut &hello[..hello.len() - 1]).await.is_err());
+        assert!(read_client_hello(&mut &hello[..3]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn over_long_client_hello() {
+        // A message claiming 16 MiB, sent in full records until the limit
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO, 0xff, 0xff, 0xff];
+        handshake.resize(16 * 1024, 0);
+        let mut stream = record(&handshake);
+        while stream.len() <= MAX_CLIENT_HELLO + 16 * 1024 {
+            stream.extend(record(&[0; 16 * 1024]));
+        }
+        let error = read_client_hello(&mut stream.as_slice()).await.unwrap_err();
+        assert!(error.to_string().contains("larger than"), "{}", error);
+    }
+
+    #[test]
+    fn truncated_or_over_long_lengths() {
+        let body = client_hello(true)[5 + 4..].to_vec();
+        assert_eq!(parse_server_name(&body).as_deref(), Some("example.com"));
+        // Cut short anywhere before the name is complete
+        let name_end = body.windows(11).position(|window| window == b"example.com").unwrap() + 11;
+        for length in 0..name_end {
+            assert_eq!(parse_server_name(&body[..length]), None, "cut at {}", length);
+        }
+
+        // An extensions block claiming more than there is
+        let mut reader = Reader::new(&body);
+        reader.skip(2 + 32).unwrap();
+        let session_id = reader.u8().unwrap() as usize;
+        reader.skip(session_id).unwrap();
+        let cipher_suites = reader.u16().unwrap() as usize;
+        reader.skip(cipher_suites).unwrap();
+        let compression_methods = reader.u8().unwrap() as usize;
+        reader.skip(compression_methods).unwrap();
+        let extensions_at = body.len() - reader.buffer.len();
+        let mut over_long = body.clone();
+        over_long[extensions_at..extensions_at + 2].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(parse_server_name(&over_long), None);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::config::TcpRule;
+use crate::connector::TargetConnector;
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{timeout_at, Instant};
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ", b"POST ", b"PUT ", b"HEAD ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE ",
+    // HTTP/2 prior-knowledge connection preface
+    b"PRI ",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tls,
+    Http,
+    Other,
+}
+
+// Picks a target per connection from the protocol the client opens with
+pub struct SniffRouter {
+    tls: Option<Arc<TargetConnector>>,
+    http: Option<Arc<TargetConnector>>,
+    default: Arc<TargetConnector>,
+}
+
+impl SniffRouter {
+    pub fn from_rule(rule: &TcpRule, default: Arc<TargetConnector>) -> Result<Self> {
+        let connector = |endpoint: &Option<String>| -> Result<Option<Arc<TargetConnector>>> {
+            endpoint.as_deref()
+                .map(|e| default.retarget(e).map(Arc::new))
+                .transpose()
+        };
+        let routes = rule.sniff_routes.clone().unwrap_or_default();
+        Ok(Self {
+            tls: connector(&routes.tls)?,
+            http: connector(&routes.http)?,
+            default,
+        })
+    }
+
+    pub fn route(&self, protocol: Protocol) -> &Arc<TargetConnector> {
+        let route = match protocol {
+            Protocol::Tls => &self.tls,
+            Protocol::Http => &self.http,
+            Protocol::Other => &None,
+        };
+        route.as_ref().unwrap_or(&self.default)
+    }
+}
+
+// Reads until the opening bytes identify the protocol and returns them so
+// they can be replayed to the target. Clients that stay silent for `wait`
+// (server-speaks-first protocols) are classified as Other.
+pub async fn sniff<S>(stream: &mut S, wait: Duration) -> Result<(Vec<u8>, Protocol)>
+where
+    S: AsyncRead + Unpin,
+{
+    let deadline = Instant::now() + wait;
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        if let Some(protocol) = classify(&data) {
+            return Ok((data, protocol));
+        }
+        match timeout_at(deadline, stream.read(&mut buffer)).await {
+            Ok(Ok(0)) | Err(_) => return Ok((data, Protocol::Other)),
+            Ok(Ok(n)) => data.extend_from_slice(&buffer[..n]),
+            Ok(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+// None means more bytes are needed to decide
+fn classify(data: &[u8]) -> Option<Protocol> {
+    match data {
+        [] | [0x16] => return None,
+        // Handshake record with a TLS/SSLv3 major version
+        [0x16, 0x03, ..] => return Some(Protocol::Tls),
+        _ => {}
+    }
+
+    let mut undecided = false;
+    for method in HTTP_METHODS {
+        if data.starts_with(method) {
+            return Some(Protocol::Http);
+        }
+        undecided |= method.starts_with(data);
+    }
+    if undecided { None } else { Some(Protocol::Other) }
+}
@@ -0,0 +1,291 @@
+use crate::copy::End;
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+// eBPF sockmap acceleration ([global] sockmap = true, Linux). A stream
+// verdict program attached to a SOCKHASH map redirects every chunk a paired
+// connection receives to the socket stored under that connection's cookie,
+// which is its peer's. Data then goes from client to target and back inside
+// the kernel's TCP stack, without a wakeup, system call or copy in porture;
+// porture only sees the connections open and close.
+//
+// The program, in C:
+//
+//     SEC("sk_skb/stream_verdict")
+//     int redirect(struct __sk_buff *skb) {
+//         __u64 cookie = bpf_get_socket_cookie(skb);
+//         return bpf_sk_redirect_hash(skb, &peers, &cookie, 0);
+//     }
+
+#[cfg(target_os = "linux")]
+pub use linux::SockMap;
+
+#[cfg(not(target_os = "linux"))]
+pub struct SockMap;
+
+#[cfg(not(target_os = "linux"))]
+impl SockMap {
+    pub fn load() -> io::Result<std::sync::Arc<SockMap>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "sockmap requires Linux"))
+    }
+
+    pub fn pair(&self, _client: &TcpStream, _target: &TcpStream) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "sockmap requires Linux"))
+    }
+}
+
+// Waits for `from` to be closed. Data the kernel still hands to porture,
+// such as what arrived just before the pair was made, is passed on.
+pub async fn until_closed(from: &TcpStream, to: &TcpStream) -> End {
+    let mut buffer = [0u8; 4096];
+    loop {
+        // Reading first also has the kernel redirect what was queued
+        // before the connections were paired
+        let n = match from.try_read(&mut buffer) {
+            Ok(0) => return End::eof(to),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Err(e) = from.readable().await {
+                    return End::Read(e);
+                }
+                continue;
+            }
+            Err(e) => return End::Read(e),
+        };
+        let mut written = 0;
+        while written < n {
+            if let Err(e) = to.writable().await {
+                return End::Write(e);
+            }
+            match to.try_write(&buffer[written..n]) {
+                Ok(sent) => written += sent,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return End::Write(e),
+            }
+        }
+    }
+}
+
+// Bytes received on the connection, counting a FIN as one, and how long
+// ago the last data arrived
+#[cfg(target_os = "linux")]
+pub fn received(stream: &TcpStream) -> io::Result<(u64, Duration)> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: all-zero is a valid tcp_info
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut length = std::mem::size_of_val(&info) as libc::socklen_t;
+    // SAFETY: `info` outlives the call and its size is passed with it
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            (&mut info as *mut libc::tcp_info).cast(),
+            &mut length,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((info.tcpi_bytes_received, Duration::from_millis(info.tcpi_last_data_recv.into())))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn received(_stream: &TcpStream) -> io::Result<(u64, Duration)> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "sockmap requires Linux"))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+
+    // Two entries per connection
+    const MAX_ENTRIES: u32 = 131072;
+
+    // From linux/bpf.h
+    const BPF_MAP_CREATE: libc::c_int = 0;
+    const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+    const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+    const BPF_PROG_LOAD: libc::c_int = 5;
+    const BPF_PROG_ATTACH: libc::c_int = 8;
+    const BPF_MAP_TYPE_SOCKHASH: u32 = 18;
+    const BPF_PROG_TYPE_SK_SKB: u32 = 14;
+    const BPF_SK_SKB_STREAM_VERDICT: u32 = 5;
+    const BPF_FUNC_GET_SOCKET_COOKIE: i32 = 46;
+    const BPF_FUNC_SK_REDIRECT_HASH: i32 = 72;
+    const BPF_PSEUDO_MAP_FD: u8 = 1;
+    const BPF_NOEXIST: u64 = 1;
+    const SO_COOKIE: libc::c_int = 57;
+
+    // The leading fields of union bpf_attr each command uses; the kernel
+    // takes the rest as zero
+    #[repr(C)]
+    struct MapCreate {
+        map_type: u32,
+        key_size: u32,
+        value_size: u32,
+        max_entries: u32,
+    }
+
+    #[repr(C)]
+    struct ProgLoad {
+        prog_type: u32,
+        insn_cnt: u32,
+        insns: u64,
+        license: u64,
+    }
+
+    #[repr(C)]
+    struct MapElem {
+        map_fd: u32,
+        _pad: u32,
+        key: u64,
+        value: u64,
+        flags: u64,
+    }
+
+    #[repr(C)]
+    struct ProgAttach {
+        target_fd: u32,
+        attach_bpf_fd: u32,
+        attach_type: u32,
+        attach_flags: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Insn {
+        code: u8,
+        // dst in the low nibble, src in the high one
+        regs: u8,
+        off: i16,
+        imm: i32,
+    }
+
+    const fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+        Insn { code, regs: dst | (src << 4), off, imm }
+    }
+
+    pub struct SockMap {
+        map: OwnedFd,
+        // Attached to the map, and detached when closed
+        _program: OwnedFd,
+    }
+
+    impl SockMap {
+        // Needs CAP_BPF and CAP_NET_ADMIN (root); pairing connections later
+        // only needs the map
+        pub fn load() -> io::Result<Arc<SockMap>> {
+            let map = bpf_fd(BPF_MAP_CREATE, &MapCreate {
+                map_type: BPF_MAP_TYPE_SOCKHASH,
+                key_size: 8,
+                value_size: 4,
+                max_entries: MAX_ENTRIES,
+            })?;
+            let program = [
+                // r6 = skb
+                insn(0xbf, 6, 1, 0, 0),
+                // r0 = bpf_get_socket_cookie(skb); *(u64 *)(r10 - 8) = r0
+                insn(0x85, 0, 0, 0, BPF_FUNC_GET_SOCKET_COOKIE),
+                insn(0x7b, 10, 0, -8, 0),
+                // return bpf_sk_redirect_hash(skb, &peers, r10 - 8, 0)
+                insn(0xbf, 1, 6, 0, 0),
+                insn(0x18, 2, BPF_PSEUDO_MAP_FD, 0, map.as_raw_fd()),
+                insn(0, 0, 0, 0, 0),
+                insn(0xbf, 3, 10, 0, 0),
+                insn(0x07, 3, 0, 0, -8),
+                insn(0xb7, 4, 0, 0, 0),
+                insn(0x85, 0, 0, 0, BPF_FUNC_SK_REDIRECT_HASH),
+                insn(0x95, 0, 0, 0, 0),
+            ];
+            let license = c"Dual MIT/GPL";
+            let program = bpf_fd(BPF_PROG_LOAD, &ProgLoad {
+                prog_type: BPF_PROG_TYPE_SK_SKB,
+                insn_cnt: program.len() as u32,
+                insns: program.as_ptr() as u64,
+                license: license.as_ptr() as u64,
+            })?;
+            bpf(BPF_PROG_ATTACH, &ProgAttach {
+                target_fd: map.as_raw_fd() as u32,
+                attach_bpf_fd: program.as_raw_fd() as u32,
+                attach_type: BPF_SK_SKB_STREAM_VERDICT,
+                attach_flags: 0,
+            })?;
+            Ok(Arc::new(SockMap { map, _program: program }))
+        }
+
+        // Sends whatever arrives on either connection straight to the
+        // other. Closed sockets leave the map by themselves.
+        pub fn pair(&self, client: &TcpStream, target: &TcpStream) -> io::Result<()> {
+            let client_cookie = cookie(client)?;
+            self.insert(client_cookie, target)?;
+            if let Err(e) = self.insert(cookie(target)?, client) {
+                self.remove(client_cookie);
+                return Err(e);
+            }
+            Ok(())
+        }
+
+        fn insert(&self, key: u64, socket: &TcpStream) -> io::Result<()> {
+            let value = socket.as_raw_fd() as u32;
+            bpf(BPF_MAP_UPDATE_ELEM, &MapElem {
+                map_fd: self.map.as_raw_fd() as u32,
+                _pad: 0,
+                key: &key as *const u64 as u64,
+                value: &value as *const u32 as u64,
+                flags: BPF_NOEXIST,
+            })
+        }
+
+        fn remove(&self, key: u64) {
+            let _ = bpf(BPF_MAP_DELETE_ELEM, &MapElem {
+                map_fd: self.map.as_raw_fd() as u32,
+                _pad: 0,
+                key: &key as *const u64 as u64,
+                value: 0,
+                flags: 0,
+            });
+        }
+    }
+
+    fn cookie(socket: &TcpStream) -> io::Result<u64> {
+        let mut cookie: u64 = 0;
+        let mut length = std::mem::size_of_val(&cookie) as libc::socklen_t;
+        // SAFETY: `cookie` outlives the call and its size is passed with it
+        let result = unsafe {
+            libc::getsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, SO_COOKIE, (&mut cookie as *mut u64).cast(), &mut length)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(cookie)
+    }
+
+    fn bpf<T>(command: libc::c_int, attr: &T) -> io::Result<()> {
+        bpf_call(command, attr).map(drop)
+    }
+
+    // For the commands that return a new descriptor
+    fn bpf_fd<T>(command: libc::c_int, attr: &T) -> io::Result<OwnedFd> {
+        let fd = bpf_call(command, attr)?;
+        // SAFETY: the kernel just opened the descriptor for us
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    fn bpf_call<T>(command: libc::c_int, attr: &T) -> io::Result<RawFd> {
+        // SAFETY: `attr` is one of the bpf_attr layouts above, and every
+        // pointer in it refers to memory that outlives the call
+        let result = unsafe {
+            libc::syscall(libc::SYS_bpf, command, attr as *const T, std::mem::size_of::<T>() as libc::c_uint)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result as RawFd)
+    }
+}
@@ -0,0 +1,249 @@
+use crate::config::{KeepaliveConfig, SocketOptions};
+use crate::handoff;
+use crate::transparent;
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+
+// Per-socket options set from rule configuration (bind_device, fwmark, dscp,
+// keepalive, [tcp.socket])
+
+// Longest interface name the kernel accepts (IFNAMSIZ without the NUL)
+const MAX_NAME: usize = 15;
+// DSCP is the upper six bits of the ToS / traffic class byte
+const MAX_DSCP: u8 = 63;
+const LISTEN_BACKLOG: i32 = 1024;
+
+// Sockets the options can be set on: file descriptors on Unix, SOCKETs on
+// Windows
+#[cfg(unix)]
+pub use std::os::fd::AsFd as AsSocket;
+#[cfg(windows)]
+pub use std::os::windows::io::AsSocket;
+
+// Sends a FIN: the peer reads EOF, and can still send
+pub fn shutdown_write<S: AsSocket>(socket: &S) -> io::Result<()> {
+    socket2::SockRef::from(socket).shutdown(std::net::Shutdown::Write)
+}
+
+// Listener options that have to be set before binding
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BindOptions {
+    // IP_TRANSPARENT, for TPROXY
+    pub transparent: bool,
+    // SO_REUSEPORT: several sockets share the address and the kernel spreads
+    // connections (or, for UDP, client flows) across them
+    pub reuse_port: bool,
+    // IP_FREEBIND: bind to an address that isn't (yet) configured on this
+    // host, such as a VIP that keepalived moves around
+    pub freebind: bool,
+    // IPV6_V6ONLY for IPv6 listeners. Platforms disagree on the default, so
+    // it is always set: dual-stack unless ipv6_only is configured.
+    pub ipv6_only: bool,
+}
+
+impl BindOptions {
+    fn apply(&self, socket: &Socket, ipv6: bool) -> io::Result<()> {
+        if self.transparent {
+            transparent::set_transparent(socket, ipv6)?;
+        }
+        if self.reuse_port {
+            set_reuse_port(socket)?;
+        }
+        if self.freebind {
+            set_freebind(socket, ipv6)?;
+        }
+        if ipv6 {
+            socket.set_only_v6(self.ipv6_only)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "reuse_port requires Unix"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_freebind(socket: &Socket, ipv6: bool) -> io::Result<()> {
+    if ipv6 { socket.set_freebind_v6(true) } else { socket.set_freebind_v4(true) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_freebind(_socket: &Socket, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "freebind requires Linux"))
+}
+
+// Listeners handed over by a previous process are taken as they are: the
+// bind options only apply to freshly bound ones
+pub fn tcp_listener(addr: SocketAddr, options: BindOptions) -> io::Result<Arc<TcpListener>> {
+    let key = format!("tcp {}", addr);
+    let socket = match handoff::inherited(&key) {
+        Some(socket) => socket,
+        None => {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+            // Lets a restarted porture bind while old connections linger in
+            // TIME_WAIT. On Windows it would let another program take over
+            // the port instead, and rebinding works without it there.
+            #[cfg(unix)]
+            socket.set_reuse_address(true)?;
+            options.apply(&socket, addr.is_ipv6())?;
+            socket.bind(&addr.into())?;
+            socket.listen(LISTEN_BACKLOG)?;
+            socket
+        }
+    };
+    socket.set_nonblocking(true)?;
+    Ok(handoff::offer(key, TcpListener::from_std(socket.into())?))
+}
+
+pub fn udp_listener(addr: SocketAddr, options: BindOptions) -> io::Result<Arc<UdpSocket>> {
+    let key = format!("udp {}", addr);
+    if let Some(socket) = handoff::inherited(&key) {
+        socket.set_nonblocking(true)?;
+        return Ok(handoff::offer(key, UdpSocket::from_std(socket.into())?));
+    }
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    socket.set_nonblocking(true)?;
+    options.apply(&socket, addr.is_ipv6())?;
+    if options.transparent {
+        // Transparent sessions answer from original destinations, which may
+        // include the listener's own address
+        socket.set_reuse_address(true)?;
+        transparent::set_recv_original_destination(&socket, addr.is_ipv6())?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(handoff::offer(key, UdpSocket::from_std(socket.into())?))
+}
+
+// Binds a socket the way tcp_listener or udp_listener would and closes it
+// again, to see whether the address is available (`porture check --bind`)
+pub fn try_bind(addr: SocketAddr, options: BindOptions, kind: Type) -> io::Result<()> {
+    let socket = Socket::new(Domain::for_address(addr), kind, None)?;
+    #[cfg(unix)]
+    if kind == Type::STREAM || options.transparent {
+        socket.set_reuse_address(true)?;
+    }
+    options.apply(&socket, addr.is_ipv6())?;
+    socket.bind(&addr.into())?;
+    if kind == Type::STREAM {
+        socket.listen(LISTEN_BACKLOG)?;
+    }
+    Ok(())
+}
+
+pub fn validate_device(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME || name.contains(['/', '\0']) || name.chars().any(char::is_whitespace) {
+        anyhow::bail!("invalid network interface name '{}'", name);
+    }
+    if !cfg!(target_os = "linux") {
+        anyhow::bail!("binding to network interface '{}' requires Linux", name);
+    }
+    Ok(())
+}
+
+pub fn validate_mark() -> anyhow::Result<()> {
+    if !cfg!(target_os = "linux") {
+        anyhow::bail!("fwmark requires Linux");
+    }
+    Ok(())
+}
+
+pub fn validate_dscp(dscp: u8) -> anyhow::Result<()> {
+    if dscp > MAX_DSCP {
+        anyhow::bail!("dscp {} is out of range (0-{})", dscp, MAX_DSCP);
+    }
+    if !cfg!(target_os = "linux") {
+        anyhow::bail!("dscp requires Linux");
+    }
+    Ok(())
+}
+
+// SO_BINDTODEVICE: the socket only sends and receives through `name`, whatever
+// the routing table says. Binding to a VRF device puts the socket in that VRF.
+#[cfg(target_os = "linux")]
+pub fn bind_device<S: std::os::fd::AsFd>(socket: &S, name: &str) -> io::Result<()> {
+    socket2::SockRef::from(socket)
+        .bind_device(Some(name.as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to bind to device {}: {}", name, e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_device<S>(_socket: &S, name: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("binding to device {} requires Linux", name)))
+}
+
+// SO_MARK: tags every packet the socket sends, for `ip rule fwmark` policy
+// routing or nftables `meta mark` matches. Needs CAP_NET_ADMIN.
+#[cfg(target_os = "linux")]
+pub fn set_mark<S: std::os::fd::AsFd>(socket: &S, mark: u32) -> io::Result<()> {
+    socket2::SockRef::from(socket)
+        .set_mark(mark)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to set fwmark {:#x}: {}", mark, e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_mark<S>(_socket: &S, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "fwmark requires Linux"))
+}
+
+// IP_TOS / IPV6_TCLASS with the ECN bits left clear. IPv6 sockets also set
+// IP_TOS, which covers IPv4-mapped peers of a dual-stack socket.
+#[cfg(target_os = "linux")]
+pub fn set_dscp<S: std::os::fd::AsFd>(socket: &S, dscp: u8, ipv6: bool) -> io::Result<()> {
+    let socket = socket2::SockRef::from(socket);
+    let tos = u32::from(dscp) << 2;
+    if ipv6 {
+        socket.set_tclass_v6(tos)?;
+    }
+    socket
+        .set_tos_v4(tos)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to set dscp {}: {}", dscp, e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dscp<S>(_socket: &S, _dscp: u8, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "dscp requires Linux"))
+}
+
+// SO_KEEPALIVE with the rule's timings. Where the interval and probe count
+// can't be set, the system defaults apply.
+pub fn set_keepalive<S: AsSocket>(socket: &S, keepalive: &KeepaliveConfig) -> io::Result<()> {
+    let params = socket2::TcpKeepalive::new().with_time(Duration::from_secs(keepalive.idle_seconds()));
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+    let params = params
+        .with_interval(Duration::from_secs(keepalive.interval_seconds()))
+        .with_retries(keepalive.probe_count());
+    // Windows takes the interval but always sends 10 probes
+    #[cfg(windows)]
+    let params = params.with_interval(Duration::from_secs(keepalive.interval_seconds()));
+    socket2::SockRef::from(socket)
+        .set_tcp_keepalive(&params)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to enable keepalive: {}", e)))
+}
+
+pub fn set_options<S: AsSocket>(socket: &S, options: &SocketOptions) -> io::Result<()> {
+    let socket = socket2::SockRef::from(socket);
+    if let Some(nodelay) = options.nodelay {
+        socket.set_tcp_nodelay(nodelay)?;
+    }
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(linger) = options.linger {
+        socket.set_linger(Some(Duration::from_secs(linger)))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,408 @@
+use crate::buffer::Buffer;
+use crate::connector::TargetConnector;
+use crate::resolver;
+use anyhow::Result;
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+pub const SOCKS_VERSION: u8 = 0x05;
+pub const SOCKS_AUTH_NONE: u8 = 0x00;
+pub const SOCKS_AUTH_PASSWORD: u8 = 0x02;
+pub const SOCKS_AUTH_UNACCEPTABLE: u8 = 0xff;
+pub const SOCKS_CMD_CONNECT: u8 = 0x01;
+pub const SOCKS_CMD_UDP_ASSOCIATE: u8 = 0x03;
+pub const SOCKS_ATYP_IPV4: u8 = 0x01;
+pub const SOCKS_ATYP_DOMAIN: u8 = 0x03;
+pub const SOCKS_ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+pub fn error_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+pub enum Command {
+    Connect(Box<TargetConnector>),
+    UdpAssociate,
+}
+
+// Server side of mode = "socks5"
+pub struct Socks5Server {
+    users: Option<BTreeMap<String, String>>,
+    // Connection settings (timeout, upstream proxy) for client-chosen targets
+    template: Arc<TargetConnector>,
+}
+
+impl Socks5Server {
+    pub fn new(users: Option<BTreeMap<String, String>>, template: Arc<TargetConnector>) -> Self {
+        Self { users, template }
+    }
+
+    // Runs method negotiation, authentication and reads the request
+    pub async fn accept<S>(&self, stream: &mut S) -> Result<Command>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut greeting = [0u8; 2];
+        stream.read_exact(&mut greeting).await?;
+        if greeting[0] != SOCKS_VERSION {
+            anyhow::bail!("not a SOCKS5 client (version {})", greeting[0]);
+        }
+        let mut methods = vec![0u8; greeting[1] as usize];
+        stream.read_exact(&mut methods).await?;
+
+        let method = if self.users.is_some() { SOCKS_AUTH_PASSWORD } else { SOCKS_AUTH_NONE };
+        if !methods.contains(&method) {
+            stream.write_all(&[SOCKS_VERSION, SOCKS_AUTH_UNACCEPTABLE]).await?;
+            anyhow::bail!("SOCKS5 client offered no acceptable authentication method");
+        }
+        stream.write_all(&[SOCKS_VERSION, method]).await?;
+
+        if let Some(users) = &self.users {
+            let mut version = [0u8; 1];
+            stream.read_exact(&mut version).await?;
+            let user = read_short_string(stream).await?;
+            let password = read_short_string(stream).await?;
+            let authenticated = users.get(&user).is_some_and(|expected| *expected == password);
+            stream.write_all(&[0x01, if authenticated { 0x00 } else { 0x01 }]).await?;
+            if !authenticated {
+                anyhow::bail!("SOCKS5 authentication failed for user '{}'", user);
+            }
+        }
+
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request).await?;
+        let (host, port) = read_address(stream, request[3]).await?;
+        match request[1] {
+            SOCKS_CMD_CONNECT => Ok(Command::Connect(Box::new(self.template.retarget_to(host, port)))),
+            SOCKS_CMD_UDP_ASSOCIATE => Ok(Command::UdpAssociate),
+            other => {
+                stream.write_all(&reply(REPLY_COMMAND_NOT_SUPPORTED, None)).await?;
+                anyhow::bail!("unsupported SOCKS5 command {}", other);
+            }
+        }
+    }
+}
+
+pub fn connect_succeeded() -> Vec<u8> {
+    reply(REPLY_SUCCEEDED, None)
+}
+
+pub fn connect_failed(error: &anyhow::Error) -> Vec<u8> {
+    let code = match error.downcast_ref::<io::Error>().map(io::Error::kind) {
+        Some(io::ErrorKind::ConnectionRefused) => REPLY_CONNECTION_REFUSED,
+        Some(io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable) => REPLY_HOST_UNREACHABLE,
+        _ => REPLY_GENERAL_FAILURE,
+    };
+    reply(code, None)
+}
+
+// BND.ADDR is only meaningful for UDP ASSOCIATE; CONNECT replies send zeros
+fn reply(code: u8, bound: Option<SocketAddr>) -> Vec<u8> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let mut reply = vec![SOCKS_VERSION, code, 0x00];
+    encode_address(&mut reply, bound);
+    reply
+}
+
+fn encode_address(buffer: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buffer.push(SOCKS_ATYP_IPV4);
+            buffer.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buffer.push(SOCKS_ATYP_IPV6);
+            buffer.extend_from_slice(&ip.octets());
+        }
+    }
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn read_short_string<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String> {
+    let length = stream.read_u8().await? as usize;
+    let mut value = vec![0u8; length];
+    stream.read_exact(&mut value).await?;
+    Ok(String::from_utf8_lossy(&value).into_owned())
+}
+
+async fn read_address<S: AsyncRead + Unpin>(stream: &mut S, address_type: u8) -> Result<(String, u16)> {
+    let host = match address_type {
+        SOCKS_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        SOCKS_ATYP_DOMAIN => read_short_string(stream).await?,
+        other => anyhow::bail!("unknown SOCKS5 address type {}", other),
+    };
+    let port = stream.read_u16().await?;
+    Ok((host, port))
+}
+
+// Relays datagrams for a UDP ASSOCIATE until the client closes the control
+// connection. Returns the number of bytes received from the client.
+pub async fn relay_udp<S>(
+    control: &mut S,
+    client_ip: IpAddr,
+    bind_ip: IpAddr,
+    buffer_size: usize,
+) -> Result<u64>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let socket = UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await?;
+    control.write_all(&reply(REPLY_SUCCEEDED, Some(socket.local_addr()?))).await?;
+    debug!("SOCKS5 UDP relay for {} on {}", client_ip, socket.local_addr()?);
+
+    let client_ip = client_ip.to_canonical();
+    let mut client_udp_addr: Option<SocketAddr> = None;
+    let mut resolved: HashMap<(String, u16), SocketAddr> = HashMap::new();
+    let mut uploaded = 0u64;
+    let mut control_buffer = [0u8; 64];
+    let mut buffer = Buffer::zeroed(buffer_size);
+
+    loop {
+        tokio::select! {
+            // The association ends with the TCP connection
+            result = control.read(&mut control_buffer) => {
+                if matches!(result, Ok(0) | Err(_)) {
+                    return Ok(uploaded);
+                }
+            }
+            result = socket.recv_from(&mut buffer) => {
+                let (n, from) = result?;
+                if from.ip().to_canonical() == client_ip && client_udp_addr.is_none_or(|addr| addr == from) {
+                    client_udp_addr = Some(from);
+                    let Some((host, port, payload)) = parse_datagram(&buffer[..n]) else {
+                        continue;
+                    };
+                    let key = (host, port);
+                    let destination = match resolved.get(&key) {
+                        Some(addr) => *addr,
+                        None => {
+                            let found = resolver::lookup(&key.0, key.1).await.ok().and_then(|addrs| addrs.into_iter().next());
+                            let Some(addr) = found else {
+                                debug!("SOCKS5 UDP relay could not resolve {}", key.0);
+                                continue;
+                            };
+                            resolved.insert(key, addr);
+                            addr
+                        }
+                    };
+                    uploaded += payload.len() as u64;
+                    let _ = socket.send_to(payload, destination).await;
+                } else if let Some(client) = client_udp_addr {
+                    let mut datagram = vec![0x00, 0x00, 0x00];
+                    encode_address(&mut datagram, from);
+                    datagram.extend_from_slice(&buffer[..n]);
+                    let _ = socket.send_to(&datagram, client).await;
+                }
+            }
+        }
+    }
+}
+
+// RSV(2) FRAG(1) ATYP DST.ADDR DST.PORT DATA; fragments are not supported
+fn parse_datagram(datagram: &[u8]) -> Option<(String, u16, &[u8])> {
+    if datagram.len() < 4 || datagram[2] != 0x00 {
+        return None;
+    }
+    let (host, rest) = match datagram[3] {
+        SOCKS_ATYP_IPV4 => {
+            let octets: [u8; 4] = datagram.get(4..8)?.try_into().ok()?;
+            (Ipv4Addr::from(octets).to_string(), &datagram[8..])
+        }
+        SOCKS_ATYP_IPV6 => {
+            let octets: [u8; 16] = datagram.get(4..20)?.try_into().ok()?;
+            (Ipv6Addr::from(octets).to_string(), &datagram[20..])
+        }
+        SOCKS_ATYP_DOMAIN => {
+            let length = *datagram.get(4)? as usize;
+            let name = datagram.get(5..5 + length)?;
+            (String::from_utf8_lossy(name).into_owned(), &datagram[5 + length..])
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+    Some((host, port, &rest[2..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn server(users: Option<&[(&str, &str)]>) -> Socks5Server {
+        let users = users.map(|users| users.iter().map(|(user, password)| (user.to_string(), password.to_string())).collect());
+        let template = TargetConnector::new("unused".to_string(), 0, Duration::from_secs(1));
+        Socks5Server::new(users, Arc::new(template))
+    }
+
+    // Runs accept on what the client sends, returning its result and what
+    // the server wrote back
+    async fn accept(server: &Socks5Server, client: &[u8]) -> (Result<Command>, Vec<u8>) {
+        let (mut client_end, mut server_end) = tokio::io::duplex(1024);
+        client_end.write_all(client).await.unwrap();
+        client_end.shutdown().await.unwrap();
+        let result = server.accept(&mut server_end).await;
+        drop(server_end);
+        let mut replies = Vec::new();
+        client_end.read_to_end(&mut replies).await.unwrap();
+        (result, replies)
+    }
+
+    async fn connect_target(client: &[u8]) -> String {
+        let (result, replies) = accept(&server(None), client).await;
+        assert_eq!(replies, [SOCKS_VERSION, SOCKS_AUTH_NONE]);
+        match result {
+            Ok(Command::Connect(connector)) => connector.endpoint(),
+            Ok(Command::UdpAssociate) => panic!("expected CONNECT, got UDP ASSOCIATE"),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_ipv4() {
+        let request = [5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 5, 0, 80];
+        assert_eq!(connect_target(&request).await, "10.0.0.5:80");
+    }
+
+    #[tokio::test]
+    async fn connect_ipv6() {
+        let mut request = vec![5, 1, 0, 5, 1, 0, 4];
+        request.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        request.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(connect_target(&request).await, "[2001:db8::1]:443");
+    }
+
+    #[tokio::test]
+    async fn connect_domain() {
+        let mut request = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(connect_target(&request).await, "example.com:443");
+    }
+
+    #[tokio::test]
+    async fn udp_associate() {
+        let request = [5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0];
+        let (result, _) = accept(&server(None), &request).await;
+        assert!(matches!(result, Ok(Command::UdpAssociate)));
+    }
+
+    #[tokio::test]
+    async fn no_acceptable_method() {
+        // Only username/password offered to a server without users
+        let (result, replies) = accept(&server(None), &[5, 1, 2]).await;
+        assert!(result.is_err());
+        assert_eq!(replies, [SOCKS_VERSION, SOCKS_AUTH_UNACCEPTABLE]);
+
+        // And the reverse
+        let (result, replies) = accept(&server(Some(&[("alice", "secret")])), &[5, 1, 0]).await;
+        assert!(result.is_err());
+        assert_eq!(replies, [SOCKS_VERSION, SOCKS_AUTH_UNACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn password_authentication() {
+        let server = server(Some(&[("alice", "secret")]));
+        let mut client = vec![5, 2, 0, 2, 1, 5];
+        client.extend_from_slice(b"alice");
+        client.push(6);
+        client.extend_from_slice(b"secret");
+        client.extend_from_slice(&[5, 1, 0, 1, 10, 0, 0, 5, 0, 80]);
+        let (result, replies) = accept(&server, &client).await;
+        assert!(matches!(result, Ok(Command::Connect(_))));
+        assert_eq!(replies, [SOCKS_VERSION, SOCKS_AUTH_PASSWORD, 1, 0]);
+
+        let mut client = vec![5, 1, 2, 1, 5];
+        client.extend_from_slice(b"alice");
+        client.push(5);
+        client.extend_from_slice(b"wrong");
+        let (result, replies) = accept(&server, &client).await;
+        assert!(result.is_err());
+        assert_eq!(replies, [SOCKS_VERSION, SOCKS_AUTH_PASSWORD, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn not_socks5() {
+        let (result, replies) = accept(&server(None), &[4, 1, 0, 80, 10, 0, 0, 5, 0]).await;
+        assert!(result.is_err());
+        assert!(replies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsupported_command() {
+        // BIND
+        let (result, replies) = accept(&server(None), &[5, 1, 0, 5, 2, 0, 1, 10, 0, 0, 5, 0, 80]).await;
+        let Err(e) = result else { panic!("BIND accepted") };
+        assert!(e.to_string().contains("unsupported SOCKS5 command 2"), "{}", e);
+        assert_eq!(replies, [5, 0, 5, REPLY_COMMAND_NOT_SUPPORTED, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn unknown_address_type() {
+        let (result, _) = accept(&server(None), &[5, 1, 0, 5, 1, 0, 5, 10, 0, 0, 5, 0, 80]).await;
+        let Err(e) = result else { panic!("unknown address type accepted") };
+        assert!(e.to_string().contains("unknown SOCKS5 address type 5"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn truncated_requests() {
+        let mut request = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&443u16.to_be_bytes());
+        for length in 0..request.len() {
+            let (result, _) = accept(&server(None), &request[..length]).await;
+            assert!(result.is_err(), "accepted {} of {} bytes", length, request.len());
+        }
+    }
+
+    #[test]
+    fn udp_datagrams() {
+        let datagram = [0, 0, 0, 1, 10, 0, 0, 5, 0, 53, b'q'];
+        assert_eq!(parse_datagram(&datagram), Some(("10.0.0.5".to_string(), 53, &b"q"[..])));
+
+        let mut datagram = vec![0, 0, 0, 4];
+        datagram.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        datagram.extend_from_slice(&[0, 53, b'q']);
+        assert_eq!(parse_datagram(&datagram), Some(("::1".to_string(), 53, &b"q"[..])));
+
+        let mut datagram = vec![0, 0, 0, 3, 11];
+        datagram.extend_from_slice(b"example.com");
+        datagram.extend_from_slice(&[0, 53, b'q']);
+        assert_eq!(parse_datagram(&datagram), Some(("example.com".to_string(), 53, &b"q"[..])));
+
+        // Fragments, unknown address types and truncated headers are dropped
+        assert_eq!(parse_datagram(&[0, 0, 1, 1, 10, 0, 0, 5, 0, 53, b'q']), None);
+        assert_eq!(parse_datagram(&[0, 0, 0, 5, 10, 0, 0, 5, 0, 53, b'q']), None);
+        for length in 0..datagram.len() - 1 {
+            assert_eq!(parse_datagram(&datagram[..length]), None, "{} bytes", length);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::connector::{AsyncStream, BoxedStream};
+use crate::copy::End;
+#[cfg(target_os = "linux")]
+use crate::ktls::{self, KtlsStream};
+use std::any::Any;
+use std::io;
+use tokio::net::TcpStream;
+
+// Zero-copy forwarding (Linux). Between two plain TCP connections, splice(2)
+// moves the data from one socket into a pipe and from the pipe into the
+// other, so it never leaves the kernel and is never copied through a buffer
+// of porture's. Only for connections whose data porture doesn't have to see:
+// no TLS (unless kTLS took it over), WebSocket, filters, mirror_target or
+// inject_delay_ms.
+
+// Default capacity of a Linux pipe
+#[cfg(target_os = "linux")]
+const DEFAULT_PIPE_SIZE: usize = 64 * 1024;
+
+// Both connections, if both are plain TCP and splice is available
+#[cfg(target_os = "linux")]
+pub fn tcp_pair<'a>(client: &'a BoxedStream, target: &'a BoxedStream) -> Option<(&'a TcpStream, &'a TcpStream)> {
+    Some((tcp_stream(client.as_ref())?, tcp_stream(target.as_ref())?))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_pair<'a>(_client: &'a BoxedStream, _target: &'a BoxedStream) -> Option<(&'a TcpStream, &'a TcpStream)> {
+    None
+}
+
+// The TCP connection of a stream tcp_pair found to be one
+pub fn into_tcp(stream: BoxedStream) -> TcpStream {
+    let stream: Box<dyn Any> = stream;
+    #[cfg(target_os = "linux")]
+    let stream = match stream.downcast::<KtlsStream>() {
+        Ok(stream) => return stream.into_inner(),
+        Err(stream) => stream,
+    };
+    match stream.downcast::<BoxedStream>() {
+        Ok(inner) => into_tcp(*inner),
+        Err(stream) => *stream.downcast::<TcpStream>().expect("stream checked by tcp_pair"),
+    }
+}
+
+// Accepted connections arrive boxed, and may be boxed again. With kTLS, the
+// socket of a TLS connection carries plaintext too.
+pub fn tcp_stream(stream: &dyn AsyncStream) -> Option<&TcpStream> {
+    let stream: &dyn Any = stream;
+    #[cfg(target_os = "linux")]
+    if let Some(stream) = stream.downcast_ref::<KtlsStream>() {
+        return Some(stream.get_ref());
+    }
+    match stream.downcast_ref::<BoxedStream>() {
+        Some(inner) => tcp_stream(inner.as_ref()),
+        None => stream.downcast_ref::<TcpStream>(),
+    }
+}
+
+// Forwards until `from` is closed, calling `on_data` with the length of
+// each chunk read
+#[cfg(target_os = "linux")]
+pub async fn forward(from: &TcpStream, to: &TcpStream, buffer_size: usize, mut on_data: impl FnMut(usize)) -> End {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    let pipe = match Pipe::new(buffer_size) {
+        Ok(pipe) => pipe,
+        Err(e) => return End::Read(e),
+    };
+    loop {
+        // The pipe is empty here, so only the socket can block
+        if let Err(e) = from.readable().await {
+            return End::Read(e);
+        }
+        let mut pending = match from.try_io(Interest::READABLE, || {
+            splice(from.as_raw_fd(), pipe.write.as_raw_fd(), pipe.capacity)
+        }) {
+            Ok(0) => return End::eof(to),
+            Ok(n) => n,
+            Err(e) if is_retryable(&e) => continue,
+            // A kTLS record that isn't data
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => match ktls::skip_control(from.as_raw_fd(), e) {
+                Ok(true) => return End::eof(to),
+                Ok(false) => continue,
+                Err(e) => return End::Read(e),
+            },
+            Err(e) => return End::Read(e),
+        };
+        on_data(pending);
+        while pending > 0 {
+            if let Err(e) = to.writable().await {
+                return End::Write(e);
+            }
+            match to.try_io(Interest::WRITABLE, || splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending)) {
+                Ok(n) => pending -= n,
+                Err(e) if is_retryable(&e) => {}
+                Err(e) => return End::Write(e),
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn forward(_from: &TcpStream, _to: &TcpStream, _buffer_size: usize, _on_data: impl FnMut(usize)) -> End {
+    End::Read(io::Error::new(io::ErrorKind::Unsupported, "splice requires Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn is_retryable(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted)
+}
+
+#[cfg(target_os = "linux")]
+struct Pipe {
+    read: std::os::fd::OwnedFd,
+    write: std::os::fd::OwnedFd,
+    capacity: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl Pipe {
+    // At least as large as buffer_size, as far as pipe-max-size allows
+    fn new(buffer_size: usize) -> io::Result<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        let mut fds = [0; 2];
+        // SAFETY: pipe2 writes two descriptors into `fds`
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both descriptors were just opened and nothing else owns them
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let capacity = if buffer_size > DEFAULT_PIPE_SIZE {
+            // SAFETY: F_SETPIPE_SZ takes an int and returns the new capacity
+            match unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, buffer_size as libc::c_int) } {
+                size if size > 0 => size as usize,
+                _ => DEFAULT_PIPE_SIZE,
+            }
+        } else {
+            DEFAULT_PIPE_SIZE
+        };
+        Ok(Self { read, write, capacity })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn splice(from: std::os::fd::RawFd, to: std::os::fd::RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both descriptors are open for the duration of the call and
+    // null offsets make splice use and advance the file positions
+    let moved = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if moved < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(moved as usize)
+}
@@ -0,0 +1,80 @@
+use crate::discovery::{pick_weighted, TargetPool};
+use crate::resolver::{self, SrvRecord};
+use log::{debug, warn};
+use std::io;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+// SRV target discovery (target_srv). A rule's target host and port come from
+// the SRV records of a name like "_minecraft._tcp.example.com", picked per
+// connection by priority and weight (RFC 2782). The records are looked up
+// again when their TTL runs out, so backends can move without a config
+// change. If a lookup fails, the previous records stay in use.
+
+// Bounds on how long records are used before looking them up again
+const MIN_REFRESH: Duration = Duration::from_secs(5);
+const MAX_REFRESH: Duration = Duration::from_secs(300);
+const RETRY: Duration = Duration::from_secs(10);
+
+pub struct SrvTargets {
+    name: String,
+    rule_name: String,
+    records: RwLock<Vec<SrvRecord>>,
+}
+
+impl SrvTargets {
+    // Does the first lookup before returning, so early connections have
+    // targets, then keeps the records fresh in the background
+    pub async fn start(name: String, rule_name: String) -> Arc<Self> {
+        let targets = Arc::new(Self { name, rule_name, records: RwLock::new(Vec::new()) });
+        let next = targets.refresh().await;
+        tokio::spawn(refresh_loop(Arc::downgrade(&targets), next));
+        targets
+    }
+
+    // Returns how long to wait before the next lookup
+    async fn refresh(&self) -> Duration {
+        match resolver::lookup_srv(&self.name).await {
+            Ok((records, valid_until)) => {
+                if records.is_empty() {
+                    warn!("TCP forwarder '{}': SRV {} lists no targets", self.rule_name, self.name);
+                }
+                debug!("TCP forwarder '{}': SRV {} has {} targets", self.rule_name, self.name, records.len());
+                *self.records.write().unwrap() = records;
+                valid_until.saturating_duration_since(Instant::now()).clamp(MIN_REFRESH, MAX_REFRESH)
+            }
+            Err(e) => {
+                warn!("TCP forwarder '{}': {}, keeping the previous targets", self.rule_name, e);
+                RETRY
+            }
+        }
+    }
+}
+
+impl TargetPool for SrvTargets {
+    fn describe(&self) -> String {
+        format!("SRV {}", self.name)
+    }
+
+    // Lowest priority first; within a priority, at random in proportion to
+    // weight. Records of weight 0 are only picked when all of them are.
+    fn pick(&self) -> io::Result<(String, u16)> {
+        let records = self.records.read().unwrap();
+        let priority = records.iter().map(|record| record.priority).min()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no SRV targets for {}", self.name)))?;
+        let candidates: Vec<&SrvRecord> = records.iter().filter(|record| record.priority == priority).collect();
+        let chosen = pick_weighted(&candidates, |record| u32::from(record.weight));
+        Ok((chosen.host.clone(), chosen.port))
+    }
+}
+
+// Ends once the rule's connector is gone
+async fn refresh_loop(targets: Weak<SrvTargets>, mut next: Duration) {
+    loop {
+        tokio::time::sleep(next).await;
+        let Some(targets) = targets.upgrade() else {
+            return;
+        };
+        next = targets.refresh().await;
+    }
+}
@@ -0,0 +1,59 @@
+use crate::ban::BanList;
+use crate::capture::CaptureRegistry;
+use crate::config::{ConsulConfig, KubernetesConfig};
+use crate::geoip::GeoIp;
+use crate::handoff::OpenConnections;
+use crate::sockmap::SockMap;
+use crate::tls::CertRegistry;
+use crate::tunnel::TunnelServer;
+use crate::udp_forwarder::SessionRegistry;
+use crate::uring::Ring;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+// Process-wide state shared by every forwarder
+pub struct SharedState {
+    pub buffer_size: usize,
+    pub connection_cap: Option<Arc<Semaphore>>,
+    pub geoip: Option<GeoIp>,
+    pub bans: Option<BanList>,
+    pub certificates: CertRegistry,
+    pub tunnel: Option<Arc<TunnelServer>>,
+    pub consul: Option<ConsulConfig>,
+    pub kubernetes: Option<KubernetesConfig>,
+    pub udp_sessions: SessionRegistry,
+    pub captures: CaptureRegistry,
+    pub open_connections: OpenConnections,
+    // Set on shutdown, while open connections and sessions finish
+    pub draining: AtomicBool,
+    // With io_backend = "io_uring"
+    pub ring: Option<Arc<Ring>>,
+    // With sockmap = true
+    pub sockmap: Option<Arc<SockMap>>,
+    // With ktls = true, if the kernel supports it
+    pub ktls: bool,
+}
+
+// Without any of the [global] settings, for a forwarder started on its own
+impl Default for SharedState {
+    fn default() -> Self {
+        Self {
+            buffer_size: 8192,
+            connection_cap: None,
+            geoip: None,
+            bans: None,
+            certificates: CertRegistry::default(),
+            tunnel: None,
+            consul: None,
+            kubernetes: None,
+            udp_sessions: SessionRegistry::default(),
+            captures: CaptureRegistry::default(),
+            open_connections: OpenConnections::default(),
+            draining: AtomicBool::new(false),
+            ring: None,
+            sockmap: None,
+            ktls: false,
+        }
+    }
+}
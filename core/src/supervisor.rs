@@ -0,0 +1,241 @@
+use crate::config::{TcpRule, UdpRule};
+use crate::rule_log;
+use crate::state::SharedState;
+use crate::tcp_forwarder::TcpForwarder;
+use crate::udp_forwarder::UdpForwarder;
+use log::{error, info, warn, LevelFilter};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+// Runs the forwarding rules and applies new rule sets without a restart
+// (SIGHUP, etcd). Rules are compared by their whole configuration: an
+// unchanged rule keeps running untouched, a changed one is stopped and
+// started again. Connections already accepted by a stopped TCP rule carry on
+// until they close.
+
+// Delay before restarting a failed forwarder, doubled for each failure in a
+// row up to the maximum
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+// The same for a forwarder whose address is busy as it starts, for up to
+// [global] bind_retry
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(250);
+const MAX_BIND_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// Tells `apply` that a starting rule's listeners are up, with their
+// addresses. Dropped unsignalled, the rule failed to start.
+pub struct Bound(Option<oneshot::Sender<Vec<SocketAddr>>>);
+
+impl Bound {
+    pub fn new(tx: oneshot::Sender<Vec<SocketAddr>>) -> Self {
+        Self(Some(tx))
+    }
+
+    pub fn signal(&mut self, local_addrs: Vec<SocketAddr>) {
+        if let Some(tx) = self.0.take() {
+            let _ = tx.send(local_addrs);
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.0.is_some()
+    }
+
+    fn fail(&mut self) {
+        self.0 = None;
+    }
+}
+
+struct Running {
+    rule_name: String,
+    tcp: bool,
+    task: JoinHandle<()>,
+}
+
+pub struct Supervisor {
+    shared: Arc<SharedState>,
+    running: HashMap<String, Running>,
+    exited_tx: mpsc::UnboundedSender<String>,
+    bind_retry: Duration,
+}
+
+impl Supervisor {
+    // The receiver yields the key of every rule whose forwarder stopped on
+    // its own without failing; pass it to `exited`
+    pub fn new(shared: Arc<SharedState>, bind_retry: Duration) -> (Self, mpsc::UnboundedReceiver<String>) {
+        let (exited_tx, exited_rx) = mpsc::unbounded_channel();
+        (Self { shared, running: HashMap::new(), exited_tx, bind_retry }, exited_rx)
+    }
+
+    // Returns once every newly started rule has bound its listeners, or
+    // failed to, with the names of those that failed
+    pub async fn apply<'a>(
+        &mut self,
+        tcp_rules: impl IntoIterator<Item = &'a TcpRule>,
+        udp_rules: impl IntoIterator<Item = &'a UdpRule>,
+    ) -> Vec<String> {
+        let mut wanted: HashMap<String, Rule> = HashMap::new();
+        let rules = tcp_rules.into_iter().map(|rule| Rule::Tcp(Box::new(rule.clone())))
+            .chain(udp_rules.into_iter().map(|rule| Rule::Udp(Box::new(rule.clone()))));
+        for rule in rules {
+            // Identical rules would clash anyway, but each still gets a run
+            let base = rule.key();
+            let mut key = base.clone();
+            let mut n = 1;
+            while wanted.contains_key(&key) {
+                n += 1;
+                key = format!("{} #{}", base, n);
+            }
+            wanted.insert(key, rule);
+        }
+
+        // Stop first, so changed rules can bind their ports again
+        let stale: Vec<String> = self.running.keys().filter(|key| !wanted.contains_key(*key)).cloned().collect();
+        for key in &stale {
+            self.stop(key).await;
+        }
+
+        let mut bound = Vec::new();
+        for (key, rule) in wanted {
+            if self.running.contains_key(&key) {
+                continue;
+            }
+            let rule_name = rule.rule_name();
+            let tcp = matches!(rule, Rule::Tcp(_));
+            let (bound_tx, bound_rx) = oneshot::channel();
+            let task = self.spawn(key.clone(), rule, bound_tx);
+            let name = rule_name.clone();
+            bound.push(async move { (name, bound_rx.await.is_ok()) });
+            self.running.insert(key, Running { rule_name, tcp, task });
+        }
+        let started = bound.len();
+        let failed: Vec<String> = futures::future::join_all(bound).await.into_iter()
+            .filter(|(_, bound)| !bound)
+            .map(|(rule_name, _)| rule_name)
+            .collect();
+        info!("Started {} forwarders, stopped {}, {} running", started, stale.len(), self.running.len());
+        failed
+    }
+
+    // Stops the TCP rules' listeners for a shutdown. Their connections carry
+    // on, as do the UDP rules, which start no new sessions once
+    // SharedState::draining is set.
+    pub async fn stop_accepting(&mut self) {
+        let tcp: Vec<String> = self.running.iter().filter(|(_, running)| running.tcp).map(|(key, _)| key.clone()).collect();
+        for key in &tcp {
+            self.stop(key).await;
+        }
+    }
+
+    pub fn exited(&mut self, key: &str) {
+        if self.running.get(key).is_some_and(|running| running.task.is_finished())
+            && let Some(running) = self.running.remove(key)
+        {
+            self.forget(&running.rule_name, running.tcp);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    async fn stop(&mut self, key: &str) {
+        if let Some(running) = self.running.remove(key) {
+            running.task.abort();
+            let _ = running.task.await;
+            self.forget(&running.rule_name, running.tcp);
+            info!("Stopped forwarder '{}'", running.rule_name);
+        }
+    }
+
+    // A forwarder that fails, e.g. to bind or on a socket error, is started
+    // again after a delay that grows with each failure in a row
+    fn spawn(&self, key: String, rule: Rule, bound: oneshot::Sender<Vec<SocketAddr>>) -> JoinHandle<()> {
+        let shared = self.shared.clone();
+        let exited_tx = self.exited_tx.clone();
+        let retry_binds_until = Instant::now() + self.bind_retry;
+        let log_level = rule.log_level();
+        tokio::spawn(rule_log::scope(log_level, async move {
+            let mut bound = Bound::new(bound);
+            let mut delay = RESTART_DELAY;
+            let mut bind_delay = BIND_RETRY_DELAY;
+            loop {
+                let started = Instant::now();
+                let (protocol, result) = match &rule {
+                    Rule::Tcp(rule) => ("TCP", TcpForwarder::new((**rule).clone(), shared.clone()).start(&mut bound).await),
+                    Rule::Udp(rule) => ("UDP", UdpForwarder::new((**rule).clone(), shared.clone()).start(&mut bound).await),
+                };
+                let Err(e) = result else {
+                    break;
+                };
+                // Not started yet, so `apply` keeps waiting
+                if bound.is_pending() && address_busy(&e) && Instant::now() + bind_delay < retry_binds_until {
+                    if bind_delay == BIND_RETRY_DELAY {
+                        warn!("{} forwarder '{}': {:#}, retrying", protocol, rule.rule_name(), e);
+                    }
+                    tokio::time::sleep(bind_delay).await;
+                    bind_delay = (bind_delay * 2).min(MAX_BIND_RETRY_DELAY);
+                    continue;
+                }
+                bound.fail();
+                // Failing again after running for a while starts over
+                if started.elapsed() >= MAX_RESTART_DELAY {
+                    delay = RESTART_DELAY;
+                }
+                error!("{} forwarder '{}' failed: {:#}, restarting in {}s", protocol, rule.rule_name(), e, delay.as_secs());
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RESTART_DELAY);
+            }
+            let _ = exited_tx.send(key);
+        }))
+    }
+
+    // Drops what a stopped rule left in the shared registries
+    fn forget(&self, rule_name: &str, tcp: bool) {
+        self.shared.certificates.unregister(rule_name);
+        self.shared.udp_sessions.unregister(rule_name);
+        self.shared.captures.unregister(if tcp { "tcp" } else { "udp" }, rule_name);
+    }
+}
+
+// Another socket holds the address, e.g. a process that is still draining,
+// or it isn't configured on an interface yet
+fn address_busy(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| matches!(e.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable))
+}
+
+enum Rule {
+    Tcp(Box<TcpRule>),
+    Udp(Box<UdpRule>),
+}
+
+impl Rule {
+    // The rule's whole configuration, so any change makes a new key
+    fn key(&self) -> String {
+        match self {
+            Rule::Tcp(rule) => format!("tcp {}", serde_json::to_string(rule).unwrap_or_default()),
+            Rule::Udp(rule) => format!("udp {}", serde_json::to_string(rule).unwrap_or_default()),
+        }
+    }
+
+    fn rule_name(&self) -> String {
+        match self {
+            Rule::Tcp(rule) => rule.rule_name(),
+            Rule::Udp(rule) => rule.rule_name(),
+        }
+    }
+
+    fn log_level(&self) -> Option<LevelFilter> {
+        match self {
+            Rule::Tcp(rule) => rule.log_level_filter(),
+            Rule::Udp(rule) => rule.log_level_filter(),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+#[cfg(unix)]
+use crate::handoff;
+#[cfg(unix)]
+use log::info;
+use socket2::{Socket, Type};
+#[cfg(unix)]
+use std::env;
+use std::io;
+#[cfg(unix)]
+use std::os::fd::{AsFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(unix)]
+use std::sync::Mutex;
+
+// systemd socket activation. A rule with bind_addr = "systemd:<name>" serves
+// on a socket systemd bound and passed in (LISTEN_FDS), picked by its
+// FileDescriptorName= or by its position among the passed sockets. systemd
+// can bind privileged ports for an unprivileged porture, and start it on the
+// first connection. Each rule gets its own copy of the socket, so a rule
+// restarted by a reload serves on it again.
+
+const PREFIX: &str = "systemd:";
+// sd_listen_fds(3): passed sockets start right after stdin/stdout/stderr
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+// Passed sockets with their names, in order
+#[cfg(unix)]
+static SOCKETS: Mutex<Vec<(String, OwnedFd)>> = Mutex::new(Vec::new());
+
+// The socket name of a "systemd:" bind_addr, or None for other addresses
+pub fn parse(addr: &str) -> Option<&str> {
+    addr.strip_prefix(PREFIX)
+}
+
+pub fn validate(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.contains(':') || name.chars().any(char::is_control) {
+        anyhow::bail!("invalid systemd socket name 'systemd:{}'", name);
+    }
+    if !cfg!(unix) {
+        anyhow::bail!("systemd socket 'systemd:{}' requires a Unix platform", name);
+    }
+    Ok(())
+}
+
+// Takes the sockets systemd passed to this process, if any. Called once at
+// startup.
+#[cfg(unix)]
+pub fn init() {
+    let Some(count) = passed_count() else {
+        return;
+    };
+    let names: Vec<String> = env::var("LISTEN_FDNAMES").unwrap_or_default()
+        .split(':')
+        .map(str::to_string)
+        .collect();
+    // Child processes must not mistake the sockets for their own
+    unsafe {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let mut sockets = SOCKETS.lock().unwrap();
+    for index in 0..count {
+        let fd = LISTEN_FDS_START + index as RawFd;
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        let name = names.get(index).cloned().unwrap_or_default();
+        sockets.push((name, unsafe { OwnedFd::from_raw_fd(fd) }));
+    }
+    let names: Vec<&str> = sockets.iter().map(|(name, _)| name.as_str()).collect();
+    info!("systemd passed {} sockets: {}", count, names.join(", "));
+}
+
+#[cfg(not(unix))]
+pub fn init() {}
+
+// The number of sockets passed, if they are meant for this process
+#[cfg(unix)]
+fn passed_count() -> Option<usize> {
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    env::var("LISTEN_FDS").ok()?.parse().ok()
+}
+
+// Key under which the socket is offered on upgrades
+pub fn key(name: &str) -> String {
+    format!("systemd {}", name)
+}
+
+// A nonblocking copy of the socket passed as `name` (its name, or else its
+// position), or the one a previous process handed over
+#[cfg(unix)]
+pub fn socket(name: &str, expected: Type) -> io::Result<Socket> {
+    let socket = match handoff::inherited(&key(name)) {
+        Some(socket) => socket,
+        None => {
+            let sockets = SOCKETS.lock().unwrap();
+            let passed = sockets.iter().find(|(passed, _)| passed == name)
+                .or_else(|| sockets.get(name.parse::<usize>().ok()?))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("systemd passed no socket '{}'", name)))?;
+            Socket::from(passed.1.as_fd().try_clone_to_owned()?)
+        }
+    };
+    if socket.r#type()? != expected {
+        let kind = if expected == Type::STREAM { "stream" } else { "datagram" };
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("systemd socket '{}' is not a {} socket", name, kind)));
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+pub fn socket(name: &str, _expected: Type) -> io::Result<Socket> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("systemd socket '{}' requires a Unix platform", name)))
+}
@@ -0,0 +1,22 @@
+use crate::rule_log;
+use tokio::task::JoinHandle;
+
+// Aborts a background task when dropped, so work a forwarder started (knock
+// listeners, certificate renewal, session cleanup) stops with the rule. The
+// task logs at the level of the rule it was started for.
+pub struct AbortOnDrop(JoinHandle<()>);
+
+impl AbortOnDrop {
+    pub fn spawn<F>(future: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self(tokio::spawn(rule_log::inherit(future)))
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,919 @@
+use crate::acl::{AccessFilter, Acl};
+use crate::acme;
+use crate::banner::BannerFilter;
+use crate::chaos::Latency;
+use crate::ban::Offense;
+use crate::config::{OverflowPolicy, TcpMode, TcpRule};
+use crate::connector::{AsyncStream, BoxedStream, HostRouter, TargetConnector};
+use crate::copy::{self, End};
+use crate::filter::{Chain, Direction, Flow, FlowFilters, StreamFilter};
+use crate::handoff;
+use crate::knock::KnockGate;
+use crate::limits::{BandwidthFilter, PerIpLimiter, TokenBucket};
+#[cfg(feature = "lua")]
+use crate::lua::LuaFilter;
+use crate::mirror::Mirror;
+use crate::proxy_protocol;
+use crate::record::Recorder;
+use crate::rewrite::RewriteFilter;
+use crate::rule_log;
+use crate::http;
+use crate::ktls::{self, RecordStream};
+use crate::sni;
+use crate::sniff::{self, SniffRouter};
+use crate::sockmap;
+use crate::sockopt;
+use crate::socks5::{self, Socks5Server};
+use crate::splice;
+use crate::consul::ConsulService;
+use crate::kubernetes::ServiceEndpoints;
+use crate::srv::SrvTargets;
+use crate::state::SharedState;
+use crate::supervisor::Bound;
+use crate::systemd;
+use crate::tls::{CertFiles, CertResolver, TlsTerminator};
+use crate::transparent;
+use crate::udp_tunnel;
+use crate::uring::Fd;
+#[cfg(feature = "wasm")]
+use crate::wasm::WasmFilter;
+#[cfg(unix)]
+use crate::unix_socket;
+use crate::websocket;
+use anyhow::{Context, Result};
+use log::{error, info, debug, warn};
+#[cfg(unix)]
+use socket2::Domain;
+use socket2::Type;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, sleep_until, timeout, Instant};
+
+const SHORT_SESSION: Duration = Duration::from_secs(1);
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(3);
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+const PROXY_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How a connection's target is chosen
+#[derive(Clone)]
+enum Target {
+    Fixed(Arc<TargetConnector>),
+    Sni(Arc<HostRouter>),
+    Sniff(Arc<SniffRouter>),
+    Http(Arc<HostRouter>),
+    Socks5(Arc<Socks5Server>),
+    // Connection settings for the targets CONNECT requests name
+    HttpConnect(Arc<TargetConnector>),
+    // UDP endpoint for datagrams unwrapped from the connection
+    UdpInTcp(String),
+    // Unwraps the WebSocket upgrade before forwarding like Fixed
+    WebSocket(Arc<TargetConnector>),
+    Redirect(Arc<RedirectTarget>),
+}
+
+// A connection's slots under max_connections and max_total_connections
+type Permits = (Option<OwnedSemaphorePermit>, Option<OwnedSemaphorePermit>);
+
+// Forwards each connection to its original destination, if allowed
+struct RedirectTarget {
+    // Connection settings (timeout, upstream proxy) for the destinations
+    template: Arc<TargetConnector>,
+    destinations: Acl,
+    ports: Option<Vec<u16>>,
+}
+
+impl RedirectTarget {
+    fn permits(&self, destination: SocketAddr) -> bool {
+        self.destinations.permits(destination.ip())
+            && self.ports.as_ref().is_none_or(|ports| ports.contains(&destination.port()))
+    }
+}
+
+// Unix socket clients have no address of their own; they count as local
+#[cfg(unix)]
+const UNIX_PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+enum Listener {
+    Tcp(Arc<TcpListener>),
+    #[cfg(unix)]
+    Unix(Arc<UnixListener>),
+    // Reports each connection's pre-REDIRECT destination as its local address
+    Redirect(Arc<TcpListener>),
+}
+
+impl Listener {
+    // One listener per bind address
+    fn bind_all(rule: &TcpRule) -> Result<Vec<Self>> {
+        if let Some(name) = rule.bind_systemd_socket() {
+            return Ok(vec![Self::activated(rule, name)?]);
+        }
+        #[cfg(unix)]
+        if let Some(path) = rule.bind_unix_path() {
+            return Ok(vec![Listener::Unix(unix_socket::listener(path)?)]);
+        }
+        rule.bind_socket_addrs()?.into_iter().map(|addr| Self::bind(rule, addr)).collect()
+    }
+
+    fn bind(rule: &TcpRule, addr: SocketAddr) -> Result<Self> {
+        let listener = sockopt::tcp_listener(addr, rule.bind_options())
+            .with_context(|| format!("failed to listen on {}", addr))?;
+        Self::configure(rule, listener)
+    }
+
+    // The TCP or Unix socket systemd bound for the rule
+    fn activated(rule: &TcpRule, name: &str) -> Result<Self> {
+        let socket = systemd::socket(name, Type::STREAM)?;
+        #[cfg(unix)]
+        if socket.local_addr()?.domain() == Domain::UNIX {
+            let listener = std::os::unix::net::UnixListener::from(OwnedFd::from(socket));
+            return Ok(Listener::Unix(handoff::offer(systemd::key(name), UnixListener::from_std(listener)?)));
+        }
+        let listener = handoff::offer(systemd::key(name), TcpListener::from_std(socket.into())?);
+        Self::configure(rule, listener)
+    }
+
+    fn configure(rule: &TcpRule, listener: Arc<TcpListener>) -> Result<Self> {
+        // Accepted connections inherit the device binding, DSCP, keepalive
+        // and socket options, so replies to clients are marked like traffic
+        // to the target and dead clients are noticed
+        if let Some(name) = &rule.bind_device {
+            sockopt::bind_device(&listener, name)?;
+        }
+        if let Some(dscp) = rule.dscp {
+            sockopt::set_dscp(&listener, dscp, listener.local_addr()?.is_ipv6())?;
+        }
+        if let Some(keepalive) = &rule.keepalive {
+            sockopt::set_keepalive(&listener, keepalive)?;
+        }
+        if let Some(options) = &rule.socket {
+            sockopt::set_options(&listener, options)?;
+        }
+        Ok(match rule.mode() {
+            TcpMode::Redirect => Listener::Redirect(listener),
+            _ => Listener::Tcp(listener),
+        })
+    }
+
+    // None for Unix sockets
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) | Listener::Redirect(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    // Returns the stream with its peer and local addresses
+    async fn accept(&self) -> io::Result<(BoxedStream, SocketAddr, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                let local_addr = stream.local_addr()?;
+                Ok((Box::new(stream), peer_addr, local_addr))
+            }
+            Listener::Redirect(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                // Without a REDIRECT rule there is no NAT entry, or conntrack
+                // reports the listener itself and forwarding there would loop
+                let local_addr = stream.local_addr()?;
+                let destination = transparent::original_destination(&stream).ok()
+                    .filter(|destination| *destination != local_addr)
+                    .ok_or_else(|| io::Error::other(format!("connection from {} was not redirected", peer_addr)))?;
+                Ok((Box::new(stream), peer_addr, destination))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), UNIX_PEER, UNIX_PEER))
+            }
+        }
+    }
+}
+
+// Waits for a connection on any of the rule's listeners
+async fn accept_any(listeners: &[Listener]) -> io::Result<(BoxedStream, SocketAddr, SocketAddr)> {
+    let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+    futures::future::select_all(accepts).await.0
+}
+
+// Tells a proxy client whether the tunnel it asked for was opened
+#[derive(Clone, Copy)]
+enum ProxyReply {
+    Socks5,
+    HttpConnect,
+}
+
+impl ProxyReply {
+    fn success(self) -> Vec<u8> {
+        match self {
+            ProxyReply::Socks5 => socks5::connect_succeeded(),
+            ProxyReply::HttpConnect => http::CONNECT_ESTABLISHED.to_vec(),
+        }
+    }
+
+    fn failure(self, error: &anyhow::Error) -> Vec<u8> {
+        match self {
+            ProxyReply::Socks5 => socks5::connect_failed(error),
+            ProxyReply::HttpConnect => http::CONNECT_FAILED.to_vec(),
+        }
+    }
+}
+
+pub struct TcpForwarder {
+    rule: TcpRule,
+    shared: Arc<SharedState>,
+    connection_limit: Option<Arc<Semaphore>>,
+    per_ip_limit: Option<Arc<PerIpLimiter>>,
+    accept_rate: Option<Mutex<TokenBucket>>,
+    // Run after the rule's own filters
+    filters: Vec<Arc<dyn StreamFilter>>,
+}
+
+impl TcpForwarder {
+    pub fn new(rule: TcpRule, shared: Arc<SharedState>) -> Self {
+        let connection_limit = rule.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        let per_ip_limit = rule.max_connections_per_ip.map(|n| Arc::new(PerIpLimiter::new(n)));
+        // Allow bursts of up to one second's worth of connections
+        let accept_rate = rule.max_new_connections_per_sec
+            .map(|n| Mutex::new(TokenBucket::new(n as f64, n as f64)));
+        Self {
+            rule,
+            shared,
+            connection_limit,
+            per_ip_limit,
+            accept_rate,
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn with_filters(mut self, filters: Vec<Arc<dyn StreamFilter>>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    pub async fn start(&self, bound: &mut Bound) -> Result<()> {
+        let bind_addr = self.rule.bind_endpoint();
+        let chain = self.filter_chain()?;
+        // Certificate renewal runs for as long as the rule does
+        let mut _renewal = None;
+        let tls_terminator = match &self.rule.tls {
+            Some(tls) => match (&tls.cert, &tls.key, &tls.acme) {
+                (Some(cert), Some(key), _) => {
+                    let files = CertFiles::load(self.rule.rule_name(), cert, key)?;
+                    self.shared.certificates.register(files.clone());
+                    Some(Arc::new(TlsTerminator::new(files.resolver())))
+                }
+                (_, _, Some(acme_config)) => {
+                    let certs = Arc::new(CertResolver::default());
+                    _renewal = Some(acme::spawn(acme_config.clone(), certs.clone(), self.rule.rule_name()));
+                    Some(Arc::new(TlsTerminator::new(certs)))
+                }
+                _ => anyhow::bail!("TCP rule '{}': tls needs both cert and key, or acme", self.rule.rule_name()),
+            },
+            None => None,
+        };
+        let mut connector = TargetConnector::from_rule(&self.rule)?;
+        if self.shared.ktls {
+            connector = connector.with_ktls();
+        }
+        if self.rule.tunnel.unwrap_or(false) {
+            let tunnel = self.shared.tunnel.clone()
+                .ok_or_else(|| anyhow::anyhow!("TCP rule '{}': no tunnel server running", self.rule.rule_name()))?;
+            connector = connector.via_tunnel(tunnel);
+        }
+        if let Some(name) = &self.rule.target_srv {
+            connector = connector.via_pool(SrvTargets::start(name.clone(), self.rule.rule_name()).await);
+        }
+        if let Some(service) = &self.rule.target_consul {
+            let consul = self.shared.consul.clone()
+                .ok_or_else(|| anyhow::anyhow!("TCP rule '{}': no [discovery.consul] configured", self.rule.rule_name()))?;
+            connector = connector.via_pool(ConsulService::start(consul, service.clone(), self.rule.rule_name()).await);
+        }
+        if let Some(service) = &self.rule.target_kubernetes {
+            let kubernetes = self.shared.kubernetes.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("TCP rule '{}': no [discovery.kubernetes] configured", self.rule.rule_name()))?;
+            let endpoints = ServiceEndpoints::start(
+                kubernetes,
+                service,
+                self.rule.target_kubernetes_port.clone(),
+                self.rule.rule_name(),
+            ).await?;
+            connector = connector.via_pool(endpoints);
+        }
+        let connector = Arc::new(connector);
+        let target = match self.rule.mode() {
+            TcpMode::Forward | TcpMode::WebsocketClient | TcpMode::Echo | TcpMode::Discard => Target::Fixed(connector),
+            TcpMode::WebsocketServer => Target::WebSocket(connector),
+            TcpMode::Sni => {
+                let routes = self.rule.sni_routes.clone().unwrap_or_default();
+                Target::Sni(Arc::new(HostRouter::new(&routes, connector)?))
+            }
+            TcpMode::Http => {
+                let routes = self.rule.host_routes.clone().unwrap_or_default();
+                Target::Http(Arc::new(HostRouter::new(&routes, connector)?))
+            }
+            TcpMode::Sniff => Target::Sniff(Arc::new(SniffRouter::from_rule(&self.rule, connector)?)),
+            TcpMode::Socks5 => Target::Socks5(Arc::new(Socks5Server::new(self.rule.socks5_users.clone(), connector))),
+            TcpMode::HttpConnect => Target::HttpConnect(connector),
+            TcpMode::UdpInTcpServer => Target::UdpInTcp(self.rule.target_endpoint()),
+            TcpMode::Redirect => Target::Redirect(Arc::new(RedirectTarget {
+                template: connector,
+                destinations: self.rule.redirect_filter()?,
+                ports: self.rule.redirect_ports.clone(),
+            })),
+        };
+        let listeners = Listener::bind_all(&self.rule)?;
+        // Validation limits knock to rules with a single bind_addr
+        let knock_gate = match &self.rule.knock {
+            Some(knock) => Some(KnockGate::start(self.rule.bind_socket_addrs()?[0].ip(), knock, self.rule.rule_name()).await?),
+            None => None,
+        };
+        
+        info!("TCP forwarder '{}' listening on {}", 
+              self.rule.rule_name(), bind_addr);
+        if self.rule.mode().is_proxy() {
+            info!("TCP listener {} acting as a proxy (mode = {})", 
+                  bind_addr, self.rule.mode().as_str());
+        } else {
+            info!("TCP forwarding {} -> {}", 
+                  bind_addr, self.rule.target_endpoint());
+        }
+        bound.signal(listeners.iter().filter_map(Listener::local_addr).collect());
+
+        // Connections whose PROXY header has been read, tagged with the real
+        // client address, and holding their connection slots
+        let (proxied_tx, mut proxied_rx) = mpsc::channel::<(BoxedStream, SocketAddr, SocketAddr, Permits)>(64);
+        let trusted_proxies = self.rule.trusted_proxies()?;
+
+        loop {
+            // In queue mode, hold off accepting until a connection slot is free
+            let queued_permit = match &self.connection_limit {
+                Some(limit) if self.rule.overflow_policy() == OverflowPolicy::Queue => {
+                    Some(limit.clone().acquire_owned().await?)
+                }
+                _ => None,
+            };
+
+            let (client_stream, client_addr, local_addr, permits) = tokio::select! {
+                result = accept_any(&listeners) => match result {
+                    // The real client address is only known once the header has
+                    // been read, so do that off the accept loop and come back
+                    Ok((client_stream, peer_addr, local_addr)) if self.rule.accept_proxy_protocol.unwrap_or(false) => {
+                        if !trusted_proxies.permits(peer_addr.ip()) {
+                            warn!("TCP forwarder '{}' rejecting {}: not in trusted_proxies", 
+                                  self.rule.rule_name(), peer_addr);
+                            continue;
+                        }
+                        // Slots are taken before the header is waited for, so
+                        // slow headers can't pile up past the limits
+                        let Some(permits) = self.permits(queued_permit, peer_addr) else {
+                            continue;
+                        };
+                        let proxied_tx = proxied_tx.clone();
+                        tokio::spawn(rule_log::inherit(async move {
+                            if let Some((client_stream, client_addr, local_addr)) = read_proxy_header(client_stream, peer_addr, local_addr).await {
+                                let _ = proxied_tx.send((client_stream, client_addr, local_addr, permits)).await;
+                            }
+                        }));
+                        continue;
+                    }
+                    Ok((client_stream, client_addr, local_addr)) => (client_stream, client_addr, local_addr, None),
+                    Err(e) => {
+                        error!("Failed to accept TCP connection: {}", e);
+                        continue;
+                    }
+                },
+                Some((client_stream, client_addr, local_addr, permits)) = proxied_rx.recv() => {
+                    (client_stream, client_addr, local_addr, Some(permits))
+                }
+            };
+
+            debug!("New TCP connection from {}", client_addr);
+
+            if let Some(bans) = &self.shared.bans
+                && (bans.is_banned(client_addr.ip())
+                    || bans.record(client_addr.ip(), Offense::Connect))
+            {
+                debug!("TCP forwarder '{}' dropping banned client {}", 
+                       self.rule.rule_name(), client_addr);
+                continue;
+            }
+
+            if let Some(gate) = &knock_gate
+                && !gate.is_unlocked(client_addr.ip())
+            {
+                debug!("TCP forwarder '{}' dropping {}: knock sequence not completed", 
+                       self.rule.rule_name(), client_addr);
+                continue;
+            }
+
+            let Some(filters) = chain.connect(&Flow { client_addr, local_addr }) else {
+                continue;
+            };
+
+            // Logged at debug level so a connection flood doesn't also flood the logs
+            if let Some(bucket) = &self.accept_rate
+                && !bucket.lock().unwrap().try_take(1.0)
+            {
+                debug!("TCP forwarder '{}' over max_new_connections_per_sec, dropping {}", 
+                       self.rule.rule_name(), client_addr);
+                continue;
+            }
+
+            let permits = match permits {
+                Some(permits) => permits,
+                None => match self.permits(queued_permit, client_addr) {
+                    Some(permits) => permits,
+                    None => continue,
+                },
+            };
+
+            let ip_guard = match &self.per_ip_limit {
+                Some(limit) => match limit.try_acquire(client_addr.ip()) {
+                    Some(guard) => Some(guard),
+                    None => {
+                        warn!("TCP forwarder '{}' at max_connections_per_ip for {}, rejecting", 
+                              self.rule.rule_name(), client_addr.ip());
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            
+            let rule = self.rule.clone();
+            let shared = self.shared.clone();
+            let tls_terminator = tls_terminator.clone();
+            let target = target.clone();
+            let open = shared.open_connections.track();
+            
+            tokio::spawn(rule_log::inherit(async move {
+                let _permits = (permits, ip_guard, open);
+                let started = Instant::now();
+                // kTLS can only take over at a TLS record boundary
+                let client_stream: BoxedStream = match &tls_terminator {
+                    Some(_) if shared.ktls => Box::new(RecordStream::new(client_stream)),
+                    _ => client_stream,
+                };
+                let result = match tls_terminator {
+                    Some(terminator) => match timeout(TLS_HANDSHAKE_TIMEOUT, terminator.accept(client_stream)).await {
+                        Ok(Ok(None)) => {
+                            debug!("Answered ACME validation from {}", client_addr);
+                            return;
+                        }
+                        Ok(Ok(Some(tls_stream))) => {
+                            handle_tcp_client(tls_stream, client_addr, local_addr, rule, &target, &shared, filters).await
+                        }
+                        Ok(Err(e)) => Err(anyhow::anyhow!("TLS handshake with {} failed: {}", client_addr, e)),
+                        Err(_) => Err(anyhow::anyhow!("TLS handshake with {} timed out", client_addr)),
+                    },
+                    None => handle_tcp_client(client_stream, client_addr, local_addr, rule, &target, &shared, filters).await,
+                };
+                match result {
+                    // Connected and hung up within a second without sending anything
+                    Ok(0) if started.elapsed() < SHORT_SESSION => {
+                        if let Some(bans) = &shared.bans {
+                            bans.record(client_addr.ip(), Offense::ShortSession);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("TCP connection error: {}", e),
+                }
+            }));
+        }
+    }
+
+    // A slot under max_connections (the one queued for, if any) and under
+    // the global cap, or None if either is full
+    fn permits(&self, queued_permit: Option<OwnedSemaphorePermit>, client_addr: SocketAddr) -> Option<Permits> {
+        let permit = match (queued_permit, &self.connection_limit) {
+            (Some(permit), _) => Some(permit),
+            (None, Some(limit)) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    warn!("TCP forwarder '{}' at max_connections, rejecting {}", 
+                          self.rule.rule_name(), client_addr);
+                    return None;
+                }
+            },
+            (None, None) => None,
+        };
+
+        // The global cap always rejects so one busy rule can't exhaust fds
+        let cap_permit = match &self.shared.connection_cap {
+            Some(cap) => match cap.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    warn!("Global max_total_connections reached, rejecting {} on '{}'", 
+                          client_addr, self.rule.rule_name());
+                    return None;
+                }
+            },
+            None => None,
+        };
+        Some((permit, cap_permit))
+    }
+
+    // Allow/deny lists, rate limits, rewrites, banners, wasm_filter,
+    // lua_script, capture_file and record_dir, then the filters added in code
+    fn filter_chain(&self) -> Result<Chain<dyn StreamFilter>> {
+        let access = AccessFilter::new(
+            self.rule.rule_name(),
+            self.rule.acl()?,
+            self.rule.country_filter()?,
+            self.shared.clone(),
+        );
+        let mut filters: Vec<Arc<dyn StreamFilter>> = vec![Arc::new(access)];
+        if let Some(bandwidth) = BandwidthFilter::new(self.rule.rate_limit_kbps, self.rule.client_rate_limit_kbps) {
+            filters.push(Arc::new(bandwidth));
+        }
+        if let Some(rewrites) = &self.rule.rewrite {
+            filters.push(Arc::new(RewriteFilter::new(rewrites)?));
+        }
+        if let Some(banners) = &self.rule.banner {
+            filters.push(Arc::new(BannerFilter::new(banners)?));
+        }
+        #[cfg(feature = "wasm")]
+        if let Some(path) = &self.rule.wasm_filter {
+            filters.push(Arc::new(WasmFilter::load(path, self.rule.rule_name())?));
+        }
+        #[cfg(feature = "lua")]
+        if let Some(path) = &self.rule.lua_script {
+            filters.push(Arc::new(LuaFilter::load(path, self.rule.rule_name())?));
+        }
+        if let Some(capture) = self.shared.captures.tcp(&self.rule) {
+            filters.push(capture);
+        }
+        if let Some(dir) = &self.rule.record_dir {
+            filters.push(Arc::new(Recorder::new(self.rule.rule_name(), dir)?));
+        }
+        filters.extend(self.filters.iter().cloned());
+        Ok(Chain::new(filters))
+    }
+}
+
+async fn read_proxy_header(
+    mut client_stream: BoxedStream,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+) -> Option<(BoxedStream, SocketAddr, SocketAddr)> {
+    match timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut client_stream)).await {
+        Ok(Ok(client_addr)) => Some((client_stream, client_addr.unwrap_or(peer_addr), local_addr)),
+        Ok(Err(e)) => {
+            warn!("Rejecting connection from {}: {}", peer_addr, e);
+            None
+        }
+        Err(_) => {
+            warn!("Rejecting connection from {}: timed out waiting for PROXY header", peer_addr);
+            None
+        }
+    }
+}
+
+// Returns the number of bytes received from the client
+async fn handle_tcp_client<S>(
+    client_stream: S,
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+    rule: TcpRule,
+    target: &Target,
+    shared: &SharedState,
+    filters: FlowFilters,
+) -> Result<u64>
+where
+    S: AsyncStream + 'static,
+{
+    let buffer_size = shared.buffer_size;
+    // WebSocket mode swaps the client stream for the unwrapped one
+    let mut client_stream: BoxedStream = Box::new(client_stream);
+    // Bytes read from the client to pick a target, replayed once connected
+    let mut initial = Vec::new();
+    let mut proxy_reply = None;
+    let connector = match target {
+        Target::Fixed(connector) => connector.clone(),
+        Target::Sni(router) => {
+            let (hello, server_name) = timeout(CLIENT_HELLO_TIMEOUT, sni::read_client_hello(&mut client_stream))
+                .await
+                .map_err(|_| anyhow::anyhow!("timed out waiting for ClientHello from {}", client_addr))??;
+            debug!("Client {} requested server name {:?}", client_addr, server_name);
+            initial = hello;
+            router.route(server_name.as_deref()).clone()
+        }
+        Target::Sniff(router) => {
+            let (data, protocol) = sniff::sniff(&mut client_stream, SNIFF_TIMEOUT).await?;
+            debug!("Client {} detected as {:?}", client_addr, protocol);
+            initial = data;
+            router.route(protocol).clone()
+        }
+        Target::Http(router) => {
+            let mut head = timeout(REQUEST_HEAD_TIMEOUT, http::read_request_head(&mut client_stream))
+                .await
+                .map_err(|_| anyhow::anyhow!("timed out waiting for HTTP request from {}", client_addr))??;
+            debug!("Client {} requested host {:?}", client_addr, head.host());
+            let connector = router.route(head.host()).clone();
+            if rule.rewrite_host.is_some() || rule.forwarded_headers.unwrap_or(false) {
+                let proto = if rule.tls.is_some() { "https" } else { "http" };
+                let forwarded_for = rule.forwarded_headers.unwrap_or(false)
+                    .then(|| (client_addr.ip().to_canonical(), proto));
+                head.rewrite(rule.rewrite_host.as_deref(), forwarded_for);
+            }
+            initial = head.into_bytes();
+            connector
+        }
+        Target::Socks5(server) => {
+            let command = timeout(PROXY_HANDSHAKE_TIMEOUT, server.accept(&mut client_stream))
+                .await
+                .map_err(|_| anyhow::anyhow!("timed out waiting for SOCKS5 request from {}", client_addr))??;
+            match command {
+                socks5::Command::Connect(connector) => {
+                    debug!("SOCKS5 client {} connecting to {}", client_addr, connector.endpoint());
+                    proxy_reply = Some(ProxyReply::Socks5);
+                    Arc::new(*connector)
+                }
+                socks5::Command::UdpAssociate => {
+                    return socks5::relay_udp(&mut client_stream, client_addr.ip(), local_addr.ip(), buffer_size).await;
+                }
+            }
+        }
+        Target::WebSocket(connector) => {
+            let stream = timeout(PROXY_HANDSHAKE_TIMEOUT, websocket::accept(client_stream, rule.websocket_path.as_deref()))
+                .await
+                .map_err(|_| anyhow::anyhow!("timed out waiting for WebSocket upgrade from {}", client_addr))??;
+            client_stream = Box::new(stream);
+            connector.clone()
+        }
+        Target::Redirect(redirect) => {
+            // The listener reports the original destination as local_addr
+            if !redirect.permits(local_addr) {
+                anyhow::bail!("client {} is not allowed to reach {}", client_addr, local_addr);
+            }
+            debug!("Redirected client {} connecting to {}", client_addr, local_addr);
+            Arc::new(redirect.template.retarget_to(local_addr.ip().to_canonical().to_string(), local_addr.port()))
+        }
+        Target::UdpInTcp(target) => return udp_tunnel::serve(client_stream, target).await,
+        Target::HttpConnect(template) => {
+            let (host, port, rest) = timeout(PROXY_HANDSHAKE_TIMEOUT, http::read_connect_request(&mut client_stream))
+                .await
+                .map_err(|_| anyhow::anyhow!("timed out waiting for CONNECT request from {}", client_addr))??;
+            debug!("HTTP proxy client {} connecting to {}:{}", client_addr, host, port);
+            proxy_reply = Some(ProxyReply::HttpConnect);
+            initial = rest;
+            Arc::new(template.retarget_to(host, port))
+        }
+    };
+    // Filters see what was read to pick the target too
+    let read_first = initial.len() as u64;
+    if !initial.is_empty() && !filters.run(Direction::Upload, &mut initial).await {
+        debug!("Filter closed TCP connection from {}", client_addr);
+        return Ok(read_first);
+    }
+    let connector = match filters.target() {
+        Some(endpoint) => Arc::new(connector.retarget(&endpoint)?),
+        None => connector,
+    };
+    let target_addr = connector.endpoint();
+
+    let header = rule.proxy_protocol
+        .map(|version| proxy_protocol::encode_header(version, client_addr, local_addr))
+        .unwrap_or_default();
+    let mut target_stream = match connector.connect(&header, client_addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Failed to connect to target {}: {}", target_addr, e);
+            if let Some(reply) = proxy_reply {
+                let _ = client_stream.write_all(&reply.failure(&e)).await;
+            }
+            return Err(e);
+        }
+    };
+
+    debug!("Connected to target {}", target_addr);
+    if let Some(reply) = proxy_reply {
+        client_stream.write_all(&reply.success()).await?;
+    }
+    client_stream.write_all(&filters.prefix(Direction::Download)).await?;
+    let prefix = filters.prefix(Direction::Upload);
+    target_stream.write_all(&prefix).await?;
+    let latency = Latency::new(rule.inject_delay_ms, rule.inject_jitter_ms);
+    if let Some(latency) = latency
+        && !initial.is_empty()
+    {
+        sleep(latency.sample()).await;
+    }
+    target_stream.write_all(&initial).await?;
+    let mut mirror = rule.mirror_target.as_deref().map(|endpoint| Mirror::stream(endpoint, client_addr));
+    if let Some(mirror) = &mut mirror {
+        mirror.send(&prefix);
+        mirror.send(&initial);
+    }
+
+    // Last activity in either direction, in milliseconds since `started`
+    let started = Instant::now();
+    let last_activity = AtomicU64::new(0);
+    let touch = || {
+        last_activity.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    };
+    let uploaded = AtomicU64::new(read_first);
+
+    let idle = idle_watchdog(rule.idle_timeout, started, &last_activity);
+
+    // With nothing in porture to see the data, the kernel moves it
+    let plain = filters.is_empty() && mirror.is_none() && latency.is_none();
+    if shared.ktls {
+        // The kernel takes over TLS, and rustls hands on what it had decrypted
+        let from_client;
+        let from_target;
+        (client_stream, from_client) = ktls::offload(client_stream, plain).await?;
+        (target_stream, from_target) = ktls::offload(target_stream, plain).await?;
+        uploaded.fetch_add(from_client.len() as u64, Ordering::Relaxed);
+        target_stream.write_all(&from_client).await?;
+        client_stream.write_all(&from_target).await?;
+    }
+    // The sockmap can't redirect kTLS records
+    if plain
+        && let Some(sockmap) = &shared.sockmap
+        && !ktls::is_offloaded(&client_stream)
+        && !ktls::is_offloaded(&target_stream)
+        && let Some((client, target)) = splice::tcp_pair(&client_stream, &target_stream)
+        && sockmap.pair(client, target)
+            .inspect_err(|e| debug!("Not pairing TCP connection to {} in the sockmap: {}", target_addr, e))
+            .is_ok()
+    {
+        debug!("Relaying TCP connection to {} with the sockmap", target_addr);
+        // The kernel moves the data; porture passes on the FINs and waits for both sides to close
+        let client_closed = AtomicBool::new(false);
+        let client_to_target = async {
+            let end = sockmap::until_closed(client, target).await;
+            client_closed.store(matches!(end, End::Eof), Ordering::Relaxed);
+            end
+        };
+        let target_to_client = sockmap::until_closed(target, client);
+        // Only the kernel knows when data last arrived
+        let idle = async {
+            let Some(idle_timeout) = rule.idle_timeout.map(Duration::from_secs) else {
+                return std::future::pending().await;
+            };
+            loop {
+                let quiet = [client, target].into_iter()
+                    .filter_map(|stream| sockmap::received(stream).ok())
+                    .map(|(_, quiet)| quiet)
+                    .min()
+                    .unwrap_or_default();
+                if quiet >= idle_timeout {
+                    break;
+                }
+                sleep(idle_timeout - quiet).await;
+            }
+        };
+        relay(client_to_target, target_to_client, idle, &rule, &target_addr).await;
+        // The kernel's count includes the client's FIN
+        let received = sockmap::received(client).map(|(bytes, _)| bytes).unwrap_or_default();
+        return Ok(received.saturating_sub(client_closed.load(Ordering::Relaxed).into()));
+    }
+    if plain
+        && let Some(ring) = &shared.ring
+        && splice::tcp_pair(&client_stream, &target_stream).is_some()
+    {
+        debug!("Relaying TCP connection to {} through io_uring", target_addr);
+        let client: Fd = Arc::new(splice::into_tcp(client_stream));
+        let target: Fd = Arc::new(splice::into_tcp(target_stream));
+        let client_to_target = async {
+            let counted = |n: usize| {
+                touch();
+                uploaded.fetch_add(n as u64, Ordering::Relaxed);
+            };
+            ring.forward(client.clone(), target.clone(), buffer_size, counted).await
+        };
+        let target_to_client = ring.forward(target.clone(), client.clone(), buffer_size, |_| touch());
+        relay(client_to_target, target_to_client, idle, &rule, &target_addr).await;
+        return Ok(uploaded.load(Ordering::Relaxed));
+    }
+    if plain
+        && let Some((client, target)) = splice::tcp_pair(&client_stream, &target_stream)
+    {
+        debug!("Splicing TCP connection to {}", target_addr);
+        let client_to_target = async {
+            let counted = |n: usize| {
+                touch();
+                uploaded.fetch_add(n as u64, Ordering::Relaxed);
+            };
+            splice::forward(client, target, buffer_size, counted).await
+        };
+        let target_to_client = splice::forward(target, client, buffer_size, |_| touch());
+        relay(client_to_target, target_to_client, idle, &rule, &target_addr).await;
+        return Ok(uploaded.load(Ordering::Relaxed));
+    }
+
+    // Split streams for bidirectional forwarding
+    let (client_read, mut client_write) = tokio::io::split(client_stream);
+    let (target_read, mut target_write) = tokio::io::split(target_stream);
+    let (mut client_read, mut target_read): (Box<dyn AsyncRead + Unpin + Send>, Box<dyn AsyncRead + Unpin + Send>) = match latency {
+        Some(latency) => (Box::new(latency.delay(client_read, buffer_size)), Box::new(latency.delay(target_read, buffer_size))),
+        None => (Box::new(client_read), Box::new(target_read)),
+    };
+
+    // Forward data bidirectionally
+    // The chunk futures borrow what they share
+    let mirror = mirror.map(Mutex::new);
+    let (touch, uploaded, filters, mirror) = (&touch, &uploaded, &filters, &mirror);
+    let client_to_target = copy::forward(&mut client_read, &mut target_write, buffer_size, |mut chunk| async move {
+        touch();
+        uploaded.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        if !filters.run(Direction::Upload, &mut chunk).await {
+            debug!("Filter closed TCP connection from {}", client_addr);
+            return None;
+        }
+        if let Some(mirror) = mirror {
+            mirror.lock().unwrap().send(&chunk);
+        }
+        Some(chunk)
+    });
+    let target_to_client = copy::forward(&mut target_read, &mut client_write, buffer_size, |mut chunk| async move {
+        touch();
+        if !filters.run(Direction::Download, &mut chunk).await {
+            debug!("Filter closed TCP connection from {}", client_addr);
+            return None;
+        }
+        Some(chunk)
+    });
+
+    relay(client_to_target, target_to_client, idle, &rule, &target_addr).await;
+    Ok(uploaded.load(Ordering::Relaxed))
+}
+
+// Resolves once neither direction has seen data for idle_timeout.
+// `last_activity` is in milliseconds since `started`.
+async fn idle_watchdog(idle_timeout: Option<u64>, started: Instant, last_activity: &AtomicU64) {
+    let Some(idle_timeout) = idle_timeout.map(Duration::from_secs) else {
+        return std::future::pending().await;
+    };
+    loop {
+        let last = Duration::from_millis(last_activity.load(Ordering::Relaxed));
+        let deadline = started + last + idle_timeout;
+        if Instant::now() >= deadline {
+            break;
+        }
+        sleep_until(deadline).await;
+    }
+}
+
+// Runs both directions concurrently until both have reached EOF, one fails
+// or the connection idles out. A direction at EOF has half-closed its
+// writer, and the other one carries on.
+async fn relay(
+    client_to_target: impl Future<Output = End>,
+    target_to_client: impl Future<Output = End>,
+    idle: impl Future<Output = ()>,
+    rule: &TcpRule,
+    target_addr: &str,
+) {
+    tokio::pin!(client_to_target, target_to_client, idle);
+    let mut uploading = true;
+    let mut downloading = true;
+    while uploading || downloading {
+        tokio::select! {
+            end = &mut client_to_target, if uploading => {
+                uploading = false;
+                if !at_eof(end, "client", "target") {
+                    break;
+                }
+            },
+            end = &mut target_to_client, if downloading => {
+                downloading = false;
+                if !at_eof(end, "target", "client") {
+                    break;
+                }
+            },
+            _ = &mut idle => {
+                debug!("Closing TCP connection to {} after {}s idle", 
+                       target_addr, rule.idle_timeout.unwrap_or_default());
+                break;
+            },
+        }
+    }
+    debug!("TCP connection closed");
+}
+
+// Whether a direction ended at EOF; anything else closes the connection
+fn at_eof(end: End, from: &str, to: &str) -> bool {
+    match end {
+        End::Eof => return true,
+        // The filter logged it
+        End::Rejected => {}
+        End::Read(e) => error!("Failed to read from {}: {}", from, e),
+        End::Write(e) => error!("Failed to write to {}: {}", to, e),
+    }
+    false
+}
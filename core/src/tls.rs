@@ -0,0 +1,292 @@
+use anyhow::Context;
+use log::{info, warn};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{Acceptor, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{LazyConfigAcceptor, TlsConnector};
+
+// ALPN protocol used by ACME TLS-ALPN-01 validation (RFC 8737)
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+pub fn load_certs(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("failed to read certificate file '{}'", path))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to parse certificate file '{}'", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in '{}'", path);
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .with_context(|| format!("failed to read private key from '{}'", path))
+}
+
+pub fn certified_key(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> anyhow::Result<Arc<CertifiedKey>> {
+    let provider = rustls::crypto::ring::default_provider();
+    let certified = CertifiedKey::from_der(certs, key, &provider)
+        .context("invalid certificate/key pair")?;
+    Ok(Arc::new(certified))
+}
+
+pub fn load_certified_key(cert_path: &str, key_path: &str) -> anyhow::Result<Arc<CertifiedKey>> {
+    certified_key(load_certs(cert_path)?, load_private_key(key_path)?)
+}
+
+// The certificate a TLS-terminating rule presents, plus any pending ACME
+// challenge certificates. Both can change while the listener is running.
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn set_certificate(&self, key: Arc<CertifiedKey>) {
+        *self.current.write().unwrap() = Some(key);
+    }
+
+    pub fn add_challenge(&self, domain: &str, key: Arc<CertifiedKey>) {
+        self.challenges.write().unwrap().insert(domain.to_ascii_lowercase(), key);
+    }
+
+    pub fn remove_challenge(&self, domain: &str) {
+        self.challenges.write().unwrap().remove(&domain.to_ascii_lowercase());
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if is_acme_challenge(&client_hello) {
+            let domain = client_hello.server_name()?;
+            return self.challenges.read().unwrap().get(domain).cloned();
+        }
+        self.current.read().unwrap().clone()
+    }
+}
+
+fn is_acme_challenge(client_hello: &ClientHello<'_>) -> bool {
+    client_hello.alpn().is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN))
+}
+
+// A rule's cert/key files, re-read when they change on disk so renewed
+// certificates apply to new handshakes without touching open connections
+pub struct CertFiles {
+    rule_name: String,
+    cert_path: String,
+    key_path: String,
+    certs: Arc<CertResolver>,
+    loaded: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl CertFiles {
+    pub fn load(rule_name: String, cert_path: &str, key_path: &str) -> anyhow::Result<Arc<Self>> {
+        let files = Arc::new(Self {
+            rule_name,
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            certs: Arc::new(CertResolver::default()),
+            loaded: Mutex::new(None),
+        });
+        files.reload()?;
+        Ok(files)
+    }
+
+    pub fn resolver(&self) -> Arc<CertResolver> {
+        self.certs.clone()
+    }
+
+    // On failure the previous certificate stays in use
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let modified = self.modified()?;
+        self.certs.set_certificate(load_certified_key(&self.cert_path, &self.key_path)?);
+        *self.loaded.lock().unwrap() = Some(modified);
+        Ok(())
+    }
+
+    pub fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        if *self.loaded.lock().unwrap() == Some(self.modified()?) {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    fn modified(&self) -> anyhow::Result<(SystemTime, SystemTime)> {
+        let mtime = |path: &str| {
+            std::fs::metadata(path)
+                .and_then(|m| m.modified())
+                .with_context(|| format!("failed to stat '{}'", path))
+        };
+        Ok((mtime(&self.cert_path)?, mtime(&self.key_path)?))
+    }
+}
+
+#[derive(Default)]
+pub struct CertRegistry {
+    files: Mutex<Vec<Arc<CertFiles>>>,
+}
+
+impl CertRegistry {
+    // Replaces the files of a restarted rule of the same name
+    pub fn register(&self, files: Arc<CertFiles>) {
+        let mut registered = self.files.lock().unwrap();
+        registered.retain(|f| f.rule_name != files.rule_name);
+        registered.push(files);
+    }
+
+    pub fn unregister(&self, rule_name: &str) {
+        self.files.lock().unwrap().retain(|f| f.rule_name != rule_name);
+    }
+
+    // Returns each rule's name with its error, if any. Unless `force` is set,
+    // only files whose modification time changed are read again.
+    pub fn reload(&self, force: bool) -> Vec<(String, Option<String>)> {
+        let files = self.files.lock().unwrap().clone();
+        let mut results = Vec::new();
+        for files in files {
+            let result = if force { files.reload().map(|_| true) } else { files.reload_if_changed() };
+            match result {
+                Ok(false) => continue,
+                Ok(true) => {
+                    info!("Reloaded TLS certificate for '{}'", files.rule_name);
+                    results.push((files.rule_name.clone(), None));
+                }
+                Err(e) => {
+                    warn!("Keeping previous TLS certificate for '{}': {:#}", files.rule_name, e);
+                    results.push((files.rule_name.clone(), Some(format!("{:#}", e))));
+                }
+            }
+        }
+        results
+    }
+}
+
+// Server side of rules that terminate TLS from clients
+pub struct TlsTerminator {
+    config: Arc<ServerConfig>,
+    // Same certificates, but negotiating acme-tls/1 as the validator requires
+    challenge_config: Arc<ServerConfig>,
+}
+
+impl TlsTerminator {
+    pub fn new(certs: Arc<CertResolver>) -> Self {
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(certs);
+        // So kTLS can take over the record layer after the handshake
+        config.enable_secret_extraction = true;
+        let mut challenge_config = config.clone();
+        challenge_config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+        Self {
+            config: Arc::new(config),
+            challenge_config: Arc::new(challenge_config),
+        }
+    }
+
+    // Returns None when the connection was only an ACME validation probe
+    pub async fn accept<S>(&self, stream: S) -> io::Result<Option<TlsStream<S>>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+        if is_acme_challenge(&start.client_hello()) {
+            start.into_stream(self.challenge_config.clone()).await?;
+            return Ok(None);
+        }
+        Ok(Some(start.into_stream(self.config.clone()).await?))
+    }
+}
+
+// Connector for rules that originate TLS to the target. Trusts the bundled
+// Mozilla roots unless a CA file is given.
+pub fn connector(ca_path: Option<&str>, skip_verify: bool) -> anyhow::Result<TlsConnector> {
+    let mut config = client_config(ca_path, skip_verify)?;
+    // So kTLS can take over the record layer after the handshake
+    config.enable_secret_extraction = true;
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+pub fn client_config(ca_path: Option<&str>, skip_verify: bool) -> anyhow::Result<ClientConfig> {
+    let config = if skip_verify {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        match ca_path {
+            Some(path) => {
+                for cert in load_certs(path)? {
+                    roots.add(cert).with_context(|| format!("invalid CA certificate in '{}'", path))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    };
+    Ok(config)
+}
+
+pub fn server_name(name: &str) -> anyhow::Result<ServerName<'static>> {
+    ServerName::try_from(name.to_string())
+        .map_err(|_| anyhow::anyhow!("invalid TLS server name '{}'", name))
+}
+
+// Accepts any certificate; only signatures are still checked so the
+// handshake itself stays well-formed
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
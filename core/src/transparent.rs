@@ -0,0 +1,233 @@
+use crate::connector::Source;
+use crate::resolver;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::Interest;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+// Transparent proxying (Linux TPROXY). The listener accepts connections that
+// iptables/nftables TPROXY rules redirect to it whatever their destination,
+// and outbound connections are bound to the client's own address so the
+// target sees the real client. Needs CAP_NET_ADMIN, and replies from the
+// target must be routed back through this host.
+//
+// NAT REDIRECT rules rewrite the destination instead; original_destination
+// recovers it from conntrack.
+
+// Connects to `host` from `client`'s IP, with the rule's other socket
+// options. Only addresses of the client's
+// family can be reached this way, so there is no Happy Eyeballs race.
+pub async fn connect(host: &str, port: u16, client: SocketAddr, options: &Source) -> io::Result<TcpStream> {
+    let source = SocketAddr::new(client.ip().to_canonical(), 0);
+    let target = resolver::lookup(host, port).await?
+        .into_iter()
+        .find(|addr| addr.is_ipv6() == source.is_ipv6())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no {} address found for {}", if source.is_ipv6() { "IPv6" } else { "IPv4" }, host),
+            )
+        })?;
+    let socket = tcp_socket(source)?;
+    options.apply(&socket, source.is_ipv6())?;
+    socket.bind(source)?;
+    socket.connect(target).await
+}
+
+// A UDP socket bound to a possibly foreign address: the client's, to reach
+// the target as the client, or the original destination, to answer the
+// client as the service it thinks it's talking to
+pub fn udp_bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = udp_socket(addr)?;
+    socket.bind(&SockAddr::from(addr))?;
+    UdpSocket::from_std(socket.into())
+}
+
+// Several sessions answer from the same original destination, and the
+// listener itself may hold that address
+fn udp_socket(addr: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    set_transparent(&socket, addr.is_ipv6())?;
+    Ok(socket)
+}
+
+// Returns the length, the sender and the original destination of the next
+// datagram. The destination is None if the kernel didn't report one.
+pub async fn recv_with_destination(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    socket.async_io(Interest::READABLE, || recvmsg(socket, buffer)).await
+}
+
+fn tcp_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
+    let socket = if addr.is_ipv6() { TcpSocket::new_v6()? } else { TcpSocket::new_v4()? };
+    set_transparent(&socket, addr.is_ipv6())?;
+    Ok(socket)
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_transparent<S: std::os::fd::AsRawFd>(socket: &S, ipv6: bool) -> io::Result<()> {
+    let (level, name) = if ipv6 {
+        (libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+    } else {
+        (libc::SOL_IP, libc::IP_TRANSPARENT)
+    };
+    setsockopt_flag(socket.as_raw_fd(), level, name)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_transparent<S>(_socket: &S, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "transparent proxying requires Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt_flag(fd: std::os::fd::RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    // SAFETY: `fd` is an open socket and the option value is a live c_int
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+// Where a connection was headed before an iptables/nftables REDIRECT
+#[cfg(target_os = "linux")]
+pub fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name) = if stream.local_addr()?.ip().to_canonical().is_ipv6() {
+        (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    };
+    // SAFETY: all-zero is a valid sockaddr_storage
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut length = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: `address` is a live buffer of `length` bytes
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            (&mut address as *mut libc::sockaddr_storage).cast(),
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: getsockopt wrote a sockaddr_in or sockaddr_in6
+    unsafe { read_sockaddr((&address as *const libc::sockaddr_storage).cast()) }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "original destination is not an IP address"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_destination(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "mode = \"redirect\" requires Linux"))
+}
+
+// Has recv_with_destination report where each TPROXY-redirected datagram was
+// originally sent
+#[cfg(target_os = "linux")]
+pub fn set_recv_original_destination<S: std::os::fd::AsRawFd>(socket: &S, ipv6: bool) -> io::Result<()> {
+    let (level, name) = if ipv6 {
+        (libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)
+    } else {
+        (libc::SOL_IP, libc::IP_RECVORIGDSTADDR)
+    };
+    setsockopt_flag(socket.as_raw_fd(), level, name)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_recv_original_destination<S>(_socket: &S, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "transparent proxying requires Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn recvmsg(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    use std::os::fd::AsRawFd;
+
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    // SAFETY: all-zero is a valid sockaddr_storage and msghdr
+    let mut source: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_name = (&mut source as *mut libc::sockaddr_storage).cast();
+    message.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = std::mem::size_of_val(&control);
+
+    // SAFETY: every pointer in `message` refers to a live buffer of the
+    // length given next to it
+    let length = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, 0) };
+    if length < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: recvmsg filled in `source` and set msg_namelen
+    let source = unsafe { read_sockaddr((&source as *const libc::sockaddr_storage).cast()) }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram from a non-IP address"))?;
+
+    let mut destination = None;
+    // SAFETY: the CMSG_* macros walk the control data recvmsg wrote, within
+    // msg_controllen
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            let (level, kind) = ((*header).cmsg_level, (*header).cmsg_type);
+            if (level == libc::SOL_IP && kind == libc::IP_ORIGDSTADDR)
+                || (level == libc::SOL_IPV6 && kind == libc::IPV6_ORIGDSTADDR)
+            {
+                destination = read_sockaddr(libc::CMSG_DATA(header));
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+    Ok((length as usize, source, destination))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recvmsg(_socket: &UdpSocket, _buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "transparent proxying requires Linux"))
+}
+
+// SAFETY: `address` must point at a sockaddr_in or sockaddr_in6, or at any
+// sockaddr with another family
+#[cfg(target_os = "linux")]
+pub unsafe fn read_sockaddr(address: *const u8) -> Option<SocketAddr> {
+    // Control data is not necessarily aligned for the sockaddr types
+    let family = unsafe { std::ptr::read_unaligned(address.cast::<libc::sa_family_t>()) };
+    match family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { std::ptr::read_unaligned(address.cast::<libc::sockaddr_in>()) };
+            let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::new(ip.into(), u16::from_be(addr.sin_port)))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { std::ptr::read_unaligned(address.cast::<libc::sockaddr_in6>()) };
+            let ip = std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(std::net::SocketAddrV6::new(
+                ip,
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,414 @@
+use crate::config::{parse_endpoint, TunnelConfig, TunnelTransport};
+use crate::connector::{connect_happy_eyeballs, BoxedStream};
+use crate::resolver;
+use crate::sockopt::{self, BindOptions};
+use crate::tls::{self, CertFiles, CertRegistry, CertResolver, TlsTerminator};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Instant};
+
+// Client: "PORTURE-TUNNEL/1 <token>", server: "OK". Then, once the server
+// needs the connection: server "<host:port>", client "OK" or "ERR <reason>",
+// followed by the raw stream to that target.
+const HELLO: &str = "PORTURE-TUNNEL/1";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// How long an open waits for a client to replenish the idle pool
+const OPEN_WAIT: Duration = Duration::from_secs(5);
+// Idle connections are replaced periodically so ones silently dropped by
+// NAT don't linger
+const IDLE_RECYCLE: Duration = Duration::from_secs(300);
+const MAX_IDLE: usize = 64;
+const MAX_LINE: usize = 512;
+const QUIC_ALPN: &[u8] = b"porture-tunnel";
+// Keeps NAT bindings for the QUIC connection alive
+const QUIC_KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+// Server end. Over TLS it keeps a pool of authenticated connections from
+// tunnel clients, each used for one tunneled connection; over QUIC it opens
+// a stream on a client's connection instead.
+pub struct TunnelServer {
+    idle: Mutex<VecDeque<BoxedStream>>,
+    quic: Mutex<Vec<quinn::Connection>>,
+    available: Notify,
+}
+
+impl TunnelServer {
+    pub async fn start(config: &TunnelConfig, certificates: &CertRegistry) -> Result<Arc<Self>> {
+        let listen: SocketAddr = config.listen.as_deref().context("tunnel server needs listen")?.parse()?;
+        let (cert, key) = config.cert.as_deref().zip(config.key.as_deref())
+            .context("tunnel server needs cert and key")?;
+        let files = CertFiles::load("tunnel".to_string(), cert, key)?;
+        certificates.register(files.clone());
+
+        let server = Arc::new(Self {
+            idle: Mutex::new(VecDeque::new()),
+            quic: Mutex::new(Vec::new()),
+            available: Notify::new(),
+        });
+        match config.transport() {
+            TunnelTransport::Tls => server.clone().accept_tls(listen, files.resolver(), config.token.clone()).await?,
+            TunnelTransport::Quic => server.clone().accept_quic(listen, files.resolver(), config.token.clone())?,
+        }
+        Ok(server)
+    }
+
+    async fn accept_tls(self: Arc<Self>, listen: SocketAddr, certs: Arc<CertResolver>, token: String) -> Result<()> {
+        let terminator = Arc::new(TlsTerminator::new(certs));
+        let listener = sockopt::tcp_listener(listen, BindOptions::default())?;
+        info!("Tunnel server listening on {}", listen);
+
+        let accepting = self;
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!("Failed to accept tunnel connection: {}", e);
+                        continue;
+                    }
+                };
+                let (server, terminator, token) = (accepting.clone(), terminator.clone(), token.clone());
+                tokio::spawn(async move {
+                    let result = timeout(HANDSHAKE_TIMEOUT, async {
+                        let stream = terminator.accept(stream).await?
+                            .context("unexpected ACME validation request")?;
+                        authenticate(stream, &token).await
+                    }).await;
+                    match result {
+                        Ok(Ok(stream)) => {
+                            debug!("Tunnel client {} connected", peer);
+                            server.add_idle(stream);
+                        }
+                        Ok(Err(e)) => warn!("Rejecting tunnel client {}: {}", peer, e),
+                        Err(_) => warn!("Rejecting tunnel client {}: handshake timed out", peer),
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn accept_quic(self: Arc<Self>, listen: SocketAddr, certs: Arc<CertResolver>, token: String) -> Result<()> {
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(certs);
+        crypto.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        // Lets resuming clients send their hello in 0-RTT
+        crypto.max_early_data_size = u32::MAX;
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+        let endpoint = quinn::Endpoint::server(server_config, listen)?;
+        info!("Tunnel server listening on {} (QUIC)", listen);
+
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let (server, token) = (self.clone(), token.clone());
+                tokio::spawn(async move {
+                    let peer = incoming.remote_address();
+                    let result = timeout(HANDSHAKE_TIMEOUT, async {
+                        let connection = match incoming.accept()?.into_0rtt() {
+                            Ok((connection, _)) => connection,
+                            Err(connecting) => connecting.await?,
+                        };
+                        let (send, recv) = connection.accept_bi().await?;
+                        if let Err(e) = authenticate(tokio::io::join(recv, send), &token).await {
+                            // Closing right away would drop the ERR reply, so say it here
+                            connection.close(1u32.into(), e.to_string().as_bytes());
+                            return Err(e);
+                        }
+                        Ok::<_, anyhow::Error>(connection)
+                    }).await;
+                    match result {
+                        Ok(Ok(connection)) => {
+                            info!("Tunnel client {} connected over QUIC", peer);
+                            server.quic.lock().unwrap().push(connection);
+                            server.available.notify_one();
+                        }
+                        Ok(Err(e)) => warn!("Rejecting tunnel client {}: {}", peer, e),
+                        Err(_) => warn!("Rejecting tunnel client {}: handshake timed out", peer),
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn add_idle(&self, stream: BoxedStream) {
+        let mut idle = self.idle.lock().unwrap();
+        idle.push_back(stream);
+        // The oldest are the likeliest to have been dropped by the client
+        while idle.len() > MAX_IDLE {
+            idle.pop_front();
+        }
+        drop(idle);
+        self.available.notify_one();
+    }
+
+    // An unused stream to a tunnel client, waiting briefly for one to
+    // (re)connect if there is none
+    async fn next_stream(&self) -> Option<BoxedStream> {
+        let deadline = Instant::now() + OPEN_WAIT;
+        loop {
+            let notified = self.available.notified();
+            if let Some(stream) = self.idle.lock().unwrap().pop_back() {
+                return Some(stream);
+            }
+            // The most recent QUIC connection is the likeliest to be alive
+            let connection = {
+                let mut connections = self.quic.lock().unwrap();
+                connections.retain(|c| c.close_reason().is_none());
+                connections.last().cloned()
+            };
+            if let Some(connection) = connection {
+                match connection.open_bi().await {
+                    Ok((send, recv)) => return Some(Box::new(tokio::io::join(recv, send))),
+                    Err(e) => {
+                        debug!("Discarding closed QUIC tunnel connection: {}", e);
+                        continue;
+                    }
+                }
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return None;
+            }
+        }
+    }
+
+    // Asks the far end to connect to host:port and returns the stream to it
+    pub async fn open(&self, host: &str, port: u16) -> Result<BoxedStream> {
+        let endpoint = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+        loop {
+            let Some(stream) = self.next_stream().await else {
+                anyhow::bail!("no tunnel client available to reach {}", endpoint);
+            };
+            let mut stream = BufReader::new(stream);
+            if let Err(e) = stream.write_all(format!("{}\n", endpoint).as_bytes()).await {
+                debug!("Discarding closed tunnel connection: {}", e);
+                continue;
+            }
+            match read_line(&mut stream).await {
+                Ok(reply) if reply == "OK" => return Ok(Box::new(stream)),
+                Ok(reply) => {
+                    let reason = reply.strip_prefix("ERR ").unwrap_or(&reply);
+                    anyhow::bail!("tunnel client could not reach {}: {}", endpoint, reason);
+                }
+                // Closed while idle; try the next one
+                Err(e) => debug!("Discarding closed tunnel connection: {}", e),
+            }
+        }
+    }
+}
+
+async fn authenticate<S>(stream: S, token: &str) -> Result<BoxedStream>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut stream = BufReader::new(stream);
+    let hello = read_line(&mut stream).await?;
+    let presented = hello.strip_prefix(HELLO).and_then(|rest| rest.strip_prefix(' ')).unwrap_or_default();
+    if !constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+        stream.write_all(b"ERR invalid token\n").await?;
+        anyhow::bail!("invalid tunnel token");
+    }
+    stream.write_all(b"OK\n").await?;
+    Ok(Box::new(stream))
+}
+
+// Client end: connects to the server and opens whatever targets it asks for
+pub fn spawn_client(config: TunnelConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let config = Arc::new(config);
+        if config.transport() == TunnelTransport::Quic {
+            return run_quic_client(config).await;
+        }
+        info!("Tunnel client connecting to {} with {} connections",
+              config.server.as_deref().unwrap_or_default(), config.connections());
+        let workers: Vec<_> = (0..config.connections())
+            .map(|_| tokio::spawn(client_worker(config.clone())))
+            .collect();
+        futures::future::join_all(workers).await;
+    })
+}
+
+async fn client_worker(config: Arc<TunnelConfig>) {
+    loop {
+        match wait_for_request(&config).await {
+            Ok(Some((stream, endpoint))) => {
+                tokio::spawn(async move {
+                    if let Err(e) = relay(stream, &endpoint).await {
+                        warn!("Tunneled connection to {} failed: {}", endpoint, e);
+                    }
+                });
+            }
+            // Recycled after sitting idle
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Tunnel connection to {} failed: {:#}", config.server.as_deref().unwrap_or_default(), e);
+                sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn wait_for_request(config: &TunnelConfig) -> Result<Option<(BufReader<BoxedStream>, String)>> {
+    let server = config.server.as_deref().context("tunnel client needs server")?;
+    let (host, port) = parse_endpoint(server)?;
+    let mut stream = timeout(HANDSHAKE_TIMEOUT, async {
+        let tcp = connect_happy_eyeballs(&host, port).await?;
+        let connector = tls::connector(config.ca.as_deref(), false)?;
+        let name = tls::server_name(config.server_name.as_deref().unwrap_or(&host))?;
+        let tls: BoxedStream = Box::new(connector.connect(name, tcp).await?);
+        let mut stream = BufReader::new(tls);
+        hello(&mut stream, &config.token).await?;
+        Ok::<_, anyhow::Error>(stream)
+    })
+    .await
+    .context("handshake timed out")??;
+
+    match timeout(IDLE_RECYCLE, read_line(&mut stream)).await {
+        Ok(endpoint) => Ok(Some((stream, endpoint?))),
+        Err(_) => Ok(None),
+    }
+}
+
+async fn hello<S>(stream: &mut BufReader<S>, token: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(format!("{} {}\n", HELLO, token).as_bytes()).await?;
+    let reply = read_line(stream).await?;
+    if reply != "OK" {
+        let reason = reply.strip_prefix("ERR ").unwrap_or(&reply);
+        anyhow::bail!("server rejected the tunnel handshake: {}", reason);
+    }
+    Ok(())
+}
+
+async fn run_quic_client(config: Arc<TunnelConfig>) {
+    let server = config.server.as_deref().unwrap_or_default();
+    let client_config = match quic_client_config(&config) {
+        Ok(client_config) => client_config,
+        Err(e) => {
+            warn!("Tunnel client disabled: {:#}", e);
+            return;
+        }
+    };
+    info!("Tunnel client connecting to {} over QUIC", server);
+    loop {
+        match quic_session(&config, &client_config).await {
+            Ok(()) => info!("QUIC tunnel to {} closed", server),
+            Err(e) => warn!("QUIC tunnel to {} failed: {:#}", server, e),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+fn quic_client_config(config: &TunnelConfig) -> Result<quinn::ClientConfig> {
+    let mut crypto = tls::client_config(config.ca.as_deref(), false)?;
+    crypto.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+    // Reconnects resume the previous session and send the hello in 0-RTT
+    crypto.enable_early_data = true;
+    let mut client_config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(QUIC_KEEP_ALIVE));
+    client_config.transport_config(Arc::new(transport));
+    Ok(client_config)
+}
+
+// Serves one QUIC connection until it closes: each stream the server opens
+// is a tunneled connection
+async fn quic_session(config: &TunnelConfig, client_config: &quinn::ClientConfig) -> Result<()> {
+    let server = config.server.as_deref().context("tunnel client needs server")?;
+    let (host, port) = parse_endpoint(server)?;
+    let addr = resolver::lookup(&host, port).await?
+        .into_iter()
+        .next()
+        .with_context(|| format!("no addresses found for {}", host))?;
+    let bind: SocketAddr = if addr.is_ipv6() { "[::]:0".parse()? } else { "0.0.0.0:0".parse()? };
+    let endpoint = quinn::Endpoint::client(bind)?;
+    let name = config.server_name.as_deref().unwrap_or(&host);
+
+    let connection = timeout(HANDSHAKE_TIMEOUT, async {
+        let connection = match endpoint.connect_with(client_config.clone(), addr, name)?.into_0rtt() {
+            Ok((connection, _)) => connection,
+            Err(connecting) => connecting.await?,
+        };
+        let (send, recv) = connection.open_bi().await?;
+        hello(&mut BufReader::new(tokio::io::join(recv, send)), &config.token).await?;
+        Ok::<_, anyhow::Error>(connection)
+    })
+    .await
+    .context("handshake timed out")??;
+    info!("QUIC tunnel to {} established", server);
+
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(quinn::ConnectionError::ApplicationClosed(_) | quinn::ConnectionError::LocallyClosed) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        tokio::spawn(async move {
+            let stream: BoxedStream = Box::new(tokio::io::join(recv, send));
+            let mut stream = BufReader::new(stream);
+            let endpoint = match read_line(&mut stream).await {
+                Ok(endpoint) => endpoint,
+                Err(e) => return debug!("Dropping tunnel stream: {}", e),
+            };
+            if let Err(e) = relay(stream, &endpoint).await {
+                warn!("Tunneled connection to {} failed: {}", endpoint, e);
+            }
+        });
+    }
+}
+
+async fn relay(mut stream: BufReader<BoxedStream>, endpoint: &str) -> Result<()> {
+    let target = async {
+        let (host, port) = parse_endpoint(endpoint)?;
+        match timeout(CONNECT_TIMEOUT, connect_happy_eyeballs(&host, port)).await {
+            Ok(result) => Ok(result?),
+            Err(_) => anyhow::bail!("connect timed out"),
+        }
+    }.await;
+    let mut target = match target {
+        Ok(target) => target,
+        Err(e) => {
+            stream.write_all(format!("ERR {}\n", e).as_bytes()).await?;
+            return Err(e);
+        }
+    };
+    stream.write_all(b"OK\n").await?;
+    debug!("Tunneled connection to {} established", endpoint);
+    match tokio::io::copy_bidirectional(&mut stream, &mut target).await {
+        // The server end hangs up without a TLS close_notify
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+        result => result.map(|_| ()).map_err(Into::into),
+    }
+}
+
+async fn read_line<S>(stream: &mut BufReader<S>) -> Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    let n = (&mut *stream).take(MAX_LINE as u64).read_until(b'\n', &mut line).await?;
+    if n == 0 {
+        anyhow::bail!("connection closed");
+    }
+    if line.last() != Some(&b'\n') {
+        anyhow::bail!("tunnel protocol line too long or truncated");
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,839 @@
+use crate::acl::AccessFilter;
+use crate::banner::BannerFilter;
+use crate::buffer::Buffer;
+use crate::chaos::{Impairment, Pipe};
+use crate::config::{UdpMode, UdpRule};
+use crate::connector::connect_happy_eyeballs_from;
+use crate::filter::{Chain, DatagramFilter, Direction, Flow, FlowFilters};
+use crate::gso;
+use crate::handoff::{self, OpenConnection, OpenConnections};
+use crate::knock::KnockGate;
+use crate::limits::BandwidthFilter;
+#[cfg(feature = "lua")]
+use crate::lua::LuaFilter;
+use crate::mdns;
+use crate::mirror::Mirror;
+use crate::rewrite::RewriteFilter;
+use crate::rule_log;
+use crate::sockopt;
+use crate::state::SharedState;
+use crate::supervisor::Bound;
+use crate::systemd;
+use crate::task::AbortOnDrop;
+use crate::transparent;
+use crate::udp_tunnel;
+use crate::uring::Ring;
+#[cfg(feature = "wasm")]
+use crate::wasm::WasmFilter;
+use anyhow::{Context, Result};
+use log::{error, info, debug, warn};
+use socket2::Type;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{Notify, RwLock};
+use tokio::time::{interval, timeout};
+
+// The rule's filters and knock gate, shared by its sockets
+struct Filters {
+    chain: Chain<dyn DatagramFilter>,
+    knock_gate: Option<Arc<KnockGate>>,
+}
+
+const TUNNEL_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Datagrams queued per session while the tunnel connection is busy
+const TUNNEL_QUEUE: usize = 256;
+// Datagrams queued per session while its filters, rate limits included,
+// hold it back. More are dropped.
+const SESSION_QUEUE: usize = 256;
+// Share of max_sessions evicted at once when a rule is full, so a flood of
+// new sources doesn't scan every session for each datagram
+const EVICTION_BATCH_DIVISOR: usize = 64;
+
+#[derive(Clone)]
+enum SessionUpstream {
+    // Connected to the target, except on broadcast rules, with the ring to
+    // send through
+    Socket(Arc<UdpSocket>, Option<Arc<Ring>>),
+    // Datagrams for the session's UDP-over-TCP connection
+    Tunnel(mpsc::Sender<Buffer>),
+    // mode = "echo": datagrams go back out of the socket they came in on,
+    // through the download filters
+    Echo(Arc<UdpSocket>, Arc<FlowFilters>, Option<Arc<Ring>>),
+    // mode = "discard"
+    Discard,
+}
+
+struct ClientDatagram {
+    from: SocketAddr,
+    // Set when GRO coalesced several datagrams of this size into `data`
+    segment_size: Option<usize>,
+    data: Buffer,
+}
+
+#[derive(Clone)]
+struct UdpSession {
+    // The session's sending task, which ends once the session is removed
+    outgoing: mpsc::Sender<ClientDatagram>,
+    last_activity: Instant,
+    // Stops the session's response task once it has been removed
+    closed: Arc<Notify>,
+    // Counted until the session is removed
+    _open: Arc<OpenConnection>,
+}
+
+// Ends a rule's sessions when it stops, so their tasks let go of the
+// listening sockets
+struct CloseOnDrop(Arc<RwLock<HashMap<SocketAddr, UdpSession>>>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        let sessions = self.0.clone();
+        tokio::spawn(async move {
+            for (_, session) in sessions.write().await.drain() {
+                session.closed.notify_one();
+            }
+        });
+    }
+}
+
+// A rule's session limit and how often it was hit, for the admin API
+pub struct SessionStats {
+    pub rule_name: String,
+    pub max_sessions: usize,
+    pub evicted: AtomicU64,
+    pub open: OpenConnections,
+}
+
+#[derive(Default)]
+pub struct SessionRegistry {
+    rules: Mutex<Vec<Arc<SessionStats>>>,
+}
+
+impl SessionRegistry {
+    // Replaces the stats of a restarted rule of the same name
+    fn register(&self, stats: Arc<SessionStats>) {
+        let mut rules = self.rules.lock().unwrap();
+        rules.retain(|s| s.rule_name != stats.rule_name);
+        rules.push(stats);
+    }
+
+    pub fn unregister(&self, rule_name: &str) {
+        self.rules.lock().unwrap().retain(|s| s.rule_name != rule_name);
+    }
+
+    // Sessions of all running rules
+    pub fn open(&self) -> usize {
+        self.rules.lock().unwrap().iter().map(|stats| stats.open.count()).sum()
+    }
+
+    pub fn snapshot(&self) -> Vec<(String, usize, u64)> {
+        self.rules.lock().unwrap()
+            .iter()
+            .map(|stats| (stats.rule_name.clone(), stats.max_sessions, stats.evicted.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+pub struct UdpForwarder {
+    rule: UdpRule,
+    shared: Arc<SharedState>,
+    // Run after the rule's own filters
+    filters: Vec<Arc<dyn DatagramFilter>>,
+}
+
+impl UdpForwarder {
+    pub fn new(rule: UdpRule, shared: Arc<SharedState>) -> Self {
+        Self { rule, shared, filters: Vec::new() }
+    }
+
+    pub fn with_filters(mut self, filters: Vec<Arc<dyn DatagramFilter>>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    pub async fn start(&self, bound: &mut Bound) -> Result<()> {
+        if self.rule.mode() == UdpMode::MdnsReflector {
+            return mdns::run(&self.rule, bound).await;
+        }
+        let bind_endpoint = self.rule.bind_endpoint();
+        
+        let chain = self.filter_chain()?;
+        let sockets = match self.rule.bind_systemd_socket() {
+            Some(name) => vec![self.activated(name)?],
+            None => self.rule.bind_socket_addrs()?.into_iter()
+                .map(|addr| self.bind(addr))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        // Validation limits knock to rules with a single IP bind_addr
+        let knock_gate = match &self.rule.knock {
+            Some(knock) => Some(KnockGate::start(self.rule.bind_socket_addrs()?[0].ip(), knock, self.rule.rule_name()).await?),
+            None => None,
+        };
+        
+        info!("UDP forwarder '{}' listening on {}", 
+              self.rule.rule_name(), bind_endpoint);
+        match self.rule.mode() {
+            UdpMode::Forward => info!("UDP forwarding {} -> {}", 
+                                      bind_endpoint, self.rule.target_socket_addr()?),
+            UdpMode::UdpInTcpClient => info!("UDP forwarding {} -> {}:{} over TCP", 
+                                             bind_endpoint, self.rule.target_addr, self.rule.target_port),
+            UdpMode::Echo | UdpMode::Discard => info!("UDP forwarding {} -> {}", 
+                                                      bind_endpoint, self.rule.target_endpoint()),
+            UdpMode::MdnsReflector => unreachable!("mDNS reflector rules don't bind forwarding sockets"),
+        }
+        bound.signal(sockets.iter().filter_map(|socket| socket.local_addr().ok()).collect());
+
+        // Session management, shared by all of the rule's sockets. Sessions
+        // answer through the socket that created them.
+        let sessions: Arc<RwLock<HashMap<SocketAddr, UdpSession>>> = 
+            Arc::new(RwLock::new(HashMap::new()));
+        let stats = Arc::new(SessionStats {
+            rule_name: self.rule.rule_name(),
+            max_sessions: self.rule.session_limit(),
+            evicted: AtomicU64::new(0),
+            open: OpenConnections::default(),
+        });
+        self.shared.udp_sessions.register(stats.clone());
+        
+        let timeout_duration = Duration::from_secs(self.rule.timeout_seconds());
+        
+        // Start cleanup task
+        let cleanup_sessions = sessions.clone();
+        let cleanup_timeout = timeout_duration;
+        let _cleanup = AbortOnDrop::spawn(async move {
+            let mut cleanup_interval = interval(Duration::from_secs(30));
+            loop {
+                cleanup_interval.tick().await;
+                cleanup_expired_sessions(cleanup_sessions.clone(), cleanup_timeout).await;
+            }
+        });
+        let _close_sessions = CloseOnDrop(sessions.clone());
+
+        let filters = Filters { chain, knock_gate };
+        futures::future::try_join_all(
+            sockets.into_iter().map(|socket| self.receive(socket, &sessions, &stats, &filters)),
+        ).await?;
+        Ok(())
+    }
+
+    fn bind(&self, addr: SocketAddr) -> Result<Arc<UdpSocket>> {
+        let socket = sockopt::udp_listener(addr, self.rule.bind_options())
+            .with_context(|| format!("failed to bind {}", addr))?;
+        self.configure(socket, addr.is_ipv6())
+    }
+
+    // The socket systemd bound for the rule
+    fn activated(&self, name: &str) -> Result<Arc<UdpSocket>> {
+        let socket = systemd::socket(name, Type::DGRAM)?;
+        let ipv6 = socket.local_addr()?.is_ipv6();
+        self.configure(handoff::offer(systemd::key(name), UdpSocket::from_std(socket.into())?), ipv6)
+    }
+
+    fn configure(&self, socket: Arc<UdpSocket>, ipv6: bool) -> Result<Arc<UdpSocket>> {
+        if let Some(name) = &self.rule.bind_device {
+            sockopt::bind_device(&socket, name)?;
+        }
+        // Replies to clients get the same DSCP as traffic to the target
+        if let Some(dscp) = self.rule.dscp {
+            sockopt::set_dscp(&socket, dscp, ipv6)?;
+        }
+        // Kernels before 5.0 lack UDP_GRO; the rule then forwards datagram
+        // by datagram as usual
+        if self.rule.gso.unwrap_or(false)
+            && let Err(e) = gso::enable_gro(&socket)
+        {
+            warn!("UDP forwarder '{}': gso unavailable: {}", self.rule.rule_name(), e);
+        }
+        Ok(socket)
+    }
+
+    // Main forwarding loop for one of the rule's sockets
+    async fn receive(
+        &self,
+        socket: Arc<UdpSocket>,
+        sessions: &Arc<RwLock<HashMap<SocketAddr, UdpSession>>>,
+        stats: &Arc<SessionStats>,
+        filters: &Filters,
+    ) -> Result<()> {
+        let Filters { chain, knock_gate } = filters;
+        let transparent = self.rule.transparent.unwrap_or(false);
+        let local_addr = socket.local_addr()?;
+        let ring = self.shared.ring.as_deref();
+        let mut buffer = Buffer::zeroed(self.receive_buffer_size());
+        loop {
+            let received = if transparent {
+                transparent::recv_with_destination(&socket, &mut buffer).await
+                    .map(|(len, addr, destination)| (len, addr, destination, None))
+            } else {
+                recv(ring, &socket, &mut buffer).await
+                    .map(|(len, addr, segment_size)| (len, addr, None, segment_size))
+            };
+            match received {
+                Ok((len, client_addr, original_destination, segment_size)) => {
+                    debug!("Received {} bytes from {}", len, client_addr);
+
+                    // Source addresses are trivially spoofed, so only access-rule
+                    // denials (not traffic volume) count towards UDP bans
+                    if let Some(bans) = &self.shared.bans
+                        && bans.is_banned(client_addr.ip())
+                    {
+                        continue;
+                    }
+
+                    if let Some(gate) = &knock_gate
+                        && !gate.is_unlocked(client_addr.ip())
+                    {
+                        continue;
+                    }
+
+                    // Sessions and their filters are only made here, under the
+                    // write lock, so a client's next datagram finds them
+                    let session = {
+                        let mut sessions_write = sessions.write().await;
+                        match sessions_write.get_mut(&client_addr) {
+                            Some(session) => {
+                                session.last_activity = Instant::now();
+                                session.clone()
+                            }
+                            // Shutting down: existing sessions carry on, new
+                            // ones aren't started
+                            None if self.shared.draining.load(Ordering::Relaxed) => continue,
+                            None => {
+                                let flow = Flow { client_addr, local_addr: original_destination.unwrap_or(local_addr) };
+                                let Some(filters) = chain.session(&flow) else {
+                                    continue;
+                                };
+                                debug!("Creating new UDP session for {}", client_addr);
+                                if sessions_write.len() >= stats.max_sessions {
+                                    evict_least_recent(&mut sessions_write, stats);
+                                }
+                                let opened = open_session(
+                                    &socket,
+                                    sessions,
+                                    stats,
+                                    client_addr,
+                                    original_destination,
+                                    &self.rule,
+                                    self.receive_buffer_size(),
+                                    self.shared.ring.clone(),
+                                    Arc::new(filters),
+                                ).await;
+                                match opened {
+                                    Ok(session) => {
+                                        sessions_write.insert(client_addr, session.clone());
+                                        session
+                                    }
+                                    Err(e) => {
+                                        error!("UDP packet handling error: {}", e);
+                                        continue;
+                                    }
+                                }
+                            }
+                        }
+                    };
+
+                    // Filtered by the session's own task, so one held back
+                    // by its rate limits doesn't hold up the others
+                    let datagram = ClientDatagram { from: client_addr, segment_size, data: Buffer::copy_of(&buffer[..len]) };
+                    if let Err(TrySendError::Full(_)) = session.outgoing.try_send(datagram) {
+                        debug!("UDP session for {} is backed up, dropping datagram", client_addr);
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to receive UDP packet: {}", e);
+                }
+            }
+        }
+    }
+
+    // A GRO read can hold up to 64 datagrams
+    fn receive_buffer_size(&self) -> usize {
+        if self.rule.gso.unwrap_or(false) { gso::MAX_COALESCED } else { self.shared.buffer_size }
+    }
+
+    // Allow/deny lists, rate limits, rewrites, banners, wasm_filter,
+    // lua_script and capture_file, then the filters added in code
+    fn filter_chain(&self) -> Result<Chain<dyn DatagramFilter>> {
+        let access = AccessFilter::new(
+            self.rule.rule_name(),
+            self.rule.acl()?,
+            self.rule.country_filter()?,
+            self.shared.clone(),
+        );
+        let mut filters: Vec<Arc<dyn DatagramFilter>> = vec![Arc::new(access)];
+        if let Some(bandwidth) = BandwidthFilter::new(self.rule.rate_limit_kbps, self.rule.client_rate_limit_kbps) {
+            filters.push(Arc::new(bandwidth));
+        }
+        if let Some(rewrites) = &self.rule.rewrite {
+            filters.push(Arc::new(RewriteFilter::new(rewrites)?));
+        }
+        if let Some(banners) = &self.rule.banner {
+            filters.push(Arc::new(BannerFilter::new(banners)?));
+        }
+        #[cfg(feature = "wasm")]
+        if let Some(path) = &self.rule.wasm_filter {
+            filters.push(Arc::new(WasmFilter::load(path, self.rule.rule_name())?));
+        }
+        #[cfg(feature = "lua")]
+        if let Some(path) = &self.rule.lua_script {
+            filters.push(Arc::new(LuaFilter::load(path, self.rule.rule_name())?));
+        }
+        if let Some(capture) = self.shared.captures.udp(&self.rule) {
+            filters.push(capture);
+        }
+        filters.extend(self.filters.iter().cloned());
+        Ok(Chain::new(filters))
+    }
+}
+
+// A new client's session: its upstream, and the task forwarding what comes back
+#[allow(clippy::too_many_arguments)]
+async fn open_session(
+    client_socket: &Arc<UdpSocket>,
+    sessions: &Arc<RwLock<HashMap<SocketAddr, UdpSession>>>,
+    stats: &SessionStats,
+    client_addr: SocketAddr,
+    original_destination: Option<SocketAddr>,
+    rule: &UdpRule,
+    buffer_size: usize,
+    ring: Option<Arc<Ring>>,
+    filters: Arc<FlowFilters>,
+) -> Result<UdpSession> {
+    let closed = Arc::new(Notify::new());
+    
+    let upstream = match rule.mode() {
+        UdpMode::Forward => {
+            let target_addr = rule.target_socket_addr()?;
+            let broadcast = rule.broadcast.unwrap_or(false);
+            let (target_socket, reply_socket) = if rule.transparent.unwrap_or(false) {
+                // Reach the target as the client, and answer the client
+                // from the address it originally sent to
+                let source = SocketAddr::new(client_addr.ip().to_canonical(), 0);
+                let reply_socket = match original_destination {
+                    Some(destination) if destination != client_socket.local_addr()? => {
+                        Arc::new(transparent::udp_bind(destination)?)
+                    }
+                    _ => client_socket.clone(),
+                };
+                let target_socket = transparent::udp_bind(source)?;
+                rule.source()?.apply(&target_socket, source.is_ipv6())?;
+                target_socket.connect(target_addr).await?;
+                (Arc::new(target_socket), reply_socket)
+            } else {
+                // Without source_addr, the unspecified address of the
+                // target's family, so an IPv6 target can be connected to
+                let source = match rule.source_ip()? {
+                    Some(ip) => SocketAddr::new(ip, 0),
+                    None if target_addr.is_ipv6() => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                    None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                };
+                let target_socket = UdpSocket::bind(source).await?;
+                rule.source()?.apply(&target_socket, target_addr.is_ipv6())?;
+                // Replies to a broadcast come from each host that
+                // answers, so only other sessions' sockets are
+                // connected. Connecting lets the kernel drop stray
+                // datagrams from anyone but the target.
+                if broadcast {
+                    target_socket.set_broadcast(true)?;
+                } else {
+                    target_socket.connect(target_addr).await?;
+                }
+                // A kernel without UDP_GRO was already reported
+                // when the listener was bound
+                if rule.gso.unwrap_or(false) {
+                    gso::enable_gro(&target_socket).ok();
+                }
+                (Arc::new(target_socket), client_socket.clone())
+            };
+
+            // Start response forwarding task
+            let target_socket_clone = target_socket.clone();
+            let sessions_clone = sessions.clone();
+            let closed_clone = closed.clone();
+            let filters_clone = filters.clone();
+            let ring_clone = ring.clone();
+            let impairment = Impairment::new(rule);
+
+            tokio::spawn(rule_log::inherit(async move {
+                if let Err(e) = forward_responses(
+                    target_socket_clone,
+                    reply_socket,
+                    client_addr,
+                    sessions_clone,
+                    closed_clone,
+                    buffer_size,
+                    ring_clone,
+                    filters_clone,
+                    impairment,
+                ).await {
+                    error!("Response forwarding error: {}", e);
+                }
+            }));
+            SessionUpstream::Socket(target_socket, ring)
+        }
+        UdpMode::UdpInTcpClient => {
+            // Connect off the lock so one slow tunnel doesn't stall other sessions
+            let (tx, rx) = mpsc::channel(TUNNEL_QUEUE);
+            let client_socket_clone = client_socket.clone();
+            let sessions_clone = sessions.clone();
+            let rule_clone = rule.clone();
+            let filters_clone = filters.clone();
+
+            tokio::spawn(rule_log::inherit(async move {
+                if let Err(e) = run_tunnel(
+                    rule_clone,
+                    rx,
+                    client_socket_clone,
+                    client_addr,
+                    sessions_clone.clone(),
+                    filters_clone,
+                    ring,
+                ).await {
+                    error!("UDP tunnel for {} failed: {}", client_addr, e);
+                }
+                sessions_clone.write().await.remove(&client_addr);
+                debug!("UDP session for {} ended", client_addr);
+            }));
+            SessionUpstream::Tunnel(tx)
+        }
+        UdpMode::Echo => SessionUpstream::Echo(client_socket.clone(), filters.clone(), ring),
+        UdpMode::Discard => SessionUpstream::Discard,
+        UdpMode::MdnsReflector => unreachable!("mDNS reflector rules don't create sessions"),
+    };
+    
+    let rule = Arc::new(rule.clone());
+    let impaired = Impairment::new(&rule).map(|impairment| {
+        let upstream = upstream.clone();
+        let rule = rule.clone();
+        let sessions = sessions.clone();
+        impairment.pipe(move |data| {
+            let (upstream, rule, sessions) = (upstream.clone(), rule.clone(), sessions.clone());
+            async move {
+                if let Err(e) = send_upstream(&upstream, &rule, Buffer::from(data), None, &sessions, client_addr).await {
+                    error!("UDP packet handling error: {}", e);
+                }
+            }
+        })
+    });
+    let mirror = rule.mirror_target.as_deref().map(|endpoint| Mirror::datagram(endpoint, client_addr));
+    let (outgoing, incoming) = mpsc::channel(SESSION_QUEUE);
+    tokio::spawn(rule_log::inherit(send_datagrams(
+        incoming,
+        filters,
+        mirror,
+        impaired,
+        upstream,
+        rule,
+        sessions.clone(),
+    )));
+    Ok(UdpSession {
+        outgoing,
+        last_activity: Instant::now(),
+        closed,
+        _open: Arc::new(stats.open.track()),
+    })
+}
+
+// Filters a session's datagrams and sends them on, in order, until the
+// session is removed. Rate limits wait here rather than in the rule's
+// receive loop.
+async fn send_datagrams(
+    mut incoming: mpsc::Receiver<ClientDatagram>,
+    filters: Arc<FlowFilters>,
+    mut mirror: Option<Mirror>,
+    // What goes to the target, with inject_* options
+    impaired: Option<Pipe>,
+    upstream: SessionUpstream,
+    rule: Arc<UdpRule>,
+    sessions: Arc<RwLock<HashMap<SocketAddr, UdpSession>>>,
+) {
+    while let Some(mut datagram) = incoming.recv().await {
+        if !filters.run(Direction::Upload, &mut datagram.data).await {
+            continue;
+        }
+        if let Some(mirror) = &mut mirror {
+            // Coalesced datagrams go to the mirror one by one
+            for segment in datagram.data.chunks(datagram.segment_size.unwrap_or(datagram.data.len()).max(1)) {
+                mirror.send(segment);
+            }
+        }
+        let sent = match &impaired {
+            Some(pipe) => {
+                pipe.send(&datagram.data, datagram.segment_size);
+                Ok(())
+            }
+            None => send_upstream(&upstream, &rule, datagram.data, datagram.segment_size, &sessions, datagram.from).await,
+        };
+        if let Err(e) = sent {
+            error!("UDP packet handling error: {}", e);
+        }
+    }
+}
+
+// Sends a client's datagram, or several of segment_size coalesced into one,
+// to the target or through the tunnel
+async fn send_upstream(
+    upstream: &SessionUpstream,
+    rule: &UdpRule,
+    data: Buffer,
+    segment_size: Option<usize>,
+    sessions: &Arc<RwLock<HashMap<SocketAddr, UdpSession>>>,
+    client_addr: SocketAddr,
+) -> Result<()> {
+    match upstream {
+        SessionUpstream::Socket(target_socket, ring) => {
+            let target_addr = rule.target_socket_addr()?;
+            // Forward packet to target
+            let destination = rule.broadcast.unwrap_or(false).then_some(target_addr);
+            let mut data = data;
+            if let Err(e) = send(ring.as_deref(), target_socket, &mut data, segment_size, destination).await {
+                error!("Failed to send to target {}: {}", target_addr, e);
+                // Remove failed session
+                sessions.write().await.remove(&client_addr);
+            } else {
+                debug!("Forwarded {} bytes to {}", data.len(), target_addr);
+            }
+        }
+        SessionUpstream::Tunnel(tx) => match tx.try_send(data) {
+            Ok(()) => {}
+            // Dropped like any datagram on a congested path
+            Err(TrySendError::Full(_)) => debug!("UDP tunnel for {} is backed up, dropping datagram", client_addr),
+            Err(TrySendError::Closed(_)) => {
+                sessions.write().await.remove(&client_addr);
+            }
+        },
+        SessionUpstream::Echo(client_socket, filters, ring) => {
+            let mut data = data;
+            if filters.run(Direction::Download, &mut data).await
+                && let Err(e) = send(ring.as_deref(), client_socket, &mut data, None, Some(client_addr)).await
+            {
+                error!("Failed to echo to client {}: {}", client_addr, e);
+                sessions.write().await.remove(&client_addr);
+            }
+        }
+        SessionUpstream::Discard => debug!("Discarded {} bytes from {}", data.len(), client_addr),
+    }
+
+    Ok(())
+}
+
+// Carries one session's datagrams over a TCP connection to a
+// udp_in_tcp_server rule until the session expires or the connection drops
+async fn run_tunnel(
+    rule: UdpRule,
+    mut outgoing: mpsc::Receiver<Buffer>,
+    client_socket: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    sessions: Arc<RwLock<HashMap<SocketAddr, UdpSession>>>,
+    filters: Arc<FlowFilters>,
+    ring: Option<Arc<Ring>>,
+) -> Result<()> {
+    let stream = match timeout(TUNNEL_CONNECT_TIMEOUT, connect_happy_eyeballs_from(&rule.target_addr, rule.target_port, &rule.source()?)).await {
+        Ok(result) => result?,
+        Err(_) => anyhow::bail!("connect to {}:{} timed out", rule.target_addr, rule.target_port),
+    };
+    let (mut reader, mut writer) = stream.into_split();
+    let impaired = Impairment::new(&rule).map(|impairment| pipe_to(impairment, &client_socket, client_addr, &ring));
+
+    // Ends when the session is removed and its sender dropped
+    let upload = async {
+        while let Some(datagram) = outgoing.recv().await {
+            udp_tunnel::write_frame(&mut writer, &datagram).await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    let download = async {
+        let mut buffer = Buffer::zeroed(udp_tunnel::MAX_DATAGRAM);
+        while let Some(len) = udp_tunnel::read_frame(&mut reader, &mut buffer).await? {
+            match sessions.write().await.get_mut(&client_addr) {
+                Some(session) => session.last_activity = Instant::now(),
+                None => break,
+            }
+            let mut datagram = Buffer::copy_of(&buffer[..len]);
+            if !filters.run(Direction::Download, &mut datagram).await {
+                continue;
+            }
+            match &impaired {
+                Some(pipe) => pipe.send(&datagram, None),
+                None => send(ring.as_deref(), &client_socket, &mut datagram, None, Some(client_addr)).await?,
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    tokio::select! {
+        result = upload => result?,
+        result = download => result?,
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn forward_responses(
+    target_socket: Arc<UdpSocket>,
+    client_socket: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    sessions: Arc<RwLock<HashMap<SocketAddr, UdpSession>>>,
+    closed: Arc<Notify>,
+    buffer_size: usize,
+    ring: Option<Arc<Ring>>,
+    filters: Arc<FlowFilters>,
+    impairment: Option<Impairment>,
+) -> Result<()> {
+    let impaired = impairment.map(|impairment| pipe_to(impairment, &client_socket, client_addr, &ring));
+    let mut buffer = Buffer::zeroed(buffer_size);
+    
+    loop {
+        let received = tokio::select! {
+            received = timeout(Duration::from_secs(60), recv(ring.as_deref(), &target_socket, &mut buffer)) => received,
+            // Evicted or expired: release the target socket now rather than
+            // at the next timeout
+            _ = closed.notified() => return Ok(()),
+        };
+        match received {
+            Ok(Ok((len, _, segment_size))) => {
+                debug!("Received {} bytes from target, forwarding to {}", len, client_addr);
+                
+                // Update session activity
+                if let Some(session) = sessions.write().await.get_mut(&client_addr) {
+                    session.last_activity = Instant::now();
+                } else {
+                    // Session was removed, stop forwarding
+                    break;
+                }
+                
+                buffer.truncate(len);
+                let passed = filters.run(Direction::Download, &mut buffer).await;
+                if passed && let Some(pipe) = &impaired {
+                    pipe.send(&buffer, segment_size);
+                    buffer.resize(buffer_size, 0);
+                    continue;
+                }
+
+                // Forward response to client
+                let sent = match passed {
+                    true => send(ring.as_deref(), &client_socket, &mut buffer, segment_size, Some(client_addr)).await,
+                    false => Ok(()),
+                };
+                buffer.resize(buffer_size, 0);
+                if let Err(e) = sent {
+                    error!("Failed to send response to client {}: {}", client_addr, e);
+                    break;
+                }
+            }
+            Ok(Err(e)) => {
+                error!("Target socket error: {}", e);
+                break;
+            }
+            Err(_) => {
+                // Timeout - check if session still exists
+                if !sessions.read().await.contains_key(&client_addr) {
+                    break;
+                }
+                // An io_uring receive cut short takes its buffer with it
+                buffer.resize(buffer_size, 0);
+            }
+        }
+    }
+    
+    // Clean up session
+    sessions.write().await.remove(&client_addr);
+    debug!("UDP session for {} ended", client_addr);
+    
+    Ok(())
+}
+
+// The next datagram, as gso::recv reads it
+async fn recv(ring: Option<&Ring>, socket: &Arc<UdpSocket>, buffer: &mut Buffer) -> io::Result<(usize, SocketAddr, Option<usize>)> {
+    match ring {
+        Some(ring) => ring.recv_from(socket.clone(), buffer).await,
+        None => gso::recv(socket, buffer).await,
+    }
+}
+
+// Sends a datagram, or several of segment_size coalesced into one, to
+// `destination` or the socket's connected peer, as gso::send does
+async fn send(
+    ring: Option<&Ring>,
+    socket: &Arc<UdpSocket>,
+    buffer: &mut Buffer,
+    segment_size: Option<usize>,
+    destination: Option<SocketAddr>,
+) -> io::Result<()> {
+    match (ring, segment_size, destination) {
+        (Some(ring), _, _) => ring.send_to(socket.clone(), buffer, segment_size, destination).await,
+        (None, Some(size), _) => gso::send(socket, buffer, size, destination).await,
+        (None, None, Some(destination)) => socket.send_to(buffer, destination).await.map(drop),
+        (None, None, None) => socket.send(buffer).await.map(drop),
+    }
+}
+
+// The impaired path for what goes back to a client
+fn pipe_to(impairment: Impairment, socket: &Arc<UdpSocket>, client_addr: SocketAddr, ring: &Option<Arc<Ring>>) -> Pipe {
+    let (socket, ring) = (socket.clone(), ring.clone());
+    impairment.pipe(move |datagram| {
+        let (socket, ring) = (socket.clone(), ring.clone());
+        async move {
+            let mut datagram = Buffer::from(datagram);
+            if let Err(e) = send(ring.as_deref(), &socket, &mut datagram, None, Some(client_addr)).await {
+                debug!("Failed to send response to client {}: {}", client_addr, e);
+            }
+        }
+    })
+}
+
+async fn cleanup_expired_sessions(
+    sessions: Arc<RwLock<HashMap<SocketAddr, UdpSession>>>,
+    timeout_duration: Duration,
+) {
+    let now = Instant::now();
+    let mut expired_clients = Vec::new();
+    
+    {
+        let sessions_read = sessions.read().await;
+        for (client_addr, session) in sessions_read.iter() {
+            if now.duration_since(session.last_activity) > timeout_duration {
+                expired_clients.push(*client_addr);
+            }
+        }
+    }
+    
+    if !expired_clients.is_empty() {
+        let mut sessions_write = sessions.write().await;
+        for client_addr in expired_clients {
+            if let Some(session) = sessions_write.remove(&client_addr) {
+                session.closed.notify_one();
+            }
+            debug!("Cleaned up expired UDP session for {}", client_addr);
+        }
+    }
+}
+
+// Makes room in a full rule by dropping the sessions that have been idle
+// longest. Sources are easily spoofed, so under a flood these are the
+// sessions least likely to belong to real clients.
+fn evict_least_recent(sessions: &mut HashMap<SocketAddr, UdpSession>, stats: &SessionStats) {
+    let count = (sessions.len() + 1)
+        .saturating_sub(stats.max_sessions)
+        .max(stats.max_sessions / EVICTION_BATCH_DIVISOR)
+        .clamp(1, sessions.len());
+    let mut by_age: Vec<(Instant, SocketAddr)> = sessions.iter()
+        .map(|(addr, session)| (session.last_activity, *addr))
+        .collect();
+    by_age.select_nth_unstable(count - 1);
+    for (_, addr) in &by_age[..count] {
+        if let Some(session) = sessions.remove(addr) {
+            session.closed.notify_one();
+        }
+    }
+    stats.evicted.fetch_add(count as u64, Ordering::Relaxed);
+    debug!("UDP forwarder '{}' at max_sessions ({}), evicted {} least recently active sessions",
+           stats.rule_name, stats.max_sessions, count);
+}
@@ -0,0 +1,90 @@
+use crate::buffer::Buffer;
+use crate::config::parse_endpoint;
+use crate::resolver;
+use anyhow::{Context, Result};
+use log::debug;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+// Datagrams travel over the TCP connection as a 2-byte big-endian length
+// followed by the payload
+pub const MAX_DATAGRAM: usize = u16::MAX as usize;
+
+pub async fn write_frame<W>(writer: &mut W, datagram: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let length = u16::try_from(datagram.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "datagram too large to tunnel"))?;
+    let mut frame = Vec::with_capacity(2 + datagram.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(datagram);
+    writer.write_all(&frame).await
+}
+
+// Returns None when the connection closes between frames. `buffer` must hold
+// at least MAX_DATAGRAM bytes.
+pub async fn read_frame<R>(reader: &mut R, buffer: &mut [u8]) -> io::Result<Option<usize>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut length = [0u8; 2];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let length = u16::from_be_bytes(length) as usize;
+    reader.read_exact(&mut buffer[..length]).await?;
+    Ok(Some(length))
+}
+
+// Server end of a UDP-over-TCP tunnel: relays datagrams between the client
+// connection and `target` until either side fails or the client hangs up.
+// Returns the number of bytes received from the client.
+pub async fn serve<S>(stream: S, target: &str) -> Result<u64>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (host, port) = parse_endpoint(target)?;
+    let target_addr = resolver::lookup(&host, port).await?
+        .into_iter()
+        .next()
+        .with_context(|| format!("no addresses found for {}", target))?;
+    let bind = if target_addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(target_addr).await?;
+    debug!("UDP tunnel relaying to {}", target_addr);
+
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut uploaded = 0u64;
+
+    let upload = async {
+        let mut buffer = Buffer::zeroed(MAX_DATAGRAM);
+        while let Some(n) = read_frame(&mut reader, &mut buffer).await? {
+            uploaded += n as u64;
+            // Unreachable targets surface as send errors; drop like UDP would
+            let _ = socket.send(&buffer[..n]).await;
+        }
+        Ok::<_, io::Error>(())
+    };
+
+    let download = async {
+        let mut buffer = Buffer::zeroed(MAX_DATAGRAM);
+        loop {
+            match socket.recv(&mut buffer).await {
+                Ok(n) => write_frame(&mut writer, &buffer[..n]).await?,
+                // ICMP port unreachable from an earlier datagram
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(e) => return Err::<(), _>(e),
+            }
+        }
+    };
+
+    tokio::select! {
+        result = upload => result?,
+        result = download => result?,
+    }
+    Ok(uploaded)
+}
@@ -0,0 +1,111 @@
+#[cfg(unix)]
+use crate::handoff;
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::sync::Arc;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+// Addresses of the form "unix:/path/to.sock", or "unix:@name" for a socket in
+// the Linux abstract namespace. Abstract sockets have no file, so there is
+// nothing to clean up and nothing to share through a volume mount.
+const PREFIX: &str = "unix:";
+// sun_path is 108 bytes including the terminating (or, for abstract names,
+// leading) NUL
+const MAX_PATH: usize = 107;
+
+// The path or "@name" of a "unix:" address, or None for other addresses
+pub fn parse(addr: &str) -> Option<&str> {
+    addr.strip_prefix(PREFIX)
+}
+
+pub fn validate(path: &str) -> anyhow::Result<()> {
+    let name = path.strip_prefix('@').unwrap_or(path);
+    if name.is_empty() {
+        anyhow::bail!("empty Unix socket address 'unix:{}'", path);
+    }
+    if path.len() > MAX_PATH {
+        anyhow::bail!("Unix socket address 'unix:{}' is longer than {} bytes", path, MAX_PATH);
+    }
+    // tokio has no Unix sockets on Windows
+    if !cfg!(unix) {
+        anyhow::bail!("Unix socket 'unix:{}' requires a Unix platform", path);
+    }
+    if path.starts_with('@') && !cfg!(target_os = "linux") {
+        anyhow::bail!("abstract Unix socket 'unix:{}' requires Linux", path);
+    }
+    Ok(())
+}
+
+// For rules; the listener is offered to the next process on upgrades
+#[cfg(unix)]
+pub fn listener(path: &str) -> io::Result<Arc<UnixListener>> {
+    let key = format!("unix {}", path);
+    let listener = match handoff::inherited(&key) {
+        Some(socket) => {
+            let listener = std::os::unix::net::UnixListener::from(OwnedFd::from(socket));
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener)?
+        }
+        None => bind(path)?,
+    };
+    Ok(handoff::offer(key, listener))
+}
+
+#[cfg(unix)]
+pub fn bind(path: &str) -> io::Result<UnixListener> {
+    if let Some(name) = path.strip_prefix('@') {
+        return bind_abstract(name);
+    }
+    // A socket file left behind by an earlier run would make bind fail
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+#[cfg(unix)]
+pub async fn connect(path: &str) -> io::Result<UnixStream> {
+    match path.strip_prefix('@') {
+        Some(name) => connect_abstract(name.to_string()).await,
+        None => UnixStream::connect(path).await,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> io::Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+    let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+// tokio only connects to path sockets, so go through std on a blocking thread
+#[cfg(target_os = "linux")]
+async fn connect_abstract(name: String) -> io::Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    let stream = tokio::task::spawn_blocking(move || {
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+        std::os::unix::net::UnixStream::connect_addr(&addr)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    stream.set_nonblocking(true)?;
+    UnixStream::from_std(stream)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn bind_abstract(name: &str) -> io::Result<UnixListener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("abstract Unix socket '@{}' requires Linux", name)))
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+async fn connect_abstract(name: String) -> io::Result<UnixStream> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("abstract Unix socket '@{}' requires Linux", name)))
+}
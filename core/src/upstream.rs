@@ -0,0 +1,188 @@
+use crate::config::parse_endpoint;
+use crate::connector::{connect_happy_eyeballs_from, Source};
+use crate::socks5::{
+    error_message, SOCKS_ATYP_DOMAIN, SOCKS_ATYP_IPV4, SOCKS_ATYP_IPV6, SOCKS_AUTH_NONE,
+    SOCKS_AUTH_PASSWORD, SOCKS_AUTH_UNACCEPTABLE, SOCKS_CMD_CONNECT, SOCKS_VERSION,
+};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+// Proxy that target connections are tunnelled through
+#[derive(Debug, Clone)]
+pub enum UpstreamProxy {
+    // Hostnames are passed to the proxy unresolved, which Tor needs
+    Socks5 {
+        host: String,
+        port: u16,
+        credentials: Option<(String, String)>,
+    },
+    // HTTP proxy, tunnelling with CONNECT
+    Http {
+        host: String,
+        port: u16,
+        // Precomputed Proxy-Authorization value
+        authorization: Option<String>,
+    },
+}
+
+impl UpstreamProxy {
+    // socks5://[user:pass@]host:port or http://[user:pass@]host:port
+    pub fn parse(url: &str) -> Result<Self> {
+        let (scheme, rest) = url.split_once("://")
+            .with_context(|| format!("upstream_proxy '{}' is not a URL", url))?;
+        let rest = rest.trim_end_matches('/');
+        let (credentials, authority) = match rest.rsplit_once('@') {
+            Some((userinfo, authority)) => {
+                let (user, pass) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (Some((user.to_string(), pass.to_string())), authority)
+            }
+            None => (None, rest),
+        };
+        let (host, port) = parse_endpoint(authority)
+            .with_context(|| format!("invalid upstream_proxy '{}'", url))?;
+
+        match scheme.to_ascii_lowercase().as_str() {
+            "socks5" | "socks5h" => {
+                if let Some((user, pass)) = &credentials
+                    && (user.len() > 255 || pass.len() > 255)
+                {
+                    anyhow::bail!("SOCKS5 username and password are limited to 255 bytes");
+                }
+                Ok(UpstreamProxy::Socks5 { host, port, credentials })
+            }
+            "http" => {
+                let authorization = credentials
+                    .map(|(user, pass)| format!("Basic {}", STANDARD.encode(format!("{}:{}", user, pass))));
+                Ok(UpstreamProxy::Http { host, port, authorization })
+            }
+            other => anyhow::bail!("unsupported upstream_proxy scheme '{}'", other),
+        }
+    }
+
+    // The proxy is reached from `source`
+    pub async fn connect(&self, target_host: &str, target_port: u16, source: &Source) -> Result<TcpStream> {
+        match self {
+            UpstreamProxy::Socks5 { host, port, credentials } => {
+                let mut stream = connect_happy_eyeballs_from(host, *port, source).await
+                    .with_context(|| format!("failed to reach SOCKS5 proxy {}:{}", host, port))?;
+                socks5_handshake(&mut stream, credentials.as_ref(), target_host, target_port).await?;
+                Ok(stream)
+            }
+            UpstreamProxy::Http { host, port, authorization } => {
+                let mut stream = connect_happy_eyeballs_from(host, *port, source).await
+                    .with_context(|| format!("failed to reach HTTP proxy {}:{}", host, port))?;
+                http_connect(&mut stream, authorization.as_deref(), target_host, target_port).await?;
+                Ok(stream)
+            }
+        }
+    }
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    authorization: Option<&str>,
+    target_host: &str,
+    target_port: u16,
+) -> Result<()> {
+    let authority = if target_host.contains(':') {
+        format!("[{}]:{}", target_host, target_port)
+    } else {
+        format!("{}:{}", target_host, target_port)
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some(authorization) = authorization {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read one byte at a time: anything after the response head already
+    // belongs to the target (e.g. an SSH banner)
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE {
+            anyhow::bail!("HTTP proxy response head too large");
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        anyhow::bail!("HTTP proxy refused CONNECT to {}: {}", authority, status_line);
+    }
+    Ok(())
+}
+
+async fn socks5_handshake(
+    stream: &mut TcpStream,
+    credentials: Option<&(String, String)>,
+    target_host: &str,
+    target_port: u16,
+) -> Result<()> {
+    let method = if credentials.is_some() { SOCKS_AUTH_PASSWORD } else { SOCKS_AUTH_NONE };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        anyhow::bail!("upstream proxy is not a SOCKS5 server");
+    }
+    if reply[1] == SOCKS_AUTH_UNACCEPTABLE || reply[1] != method {
+        anyhow::bail!("SOCKS5 proxy rejected our authentication method");
+    }
+
+    // RFC 1929 username/password sub-negotiation
+    if let Some((user, pass)) = credentials {
+        let mut request = vec![0x01, user.len() as u8];
+        request.extend_from_slice(user.as_bytes());
+        request.push(pass.len() as u8);
+        request.extend_from_slice(pass.as_bytes());
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            anyhow::bail!("SOCKS5 proxy rejected the username/password");
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0x00];
+    match target_host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS_ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS_ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            request.push(SOCKS_ATYP_DOMAIN);
+            request.push(target_host.len() as u8);
+            request.extend_from_slice(target_host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&target_port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != 0x00 {
+        anyhow::bail!("SOCKS5 proxy refused the connection: {}", error_message(header[1]));
+    }
+    // Skip the bound address, which we have no use for
+    let address_length = match header[3] {
+        SOCKS_ATYP_IPV4 => 4,
+        SOCKS_ATYP_IPV6 => 16,
+        SOCKS_ATYP_DOMAIN => stream.read_u8().await? as usize,
+        other => anyhow::bail!("SOCKS5 proxy sent unknown address type {}", other),
+    };
+    let mut bound = vec![0u8; address_length + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
@@ -0,0 +1,444 @@
+use crate::buffer::Buffer;
+use crate::copy::End;
+use crate::ktls;
+#[cfg(not(target_os = "linux"))]
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::sync::Arc;
+
+// The io_uring backend ([global] io_backend = "io_uring", Linux). One thread
+// owns a ring and submits the reads and writes of all forwarders to the
+// kernel in batches, and the kernel completes them without porture waiting
+// for readiness first, so busy rules cost far fewer system calls. It handles
+// TCP connections between two plain sockets (as splice would otherwise) and
+// UDP forwarding, receives and sends on both the client and target side;
+// everything else goes through tokio as usual.
+//
+// Buffers are handed to the ring thread for as long as the kernel may write
+// to them, and handed back on completion. Dropping an operation's future
+// cancels it, and leaves the caller's buffer empty.
+
+// The socket an operation works on, kept open until it completes
+pub type Fd = Arc<dyn AsRawFd + Send + Sync>;
+
+#[cfg(target_os = "linux")]
+pub use linux::Ring;
+
+#[cfg(not(target_os = "linux"))]
+pub struct Ring;
+
+#[cfg(not(target_os = "linux"))]
+impl Ring {
+    pub fn start() -> io::Result<Arc<Ring>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "io_uring requires Linux"))
+    }
+
+    pub async fn recv(&self, _fd: Fd, _buffer: &mut Buffer) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "io_uring requires Linux"))
+    }
+
+    pub async fn send_all(&self, _fd: Fd, _buffer: &mut Buffer) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "io_uring requires Linux"))
+    }
+
+    pub async fn recv_from(&self, _fd: Fd, _buffer: &mut Buffer) -> io::Result<(usize, std::net::SocketAddr, Option<usize>)> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "io_uring requires Linux"))
+    }
+
+    pub async fn send_to(
+        &self,
+        _fd: Fd,
+        _buffer: &mut Buffer,
+        _segment_size: Option<usize>,
+        _destination: Option<std::net::SocketAddr>,
+    ) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "io_uring requires Linux"))
+    }
+}
+
+impl Ring {
+    // Forwards until `from` is closed, calling `on_data` with the length of
+    // each chunk read. Each chunk is sent while the next is read into a
+    // second buffer, so the kernel always has a receive to complete.
+    pub async fn forward(&self, from: Fd, to: Fd, buffer_size: usize, mut on_data: impl FnMut(usize)) -> End {
+        // SAFETY: `to` keeps the socket open for as long as this runs
+        let eof = || End::eof(&unsafe { BorrowedFd::borrow_raw(to.as_raw_fd()) });
+        let mut sending = Buffer::zeroed(buffer_size);
+        let mut reading = Buffer::zeroed(buffer_size);
+        let mut next = self.read(&from, &mut sending).await;
+        loop {
+            let n = match next {
+                Ok(Some(n)) => n,
+                Ok(None) => return eof(),
+                Err(end) => return end,
+            };
+            on_data(n);
+            sending.truncate(n);
+            // A failed send gives up on the read; the end of the stream
+            // waits for the send, so the chunk arrives before the EOF
+            let sent = tokio::try_join!(
+                async { self.send_all(to.clone(), &mut sending).await.map_err(End::Write) },
+                async { Ok(self.read(&from, &mut reading).await) },
+            );
+            next = match sent {
+                Ok(((), read)) => read,
+                Err(end) => return end,
+            };
+            sending.resize(buffer_size, 0);
+            std::mem::swap(&mut sending, &mut reading);
+        }
+    }
+
+    // The length of the next chunk read into the buffer, None at the end
+    async fn read(&self, from: &Fd, buffer: &mut Buffer) -> Result<Option<usize>, End> {
+        loop {
+            return match self.recv(from.clone(), buffer).await {
+                Ok(0) => Ok(None),
+                Ok(n) => Ok(Some(n)),
+                // A kTLS record that isn't data
+                Err(e) => match ktls::skip_control(from.as_raw_fd(), e) {
+                    Ok(true) => Ok(None),
+                    Ok(false) => continue,
+                    Err(e) => Err(End::Read(e)),
+                },
+            };
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::Fd;
+    use crate::buffer::Buffer;
+    use crate::gso;
+    use crate::transparent;
+    use io_uring::{opcode, squeue, types, IoUring};
+    use log::error;
+    use std::collections::HashMap;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::ops::Range;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::mpsc;
+    use tokio::sync::oneshot;
+
+    // Operations submitted at once; more wait in the channel
+    const ENTRIES: u32 = 1024;
+    // user_data of the ring's own operations
+    const WAKE: u64 = u64::MAX;
+    const CANCEL: u64 = u64::MAX - 1;
+
+    pub struct Ring {
+        commands: mpsc::Sender<Command>,
+        // Written to after each command, to wake the ring thread
+        wake: OwnedFd,
+        next_id: AtomicU64,
+    }
+
+    enum Command {
+        Submit(Request),
+        Cancel(u64),
+    }
+
+    struct Request {
+        id: u64,
+        fd: Fd,
+        op: Op,
+        done: oneshot::Sender<(i32, Op)>,
+    }
+
+    enum Op {
+        Recv(Buffer),
+        // Sends the buffer from the offset on
+        Send(Buffer, usize),
+        RecvMsg(Buffer, Box<Message>),
+        // Sends the part of the buffer the message points to
+        SendMsg(Buffer, Box<Message>),
+    }
+
+    // What recvmsg writes besides the data, or what sendmsg sends it with,
+    // boxed so the kernel's pointers into it stay valid wherever the
+    // operation is moved
+    struct Message {
+        header: libc::msghdr,
+        iov: libc::iovec,
+        // The sender of a datagram received, or where to send one
+        address: libc::sockaddr_storage,
+        // u64 keeps the control buffer aligned for cmsghdr
+        control: [u64; 8],
+    }
+
+    // SAFETY: the pointers in `header` and `iov` refer to the message itself
+    // and to the buffer of the operation it belongs to, which move with it
+    unsafe impl Send for Message {}
+
+    impl Message {
+        fn new() -> Box<Self> {
+            // SAFETY: all-zero is a valid msghdr, iovec and sockaddr_storage
+            Box::new(unsafe { std::mem::zeroed() })
+        }
+
+        // A send of `data`, which must stay put until it completes
+        fn outgoing(data: &[u8], segment_size: Option<u16>, destination: Option<SocketAddr>) -> Box<Self> {
+            let mut boxed = Self::new();
+            let message = &mut *boxed;
+            message.iov = libc::iovec { iov_base: data.as_ptr().cast_mut().cast(), iov_len: data.len() };
+            message.header.msg_iov = &mut message.iov;
+            message.header.msg_iovlen = 1;
+            if let Some(destination) = destination {
+                let address = socket2::SockAddr::from(destination);
+                // SAFETY: any socket address fits in a sockaddr_storage
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        address.as_ptr().cast::<u8>(),
+                        (&mut message.address as *mut libc::sockaddr_storage).cast::<u8>(),
+                        address.len() as usize,
+                    );
+                }
+                message.header.msg_name = (&mut message.address as *mut libc::sockaddr_storage).cast();
+                message.header.msg_namelen = address.len();
+            }
+            if let Some(segment_size) = segment_size {
+                message.header.msg_control = message.control.as_mut_ptr().cast();
+                // SAFETY: `control` is aligned for cmsghdr and has room for a u16
+                unsafe { gso::set_segment_size(&mut message.header, segment_size) };
+            }
+            boxed
+        }
+    }
+
+    impl Ring {
+        pub fn start() -> io::Result<Arc<Ring>> {
+            let ring = IoUring::new(ENTRIES)?;
+            // SAFETY: eventfd takes no pointers
+            let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+            if wake < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the descriptor was just opened and nothing else owns it
+            let wake = unsafe { OwnedFd::from_raw_fd(wake) };
+            let (commands, rx) = mpsc::channel();
+            let eventfd = wake.as_raw_fd();
+            std::thread::Builder::new()
+                .name("porture-uring".to_string())
+                .spawn(move || run(ring, rx, eventfd))?;
+            Ok(Arc::new(Ring { commands, wake, next_id: AtomicU64::new(0) }))
+        }
+
+        // Reads into the whole buffer, returning how much was read
+        pub async fn recv(&self, fd: Fd, buffer: &mut Buffer) -> io::Result<usize> {
+            let (result, op) = self.submit(fd, Op::Recv(std::mem::take(buffer))).await?;
+            let Op::Recv(returned) = op else { unreachable!() };
+            *buffer = returned;
+            result
+        }
+
+        pub async fn send_all(&self, fd: Fd, buffer: &mut Buffer) -> io::Result<()> {
+            let mut offset = 0;
+            while offset < buffer.len() {
+                let (result, op) = self.submit(fd.clone(), Op::Send(std::mem::take(buffer), offset)).await?;
+                let Op::Send(returned, _) = op else { unreachable!() };
+                *buffer = returned;
+                match result? {
+                    0 => return Err(io::ErrorKind::WriteZero.into()),
+                    n => offset += n,
+                }
+            }
+            Ok(())
+        }
+
+        // Like gso::recv: the length and sender of the next read, and the
+        // size of the datagrams in it if the kernel coalesced several
+        pub async fn recv_from(&self, fd: Fd, buffer: &mut Buffer) -> io::Result<(usize, SocketAddr, Option<usize>)> {
+            let (result, op) = self.submit(fd, Op::RecvMsg(std::mem::take(buffer), Message::new())).await?;
+            let Op::RecvMsg(returned, message) = op else { unreachable!() };
+            *buffer = returned;
+            let length = result?;
+            // SAFETY: the kernel filled in `address` and set msg_namelen
+            let source = unsafe { transparent::read_sockaddr((&message.address as *const libc::sockaddr_storage).cast()) }
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram from a non-IP address"))?;
+            // SAFETY: the kernel filled in the control data
+            let segment_size = unsafe { gso::segment_size(&message.header, length) };
+            Ok((length, source, segment_size))
+        }
+
+        // Like gso::send: sends the buffer to `destination`, or the socket's
+        // connected peer, as datagrams of `segment_size` bytes
+        pub async fn send_to(
+            &self,
+            fd: Fd,
+            buffer: &mut Buffer,
+            segment_size: Option<usize>,
+            destination: Option<SocketAddr>,
+        ) -> io::Result<()> {
+            let Some(segment_size) = segment_size.filter(|&size| size < buffer.len()) else {
+                return self.send_msg(fd, buffer, 0..buffer.len(), None, destination).await;
+            };
+            let size = u16::try_from(segment_size)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "segment size exceeds 65535 bytes"))?;
+            match self.send_msg(fd.clone(), buffer, 0..buffer.len(), Some(size), destination).await {
+                // The route's device can't segment, so one by one
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => {}
+                result => return result,
+            }
+            let mut start = 0;
+            while start < buffer.len() {
+                let end = (start + segment_size).min(buffer.len());
+                self.send_msg(fd.clone(), buffer, start..end, None, destination).await?;
+                start = end;
+            }
+            Ok(())
+        }
+
+        async fn send_msg(
+            &self,
+            fd: Fd,
+            buffer: &mut Buffer,
+            range: Range<usize>,
+            segment_size: Option<u16>,
+            destination: Option<SocketAddr>,
+        ) -> io::Result<()> {
+            let message = Message::outgoing(&buffer[range], segment_size, destination);
+            let (result, op) = self.submit(fd, Op::SendMsg(std::mem::take(buffer), message)).await?;
+            let Op::SendMsg(returned, _) = op else { unreachable!() };
+            *buffer = returned;
+            result.map(drop)
+        }
+
+        // The result of the operation, with the operation back
+        async fn submit(&self, fd: Fd, op: Op) -> io::Result<(io::Result<usize>, Op)> {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let (done, rx) = oneshot::channel();
+            self.command(Command::Submit(Request { id, fd, op, done }))?;
+            let mut pending = Pending { ring: self, id, finished: false };
+            let (result, op) = rx.await.map_err(|_| io::Error::other("io_uring thread stopped"))?;
+            pending.finished = true;
+            let result = match result {
+                n if n < 0 => Err(io::Error::from_raw_os_error(-n)),
+                n => Ok(n as usize),
+            };
+            Ok((result, op))
+        }
+
+        fn command(&self, command: Command) -> io::Result<()> {
+            self.commands.send(command).map_err(|_| io::Error::other("io_uring thread stopped"))?;
+            let one: u64 = 1;
+            // SAFETY: `one` outlives the call and its size is passed with it
+            unsafe { libc::write(self.wake.as_raw_fd(), (&one as *const u64).cast(), 8) };
+            Ok(())
+        }
+    }
+
+    // Cancels the operation if its future is dropped first
+    struct Pending<'a> {
+        ring: &'a Ring,
+        id: u64,
+        finished: bool,
+    }
+
+    impl Drop for Pending<'_> {
+        fn drop(&mut self) {
+            if !self.finished {
+                let _ = self.ring.command(Command::Cancel(self.id));
+            }
+        }
+    }
+
+    fn run(mut ring: IoUring, commands: mpsc::Receiver<Command>, wake: i32) {
+        // Operations the kernel has, with the buffers it writes to
+        let mut in_flight: HashMap<u64, Request> = HashMap::new();
+        let mut counter: u64 = 0;
+        let mut waiting = false;
+        loop {
+            if !waiting {
+                let entry = opcode::Read::new(types::Fd(wake), (&mut counter as *mut u64).cast(), 8)
+                    .build()
+                    .user_data(WAKE);
+                push(&mut ring, &entry);
+                waiting = true;
+            }
+            loop {
+                match commands.try_recv() {
+                    Ok(Command::Submit(mut request)) => {
+                        let entry = prepare(&mut request).user_data(request.id);
+                        push(&mut ring, &entry);
+                        in_flight.insert(request.id, request);
+                    }
+                    Ok(Command::Cancel(id)) if in_flight.contains_key(&id) => {
+                        push(&mut ring, &opcode::AsyncCancel::new(id).build().user_data(CANCEL));
+                    }
+                    Ok(Command::Cancel(_)) => {}
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return abandon(in_flight),
+                }
+            }
+            if let Err(e) = ring.submit_and_wait(1)
+                && !matches!(e.raw_os_error(), Some(libc::EINTR) | Some(libc::EBUSY))
+            {
+                error!("io_uring failed: {}", e);
+                return abandon(in_flight);
+            }
+            for completion in ring.completion() {
+                match completion.user_data() {
+                    WAKE => waiting = false,
+                    CANCEL => {}
+                    id => {
+                        if let Some(request) = in_flight.remove(&id) {
+                            let _ = request.done.send((completion.result(), request.op));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // The kernel may still write to the buffers of operations it has after
+    // the ring is closed, so they are never freed
+    fn abandon(in_flight: HashMap<u64, Request>) {
+        std::mem::forget(in_flight);
+    }
+
+    // The entry for a request, pointing into its buffers
+    fn prepare(request: &mut Request) -> squeue::Entry {
+        let fd = types::Fd(request.fd.as_raw_fd());
+        match &mut request.op {
+            Op::Recv(buffer) => opcode::Recv::new(fd, buffer.as_mut_ptr(), buffer.len() as u32).build(),
+            Op::Send(buffer, offset) => {
+                let data = &buffer[*offset..];
+                opcode::Send::new(fd, data.as_ptr(), data.len() as u32)
+                    .flags(libc::MSG_NOSIGNAL)
+                    .build()
+            }
+            Op::RecvMsg(buffer, message) => {
+                let message = &mut **message;
+                message.iov = libc::iovec { iov_base: buffer.as_mut_ptr().cast(), iov_len: buffer.len() };
+                message.header.msg_name = (&mut message.address as *mut libc::sockaddr_storage).cast();
+                message.header.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                message.header.msg_iov = &mut message.iov;
+                message.header.msg_iovlen = 1;
+                message.header.msg_control = message.control.as_mut_ptr().cast();
+                message.header.msg_controllen = std::mem::size_of_val(&message.control);
+                opcode::RecvMsg::new(fd, &mut message.header).build()
+            }
+            // Filled in by Message::outgoing
+            Op::SendMsg(_, message) => opcode::SendMsg::new(fd, &message.header).build(),
+        }
+    }
+
+    // Makes room in the submission queue by submitting what's in it
+    fn push(ring: &mut IoUring, entry: &squeue::Entry) {
+        loop {
+            // SAFETY: the buffers and message an entry points into are kept
+            // in `in_flight` until it completes, and the wake counter lives
+            // as long as the ring thread
+            if unsafe { ring.submission().push(entry) }.is_ok() {
+                return;
+            }
+            if let Err(e) = ring.submit() {
+                error!("io_uring submit failed: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use crate::config::parse_endpoint;
+use crate::filter::{Admission, DatagramFilter, Direction, Flow, FlowFilter, StreamFilter, Verdict};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::sync::OnceLock;
+use wasmtime::{
+    Caller, Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+    WasmParams, WasmResults,
+};
+
+// WebAssembly filters (a rule's wasm_filter). The module is compiled when
+// the rule starts, and every connection or UDP session gets an instance of
+// its own, so globals hold per-flow state. The module exports:
+//
+//   memory                                 required
+//   on_connect() -> i32                    0 admits the client, anything else rejects it
+//   on_data(direction, ptr, len) -> i32    0 passes the data, anything else rejects it
+//   alloc(len) -> ptr                      where on_data's data is copied; required with on_data
+//
+// direction is 0 for data from the client and 1 for data to it. It may
+// import from "porture":
+//
+//   client_addr(ptr, len) -> i32           writes the client's ip:port, returns its length
+//   local_addr(ptr, len) -> i32            writes the address the client reached
+//   set_target(ptr, len) -> i32            in on_connect of TCP rules: connect to this host:port instead
+//   set_data(ptr, len) -> i32              in on_data: pass these bytes on instead
+//   log(ptr, len)                          logs a message at info level
+//
+// Imports that take a string return -1 if it is invalid. A filter that
+// traps, or runs out of fuel or memory, rejects the flow.
+
+// Instructions for each call, so a filter stuck in a loop fails quickly
+const FUEL_PER_CALL: u64 = 10_000_000;
+const MAX_MEMORY: usize = 16 << 20;
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        // Copy-on-write initialization needs memfd_create, which [global]
+        // seccomp doesn't allow
+        config.memory_init_cow(false);
+        Engine::new(&config).expect("fuel is supported by every engine")
+    })
+}
+
+// alloc(len) -> ptr and on_data(direction, ptr, len) -> i32
+type DataExports = (TypedFunc<i32, i32>, TypedFunc<(i32, i32, i32), i32>);
+
+// What an instance's imports work with
+struct Host {
+    rule_name: String,
+    flow: Flow,
+    tcp: bool,
+    in_on_data: bool,
+    target: Option<String>,
+    // What set_data replaced the data being filtered with
+    data: Option<Vec<u8>>,
+    limits: StoreLimits,
+}
+
+pub struct WasmFilter {
+    rule_name: String,
+    path: String,
+    module: Module,
+    linker: Linker<Host>,
+}
+
+impl WasmFilter {
+    pub fn load(path: &str, rule_name: String) -> Result<Self> {
+        let module = Module::from_file(engine(), path).with_context(|| format!("failed to load wasm_filter {}", path))?;
+        let mut linker = Linker::new(engine());
+        define_imports(&mut linker)?;
+        Ok(Self { rule_name, path: path.to_string(), module, linker })
+    }
+
+    fn admit(&self, flow: &Flow, tcp: bool) -> Admission {
+        match self.instantiate(flow, tcp) {
+            Ok(Some(instance)) => Admission::Watch(Box::new(instance)),
+            Ok(None) => {
+                debug!("wasm_filter of rule '{}' rejected {}", self.rule_name, flow.client_addr);
+                Admission::Reject
+            }
+            Err(e) => {
+                warn!("wasm_filter {} of rule '{}' failed, rejecting {}: {}", self.path, self.rule_name, flow.client_addr, e.root_cause());
+                Admission::Reject
+            }
+        }
+    }
+
+    // None if on_connect rejected the client
+    fn instantiate(&self, flow: &Flow, tcp: bool) -> Result<Option<WasmFlow>> {
+        let host = Host {
+            rule_name: self.rule_name.clone(),
+            flow: *flow,
+            tcp,
+            in_on_data: false,
+            target: None,
+            data: None,
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+        };
+        let mut store = Store::new(engine(), host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        let memory = instance.get_memory(&mut store, "memory").context("the module exports no memory")?;
+        let on_data = match optional_export(&instance, &mut store, "on_data")? {
+            Some(on_data) => Some((instance.get_typed_func(&mut store, "alloc").context("on_data needs alloc")?, on_data)),
+            None => None,
+        };
+        if let Some(on_connect) = optional_export::<(), i32>(&instance, &mut store, "on_connect")? {
+            store.set_fuel(FUEL_PER_CALL)?;
+            if on_connect.call(&mut store, ())? != 0 {
+                return Ok(None);
+            }
+        }
+        Ok(Some(WasmFlow { store, memory, on_data }))
+    }
+}
+
+impl StreamFilter for WasmFilter {
+    fn on_connect(&self, flow: &Flow) -> Admission {
+        self.admit(flow, true)
+    }
+}
+
+impl DatagramFilter for WasmFilter {
+    fn on_session(&self, flow: &Flow) -> Admission {
+        self.admit(flow, false)
+    }
+}
+
+// One connection's or session's instance
+struct WasmFlow {
+    store: Store<Host>,
+    memory: Memory,
+    on_data: Option<DataExports>,
+}
+
+impl WasmFlow {
+    fn filter(&mut self, direction: Direction, data: &mut Vec<u8>) -> Result<bool> {
+        let Some((alloc, on_data)) = &self.on_data else {
+            return Ok(true);
+        };
+        let len = i32::try_from(data.len())?;
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        let ptr = alloc.call(&mut self.store, len)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, data)?;
+        let direction = match direction {
+            Direction::Upload => 0,
+            Direction::Download => 1,
+        };
+        self.store.data_mut().in_on_data = true;
+        let result = on_data.call(&mut self.store, (direction, ptr, len));
+        let host = self.store.data_mut();
+        host.in_on_data = false;
+        let passed = result? == 0;
+        if let Some(replacement) = host.data.take() {
+            *data = replacement;
+        }
+        Ok(passed)
+    }
+}
+
+impl FlowFilter for WasmFlow {
+    fn on_data(&mut self, direction: Direction, data: &mut Vec<u8>) -> Verdict {
+        match self.filter(direction, data) {
+            Ok(true) => Verdict::Pass,
+            Ok(false) => Verdict::Reject,
+            Err(e) => {
+                let host = self.store.data();
+                warn!("wasm_filter of rule '{}' failed on data of {}: {}", host.rule_name, host.flow.client_addr, e.root_cause());
+                Verdict::Reject
+            }
+        }
+    }
+
+    fn target(&self) -> Option<String> {
+        self.store.data().target.clone()
+    }
+}
+
+fn optional_export<P: WasmParams, R: WasmResults>(
+    instance: &Instance,
+    store: &mut Store<Host>,
+    name: &str,
+) -> Result<Option<TypedFunc<P, R>>> {
+    if instance.get_export(&mut *store, name).is_none() {
+        return Ok(None);
+    }
+    instance.get_typed_func(store, name).map(Some).with_context(|| format!("export {} has the wrong type", name))
+}
+
+fn define_imports(linker: &mut Linker<Host>) -> Result<()> {
+    linker.func_wrap("porture", "client_addr", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+        let addr = caller.data().flow.client_addr.to_string();
+        write_guest(&mut caller, ptr, len, addr.as_bytes())
+    })?;
+    linker.func_wrap("porture", "local_addr", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+        let addr = caller.data().flow.local_addr.to_string();
+        write_guest(&mut caller, ptr, len, addr.as_bytes())
+    })?;
+    linker.func_wrap("porture", "set_target", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+        let target = read_guest(&mut caller, ptr, len).and_then(|bytes| String::from_utf8(bytes).ok());
+        match target {
+            Some(target) if caller.data().tcp && !caller.data().in_on_data && parse_endpoint(&target).is_ok() => {
+                caller.data_mut().target = Some(target);
+                0
+            }
+            _ => -1,
+        }
+    })?;
+    linker.func_wrap("porture", "set_data", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+        match read_guest(&mut caller, ptr, len) {
+            Some(data) if caller.data().in_on_data => {
+                caller.data_mut().data = Some(data);
+                0
+            }
+            _ => -1,
+        }
+    })?;
+    linker.func_wrap("porture", "log", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+        if let Some(message) = read_guest(&mut caller, ptr, len) {
+            info!("wasm_filter of rule '{}': {}", caller.data().rule_name, String::from_utf8_lossy(&message));
+        }
+    })?;
+    Ok(())
+}
+
+fn guest_memory(caller: &mut Caller<'_, Host>) -> Option<Memory> {
+    caller.get_export("memory")?.into_memory()
+}
+
+// None if the range is outside the guest's memory
+fn read_guest(caller: &mut Caller<'_, Host>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let memory = guest_memory(caller)?;
+    let start = ptr as u32 as usize;
+    let data = memory.data(&caller).get(start..start.checked_add(len as u32 as usize)?)?;
+    Some(data.to_vec())
+}
+
+// Writes as much of `bytes` as fits and returns their full length, so the
+// guest can retry with a larger buffer
+fn write_guest(caller: &mut Caller<'_, Host>, ptr: i32, len: i32, bytes: &[u8]) -> i32 {
+    let Some(memory) = guest_memory(caller) else {
+        return -1;
+    };
+    let count = bytes.len().min(len.max(0) as usize);
+    match memory.write(&mut *caller, ptr as u32 as usize, &bytes[..count]) {
+        Ok(()) => bytes.len() as i32,
+        Err(_) => -1,
+    }
+}
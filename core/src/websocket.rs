@@ -0,0 +1,282 @@
+use crate::http;
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+// Outgoing frames are capped so a large write doesn't sit in one frame
+const MAX_WRITE_PAYLOAD: usize = 16 * 1024;
+const MAX_FRAME_PAYLOAD: u64 = 16 * 1024 * 1024;
+const READ_CHUNK: usize = 8192;
+
+// Client side: upgrades `stream` by requesting `path` on `host`
+pub async fn connect<S>(mut stream: S, host: &str, path: &str) -> Result<WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut nonce = [0u8; 16];
+    SystemRandom::new().fill(&mut nonce).map_err(|_| anyhow::anyhow!("failed to generate WebSocket key"))?;
+    let key = STANDARD.encode(nonce);
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nUser-Agent: porture/{}\r\n\r\n",
+        path, host, key, env!("CARGO_PKG_VERSION")
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let response = http::read_request_head(&mut stream).await?;
+    let status = response.start_line().split_whitespace().nth(1).unwrap_or_default().to_string();
+    if status != "101" {
+        anyhow::bail!("WebSocket upgrade to {}{} refused: {}", host, path, response.start_line());
+    }
+    if response.header("sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        anyhow::bail!("WebSocket upgrade to {}{} returned a bad Sec-WebSocket-Accept", host, path);
+    }
+    Ok(WebSocketStream::new(stream, true, response.into_rest()))
+}
+
+// Server side: answers the client's upgrade request. Requests for another
+// path than `path`, if set, are turned away.
+pub async fn accept<S>(mut stream: S, path: Option<&str>) -> Result<WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = http::read_request_head(&mut stream).await?;
+    let requested = request.request_line().map(|(_, target)| target.split('?').next().unwrap_or_default());
+    if let Some(path) = path
+        && requested != Some(path)
+    {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+        anyhow::bail!("WebSocket request for unknown path {:?}", requested.unwrap_or_default());
+    }
+    let upgrade = request.header("upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let key = match request.header("sec-websocket-key") {
+        Some(key) if upgrade => key.to_string(),
+        _ => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+            anyhow::bail!("not a WebSocket upgrade request");
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(WebSocketStream::new(stream, false, request.into_rest()))
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, ACCEPT_GUID).as_bytes(),
+    );
+    STANDARD.encode(digest.as_ref())
+}
+
+// A byte stream carried in binary WebSocket messages
+pub struct WebSocketStream<S> {
+    inner: S,
+    // Clients must mask their frames, servers must not
+    mask: bool,
+    // Bytes read from `inner` that don't make up a whole frame yet
+    raw: Vec<u8>,
+    payload: Vec<u8>,
+    payload_pos: usize,
+    // Encoded frames not yet written to `inner`
+    outgoing: Vec<u8>,
+    outgoing_pos: usize,
+    read_closed: bool,
+    close_sent: bool,
+}
+
+impl<S> WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(inner: S, mask: bool, raw: Vec<u8>) -> Self {
+        Self {
+            inner,
+            mask,
+            raw,
+            payload: Vec::new(),
+            payload_pos: 0,
+            outgoing: Vec::new(),
+            outgoing_pos: 0,
+            read_closed: false,
+            close_sent: false,
+        }
+    }
+
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.outgoing_pos == self.outgoing.len() {
+            self.outgoing.clear();
+            self.outgoing_pos = 0;
+        }
+        self.outgoing.push(0x80 | opcode);
+        let mask_bit = if self.mask { 0x80 } else { 0 };
+        match payload.len() {
+            n if n < 126 => self.outgoing.push(mask_bit | n as u8),
+            n if n <= u16::MAX as usize => {
+                self.outgoing.push(mask_bit | 126);
+                self.outgoing.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                self.outgoing.push(mask_bit | 127);
+                self.outgoing.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        if self.mask {
+            let mut key = [0u8; 4];
+            SystemRandom::new().fill(&mut key).map_err(|_| io::Error::other("failed to generate frame mask"))?;
+            self.outgoing.extend_from_slice(&key);
+            self.outgoing.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        } else {
+            self.outgoing.extend_from_slice(payload);
+        }
+        Ok(())
+    }
+
+    fn poll_send_outgoing(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while self.outgoing_pos < self.outgoing.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.outgoing_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.outgoing_pos += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Decodes the next complete frame in `raw`, if there is one
+    fn next_frame(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let raw = &self.raw;
+        if raw.len() < 2 {
+            return Ok(None);
+        }
+        let opcode = raw[0] & 0x0f;
+        let masked = raw[1] & 0x80 != 0;
+        let (length, mut offset) = match raw[1] & 0x7f {
+            126 if raw.len() >= 4 => (u16::from_be_bytes([raw[2], raw[3]]) as u64, 4),
+            127 if raw.len() >= 10 => (u64::from_be_bytes(raw[2..10].try_into().unwrap()), 10),
+            126 | 127 => return Ok(None),
+            n => (n as u64, 2),
+        };
+        if length > MAX_FRAME_PAYLOAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "WebSocket frame too large"));
+        }
+        let key = if masked {
+            let Some(key) = raw.get(offset..offset + 4) else {
+                return Ok(None);
+            };
+            offset += 4;
+            Some([key[0], key[1], key[2], key[3]])
+        } else {
+            None
+        };
+        let end = offset + length as usize;
+        if raw.len() < end {
+            return Ok(None);
+        }
+        let mut payload = raw[offset..end].to_vec();
+        if let Some(key) = key {
+            payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= key[i % 4]);
+        }
+        self.raw.drain(..end);
+        Ok(Some((opcode, payload)))
+    }
+}
+
+impl<S> AsyncRead for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.payload_pos < this.payload.len() {
+                let n = buf.remaining().min(this.payload.len() - this.payload_pos);
+                buf.put_slice(&this.payload[this.payload_pos..this.payload_pos + n]);
+                this.payload_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.read_closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            match this.next_frame()? {
+                Some((OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY, payload)) => {
+                    this.payload = payload;
+                    this.payload_pos = 0;
+                }
+                Some((OPCODE_CLOSE, _)) => this.read_closed = true,
+                Some((OPCODE_PING, payload)) => {
+                    this.queue_frame(OPCODE_PONG, &payload)?;
+                    // Best effort; whatever is left goes out with the next write
+                    if let Poll::Ready(Err(e)) = this.poll_send_outgoing(cx) {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+                // Pongs and unknown control frames
+                Some(_) => {}
+                None => {
+                    let start = this.raw.len();
+                    this.raw.resize(start + READ_CHUNK, 0);
+                    let mut read_buf = ReadBuf::new(&mut this.raw[start..]);
+                    let result = Pin::new(&mut this.inner).poll_read(cx, &mut read_buf);
+                    let filled = read_buf.filled().len();
+                    this.raw.truncate(start + filled);
+                    ready!(result)?;
+                    if filled == 0 {
+                        this.read_closed = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Earlier frames go out first so frames are never interleaved
+        ready!(this.poll_send_outgoing(cx))?;
+        let n = buf.len().min(MAX_WRITE_PAYLOAD);
+        this.queue_frame(OPCODE_BINARY, &buf[..n])?;
+        // The frame is queued either way; Pending just means it goes out later
+        if let Poll::Ready(Err(e)) = this.poll_send_outgoing(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            ready!(this.poll_send_outgoing(cx))?;
+            this.queue_frame(OPCODE_CLOSE, &[])?;
+            this.close_sent = true;
+        }
+        ready!(this.poll_send_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
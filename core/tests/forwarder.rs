@@ -0,0 +1,76 @@
+use porture_core::{Forwarder, TcpRule, UdpRule};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn tcp_round_trip_and_shutdown() {
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let rule = TcpRule {
+        bind: Some("127.0.0.1:0".into()),
+        target: Some(target.to_string()),
+        ..Default::default()
+    };
+    let forwarder = Forwarder::spawn(rule).await.unwrap();
+    let [listening] = forwarder.local_addrs() else {
+        panic!("expected one listener, got {:?}", forwarder.local_addrs());
+    };
+    let listening = *listening;
+    assert_ne!(listening.port(), 0);
+
+    let mut client = TcpStream::connect(listening).await.unwrap();
+    client.write_all(b"hello porture").await.unwrap();
+    let mut reply = [0; 13];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"hello porture");
+
+    forwarder.shutdown().await;
+    assert!(TcpStream::connect(listening).await.is_err());
+}
+
+#[tokio::test]
+async fn udp_client_over_its_rate_limit_holds_up_only_itself() {
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = [0; 2048];
+        loop {
+            let (len, from) = echo.recv_from(&mut buffer).await.unwrap();
+            echo.send_to(&buffer[..len], from).await.unwrap();
+        }
+    });
+
+    // 1000 bytes a second for each client IP
+    let rule = UdpRule {
+        bind: Some("127.0.0.1:0".into()),
+        target: Some(target.to_string()),
+        client_rate_limit_kbps: Some(8),
+        ..Default::default()
+    };
+    let forwarder = Forwarder::spawn(rule).await.unwrap();
+    let listening = forwarder.local_addrs()[0];
+
+    // Several seconds' worth from one client
+    let flooding = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for _ in 0..10 {
+        flooding.send_to(&[0; 1000], listening).await.unwrap();
+    }
+
+    let other = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+    other.send_to(b"hello porture", listening).await.unwrap();
+    let mut reply = [0; 64];
+    let (len, _) = timeout(Duration::from_secs(1), other.recv_from(&mut reply)).await
+        .expect("another client's datagram waited on the flooding one")
+        .unwrap();
+    assert_eq!(&reply[..len], b"hello porture");
+
+    forwarder.shutdown().await;
+}
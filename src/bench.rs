@@ -0,0 +1,312 @@
+use crate::check;
+use porture_core::config::{TcpMode, TcpRule};
+use porture_core::state::SharedState;
+use porture_core::supervisor::Bound;
+use porture_core::tcp_forwarder::TcpForwarder;
+use anyhow::{Context, Result};
+use clap::{Arg, ArgMatches, Command};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+// Measuring a TCP rule (`porture bench`): the rule is started on a loopback
+// port in front of a server porture runs itself, and clients push data
+// through it both ways, open connections one after another and time small
+// round trips. Everything runs in this process, so the numbers are
+// porture's overhead and the rule's settings, not the network's. --direct
+// runs the same tests without porture in between, for comparison.
+
+// What a benchmark connection asks the server for, in its first byte
+const SINK: u8 = b'U';
+const SOURCE: u8 = b'D';
+const ECHO: u8 = b'E';
+
+const BLOCK: usize = 64 * 1024;
+
+pub fn command() -> Command {
+    Command::new("bench")
+        .about("Measure the throughput, connection rate and latency of a TCP rule")
+        .arg(
+            Arg::new("rule")
+                .value_name("RULE")
+                .help("Name of the TCP rule to measure; by default the first one")
+        )
+        .arg(
+            Arg::new("duration")
+                .long("duration")
+                .value_name("SECONDS")
+                .help("How long to run each test")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("5")
+        )
+        .arg(
+            Arg::new("connections")
+                .long("connections")
+                .value_name("N")
+                .help("Connections to run at once in the throughput and connection rate tests")
+                .value_parser(clap::value_parser!(u64).range(1..=1024))
+                .default_value("4")
+        )
+        .arg(
+            Arg::new("size")
+                .long("size")
+                .value_name("BYTES")
+                .help("Size of the messages timed in the latency test")
+                .value_parser(clap::value_parser!(u64).range(1..=1048576))
+                .default_value("64")
+        )
+        .arg(
+            Arg::new("direct")
+                .long("direct")
+                .help("Connect straight to the benchmark server, without porture")
+                .action(clap::ArgAction::SetTrue)
+        )
+}
+
+pub fn run_command(matches: &ArgMatches, bench: &ArgMatches) -> Result<()> {
+    let duration = Duration::from_secs(*bench.get_one::<u64>("duration").unwrap());
+    let connections = *bench.get_one::<u64>("connections").unwrap() as usize;
+    let size = *bench.get_one::<u64>("size").unwrap() as usize;
+    let direct = bench.get_flag("direct");
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let server = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server.local_addr()?;
+        tokio::spawn(serve(server));
+
+        let addr = match direct {
+            true => {
+                println!("Benchmarking without porture, {}s per test", duration.as_secs());
+                server_addr
+            }
+            false => {
+                let (config, _) = check::load(matches)?;
+                let buffer_size = config.global.as_ref().and_then(|g| g.buffer_size).unwrap_or(8192);
+                let rule = pick_rule(config.tcp.as_deref().unwrap_or_default(), bench.get_one::<String>("rule"))?;
+                println!(
+                    "Benchmarking TCP rule '{}' with buffer_size {}, {}s per test",
+                    rule.rule_name(), buffer_size, duration.as_secs()
+                );
+                start_rule(rule, server_addr, buffer_size).await?
+            }
+        };
+
+        let (bytes, elapsed) = throughput(addr, SINK, connections, duration).await?;
+        println!("Upload:      {} over {} connections", rate(bytes, elapsed), connections);
+        let (bytes, elapsed) = throughput(addr, SOURCE, connections, duration).await?;
+        println!("Download:    {} over {} connections", rate(bytes, elapsed), connections);
+        let (opened, elapsed) = connection_rate(addr, connections, duration).await?;
+        println!(
+            "Connections: {:.0} per second, {} at a time",
+            opened as f64 / elapsed.as_secs_f64(), connections
+        );
+        let mut samples = latency(addr, size, duration).await?;
+        samples.sort();
+        println!(
+            "Latency:     p50 {}, p90 {}, p99 {}, max {} ({} round trips of {} bytes)",
+            micros(percentile(&samples, 0.5)), micros(percentile(&samples, 0.9)),
+            micros(percentile(&samples, 0.99)), micros(samples.last().copied().unwrap_or_default()),
+            samples.len(), size
+        );
+        Ok(())
+    })
+}
+
+fn pick_rule(rules: &[TcpRule], name: Option<&String>) -> Result<TcpRule> {
+    let rule = match name {
+        Some(name) => rules.iter().find(|rule| rule.rule_name() == *name)
+            .with_context(|| format!("no TCP rule named '{}'", name))?,
+        None => rules.first().context("the configuration has no TCP rules")?,
+    };
+    if rule.mode() != TcpMode::Forward {
+        anyhow::bail!("TCP rule '{}' is in {} mode; only forward rules can be benchmarked", rule.rule_name(), rule.mode().as_str());
+    }
+    let unsupported = [
+        ("tls", rule.tls.is_some()),
+        ("target_tls", rule.target_tls.unwrap_or(false)),
+        ("proxy_protocol", rule.proxy_protocol.is_some()),
+        ("accept_proxy_protocol", rule.accept_proxy_protocol.unwrap_or(false)),
+        ("upstream_proxy", rule.upstream_proxy.is_some()),
+        ("tunnel", rule.tunnel.unwrap_or(false)),
+        ("banner", rule.banner.is_some()),
+    ];
+    if let Some((field, _)) = unsupported.iter().find(|(_, set)| *set) {
+        anyhow::bail!("TCP rule '{}' sets {}, which porture bench can't talk through", rule.rule_name(), field);
+    }
+    Ok(rule.clone())
+}
+
+// Runs the rule on a free loopback port, forwarding to the benchmark
+// server. What would keep the benchmark's connections out, or leave files
+// and copies of them behind, is turned off.
+async fn start_rule(mut rule: TcpRule, server_addr: SocketAddr, buffer_size: usize) -> Result<SocketAddr> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    rule.bind = None;
+    rule.bind_addr = vec!["127.0.0.1".to_string()];
+    rule.bind_port = port;
+    rule.target = None;
+    rule.target_addr = server_addr.ip().to_string();
+    rule.target_port = server_addr.port();
+    rule.target_srv = None;
+    rule.target_consul = None;
+    rule.target_kubernetes = None;
+    rule.transparent = None;
+    rule.source_addr = None;
+    rule.bind_device = None;
+    rule.source_device = None;
+    rule.allow = None;
+    rule.deny = None;
+    rule.allow_countries = None;
+    rule.deny_countries = None;
+    rule.knock = None;
+    rule.mirror_target = None;
+    rule.capture_file = None;
+    rule.record_dir = None;
+
+    let shared = Arc::new(SharedState { buffer_size, ..Default::default() });
+    let (bound_tx, bound_rx) = oneshot::channel();
+    let forwarder = tokio::spawn(async move {
+        let mut bound = Bound::new(bound_tx);
+        TcpForwarder::new(rule, shared).start(&mut bound).await
+    });
+    if bound_rx.await.is_err() {
+        return Err(match forwarder.await {
+            Ok(Err(e)) => e.context("failed to start the rule"),
+            _ => anyhow::anyhow!("the rule stopped while starting"),
+        });
+    }
+    Ok(SocketAddr::from(([127, 0, 0, 1], port)))
+}
+
+async fn serve(listener: TcpListener) {
+    while let Ok((stream, _)) = listener.accept().await {
+        stream.set_nodelay(true).ok();
+        tokio::spawn(async move {
+            let _ = answer(stream).await;
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; BLOCK];
+    let mut command = [0u8; 1];
+    stream.read_exact(&mut command).await?;
+    match command[0] {
+        SINK => while stream.read(&mut buffer).await? > 0 {},
+        SOURCE => loop {
+            stream.write_all(&buffer).await?;
+        },
+        _ => loop {
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buffer[..n]).await?;
+        },
+    }
+    Ok(())
+}
+
+async fn connect(addr: SocketAddr, command: u8) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr).await.with_context(|| format!("failed to connect to {}", addr))?;
+    stream.set_nodelay(true)?;
+    stream.write_all(&[command]).await?;
+    Ok(stream)
+}
+
+// Bytes moved through `connections` connections at once, and how long it took
+async fn throughput(addr: SocketAddr, command: u8, connections: usize, duration: Duration) -> Result<(u64, Duration)> {
+    let moved = Arc::new(AtomicU64::new(0));
+    let started = Instant::now();
+    let deadline = started + duration;
+    let mut tasks = Vec::new();
+    for _ in 0..connections {
+        let mut stream = connect(addr, command).await?;
+        let moved = moved.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut buffer = vec![0u8; BLOCK];
+            while Instant::now() < deadline {
+                let n = match command {
+                    SINK => stream.write(&buffer).await?,
+                    _ => stream.read(&mut buffer).await?,
+                };
+                if n == 0 {
+                    anyhow::bail!("the connection closed during the test");
+                }
+                moved.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            Ok(())
+        }));
+    }
+    for task in tasks {
+        task.await??;
+    }
+    Ok((moved.load(Ordering::Relaxed), started.elapsed()))
+}
+
+// Connections opened, each checked with a one-byte round trip and closed
+async fn connection_rate(addr: SocketAddr, connections: usize, duration: Duration) -> Result<(u64, Duration)> {
+    let opened = Arc::new(AtomicU64::new(0));
+    let started = Instant::now();
+    let deadline = started + duration;
+    let mut tasks = Vec::new();
+    for _ in 0..connections {
+        let opened = opened.clone();
+        tasks.push(tokio::spawn(async move {
+            while Instant::now() < deadline {
+                let mut stream = connect(addr, ECHO).await?;
+                stream.write_all(b"x").await?;
+                let mut reply = [0u8; 1];
+                stream.read_exact(&mut reply).await.context("no reply through the rule")?;
+                opened.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok::<_, anyhow::Error>(())
+        }));
+    }
+    for task in tasks {
+        task.await??;
+    }
+    Ok((opened.load(Ordering::Relaxed), started.elapsed()))
+}
+
+// Round trip times of `size`-byte messages on one connection
+async fn latency(addr: SocketAddr, size: usize, duration: Duration) -> Result<Vec<Duration>> {
+    let mut stream = connect(addr, ECHO).await?;
+    let message = vec![b'x'; size];
+    let mut reply = vec![0u8; size];
+    let mut samples = Vec::new();
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        let sent = Instant::now();
+        stream.write_all(&message).await?;
+        stream.read_exact(&mut reply).await.context("no reply through the rule")?;
+        samples.push(sent.elapsed());
+    }
+    Ok(samples)
+}
+
+// Of sorted samples
+fn percentile(samples: &[Duration], p: f64) -> Duration {
+    match samples.len() {
+        0 => Duration::ZERO,
+        n => samples[((n - 1) as f64 * p).round() as usize],
+    }
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> String {
+    let per_second = bytes as f64 / elapsed.as_secs_f64();
+    format!("{:.1} MiB/s ({:.2} Gbit/s)", per_second / 1048576.0, per_second * 8.0 / 1e9)
+}
+
+fn micros(duration: Duration) -> String {
+    match duration.as_micros() {
+        us if us < 1000 => format!("{}µs", us),
+        us => format!("{:.1}ms", us as f64 / 1000.0),
+    }
+}
@@ -0,0 +1,174 @@
+use crate::probe::{self, Outcome};
+use crate::ConfigSource;
+use porture_core::config::{Config, TcpMode, TcpRule, UdpMode, UdpRule};
+use porture_core::geoip::GeoIp;
+use porture_core::resolver;
+use porture_core::sockopt;
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+use socket2::Type;
+use std::path::Path;
+use std::time::Duration;
+
+// Testing a configuration without starting it (`porture check`), like
+// `nginx -t`. The config is loaded and validated as porture would at
+// startup; --bind also binds and closes every listener, and --probe connects
+// to every TCP target as `porture probe` does. The exit status is 0 only if everything passed, so a
+// deployment script can run it before reloading or restarting porture.
+
+pub fn command() -> Command {
+    Command::new("check")
+        .about("Check the configuration and exit, with status 0 if it is valid")
+        .arg(
+            Arg::new("bind")
+                .long("bind")
+                .help("Also try binding every listener; fails for ports a running porture holds")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("probe")
+                .long("probe")
+                .help("Also connect to every TCP target")
+                .action(clap::ArgAction::SetTrue)
+        )
+}
+
+// The rules given with -L and -T or in PORTURE_ variables, or else the config
+// file. Unlike starting porture, a missing file is an error rather than a
+// reason to create one.
+pub fn load(matches: &ArgMatches) -> Result<(Config, ConfigSource)> {
+    let (mut config, source) = match crate::config_without_file(matches) {
+        Some((config, source)) => (config?, source),
+        None => {
+            let config_path = matches.get_one::<String>("config").unwrap();
+            let config = Config::from_file(config_path, crate::config_format(matches))
+                .map_err(|e| anyhow::anyhow!("Failed to load configuration file '{}': {}", config_path, e))?;
+            (config, ConfigSource::File { created: false })
+        }
+    };
+    config.select_tags(&crate::tag_filter(matches));
+    config.validate().map_err(|e| anyhow::anyhow!("Configuration validation failed: {}", e))?;
+    Ok((config, source))
+}
+
+pub fn run_command(matches: &ArgMatches, check: &ArgMatches) -> Result<()> {
+    let (config, source) = load(matches)?;
+    let source = match source {
+        ConfigSource::CommandLine => "from the command line".to_string(),
+        ConfigSource::Environment => "from the environment".to_string(),
+        ConfigSource::File { .. } => matches.get_one::<String>("config").unwrap().clone(),
+    };
+    if let Some(path) = config.global.as_ref().and_then(|g| g.geoip_db.as_deref()) {
+        GeoIp::open(path)?;
+    }
+
+    let tcp_rules: Vec<&TcpRule> = config.tcp.iter().flatten().collect();
+    let udp_rules: Vec<&UdpRule> = config.udp.iter().flatten().collect();
+    let mut report = Report::default();
+    if check.get_flag("bind") {
+        for rule in &tcp_rules {
+            bind_tcp(rule, &mut report);
+        }
+        for rule in &udp_rules {
+            bind_udp(rule, &mut report);
+        }
+    }
+    if check.get_flag("probe") {
+        // Hostnames resolve as they would when forwarding, or through
+        // getaddrinfo if the resolver can't be set up
+        let _ = resolver::init(config.global.as_ref().and_then(|g| g.dns.as_ref()));
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        for rule in &tcp_rules {
+            runtime.block_on(probe(rule, &mut report));
+        }
+    }
+
+    if report.failed > 0 {
+        anyhow::bail!("{} of {} checks failed for configuration {}", report.failed, report.passed + report.failed, source);
+    }
+    println!("Configuration {} is valid: {} TCP rules, {} UDP rules", source, tcp_rules.len(), udp_rules.len());
+    Ok(())
+}
+
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn result(&mut self, what: &str, rule: &str, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                self.passed += 1;
+                println!("ok       {} ({})", what, rule);
+            }
+            Err(e) => {
+                self.failed += 1;
+                println!("FAILED   {} ({}): {}", what, rule, e);
+            }
+        }
+    }
+
+    fn skipped(&self, what: &str, rule: &str, reason: &str) {
+        println!("skipped  {} ({}): {}", what, rule, reason);
+    }
+}
+
+fn bind_tcp(rule: &TcpRule, report: &mut Report) {
+    let name = rule.rule_name();
+    if let Some(socket) = rule.bind_systemd_socket() {
+        return report.skipped(&format!("listen systemd:{}", socket), &name, "bound by systemd");
+    }
+    if let Some(path) = rule.bind_unix_path() {
+        let what = format!("listen unix:{}", path);
+        if path.starts_with('@') {
+            return report.skipped(&what, &name, "abstract socket");
+        }
+        // Binding would replace the socket of a running porture
+        let directory = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let result = match directory.is_dir() {
+            true => Ok(()),
+            false => Err(format!("directory {} does not exist", directory.display())),
+        };
+        return report.result(&what, &name, result);
+    }
+    for addr in rule.bind_socket_addrs().into_iter().flatten() {
+        let result = sockopt::try_bind(addr, rule.bind_options(), Type::STREAM).map_err(|e| e.to_string());
+        report.result(&format!("listen tcp {}", addr), &name, result);
+    }
+}
+
+fn bind_udp(rule: &UdpRule, report: &mut Report) {
+    let name = rule.rule_name();
+    if rule.mode() == UdpMode::MdnsReflector {
+        return report.skipped("listen udp 5353", &name, "mDNS reflector");
+    }
+    if let Some(socket) = rule.bind_systemd_socket() {
+        return report.skipped(&format!("listen systemd:{}", socket), &name, "bound by systemd");
+    }
+    for addr in rule.bind_socket_addrs().into_iter().flatten() {
+        let result = sockopt::try_bind(addr, rule.bind_options(), Type::DGRAM).map_err(|e| e.to_string());
+        report.result(&format!("listen udp {}", addr), &name, result);
+    }
+}
+
+async fn probe(rule: &TcpRule, report: &mut Report) {
+    let name = rule.rule_name();
+    let targets = match rule.mode() {
+        TcpMode::UdpInTcpServer => Err("the target is UDP"),
+        _ => probe::tcp_targets(rule),
+    };
+    let targets = match targets {
+        Ok(targets) => targets,
+        Err(reason) => return report.skipped("target", &name, reason),
+    };
+    let timeout = Duration::from_secs(rule.connect_timeout_seconds());
+    for target in targets {
+        let result = match target.probe(timeout, None).await {
+            Outcome::Failed(e) => Err(e),
+            _ => Ok(()),
+        };
+        report.result(&format!("target {}", target), &name, result);
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use log::warn;
+use std::fs;
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Mutex;
+
+// Running from init scripts (--daemon, --pid-file). The daemon forks twice
+// and starts a new session, so it has no controlling terminal and can't
+// acquire one. The process that was started waits until the daemon has
+// started its rules and exits 0, or 1 if the daemon died first. Until then
+// the daemon's output still goes to the terminal, so startup errors are
+// seen there; afterwards it goes to --log-file, or nowhere.
+
+// The daemon's end of the readiness pipe, until it is ready
+#[cfg(unix)]
+static STARTING: Mutex<Option<Starting>> = Mutex::new(None);
+// The pid file this process wrote
+static PID_FILE: Mutex<Option<String>> = Mutex::new(None);
+
+#[cfg(unix)]
+struct Starting {
+    // Written to once ready; closed unwritten if the daemon exits first
+    ready: File,
+    // Whether output still goes to the terminal
+    attached: bool,
+}
+
+// Returns in the daemon only. Must be called before any threads start.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&str>) -> Result<()> {
+    let log = log_file
+        .map(|path| {
+            File::options().create(true).append(true).open(path)
+                .with_context(|| format!("failed to open log file {}", path))
+        })
+        .transpose()?;
+    let mut fds: [RawFd; 2] = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error()).context("failed to create a pipe");
+    }
+    let (mut read, ready) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    // Processes the daemon starts must not hold the pipe open
+    unsafe {
+        libc::fcntl(ready.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+
+    if fork()? {
+        drop(ready);
+        let mut byte = [0u8; 1];
+        if matches!(read.read(&mut byte), Ok(1)) {
+            std::process::exit(0);
+        }
+        if let Some(path) = log_file {
+            eprintln!("porture failed to start, see {}", path);
+        }
+        std::process::exit(1);
+    }
+    drop(read);
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error()).context("failed to start a new session");
+    }
+    // Only a session leader can acquire a controlling terminal
+    if fork()? {
+        unsafe { libc::_exit(0) };
+    }
+
+    redirect(&File::open("/dev/null")?, &[libc::STDIN_FILENO])?;
+    if let Some(log) = &log {
+        redirect(log, &[libc::STDOUT_FILENO, libc::STDERR_FILENO])?;
+    }
+    *STARTING.lock().unwrap() = Some(Starting { ready, attached: log.is_none() });
+    Ok(())
+}
+
+// True in the parent
+#[cfg(unix)]
+fn fork() -> Result<bool> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()).context("failed to fork"),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+#[cfg(unix)]
+fn redirect(file: &File, targets: &[RawFd]) -> Result<()> {
+    for &target in targets {
+        if unsafe { libc::dup2(file.as_raw_fd(), target) } < 0 {
+            return Err(io::Error::last_os_error()).context("failed to redirect output");
+        }
+    }
+    Ok(())
+}
+
+// Called once the rules have started, before dropping root
+pub fn write_pid_file(pid_file: Option<&str>) -> Result<()> {
+    if let Some(path) = pid_file {
+        fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("failed to write pid file {}", path))?;
+        *PID_FILE.lock().unwrap() = Some(path.to_string());
+    }
+    Ok(())
+}
+
+// Lets the process that started the daemon exit
+pub fn ready() -> Result<()> {
+    #[cfg(unix)]
+    if let Some(starting) = STARTING.lock().unwrap().take() {
+        if starting.attached {
+            let null = File::options().write(true).open("/dev/null")?;
+            redirect(&null, &[libc::STDOUT_FILENO, libc::STDERR_FILENO])?;
+        }
+        (&starting.ready).write_all(b"1")?;
+    }
+    Ok(())
+}
+
+// Removes the pid file on exit, unless a process that took over since has
+// written its own pid there
+pub fn remove_pid_file() {
+    let Some(path) = PID_FILE.lock().unwrap().take() else {
+        return;
+    };
+    let ours = fs::read_to_string(&path).is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+    if ours && let Err(e) = fs::remove_file(&path) {
+        warn!("Failed to remove pid file {}: {}", path, e);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::check;
+use porture_core::config::{TcpMode, TcpRule, UdpMode, UdpRule};
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+use serde::Serialize;
+
+// Printing the rules a configuration defines (`porture list`), with the
+// shorthands expanded, names generated and defaults filled in, as porture
+// would run them. Rules from etcd and Docker only exist at runtime and are
+// not included.
+
+pub fn command() -> Command {
+    Command::new("list")
+        .about("Print the configured rules with their effective settings")
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Print JSON instead of a table")
+                .action(clap::ArgAction::SetTrue)
+        )
+}
+
+pub fn run_command(matches: &ArgMatches, list: &ArgMatches) -> Result<()> {
+    let (config, _) = check::load(matches)?;
+    let buffer_size = config.global.as_ref().and_then(|g| g.buffer_size).unwrap_or(8192);
+    let rules: Vec<Entry> = config.tcp.iter().flatten().map(|rule| Entry::tcp(rule, buffer_size))
+        .chain(config.udp.iter().flatten().map(|rule| Entry::udp(rule, buffer_size)))
+        .collect();
+    if list.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(&rules)?);
+    } else {
+        print_table(&rules);
+    }
+    Ok(())
+}
+
+// One rule as porture runs it. TCP rules' timeouts are for connecting and
+// for idle connections, UDP rules' for idle sessions. Fields that don't
+// apply to the protocol, and limits that aren't set, are left out; a null
+// idle_timeout means TCP connections never time out.
+#[derive(Serialize)]
+struct Entry {
+    protocol: &'static str,
+    name: String,
+    mode: &'static str,
+    listen: String,
+    target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overflow: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_sessions: Option<usize>,
+    buffer_size: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+impl Entry {
+    fn tcp(rule: &TcpRule, buffer_size: usize) -> Self {
+        let routes = rule.sni_routes.iter().chain(&rule.host_routes).map(|routes| routes.len()).sum::<usize>()
+            + rule.sniff_routes.iter().flat_map(|routes| [&routes.tls, &routes.http]).flatten().count();
+        let target = match rule.mode() {
+            TcpMode::Socks5 | TcpMode::HttpConnect => "chosen by the client".to_string(),
+            TcpMode::Redirect => "original destination".to_string(),
+            _ if routes > 0 => format!("{} routes, else {}", routes, rule.target_endpoint()),
+            _ => rule.target_endpoint(),
+        };
+        Self {
+            protocol: "tcp",
+            name: rule.rule_name(),
+            mode: rule.mode().as_str(),
+            listen: rule.bind_endpoint(),
+            target,
+            connect_timeout: Some(rule.connect_timeout_seconds()),
+            idle_timeout: rule.idle_timeout,
+            max_connections: rule.max_connections,
+            overflow: rule.max_connections.map(|_| rule.overflow_policy().as_str()),
+            max_sessions: None,
+            buffer_size,
+            tags: rule.tags.clone().unwrap_or_default(),
+        }
+    }
+
+    fn udp(rule: &UdpRule, buffer_size: usize) -> Self {
+        let (listen, target) = match rule.mode() {
+            UdpMode::MdnsReflector => {
+                let group = format!("224.0.0.251:5353 on {}", rule.interfaces.as_deref().unwrap_or_default().join(", "));
+                (group.clone(), group)
+            }
+            _ => (rule.bind_endpoint(), rule.target_endpoint()),
+        };
+        Self {
+            protocol: "udp",
+            name: rule.rule_name(),
+            mode: rule.mode().as_str(),
+            listen,
+            target,
+            connect_timeout: None,
+            idle_timeout: Some(rule.timeout_seconds()),
+            max_connections: None,
+            overflow: None,
+            max_sessions: Some(rule.session_limit()),
+            buffer_size,
+            tags: rule.tags.clone().unwrap_or_default(),
+        }
+    }
+}
+
+fn print_table(rules: &[Entry]) {
+    const HEADER: [&str; 8] = ["PROTOCOL", "NAME", "MODE", "LISTEN", "TARGET", "CONNECT", "IDLE", "MAX"];
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let rows: Vec<[String; 8]> = rules.iter()
+        .map(|rule| [
+            rule.protocol.to_string(),
+            rule.name.clone(),
+            rule.mode.to_string(),
+            rule.listen.clone(),
+            rule.target.clone(),
+            or_dash(rule.connect_timeout.map(|seconds| format!("{}s", seconds))),
+            or_dash(rule.idle_timeout.map(|seconds| format!("{}s", seconds))),
+            or_dash(rule.max_connections.or(rule.max_sessions).map(|max| max.to_string())),
+        ])
+        .collect();
+    let mut widths = HEADER.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: [&str; 8]| {
+        let line: Vec<String> = cells.iter().zip(widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(HEADER);
+    for row in &rows {
+        print_row(row.each_ref().map(String::as_str));
+    }
+}
@@ -0,0 +1,55 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use porture_core::rule_log;
+
+// The binary's loggers: stderr here, the event log in service.rs. Both let
+// a rule's log_level (see rule_log) override their own filter.
+
+// Whether a message passes the current rule's level, or else `global`
+pub fn enabled(global: &env_logger::Logger, metadata: &Metadata<'_>) -> bool {
+    match rule_log::level(metadata) {
+        Some(level) => metadata.level() <= level,
+        None => global.enabled(metadata),
+    }
+}
+
+pub fn matches(global: &env_logger::Logger, record: &Record<'_>) -> bool {
+    match rule_log::level(record.metadata()) {
+        Some(level) => record.level() <= level,
+        None => global.matches(record),
+    }
+}
+
+// Logs to stderr. `log_level` takes env_logger's module filters, as
+// RUST_LOG would.
+pub fn init_stderr(log_level: &str) {
+    let global = env_logger::Builder::new().parse_filters(log_level).build();
+    let output = env_logger::Builder::from_env(env_logger::Env::new().write_style("RUST_LOG_STYLE"))
+        .filter_level(LevelFilter::Trace)
+        .build();
+    let max_level = global.filter();
+    if log::set_boxed_logger(Box::new(Stderr { global, output })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+struct Stderr {
+    global: env_logger::Logger,
+    // Formats and writes whatever passes
+    output: env_logger::Logger,
+}
+
+impl Log for Stderr {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        enabled(&self.global, metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if matches(&self.global, record) {
+            self.output.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
@@ -1,22 +1,44 @@
-mod config;
-mod tcp_forwarder;
-mod udp_forwarder;
+mod bench;
+mod check;
+mod daemon;
+mod list;
+mod logging;
+mod probe;
+mod replay;
+#[cfg(windows)]
+mod service;
+mod signals;
 
 use anyhow::Result;
-use clap::{Arg, Command};
-use config::Config;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, Command};
+use config::{Config, ConfigFormat, IoBackend, TagFilter, TunnelRole};
 use log::{error, info, warn};
+use porture_core::ban::BanList;
+use porture_core::capture::CaptureRegistry;
+use porture_core::docker::DockerRules;
+use porture_core::etcd::EtcdRules;
+use porture_core::geoip::GeoIp;
+use porture_core::handoff::OpenConnections;
+use porture_core::sockmap::SockMap;
+use porture_core::state::SharedState;
+use porture_core::supervisor::Supervisor;
+use porture_core::task::AbortOnDrop;
+use porture_core::tls::CertRegistry;
+use porture_core::udp_forwarder::SessionRegistry;
+use porture_core::uring::Ring;
+use porture_core::{
+    admin, config, confine, docker, etcd, handoff, ktls, privileges, resolver, seccomp, systemd, tunnel,
+};
 use std::env;
-use tcp_forwarder::TcpForwarder;
-use udp_forwarder::UdpForwarder;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Setup panic handler
-    human_panic::setup_panic!();
-
-    // Parse command line arguments
-    let matches = Command::new("porture")
+fn cli() -> Command {
+    let cli = Command::new("porture")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about("A minimal, programmable port forwarder written in Rust")
@@ -27,6 +49,15 @@ async fn main() -> Result<()> {
                 .value_name("FILE")
                 .help("Configuration file path")
                 .default_value("config.toml")
+                .global(true)
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Language of the configuration file; by default taken from its extension, else toml")
+                .value_parser(ConfigFormat::NAMES)
+                .global(true)
         )
         .arg(
             Arg::new("log-level")
@@ -34,19 +65,100 @@ async fn main() -> Result<()> {
                 .long("log-level")
                 .value_name("LEVEL")
                 .help("Log level (error, warn, info, debug, trace)")
+                .global(true)
         )
         .arg(
             Arg::new("init")
                 .long("init")
                 .help("Generate default configuration file and exit")
                 .action(clap::ArgAction::SetTrue)
+                .conflicts_with("listen")
+        )
+        .arg(
+            Arg::new("pid-file")
+                .long("pid-file")
+                .value_name("FILE")
+                .help("Write the process ID to FILE once started")
+        )
+        .arg(
+            Arg::new("listen")
+                .short('L')
+                .long("listen")
+                .value_name("[PROTO://]ADDR:PORT")
+                .help("Forward from this address (tcp:// or udp://, default tcp) to the matching -T, instead of using a config file; repeatable")
+                .action(clap::ArgAction::Append)
+                .requires("target")
+                .global(true)
+        )
+        .arg(
+            Arg::new("target")
+                .short('T')
+                .long("target")
+                .value_name("ADDR:PORT")
+                .help("Where the -L in the same position forwards to; repeatable")
+                .action(clap::ArgAction::Append)
+                .requires("listen")
+                .global(true)
+        )
+        .arg(
+            Arg::new("only-tag")
+                .long("only-tag")
+                .value_name("TAG")
+                .help("Only run the rules with this tag; repeatable")
+                .action(clap::ArgAction::Append)
+                .global(true)
+        )
+        .arg(
+            Arg::new("skip-tag")
+                .long("skip-tag")
+                .value_name("TAG")
+                .help("Don't run the rules with this tag; repeatable")
+                .action(clap::ArgAction::Append)
+                .global(true)
+        )
+        .arg(
+            Arg::new("strict-start")
+                .long("strict-start")
+                .help("Exit with an error if any rule fails to start")
+                .action(clap::ArgAction::SetTrue)
+        );
+    #[cfg(unix)]
+    let cli = cli
+        .arg(
+            Arg::new("daemon")
+                .long("daemon")
+                .help("Run in the background, returning once started")
+                .action(clap::ArgAction::SetTrue)
         )
-        .get_matches();
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("FILE")
+                .help("Append the daemon's output to FILE instead of discarding it")
+                .requires("daemon")
+        );
+    let cli = cli
+        .subcommand(check::command())
+        .subcommand(list::command())
+        .subcommand(replay::command())
+        .subcommand(bench::command())
+        .subcommand(probe::command());
+    #[cfg(windows)]
+    let cli = cli.subcommand(service::command());
+    cli
+}
+
+fn main() -> Result<()> {
+    // Setup panic handler
+    human_panic::setup_panic!();
+
+    // Parse command line arguments
+    let matches = cli().get_matches();
 
     // Handle init command
     if matches.get_flag("init") {
         let config_path = matches.get_one::<String>("config").unwrap();
-        match Config::create_default_config().save_to_file(config_path) {
+        match Config::create_default_config().save_to_file(config_path, config_format(&matches)) {
             Ok(_) => {
                 println!("Default configuration file created: {}", config_path);
                 println!("Please edit the configuration file to suit your needs.");
@@ -60,23 +172,110 @@ async fn main() -> Result<()> {
         }
     }
 
+    let command = match matches.subcommand() {
+        Some(("check", check)) => Some(check::run_command(&matches, check)),
+        Some(("list", list)) => Some(list::run_command(&matches, list)),
+        Some(("replay", replay)) => Some(replay::run_command(replay)),
+        Some(("bench", bench)) => Some(bench::run_command(&matches, bench)),
+        Some(("probe", probe)) => Some(probe::run_command(&matches, probe)),
+        _ => None,
+    };
+    if let Some(result) = command {
+        if let Err(e) = result {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    #[cfg(windows)]
+    if let Some(("service", service)) = matches.subcommand() {
+        return service::run_command(&matches, service);
+    }
+
+    // Before the runtime starts any threads
+    #[cfg(unix)]
+    if matches.get_flag("daemon")
+        && let Err(e) = daemon::daemonize(matches.get_one::<String>("log-file").map(String::as_str))
+    {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = run(&matches, logging::init_stderr) {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Where the rules came from
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConfigSource {
+    // The config file, and whether it was just created with example rules
+    File { created: bool },
+    // -L and -T
+    CommandLine,
+    // PORTURE_TCP_<n> and PORTURE_UDP_<n> variables
+    Environment,
+}
+
+// The rules given with -L and -T, or else in PORTURE_TCP_<n> and
+// PORTURE_UDP_<n> variables, if any, which take the place of the config file.
+// Variables and an explicit -c are an error, since one would be ignored.
+fn config_without_file(matches: &ArgMatches) -> Option<(Result<Config>, ConfigSource)> {
+    let values = |id: &str| -> Vec<&str> {
+        matches.get_many::<String>(id).into_iter().flatten().map(String::as_str).collect()
+    };
+    let listens = values("listen");
+    if listens.is_empty() {
+        let config = Config::from_env()?;
+        if matches.value_source("config") == Some(ValueSource::CommandLine) {
+            let config_path = matches.get_one::<String>("config").unwrap();
+            let error = anyhow::anyhow!(
+                "Rules are set in PORTURE_TCP_<n> or PORTURE_UDP_<n> variables and a configuration file is given with --config '{}': unset one or the other",
+                config_path,
+            );
+            return Some((Err(error), ConfigSource::Environment));
+        }
+        return Some((config, ConfigSource::Environment));
+    }
+    Some((Config::from_forwards(&listens, &values("target")), ConfigSource::CommandLine))
+}
+
+// --format, or else the config file's extension
+fn config_format(matches: &ArgMatches) -> ConfigFormat {
+    match matches.get_one::<String>("format") {
+        Some(name) => ConfigFormat::from_name(name).unwrap(),
+        None => ConfigFormat::from_path(matches.get_one::<String>("config").unwrap()),
+    }
+}
+
+fn tag_filter(matches: &ArgMatches) -> TagFilter {
+    let values = |id: &str| matches.get_many::<String>(id).into_iter().flatten().cloned().collect();
+    TagFilter { only: values("only-tag"), skip: values("skip-tag") }
+}
+
+// Runs porture until it is shut down. `init_logging` gets the configured
+// log level once the config is read.
+fn run(matches: &ArgMatches, init_logging: fn(&str)) -> Result<()> {
     // Load configuration
     let config_path = matches.get_one::<String>("config").unwrap();
-    let config_existed = std::path::Path::new(config_path).exists();
     
-    let config = match Config::from_file_or_create_default(config_path) {
-        Ok(config) => config,
-        Err(e) => {
-            eprintln!("Failed to load or create configuration file '{}': {}", config_path, e);
-            std::process::exit(1);
+    // Returned rather than printed, so a Windows service logs them too
+    let (mut config, source) = match config_without_file(matches) {
+        Some((config, source)) => (config?, source),
+        None => {
+            let created = !std::path::Path::new(config_path).exists();
+            let config = Config::from_file_or_create_default(config_path, config_format(matches))
+                .map_err(|e| anyhow::anyhow!("Failed to load or create configuration file '{}': {}", config_path, e))?;
+            (config, ConfigSource::File { created })
         }
     };
+    config.select_tags(&tag_filter(matches));
 
     // Validate configuration
-    if let Err(e) = config.validate() {
-        eprintln!("Configuration validation failed: {}", e);
-        std::process::exit(1);
-    }
+    config.validate().map_err(|e| anyhow::anyhow!("Configuration validation failed: {}", e))?;
 
     // Setup logging
     let log_level = matches.get_one::<String>("log-level")
@@ -84,20 +283,64 @@ async fn main() -> Result<()> {
         .map(|s| s.as_str())
         .unwrap_or("info");
 
-    unsafe {
-        env::set_var("RUST_LOG", log_level);
-    }
-    env_logger::init();
+    init_logging(log_level);
 
     info!("Starting Porture v{}", env!("CARGO_PKG_VERSION"));
+
+    // Before the runtime starts its threads, which would escape it otherwise
+    if config.global.as_ref().and_then(|g| g.landlock).unwrap_or(false) {
+        confine::restrict(&config, config_path, matches.get_one::<String>("pid-file").map(String::as_str))?;
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve(matches, config, source))
+}
+
+async fn serve(matches: &ArgMatches, mut config: Config, source: ConfigSource) -> Result<()> {
+    let config_path = matches.get_one::<String>("config").unwrap();
+
+    // Sockets passed by systemd socket activation
+    systemd::init();
     
-    if !config_existed {
-        info!("Created default configuration file: {}", config_path);
-        info!("Please edit the configuration file to suit your needs");
-        info!("Current configuration contains example rules that bind to localhost");
-    } else {
-        info!("Loaded configuration from: {}", config_path);
+    match source {
+        ConfigSource::File { created: true } => {
+            info!("Created default configuration file: {}", config_path);
+            info!("Please edit the configuration file to suit your needs");
+            info!("Current configuration contains example rules that bind to localhost");
+        }
+        ConfigSource::File { created: false } => info!("Loaded configuration from: {}", config_path),
+        ConfigSource::CommandLine => info!("Forwarding as given on the command line, without a configuration file"),
+        ConfigSource::Environment => {
+            info!("Forwarding as given in PORTURE_TCP_<n> and PORTURE_UDP_<n> variables, without a configuration file");
+            if std::path::Path::new(config_path).exists() {
+                warn!("Not reading {}, as rules are set in the environment", config_path);
+            }
+        }
     }
+    let tags = tag_filter(matches);
+    if !tags.only.is_empty() {
+        info!("Only running rules tagged {}", tags.only.join(", "));
+    }
+    if !tags.skip.is_empty() {
+        info!("Not running rules tagged {}", tags.skip.join(", "));
+    }
+
+    // Looked up before anything is bound, and switched to once the initial
+    // rules are listening
+    let account = match config.global.as_ref().and_then(|g| g.user.as_deref()) {
+        Some(user) => privileges::resolve(user, config.global.as_ref().and_then(|g| g.group.as_deref()))?,
+        None => None,
+    };
+
+    // A process already serving on the upgrade socket hands its listening
+    // sockets over to this one
+    let upgrade_socket = config.global.as_ref().and_then(|g| g.upgrade_socket.clone());
+    let predecessor = match &upgrade_socket {
+        Some(path) => handoff::take_over(path).await,
+        None => None,
+    };
 
     // Get buffer size
     let buffer_size = config.global
@@ -107,71 +350,356 @@ async fn main() -> Result<()> {
 
     info!("Using buffer size: {} bytes", buffer_size);
 
-    // Start TCP forwarders
-    let mut tcp_tasks = Vec::new();
-    if let Some(tcp_rules) = config.tcp {
-        for rule in tcp_rules {
-            let forwarder = TcpForwarder::new(rule, buffer_size);
-            let task = tokio::spawn(async move {
-                if let Err(e) = forwarder.start().await {
-                    error!("TCP forwarder failed: {}", e);
-                }
-            });
-            tcp_tasks.push(task);
-        }
+    // Process-wide cap shared by every TCP forwarder
+    let connection_cap = config.global
+        .as_ref()
+        .and_then(|g| g.max_total_connections)
+        .map(|n| {
+            info!("Limiting total TCP connections to {}", n);
+            Arc::new(Semaphore::new(n))
+        });
+
+    // Hostname targets resolve through one shared, caching resolver
+    if let Err(e) = resolver::init(config.global.as_ref().and_then(|g| g.dns.as_ref())) {
+        warn!("{}, resolving hostnames with getaddrinfo", e);
     }
 
-    // Start UDP forwarders
-    let mut udp_tasks = Vec::new();
-    if let Some(udp_rules) = config.udp {
-        for rule in udp_rules {
-            let forwarder = UdpForwarder::new(rule, buffer_size);
-            let task = tokio::spawn(async move {
-                if let Err(e) = forwarder.start().await {
-                    error!("UDP forwarder failed: {}", e);
-                }
-            });
-            udp_tasks.push(task);
+    let geoip = match config.global.as_ref().and_then(|g| g.geoip_db.as_deref()) {
+        Some(path) => {
+            let geoip = GeoIp::open(path)?;
+            info!("Loaded GeoIP database: {}", path);
+            Some(geoip)
         }
+        None => None,
+    };
+
+    let bans = config.global
+        .as_ref()
+        .and_then(|g| g.ban.clone())
+        .map(BanList::new);
+
+    let certificates = CertRegistry::default();
+    let mut tunnel_tasks = Vec::new();
+    let tunnel = match &config.tunnel {
+        Some(tunnel) if tunnel.role == TunnelRole::Server => {
+            let server = tunnel::TunnelServer::start(tunnel, &certificates).await
+                .map_err(|e| anyhow::anyhow!("Failed to start tunnel server: {}", e))?;
+            Some(server)
+        }
+        Some(tunnel) => {
+            tunnel_tasks.push(tunnel::spawn_client(tunnel.clone()));
+            None
+        }
+        None => None,
+    };
+
+    // Kernels without io_uring, or with it disabled, keep to tokio
+    let ring = match config.global.as_ref().and_then(|g| g.io_backend) {
+        Some(IoBackend::IoUring) => match Ring::start() {
+            Ok(ring) => {
+                info!("Using io_uring for socket I/O");
+                Some(ring)
+            }
+            Err(e) => {
+                warn!("io_uring unavailable, using tokio: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+    // Loading the program takes root, so it happens before dropping it
+    let sockmap = match config.global.as_ref().and_then(|g| g.sockmap) {
+        Some(true) => match SockMap::load() {
+            Ok(sockmap) => {
+                info!("Relaying plain TCP connections with an eBPF sockmap");
+                Some(sockmap)
+            }
+            Err(e) => {
+                warn!("sockmap unavailable: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+    // Also loads the kernel's tls module, which takes root
+    let ktls = match config.global.as_ref().and_then(|g| g.ktls) {
+        Some(true) => match ktls::probe() {
+            Ok(()) => {
+                info!("Offloading TLS records to the kernel");
+                true
+            }
+            Err(e) => {
+                warn!("kTLS unavailable: {}", e);
+                false
+            }
+        },
+        _ => false,
+    };
+
+    let shared = Arc::new(SharedState {
+        buffer_size,
+        connection_cap,
+        geoip,
+        bans,
+        certificates,
+        tunnel,
+        consul: config.discovery.as_ref().and_then(|d| d.consul.clone()),
+        kubernetes: config.discovery.as_ref().and_then(|d| d.kubernetes.clone()),
+        udp_sessions: SessionRegistry::default(),
+        captures: CaptureRegistry::default(),
+        open_connections: OpenConnections::default(),
+        draining: AtomicBool::new(false),
+        ring,
+        sockmap,
+        ktls,
+    });
+
+    if shared.bans.is_some() {
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let mut prune_interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                prune_interval.tick().await;
+                if let Some(bans) = &shared.bans {
+                    bans.prune();
+                }
+            }
+        });
+    }
+
+    // Pick up renewed certificates for rules using cert/key files
+    {
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let mut reload_interval = tokio::time::interval(Duration::from_secs(30));
+            loop {
+                reload_interval.tick().await;
+                shared.certificates.reload(false);
+            }
+        });
     }
 
+    let mut admin = None;
+    if let Some(admin_addr) = config.global.as_ref().and_then(|g| g.admin_addr.as_deref()) {
+        let admin_addr: SocketAddr = admin_addr.parse()?;
+        let shared = shared.clone();
+        admin = Some(AbortOnDrop::spawn(async move {
+            if let Err(e) = admin::serve(admin_addr, shared).await {
+                error!("Admin API failed: {}", e);
+            }
+        }));
+    }
+
+    let bind_retry = config.global.as_ref().and_then(|g| g.bind_retry).unwrap_or(0);
+    let (mut supervisor, mut exited_rx) = Supervisor::new(shared.clone(), Duration::from_secs(bind_retry));
+    let mut etcd_rules = EtcdRules::default();
+    let mut etcd_rx = config.etcd.clone().map(etcd::spawn);
+    let mut docker_rules = DockerRules::default();
+    let mut docker_rx = config.docker.clone().map(docker::spawn);
+    // etcd and Docker may deliver rules at any time
+    let watching = etcd_rx.is_some() || docker_rx.is_some();
+
     // Check if we have any forwarders
-    if tcp_tasks.is_empty() && udp_tasks.is_empty() {
+    let file_rules = config.tcp.iter().flatten().count() + config.udp.iter().flatten().count();
+    if file_rules == 0 && tunnel_tasks.is_empty() && !watching {
         warn!("No forwarding rules configured. Nothing to do.");
         return Ok(());
     }
 
-    info!("Started {} TCP forwarders and {} UDP forwarders", 
-          tcp_tasks.len(), udp_tasks.len());
+    let failed = supervisor.apply(config.tcp.iter().flatten(), config.udp.iter().flatten()).await;
+    // Otherwise failed rules keep being retried while the others run
+    if matches.get_flag("strict-start") && !failed.is_empty() {
+        anyhow::bail!("failed to start {}", failed.iter().map(|name| format!("'{}'", name)).collect::<Vec<_>>().join(", "));
+    }
+    daemon::write_pid_file(matches.get_one::<String>("pid-file").map(String::as_str))?;
+    if let Some(account) = &account {
+        account.switch()?;
+    }
+    if let Some(mode) = config.global.as_ref().and_then(|g| g.seccomp) {
+        seccomp::install(mode, &shared)?;
+    }
+    daemon::ready()?;
+
+    // Once the rules have taken over the previous process's sockets, it
+    // stops, and this process accepts upgrades in turn
+    let mut takeover = predecessor.map(|predecessor| tokio::spawn(predecessor.finish()));
+    let mut upgrades = match (&upgrade_socket, &takeover) {
+        (Some(path), None) => bind_upgrades(path),
+        _ => None,
+    };
+    let mut handed_over = false;
+    let mut shutting_down = false;
 
     // Setup signal handling
-    let mut sigterm = tokio::signal::unix::signal(
-        tokio::signal::unix::SignalKind::terminate()
-    )?;
-    let mut sigint = tokio::signal::unix::signal(
-        tokio::signal::unix::SignalKind::interrupt()
-    )?;
+    let mut shutdown = signals::Shutdown::new()?;
+    let mut reload = signals::Reload::new()?;
 
-    // Wait for termination signal or all tasks to complete
-    let mut all_tasks = Vec::new();
-    all_tasks.extend(tcp_tasks);
-    all_tasks.extend(udp_tasks);
+    // Wait for termination signal or all forwarders to stop
+    let mut tunnels_running = !tunnel_tasks.is_empty();
+    let mut tunnels = futures::future::join_all(tunnel_tasks);
 
-    tokio::select! {
-        _ = sigterm.recv() => {
-            info!("Received SIGTERM, shutting down...");
+    loop {
+        tokio::select! {
+            signal = shutdown.recv() => {
+                info!("Received {}, shutting down...", signal);
+                shutting_down = true;
+                break;
+            }
+            _ = reload.recv() => {
+                match source {
+                    ConfigSource::CommandLine => {
+                        warn!("Received SIGHUP, but the rules were given on the command line; nothing to reload");
+                        continue;
+                    }
+                    ConfigSource::Environment => {
+                        warn!("Received SIGHUP, but the rules were given in environment variables; nothing to reload");
+                        continue;
+                    }
+                    ConfigSource::File { .. } => {}
+                }
+                info!("Received SIGHUP, reloading {}", config_path);
+                let loaded = Config::from_file(config_path, config_format(matches)).and_then(|mut new| {
+                    new.select_tags(&tags);
+                    new.validate().map(|_| new)
+                });
+                match loaded {
+                    Ok(new) => {
+                        if needs_restart(&config, &new) {
+                            warn!("Changes outside [[tcp]] and [[udp]] take effect after a restart");
+                        }
+                        config.tcp = new.tcp;
+                        config.udp = new.udp;
+                        apply_rules(&mut supervisor, &config, &etcd_rules, &docker_rules, &tags).await;
+                    }
+                    Err(e) => error!("Failed to reload {}: {}, keeping the current rules", config_path, e),
+                }
+            }
+            Some(snapshot) = async { etcd_rx.as_mut()?.recv().await } => {
+                etcd_rules.update(snapshot, &config);
+                apply_rules(&mut supervisor, &config, &etcd_rules, &docker_rules, &tags).await;
+            }
+            Some(rules) = async { docker_rx.as_mut()?.recv().await } => {
+                docker_rules = rules;
+                apply_rules(&mut supervisor, &config, &etcd_rules, &docker_rules, &tags).await;
+            }
+            Some(result) = async { Some(takeover.as_mut()?.await) } => {
+                takeover = None;
+                if let Err(e) = result.map_err(anyhow::Error::from).and_then(|result| result) {
+                    warn!("Taking over from the previous process: {:#}", e);
+                }
+                upgrades = upgrade_socket.as_deref().and_then(bind_upgrades);
+            }
+            Some(successor) = async { upgrades.as_ref()?.accept().await.ok() } => {
+                info!("A new process is taking over");
+                match successor.hand_over().await {
+                    Ok(successor) => {
+                        if let Some(upgrades) = upgrades.take() {
+                            upgrades.close();
+                        }
+                        drop(admin.take());
+                        supervisor.apply(std::iter::empty(), std::iter::empty()).await;
+                        if let Err(e) = successor.release().await {
+                            warn!("Failed to tell the new process to take over: {:#}", e);
+                        }
+                        handed_over = true;
+                        break;
+                    }
+                    Err(e) => warn!("Upgrade failed: {:#}, carrying on", e),
+                }
+            }
+            Some(key) = exited_rx.recv() => {
+                supervisor.exited(&key);
+            }
+            _ = &mut tunnels, if tunnels_running => {
+                tunnels_running = false;
+            }
         }
-        _ = sigint.recv() => {
-            info!("Received SIGINT, shutting down...");
+        // With etcd or Docker, rules may still arrive later
+        if supervisor.is_empty() && !tunnels_running && !watching {
+            warn!("All forwarders stopped");
+            break;
         }
-        result = futures::future::try_join_all(all_tasks) => {
-            match result {
-                Ok(_) => warn!("All forwarders stopped"),
-                Err(e) => error!("Forwarder error: {}", e),
-            }
+    }
+
+    // Connections accepted before the handover carry on until they close
+    if handed_over {
+        let drain_timeout = config.global.as_ref().and_then(|g| g.drain_timeout).unwrap_or(300);
+        info!("Handed over, waiting up to {}s for {} open connections", drain_timeout, shared.open_connections.count());
+        drain(&shared, drain_timeout, &mut shutdown).await;
+    }
+
+    // Nothing new is accepted, but what is under way may finish
+    if shutting_down {
+        let shutdown_timeout = config.global.as_ref().and_then(|g| g.shutdown_timeout).unwrap_or(30);
+        if let Some(upgrades) = upgrades.take() {
+            upgrades.close();
+        }
+        shared.draining.store(true, Ordering::Relaxed);
+        supervisor.stop_accepting().await;
+        let (connections, sessions) = (shared.open_connections.count(), shared.udp_sessions.open());
+        if shutdown_timeout > 0 && connections + sessions > 0 {
+            info!("Waiting up to {}s for {} connections and {} UDP sessions to finish", shutdown_timeout, connections, sessions);
+            drain(&shared, shutdown_timeout, &mut shutdown).await;
         }
     }
 
+    daemon::remove_pid_file();
     info!("Porture shutdown complete");
     Ok(())
 }
+
+// Waits up to `timeout` seconds for open connections and UDP sessions to
+// end; another shutdown signal cuts the wait short
+async fn drain(shared: &SharedState, timeout: u64, shutdown: &mut signals::Shutdown) {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(timeout);
+    loop {
+        let open = shared.open_connections.count() + shared.udp_sessions.open();
+        if open == 0 {
+            break;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            warn!("Closing {} connections and sessions still open after {}s", open, timeout);
+            break;
+        }
+        tokio::select! {
+            signal = shutdown.recv() => {
+                info!("Received {}, closing {} connections and sessions now", signal, open);
+                break;
+            }
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+    }
+}
+
+fn bind_upgrades(path: &str) -> Option<handoff::Upgrades> {
+    handoff::Upgrades::bind(path).map_err(|e| error!("{:#}", e)).ok()
+}
+
+// The file's rules plus those from etcd and Docker
+// The config's rules are already filtered by tag, the ones from etcd are
+// filtered here. Rules from Docker labels have no tags and always run.
+async fn apply_rules(
+    supervisor: &mut Supervisor,
+    config: &Config,
+    etcd_rules: &EtcdRules,
+    docker_rules: &DockerRules,
+    tags: &TagFilter,
+) {
+    supervisor.apply(
+        config.tcp.iter().flatten()
+            .chain(etcd_rules.tcp().filter(|rule| tags.selects(rule.tags.as_deref())))
+            .chain(docker_rules.tcp.iter()),
+        config.udp.iter().flatten()
+            .chain(etcd_rules.udp().filter(|rule| tags.selects(rule.tags.as_deref())))
+            .chain(docker_rules.udp.iter()),
+    ).await;
+}
+
+// [global], [tunnel], [discovery], [etcd] and [docker] are only read at startup
+fn needs_restart(current: &Config, new: &Config) -> bool {
+    let settings = |config: &Config| {
+        serde_json::to_string(&(&config.global, &config.tunnel, &config.discovery, &config.etcd, &config.docker))
+            .unwrap_or_default()
+    };
+    settings(current) != settings(new)
+}
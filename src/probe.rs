@@ -0,0 +1,336 @@
+use crate::check;
+use porture_core::config::{parse_endpoint, parse_hex, TcpMode, TcpRule, UdpMode, UdpRule};
+use porture_core::resolver;
+#[cfg(unix)]
+use porture_core::unix_socket;
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::Instant;
+
+// Checking that the configured targets answer (`porture probe`), as a
+// pre-flight report: TCP targets are connected to and UDP targets sent a
+// datagram that should get a reply. DNS and NTP targets get a real query;
+// other UDP services may ignore what they don't understand, so without
+// --payload their silence isn't counted as a failure. The exit status is 0
+// only if no target failed. `porture check --probe` connects to the TCP
+// targets the same way.
+
+// How long a UDP target has to reply, unless --timeout says otherwise
+const UDP_REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+// Sent to UDP services without a query of their own
+const GENERIC_PAYLOAD: &[u8] = b"porture probe\n";
+// A recursive query for the root name servers
+const DNS_QUERY: [u8; 17] = [0x70, 0x72, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1];
+// A client request (version 3, mode 3) with everything else left at zero
+const NTP_REQUEST: [u8; 48] = {
+    let mut request = [0u8; 48];
+    request[0] = 0x1b;
+    request
+};
+
+pub fn command() -> Command {
+    Command::new("probe")
+        .about("Check that every configured target answers")
+        .arg(
+            Arg::new("rule")
+                .value_name("RULE")
+                .help("Names of the rules to probe; by default all of them")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("How long to wait for each target; by default a TCP rule's connect_timeout, or 2s for a UDP reply")
+                .value_parser(clap::value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("payload")
+                .long("payload")
+                .value_name("TEXT")
+                .help("Datagram to send to UDP targets, which then have to reply")
+                .conflicts_with("payload_hex")
+        )
+        .arg(
+            Arg::new("payload_hex")
+                .long("payload-hex")
+                .value_name("HEX")
+                .help("Like --payload, given as hex bytes")
+        )
+}
+
+pub fn run_command(matches: &ArgMatches, probe: &ArgMatches) -> Result<()> {
+    let (config, _) = check::load(matches)?;
+    let timeout = probe.get_one::<u64>("timeout").map(|seconds| Duration::from_secs(*seconds));
+    let payload = match (probe.get_one::<String>("payload"), probe.get_one::<String>("payload_hex")) {
+        (Some(text), _) => Some(text.as_bytes().to_vec()),
+        (None, Some(hex)) => Some(parse_hex(hex).map_err(|e| anyhow::anyhow!("--payload-hex: {}", e))?),
+        (None, None) => None,
+    };
+    let names: Vec<&String> = probe.get_many::<String>("rule").into_iter().flatten().collect();
+    let tcp_rules: Vec<&TcpRule> = config.tcp.iter().flatten().filter(|rule| selected(&names, &rule.rule_name())).collect();
+    let udp_rules: Vec<&UdpRule> = config.udp.iter().flatten().filter(|rule| selected(&names, &rule.rule_name())).collect();
+    if let Some(name) = names.iter().find(|name| {
+        !tcp_rules.iter().any(|rule| rule.rule_name() == ***name) && !udp_rules.iter().any(|rule| rule.rule_name() == ***name)
+    }) {
+        anyhow::bail!("no rule named '{}'", name);
+    }
+
+    // One row per target, or per rule without any that can be probed
+    let mut rows: Vec<(String, Result<Target, &'static str>, Duration)> = Vec::new();
+    for rule in tcp_rules {
+        let wait = timeout.unwrap_or(Duration::from_secs(rule.connect_timeout_seconds()));
+        add_rows(&mut rows, rule.rule_name(), tcp_targets(rule), wait);
+    }
+    for rule in udp_rules {
+        add_rows(&mut rows, rule.rule_name(), udp_targets(rule), timeout.unwrap_or(UDP_REPLY_TIMEOUT));
+    }
+
+    // Hostnames resolve as they would when forwarding, or through
+    // getaddrinfo if the resolver can't be set up
+    let _ = resolver::init(config.global.as_ref().and_then(|g| g.dns.as_ref()));
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let outcomes = runtime.block_on(futures::future::join_all(rows.iter().map(|(_, target, wait)| async {
+        match target {
+            Ok(target) => target.probe(*wait, payload.as_deref()).await,
+            Err(reason) => Outcome::Skipped(reason),
+        }
+    })));
+    let rows: Vec<([String; 3], Outcome)> = rows.iter().zip(outcomes)
+        .map(|((rule, target, _), outcome)| {
+            let (protocol, target) = match target {
+                Ok(target) => (target.protocol().to_string(), target.to_string()),
+                Err(_) => ("-".to_string(), "-".to_string()),
+            };
+            ([rule.clone(), protocol, target], outcome)
+        })
+        .collect();
+    print_table(&rows);
+
+    let failed = rows.iter().filter(|(_, outcome)| matches!(outcome, Outcome::Failed(_))).count();
+    let probed = rows.iter().filter(|(_, outcome)| !matches!(outcome, Outcome::Skipped(_))).count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} targets failed", failed, probed);
+    }
+    Ok(())
+}
+
+fn selected(names: &[&String], name: &str) -> bool {
+    names.is_empty() || names.iter().any(|selected| *selected == name)
+}
+
+fn add_rows(
+    rows: &mut Vec<(String, Result<Target, &'static str>, Duration)>,
+    rule: String,
+    targets: Result<Vec<Target>, &'static str>,
+    wait: Duration,
+) {
+    match targets {
+        Ok(targets) => rows.extend(targets.into_iter().map(|target| (rule.clone(), Ok(target), wait))),
+        Err(reason) => rows.push((rule, Err(reason), wait)),
+    }
+}
+
+// Where a rule sends its traffic
+pub enum Target {
+    Tcp(String, u16),
+    Unix(String),
+    Udp(String, u16),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Tcp(host, port) | Target::Udp(host, port) if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            Target::Tcp(host, port) | Target::Udp(host, port) => write!(f, "{}:{}", host, port),
+            Target::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+pub enum Outcome {
+    // How long the connection or reply took, and the reply's size
+    Answered(Duration, Option<usize>),
+    // A UDP target that may not reply to the generic payload
+    Silent(Duration),
+    Failed(String),
+    Skipped(&'static str),
+}
+
+impl Target {
+    fn protocol(&self) -> &'static str {
+        match self {
+            Target::Tcp(..) => "tcp",
+            Target::Unix(_) => "unix",
+            Target::Udp(..) => "udp",
+        }
+    }
+
+    pub async fn probe(&self, timeout: Duration, payload: Option<&[u8]>) -> Outcome {
+        let started = Instant::now();
+        let result = match self {
+            Target::Tcp(host, port) => connect(host, *port, timeout).await.map(|()| None),
+            Target::Unix(path) => within(timeout, connect_unix(path)).await.map(|()| None),
+            Target::Udp(host, port) => {
+                let (payload, must_reply) = match payload {
+                    Some(payload) => (payload, true),
+                    None => match port {
+                        53 => (&DNS_QUERY[..], true),
+                        123 => (&NTP_REQUEST[..], true),
+                        _ => (GENERIC_PAYLOAD, false),
+                    },
+                };
+                match exchange(host, *port, payload, timeout).await {
+                    Ok(Some(size)) => Ok(Some(size)),
+                    Ok(None) if must_reply => Err(format!("no reply within {}s", timeout.as_secs())),
+                    Ok(None) => return Outcome::Silent(timeout),
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        match result {
+            Ok(reply) => Outcome::Answered(started.elapsed(), reply),
+            Err(e) => Outcome::Failed(e),
+        }
+    }
+}
+
+// What can be probed of a TCP rule's targets, or why nothing can
+pub fn tcp_targets(rule: &TcpRule) -> Result<Vec<Target>, &'static str> {
+    if rule.mode().is_proxy() {
+        return Err("clients choose the targets");
+    } else if rule.mode().is_builtin() {
+        return Err("porture answers itself");
+    } else if rule.target_srv.is_some() || rule.target_consul.is_some() || rule.target_kubernetes.is_some() {
+        return Err("targets are discovered at runtime");
+    } else if rule.upstream_proxy.is_some() {
+        return Err("reached through upstream_proxy");
+    } else if rule.tunnel.unwrap_or(false) {
+        return Err("reached through the tunnel");
+    }
+    if let Some(path) = rule.target_unix_path() {
+        return Ok(vec![Target::Unix(path.to_string())]);
+    }
+    if rule.mode() == TcpMode::UdpInTcpServer {
+        return Ok(vec![Target::Udp(rule.target_addr.clone(), rule.target_port)]);
+    }
+
+    let mut targets = Vec::new();
+    if !rule.target_addr.is_empty() {
+        targets.push((rule.target_addr.clone(), rule.target_port));
+    }
+    let routes = rule.sni_routes.iter().chain(&rule.host_routes).flat_map(|routes| routes.values());
+    let sniff_routes = rule.sniff_routes.iter().flat_map(|routes| [&routes.tls, &routes.http]).flatten();
+    for endpoint in routes.chain(sniff_routes) {
+        if let Ok(target) = parse_endpoint(endpoint)
+            && !targets.contains(&target)
+        {
+            targets.push(target);
+        }
+    }
+    Ok(targets.into_iter().map(|(host, port)| Target::Tcp(host, port)).collect())
+}
+
+fn udp_targets(rule: &UdpRule) -> Result<Vec<Target>, &'static str> {
+    match rule.mode() {
+        UdpMode::MdnsReflector => Err("mDNS reflector"),
+        mode if mode.is_builtin() => Err("porture answers itself"),
+        // The tunnel's far end is a porture TCP rule
+        UdpMode::UdpInTcpClient => Ok(vec![Target::Tcp(rule.target_addr.clone(), rule.target_port)]),
+        _ if rule.broadcast.unwrap_or(false) => Err("broadcast target"),
+        _ => Ok(vec![Target::Udp(rule.target_addr.clone(), rule.target_port)]),
+    }
+}
+
+// Any of the target's addresses will do, as when forwarding
+async fn connect(host: &str, port: u16, timeout: Duration) -> Result<(), String> {
+    let addrs = resolver::lookup(host, port).await.map_err(|e| e.to_string())?;
+    let mut last_error = format!("{} has no addresses", host);
+    for addr in addrs {
+        match within(timeout, TcpStream::connect(addr)).await {
+            Ok(()) => return Ok(()),
+            Err(e) => last_error = format!("{}: {}", addr, e),
+        }
+    }
+    Err(last_error)
+}
+
+// The size of the reply, or None if there was none in time. A connected
+// socket reports the ICMP port unreachable of a closed port as an error.
+async fn exchange(host: &str, port: u16, payload: &[u8], timeout: Duration) -> Result<Option<usize>, String> {
+    let addr = *resolver::lookup(host, port).await.map_err(|e| e.to_string())?
+        .first()
+        .ok_or_else(|| format!("{} has no addresses", host))?;
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local).await.map_err(|e| e.to_string())?;
+    socket.connect(addr).await.map_err(|e| e.to_string())?;
+    socket.send(payload).await.map_err(|e| e.to_string())?;
+    let mut buffer = vec![0u8; 65536];
+    match tokio::time::timeout(timeout, socket.recv(&mut buffer)).await {
+        Ok(Ok(size)) => Ok(Some(size)),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Ok(None),
+    }
+}
+
+async fn within<T>(timeout: Duration, connect: impl Future<Output = std::io::Result<T>>) -> Result<(), String> {
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("timed out after {}s", timeout.as_secs())),
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> std::io::Result<()> {
+    unix_socket::connect(path).await.map(drop)
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix sockets require a Unix platform"))
+}
+
+fn print_table(rows: &[([String; 3], Outcome)]) {
+    const HEADER: [&str; 4] = ["RULE", "PROTOCOL", "TARGET", "RESULT"];
+    let rows: Vec<[String; 4]> = rows.iter()
+        .map(|([rule, protocol, target], outcome)| [rule.clone(), protocol.clone(), target.clone(), outcome.to_string()])
+        .collect();
+    let mut widths = HEADER.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: [&str; 4]| {
+        let line: Vec<String> = cells.iter().zip(widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(HEADER);
+    for row in &rows {
+        print_row(row.each_ref().map(String::as_str));
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Answered(elapsed, None) => write!(f, "ok, connected in {}", millis(*elapsed)),
+            Outcome::Answered(elapsed, Some(size)) => write!(f, "ok, {}-byte reply in {}", size, millis(*elapsed)),
+            Outcome::Silent(timeout) => write!(f, "no reply within {}s, which may be normal", timeout.as_secs()),
+            Outcome::Failed(e) => write!(f, "FAILED: {}", e),
+            Outcome::Skipped(reason) => write!(f, "skipped: {}", reason),
+        }
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,152 @@
+use porture_core::record::Record;
+use anyhow::{Context, Result};
+use clap::{Arg, ArgMatches, Command};
+use std::io::BufRead;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{Instant, sleep, sleep_until};
+
+// Playing a connection recorded with record_dir back (`porture replay`):
+// what the client sent goes to a target at the recorded pace, and what comes
+// back is compared with what the target answered then. The exit status is 0
+// only if it answered the same, so a protocol bug seen through porture can
+// be reproduced against a fixed or unfixed backend.
+
+pub fn command() -> Command {
+    Command::new("replay")
+        .about("Play a recorded TCP connection back against a target")
+        .arg(
+            Arg::new("file")
+                .value_name("FILE")
+                .help("A recording from a rule's record_dir")
+                .required(true)
+        )
+        .arg(
+            Arg::new("target")
+                .long("target")
+                .value_name("HOST:PORT")
+                .help("Where to connect")
+                .required(true)
+        )
+        .arg(
+            Arg::new("speed")
+                .long("speed")
+                .value_name("FACTOR")
+                .help("Play back this many times as fast; 0 sends everything without waiting")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("1")
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("How long to wait for the target to close after everything is sent")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("10")
+        )
+}
+
+pub fn run_command(replay: &ArgMatches) -> Result<()> {
+    let path = replay.get_one::<String>("file").unwrap();
+    let target = replay.get_one::<String>("target").unwrap();
+    let speed = *replay.get_one::<f64>("speed").unwrap();
+    if !speed.is_finite() || speed < 0.0 {
+        anyhow::bail!("--speed must be 0 or more");
+    }
+    let wait = Duration::from_secs(*replay.get_one::<u64>("timeout").unwrap());
+    let recording = Recording::read(path)?;
+    if recording.truncated {
+        eprintln!("Warning: the recording was cut short, so the end of the connection is missing");
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let received = runtime.block_on(play(&recording, target, speed, wait))?;
+    let sent: usize = recording.uploads.iter().map(|(_, data)| data.len()).sum();
+    println!(
+        "Sent {} bytes in {} writes to {}, received {} bytes ({} recorded)",
+        sent, recording.uploads.len(), target, received.len(), recording.downloaded.len()
+    );
+    match received.iter().zip(&recording.downloaded).position(|(got, recorded)| got != recorded) {
+        Some(offset) => anyhow::bail!("The response differs from the recording at byte {}", offset),
+        None if received.len() != recording.downloaded.len() => {
+            anyhow::bail!("The response matches the recording, but is {} bytes instead of {}", received.len(), recording.downloaded.len())
+        }
+        None => println!("The response matches the recording"),
+    }
+    Ok(())
+}
+
+struct Recording {
+    // What the client sent, with milliseconds since it connected
+    uploads: Vec<(u64, Vec<u8>)>,
+    // Everything the target sent back
+    downloaded: Vec<u8>,
+    truncated: bool,
+}
+
+impl Recording {
+    fn read(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("failed to open {}", path))?;
+        let mut recording = Recording { uploads: Vec::new(), downloaded: Vec::new(), truncated: false };
+        for (number, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record = serde_json::from_str(&line)
+                .with_context(|| format!("{} line {} is not a recording", path, number + 1))?;
+            let data = record.data().with_context(|| format!("{} line {} has invalid data", path, number + 1))?;
+            match record {
+                Record::Session { .. } => {}
+                Record::Upload { ms, .. } => recording.uploads.push((ms, data)),
+                Record::Download { .. } => recording.downloaded.extend_from_slice(&data),
+                Record::End { truncated, .. } => recording.truncated = truncated,
+            }
+        }
+        Ok(recording)
+    }
+}
+
+// What the target sent back, until it closed or stopped sending for `wait`
+async fn play(recording: &Recording, target: &str, speed: f64, wait: Duration) -> Result<Vec<u8>> {
+    let stream = TcpStream::connect(target).await.with_context(|| format!("failed to connect to {}", target))?;
+    let (mut reader, mut writer) = stream.into_split();
+    let started = Instant::now();
+    let (sent_all, mut all_sent) = watch::channel(false);
+    let send = async {
+        for (ms, data) in &recording.uploads {
+            if speed > 0.0 {
+                sleep_until(started + Duration::from_millis(*ms).div_f64(speed)).await;
+            }
+            writer.write_all(data).await?;
+        }
+        writer.shutdown().await?;
+        let _ = sent_all.send(true);
+        Ok::<_, std::io::Error>(())
+    };
+    let receive = async {
+        let mut received = Vec::new();
+        let mut buffer = vec![0u8; 16384];
+        loop {
+            // Only once everything is sent, as the target may not answer
+            // before then
+            let idle = async {
+                let _ = all_sent.wait_for(|sent| *sent).await;
+                sleep(wait).await;
+            };
+            tokio::select! {
+                read = reader.read(&mut buffer) => match read? {
+                    0 => break,
+                    n => received.extend_from_slice(&buffer[..n]),
+                },
+                _ = idle => break,
+            }
+        }
+        Ok::<_, std::io::Error>(received)
+    };
+    let (sent, received) = tokio::join!(send, receive);
+    sent.with_context(|| format!("failed to send to {}", target))?;
+    received.with_context(|| format!("failed to receive from {}", target))
+}
@@ -0,0 +1,207 @@
+use crate::logging;
+use crate::signals;
+use anyhow::{Context, Result};
+use clap::{ArgMatches, Command};
+use log::{error, info, LevelFilter, Log, Metadata, Record};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::windows::ffi::OsStrExt;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+    ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::System::EventLog::{
+    RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+};
+
+// Running as a Windows service (`porture service install`). The service
+// control manager starts `porture service run` with the config file given
+// at install time; a stop request from it shuts porture down like Ctrl-C
+// would, and log output goes to the Application event log under the source
+// "porture".
+
+const NAME: &str = "porture";
+const DISPLAY_NAME: &str = "Porture";
+
+static LOGGER: OnceLock<EventLog> = OnceLock::new();
+
+pub fn command() -> Command {
+    Command::new("service")
+        .about("Manage the Windows service")
+        .subcommand_required(true)
+        .subcommand(Command::new("install").about("Install porture as a service that starts with Windows"))
+        .subcommand(Command::new("uninstall").about("Stop and remove the service"))
+        .subcommand(Command::new("run").about("Run as the service; started by the service control manager"))
+}
+
+pub fn run_command(matches: &ArgMatches, service: &ArgMatches) -> Result<()> {
+    match service.subcommand_name() {
+        Some("install") => install(matches.get_one::<String>("config").unwrap(), matches.get_one::<String>("format")),
+        Some("uninstall") => uninstall(),
+        // Blocks until the service stops
+        Some("run") => service_dispatcher::start(NAME, ffi_service_main)
+            .context("failed to start the service; `porture service run` only works when started as a service"),
+        _ => unreachable!("clap requires a service subcommand"),
+    }
+}
+
+fn install(config_path: &str, format: Option<&String>) -> Result<()> {
+    // Services start in the system directory, so the path has to be absolute
+    let config_path = std::path::absolute(config_path)
+        .with_context(|| format!("invalid config path '{}'", config_path))?;
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+        .context("failed to open the service control manager (run as Administrator)")?;
+    let mut launch_arguments = vec!["--config".into(), config_path.clone().into_os_string()];
+    if let Some(format) = format {
+        launch_arguments.extend(["--format".into(), format.into()]);
+    }
+    launch_arguments.extend(["service".into(), "run".into()]);
+    let info = ServiceInfo {
+        name: OsString::from(NAME),
+        display_name: OsString::from(DISPLAY_NAME),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe()?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        // LocalSystem
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .context("failed to install the service")?;
+    service.set_description(env!("CARGO_PKG_DESCRIPTION"))?;
+    println!("Installed service '{}' with configuration {}", NAME, config_path.display());
+    println!("Start it with: sc start {}", NAME);
+    Ok(())
+}
+
+fn uninstall() -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .context("failed to open the service control manager (run as Administrator)")?;
+    let service = manager.open_service(NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+        .context("failed to open the service")?;
+    // Removed once it has stopped and every handle to it is closed
+    service.delete().context("failed to uninstall the service")?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop().context("failed to stop the service")?;
+    }
+    println!("Uninstalled service '{}'", NAME);
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    let logger = LOGGER.get_or_init(EventLog::register);
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+    if let Err(e) = serve() {
+        error!("{:#}", e);
+    }
+}
+
+fn serve() -> Result<()> {
+    let status = service_control_handler::register(NAME, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            signals::stop();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    set_state(&status, ServiceState::Running, ServiceExitCode::NO_ERROR)?;
+
+    // The service's own arguments are those it was installed with
+    let result = crate::run(&crate::cli().get_matches(), log_to_event_log);
+    if let Err(e) = &result {
+        error!("{:#}", e);
+    }
+    let exit_code = match result {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    info!("Service stopped");
+    set_state(&status, ServiceState::Stopped, exit_code)?;
+    Ok(())
+}
+
+fn set_state(status: &ServiceStatusHandle, state: ServiceState, exit_code: ServiceExitCode) -> Result<()> {
+    status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })?;
+    Ok(())
+}
+
+// Sets the log filter once the config is read; same syntax as RUST_LOG
+pub fn log_to_event_log(log_level: &str) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let filter = env_logger::Builder::new().parse_filters(log_level).build();
+    log::set_max_level(filter.filter());
+    *logger.filter.write().unwrap() = filter;
+}
+
+struct EventLog {
+    source: HANDLE,
+    filter: RwLock<env_logger::Logger>,
+}
+
+// The handle is only passed to ReportEventW, which may be called from any
+// thread
+unsafe impl Send for EventLog {}
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+    fn register() -> Self {
+        let source = unsafe { RegisterEventSourceW(std::ptr::null(), wide(OsStr::new(NAME)).as_ptr()) };
+        Self { source, filter: RwLock::new(env_logger::Builder::new().parse_filters("info").build()) }
+    }
+}
+
+impl Log for EventLog {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        logging::enabled(&self.filter.read().unwrap(), metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.source.is_null() || !logging::matches(&self.filter.read().unwrap(), record) {
+            return;
+        }
+        let kind = match record.level() {
+            log::Level::Error => EVENTLOG_ERROR_TYPE,
+            log::Level::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = wide(OsStr::new(&format!("{}: {}", record.target(), record.args())));
+        let strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(self.source, kind, 0, 0, std::ptr::null_mut(), 1, 0, strings.as_ptr(), std::ptr::null());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// NUL-terminated UTF-16
+fn wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(std::iter::once(0)).collect()
+}
@@ -0,0 +1,101 @@
+use std::io;
+
+// Signals porture acts on. On Unix, SIGTERM and SIGINT shut it down and
+// SIGHUP reloads the rules. Windows has neither: Ctrl-C, Ctrl-Break,
+// closing the console window and, for the service, a stop request shut it
+// down, and the rules are reloaded by restarting it.
+
+// Stop requests from the service control manager
+#[cfg(windows)]
+static STOP: tokio::sync::Notify = tokio::sync::Notify::const_new();
+
+#[cfg(unix)]
+pub struct Shutdown {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Shutdown {
+    pub fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    // Waits for the next shutdown signal and returns its name
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
+
+#[cfg(windows)]
+pub struct Shutdown {
+    ctrl_c: tokio::signal::windows::CtrlC,
+    ctrl_break: tokio::signal::windows::CtrlBreak,
+    ctrl_close: tokio::signal::windows::CtrlClose,
+}
+
+#[cfg(windows)]
+impl Shutdown {
+    pub fn new() -> io::Result<Self> {
+        use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close};
+        Ok(Self {
+            ctrl_c: ctrl_c()?,
+            ctrl_break: ctrl_break()?,
+            ctrl_close: ctrl_close()?,
+        })
+    }
+
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.ctrl_c.recv() => "Ctrl-C",
+            _ = self.ctrl_break.recv() => "Ctrl-Break",
+            // Windows ends the process a few seconds after this
+            _ = self.ctrl_close.recv() => "console close",
+            _ = STOP.notified() => "service stop",
+        }
+    }
+}
+
+// Shuts porture down as if by Ctrl-C; called from the service control
+// handler's thread
+#[cfg(windows)]
+pub fn stop() {
+    STOP.notify_one();
+}
+
+#[cfg(unix)]
+pub struct Reload(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Reload {
+    pub fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self(signal(SignalKind::hangup())?))
+    }
+
+    // Waits for the next SIGHUP
+    pub async fn recv(&mut self) {
+        self.0.recv().await;
+    }
+}
+
+#[cfg(windows)]
+pub struct Reload;
+
+#[cfg(windows)]
+impl Reload {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self)
+    }
+
+    pub async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}